@@ -189,6 +189,7 @@ fi
 %{_prefix}/libexec/libostree/ext/*
 %endif
 %{_unitdir}/*
+%{_datadir}/polkit-1/actions/org.containers.bootc.policy
 %{_mandir}/man*/*bootc*
 
 %files -n system-reinstall-bootc
@@ -146,7 +146,9 @@ pub struct PartitionTable {
     // pub unit: String,
     // pub firstlba: u64,
     // pub lastlba: u64,
-    // pub sectorsize: u64,
+    /// The logical sector size; partition offsets and sizes are in these units.
+    /// This may be missing with older versions of util-linux.
+    pub sectorsize: Option<u64>,
     pub partitions: Vec<Partition>,
 }
 
@@ -161,6 +163,11 @@ impl PartitionTable {
         self.device.as_str().into()
     }
 
+    /// The logical sector size in bytes, defaulting to 512 if unknown.
+    pub fn sector_size(&self) -> u64 {
+        self.sectorsize.unwrap_or(512)
+    }
+
     // Find the partition with the given offset (starting at 1)
     #[allow(dead_code)]
     pub fn find_partno(&self, partno: u32) -> Result<&Partition> {
@@ -551,6 +558,7 @@ mod test {
             table.partitiontable.find("/dev/loop0p2").unwrap().size,
             20961247
         );
+        assert_eq!(table.partitiontable.sector_size(), 512);
         Ok(())
     }
 
@@ -90,6 +90,23 @@ pub struct Diff {
     removed: Vec<PathBuf>,
 }
 
+impl Diff {
+    /// Paths that exist in the current tree but not in the pristine one
+    pub fn added(&self) -> &[PathBuf] {
+        &self.added
+    }
+
+    /// Paths that exist in both trees but differ
+    pub fn modified(&self) -> &[PathBuf] {
+        &self.modified
+    }
+
+    /// Paths that exist in the pristine tree but not in the current one
+    pub fn removed(&self) -> &[PathBuf] {
+        &self.removed
+    }
+}
+
 fn collect_all_files(
     root: &Directory<CustomMetadata>,
     current_path: PathBuf,
@@ -13,6 +13,8 @@ depends() {
 install() {
     local service=bootc-root-setup.service
     dracut_install /usr/lib/bootc/initramfs-setup
+    # Optional; used to measure the composefs image if configured
+    inst_multiple -o /usr/lib/systemd/systemd-pcrextend
     inst_simple "${systemdsystemunitdir}/${service}"
     mkdir -p "${initdir}${systemdsystemconfdir}/initrd-root-fs.target.wants"
     ln_r "${systemdsystemunitdir}/${service}" \
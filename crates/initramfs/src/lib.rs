@@ -1,17 +1,26 @@
 //! Mount helpers for bootc-initramfs
 
 use std::{
-    ffi::OsString,
+    ffi::{CStr, OsStr, OsString},
     fmt::Debug,
+    fs::File,
     io::ErrorKind,
-    os::fd::{AsFd, AsRawFd, OwnedFd},
-    path::{Path, PathBuf},
+    os::{
+        fd::{AsFd, AsRawFd, OwnedFd},
+        unix::ffi::OsStrExt,
+    },
+    path::{Component, Path, PathBuf},
+    process::Command,
 };
 
 use anyhow::{Context, Result};
 use clap::Parser;
 use rustix::{
-    fs::{major, minor, mkdirat, openat, stat, symlink, Mode, OFlags, CWD},
+    fs::{
+        chmodat, chownat, lgetxattr, llistxattr, lsetxattr, major, minor, mkdirat, mknodat, openat,
+        readlinkat, stat, statat, symlink, symlinkat, AtFlags, FileType, Gid, Mode, OFlags, Stat,
+        Uid, XattrFlags, CWD,
+    },
     io::Errno,
     mount::{
         fsconfig_create, fsconfig_set_string, fsmount, open_tree, unmount, FsMountFlags,
@@ -74,20 +83,58 @@ fn set_mount_readonly(fd: impl AsFd) -> Result<()> {
     mount_setattr(fd, libc::AT_EMPTY_PATH, &attr)
 }
 
+/// Set additional attributes, such as `nosuid`, on a mount
+#[context("Setting mount options")]
+fn set_mount_attrs(fd: impl AsFd, attrs: MountAttrFlags) -> Result<()> {
+    if attrs.is_empty() {
+        return Ok(());
+    }
+    let attr = MountAttr {
+        attr_set: attrs.bits().into(),
+        attr_clr: 0,
+        propagation: 0,
+        userns_fd: 0,
+    };
+    mount_setattr(fd, libc::AT_EMPTY_PATH, &attr)
+}
+
+/// Parses comma-separated mount options, e.g. `nosuid,nodev`
+fn parse_mount_options(options: &str) -> Result<MountAttrFlags> {
+    options
+        .split(',')
+        .map(str::trim)
+        .filter(|o| !o.is_empty())
+        .try_fold(MountAttrFlags::empty(), |attrs, option| {
+            let attr = match option {
+                "ro" => MountAttrFlags::MOUNT_ATTR_RDONLY,
+                "nosuid" => MountAttrFlags::MOUNT_ATTR_NOSUID,
+                "nodev" => MountAttrFlags::MOUNT_ATTR_NODEV,
+                "noexec" => MountAttrFlags::MOUNT_ATTR_NOEXEC,
+                "nodiratime" => MountAttrFlags::MOUNT_ATTR_NODIRATIME,
+                o => anyhow::bail!("Unsupported mount option {o:?}"),
+            };
+            Ok(attrs | attr)
+        })
+}
+
 // Config file
-#[derive(Clone, Copy, Debug, Deserialize)]
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Eq)]
 #[serde(rename_all = "lowercase")]
 enum MountType {
     None,
     Bind,
     Overlay,
     Transient,
+    /// A tmpfs populated with a copy of the directory in the image
+    Tmpfs,
 }
 
 #[derive(Debug, Default, Deserialize)]
 struct RootConfig {
     #[serde(default)]
     transient: bool,
+    /// Maximum size of the tmpfs backing a transient root, e.g. `2G` or `50%`
+    size: Option<String>,
 }
 
 #[derive(Debug, Default, Deserialize)]
@@ -95,6 +142,43 @@ struct MountConfig {
     mount: Option<MountType>,
     #[serde(default)]
     transient: bool,
+    /// Comma-separated mount options, e.g. `nosuid,nodev`
+    options: Option<String>,
+    /// Maximum size of the tmpfs backing transient and tmpfs mounts
+    size: Option<String>,
+}
+
+/// An additional directory mounted from the deployment state
+#[derive(Debug, Deserialize)]
+struct StateMountConfig {
+    /// The mount point, which must exist in the image
+    path: PathBuf,
+    /// The directory to mount, relative to the deployment state directory; defaults to
+    /// `path` in the state shared by all deployments, like `/var`
+    source: Option<PathBuf>,
+    #[serde(flatten)]
+    config: MountConfig,
+}
+
+/// The state shared by all deployments, relative to a deployment state directory
+const SHARED_STATE: &str = "../../os/default";
+
+#[derive(Debug, Default, Deserialize)]
+struct MeasureConfig {
+    /// The PCR to extend with the booted image; nothing is measured if unset
+    pcr: Option<u32>,
+    /// Fail instead of skipping the measurement if there is no TPM
+    #[serde(default)]
+    required: bool,
+    /// The TPM2 device to use, as understood by systemd-pcrextend's `--tpm2-device`
+    tpm2_device: Option<String>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+struct FallbackConfig {
+    /// Boot the last image which was mounted successfully if the requested one fails
+    #[serde(default)]
+    enabled: bool,
 }
 
 #[derive(Deserialize, Default)]
@@ -105,6 +189,12 @@ struct Config {
     var: MountConfig,
     #[serde(default)]
     root: RootConfig,
+    #[serde(default)]
+    measure: MeasureConfig,
+    #[serde(default)]
+    mounts: Vec<StateMountConfig>,
+    #[serde(default)]
+    fallback: FallbackConfig,
 }
 
 /// Command-line arguments
@@ -178,8 +268,122 @@ fn ensure_dir(dirfd: impl AsFd, name: &str) -> Result<OwnedFd> {
     open_dir(dirfd, name)
 }
 
-#[context("Bind mounting to path {path}")]
-fn bind_mount(fd: impl AsFd, path: &str) -> Result<OwnedFd> {
+/// Creates the directory `path` relative to `dirfd`, along with any missing parents
+#[context("Creating dir {path:?}")]
+fn ensure_dir_all(dirfd: impl AsFd, path: &Path) -> Result<()> {
+    let mut dir = open_dir(dirfd, ".")?;
+    for component in path.components() {
+        match mkdirat(&dir, component.as_os_str(), 0o755.into()) {
+            Ok(()) | Err(Errno::EXIST) => {}
+            Err(err) => Err(err)?,
+        }
+        dir = open_dir(&dir, component.as_os_str())?;
+    }
+    Ok(())
+}
+
+/// Returns a path for `name` relative to `dirfd`, for path-based calls
+fn proc_fd_path(dirfd: impl AsFd, name: &CStr) -> PathBuf {
+    Path::new(&format!("/proc/self/fd/{}", dirfd.as_fd().as_raw_fd()))
+        .join(OsStr::from_bytes(name.to_bytes()))
+}
+
+/// Copies the ownership, permissions and extended attributes such as the SELinux
+/// label of `name` in `src`, whose status is `st`, to `name` in `dest`
+fn copy_metadata(src: impl AsFd, dest: impl AsFd, name: &CStr, st: &Stat) -> Result<()> {
+    chownat(
+        &dest,
+        name,
+        Some(Uid::from_raw(st.st_uid)),
+        Some(Gid::from_raw(st.st_gid)),
+        AtFlags::SYMLINK_NOFOLLOW,
+    )
+    .context("chown")?;
+    if FileType::from_raw_mode(st.st_mode) != FileType::Symlink {
+        chmodat(
+            &dest,
+            name,
+            Mode::from_raw_mode(st.st_mode),
+            AtFlags::empty(),
+        )
+        .context("chmod")?;
+    }
+
+    let src = proc_fd_path(&src, name);
+    let dest = proc_fd_path(&dest, name);
+    // Query the sizes first, then read
+    let mut names = Vec::<u8>::new();
+    let size = llistxattr(&src, &mut names[..]).context("llistxattr")?;
+    names.resize(size, 0);
+    let size = llistxattr(&src, &mut names[..]).context("llistxattr")?;
+    for xattr in names[..size].split(|&b| b == 0).filter(|n| !n.is_empty()) {
+        let mut value = Vec::<u8>::new();
+        let size = lgetxattr(&src, xattr, &mut value[..]).context("lgetxattr")?;
+        value.resize(size, 0);
+        let size = lgetxattr(&src, xattr, &mut value[..]).context("lgetxattr")?;
+        lsetxattr(&dest, xattr, &value[..size], XattrFlags::empty())
+            .with_context(|| format!("setxattr {}", String::from_utf8_lossy(xattr)))?;
+    }
+    Ok(())
+}
+
+/// Recursively copies the contents of the directory `src` into `dest`
+fn copy_dir_contents(src: impl AsFd, dest: impl AsFd) -> Result<()> {
+    let src = openat(
+        &src,
+        ".",
+        OFlags::RDONLY | OFlags::DIRECTORY | OFlags::CLOEXEC,
+        Mode::empty(),
+    )?;
+    for entry in rustix::fs::Dir::read_from(&src)? {
+        let entry = entry?;
+        let name = entry.file_name();
+        if name == c"." || name == c".." {
+            continue;
+        }
+
+        copy_entry(&src, &dest, name)?;
+    }
+    Ok(())
+}
+
+/// Copies the directory entry `name` in `src` to `dest`, recursively
+#[context("Copying {name:?}")]
+fn copy_entry(src: impl AsFd, dest: impl AsFd, name: &CStr) -> Result<()> {
+    let st = statat(&src, name, AtFlags::SYMLINK_NOFOLLOW)?;
+    let mode = Mode::from_raw_mode(st.st_mode);
+    match FileType::from_raw_mode(st.st_mode) {
+        FileType::Directory => {
+            mkdirat(&dest, name, mode)?;
+            let name = OsStr::from_bytes(name.to_bytes());
+            copy_dir_contents(open_dir(&src, name)?, open_dir(&dest, name)?)?;
+        }
+        FileType::RegularFile => {
+            let flags = OFlags::CLOEXEC | OFlags::NOFOLLOW;
+            let mut from = File::from(openat(&src, name, flags, Mode::empty())?);
+            let flags = flags | OFlags::WRONLY | OFlags::CREATE | OFlags::EXCL;
+            let mut to = File::from(openat(&dest, name, flags, mode)?);
+            std::io::copy(&mut from, &mut to)?;
+        }
+        FileType::Symlink => symlinkat(&readlinkat(&src, name, Vec::new())?, &dest, name)?,
+        // Sockets are useless without the process listening on them
+        FileType::Socket => return Ok(()),
+        file_type => mknodat(&dest, name, file_type, mode, st.st_rdev)?,
+    }
+    copy_metadata(&src, &dest, name, &st)
+}
+
+/// Recursively copies the directory `src` to `dest`, preserving ownership, permissions
+/// and extended attributes
+#[context("Copying directory")]
+fn copy_dir_all(src: impl AsFd, dest: impl AsFd) -> Result<()> {
+    copy_dir_contents(&src, &dest)?;
+    let st = statat(&src, c".", AtFlags::empty())?;
+    copy_metadata(&src, &dest, c".", &st)
+}
+
+#[context("Bind mounting to path {path:?}")]
+fn bind_mount(fd: impl AsFd, path: &Path) -> Result<OwnedFd> {
     let res = open_tree(
         fd.as_fd(),
         path,
@@ -192,8 +396,12 @@ fn bind_mount(fd: impl AsFd, path: &str) -> Result<OwnedFd> {
 }
 
 #[context("Mounting tmpfs")]
-fn mount_tmpfs() -> Result<OwnedFd> {
+fn mount_tmpfs(size: Option<&str>) -> Result<OwnedFd> {
     let tmpfs = FsHandle::open("tmpfs")?;
+    if let Some(size) = size {
+        fsconfig_set_string(tmpfs.as_fd(), "size", size)
+            .with_context(|| format!("Invalid tmpfs size {size:?}"))?;
+    }
     fsconfig_create(tmpfs.as_fd())?;
     Ok(fsmount(
         tmpfs.as_fd(),
@@ -203,7 +411,12 @@ fn mount_tmpfs() -> Result<OwnedFd> {
 }
 
 #[context("Mounting state as overlay")]
-fn overlay_state(base: impl AsFd, state: impl AsFd, source: &str) -> Result<()> {
+fn overlay_state(
+    base: impl AsFd,
+    state: impl AsFd,
+    source: &str,
+    attrs: MountAttrFlags,
+) -> Result<()> {
     let upper = ensure_dir(state.as_fd(), "upper")?;
     let work = ensure_dir(state.as_fd(), "work")?;
 
@@ -218,6 +431,7 @@ fn overlay_state(base: impl AsFd, state: impl AsFd, source: &str) -> Result<()>
         FsMountFlags::FSMOUNT_CLOEXEC,
         MountAttrFlags::empty(),
     )?;
+    set_mount_attrs(&fs, attrs)?;
 
     mount_at_wrapper(fs, base, ".").context("Moving mount")
 }
@@ -225,7 +439,13 @@ fn overlay_state(base: impl AsFd, state: impl AsFd, source: &str) -> Result<()>
 /// Mounts a transient overlayfs with passed in fd as the lowerdir
 #[context("Mounting transient overlayfs")]
 pub fn overlay_transient(base: impl AsFd) -> Result<()> {
-    overlay_state(base, prepare_mount(mount_tmpfs()?)?, "transient")
+    transient_overlay(base, None, MountAttrFlags::empty())
+}
+
+/// Mounts a transient overlayfs backed by a tmpfs of at most `size`
+fn transient_overlay(base: impl AsFd, size: Option<&str>, attrs: MountAttrFlags) -> Result<()> {
+    let tmpfs = prepare_mount(mount_tmpfs(size)?)?;
+    overlay_state(base, tmpfs, "transient", attrs)
 }
 
 #[context("Opening rootfs")]
@@ -260,12 +480,15 @@ pub fn mount_composefs_image(sysroot: &OwnedFd, name: &str, insecure: bool) -> R
     Ok(rootfs)
 }
 
-#[context("Mounting subdirectory")]
+/// Mounts `source` from the deployment state, or a transient copy of `subdir` of the
+/// image, on `subdir` of `new_root`
+#[context("Mounting subdirectory {subdir:?}")]
 fn mount_subdir(
     new_root: impl AsFd,
     state: impl AsFd,
-    subdir: &str,
-    config: MountConfig,
+    subdir: &Path,
+    source: &Path,
+    config: &MountConfig,
     default: MountType,
 ) -> Result<()> {
     let mount_type = match config.mount {
@@ -275,21 +498,67 @@ fn mount_subdir(
             false => default,
         },
     };
+    let attrs = parse_mount_options(config.options.as_deref().unwrap_or_default())?;
+    let size = config.size.as_deref();
 
     match mount_type {
         MountType::None => Ok(()),
-        MountType::Bind => Ok(mount_at_wrapper(
-            bind_mount(&state, subdir)?,
-            &new_root,
-            subdir,
-        )?),
+        MountType::Bind => {
+            let fs = bind_mount(&state, source)?;
+            set_mount_attrs(&fs, attrs)?;
+            mount_at_wrapper(fs, &new_root, subdir)
+        }
         MountType::Overlay => overlay_state(
             open_dir(&new_root, subdir)?,
-            open_dir(&state, subdir)?,
+            open_dir(&state, source)?,
             "overlay",
+            attrs,
         ),
-        MountType::Transient => overlay_transient(open_dir(&new_root, subdir)?),
+        MountType::Transient => transient_overlay(open_dir(&new_root, subdir)?, size, attrs),
+        MountType::Tmpfs => {
+            let fs = mount_tmpfs(size)?;
+            copy_dir_all(open_dir(&new_root, subdir)?, &fs)?;
+            set_mount_attrs(&fs, attrs)?;
+            mount_at_wrapper(fs, &new_root, subdir)
+        }
+    }
+}
+
+/// Mounts an additional directory from the deployment state
+#[context("Mounting {:?}", mount.path)]
+fn mount_state_dir(new_root: impl AsFd, state: impl AsFd, mount: &StateMountConfig) -> Result<()> {
+    let path = mount
+        .path
+        .strip_prefix("/")
+        .context("Mount point must be an absolute path")?;
+    anyhow::ensure!(
+        path.components().all(|c| matches!(c, Component::Normal(_))),
+        "Invalid mount point"
+    );
+    let source = match &mount.source {
+        Some(source) => {
+            anyhow::ensure!(source.is_relative(), "Source must be a relative path");
+            source.clone()
+        }
+        None => Path::new(SHARED_STATE).join(path),
+    };
+
+    if matches!(
+        mount.config.mount,
+        None | Some(MountType::Bind | MountType::Overlay)
+    ) && !mount.config.transient
+    {
+        ensure_dir_all(&state, &source)?;
     }
+
+    mount_subdir(
+        new_root,
+        state,
+        path,
+        &source,
+        &mount.config,
+        MountType::Bind,
+    )
 }
 
 #[context("GPT workaround")]
@@ -313,14 +582,304 @@ pub fn gpt_workaround() -> Result<()> {
     Ok(())
 }
 
+/// Name of the directory in a deployment's state directory holding a pristine
+/// `/etc` queued by `bootc factory-reset`; it replaces `etc` on the next boot.
+pub const ETC_RESET_DIR: &str = "etc.factory-reset";
+
+/// Name of the directory in a deployment's state directory holding the upper
+/// and work directories of a persistent overlay on `/usr`.
+pub const USR_OVERLAY_DIR: &str = "usr-overlay";
+
+/// A persistent `/usr` overlay discarded by `bootc usr-overlay discard`; it
+/// is removed on the next boot.
+pub const USR_OVERLAY_DISCARDED_DIR: &str = "usr-overlay.discarded";
+
+/// The digest of the last composefs image which booted successfully, relative to the
+/// sysroot; only maintained if falling back is enabled.
+pub const KNOWN_GOOD_FILE: &str = "state/known-good";
+
+/// Name of the file in a deployment's state directory recording why its image could not
+/// be mounted when it was last booted.
+pub const BOOT_FAILURE_FILE: &str = "boot-failure";
+
+/// Records the `composefs=` value of the image booted instead of the one on the kernel
+/// command line, after falling back.
+pub const FALLBACK_BOOTED_FILE: &str = "/run/composefs/fallback-booted";
+
+fn remove_dir_all_optional(path: &Path) -> Result<()> {
+    match std::fs::remove_dir_all(path) {
+        Ok(()) => Ok(()),
+        Err(err) if err.kind() == ErrorKind::NotFound => Ok(()),
+        Err(err) => Err(err).with_context(|| format!("Removing {path:?}")),
+    }
+}
+
+/// Swaps in a pristine `/etc` queued by `bootc factory-reset`, if any.
+///
+/// Every step here is safe to re-run if we are interrupted midway.
+#[context("Applying queued /etc reset")]
+fn apply_etc_reset(state: &Path) -> Result<()> {
+    let etc = state.join("etc");
+    let pending = state.join(ETC_RESET_DIR);
+    let previous = state.join(format!("{ETC_RESET_DIR}.old"));
+
+    remove_dir_all_optional(&previous)?;
+    if !pending.try_exists()? {
+        return Ok(());
+    }
+
+    if etc.try_exists()? {
+        std::fs::rename(&etc, &previous).context("Moving previous etc")?;
+    }
+    std::fs::rename(&pending, &etc).context("Moving pristine etc")?;
+    remove_dir_all_optional(&previous)
+}
+
+/// Used to extend PCRs, as it also records the measurements in the TPM event log
+/// used by systemd-pcrlock.
+const PCREXTEND: &str = "/usr/lib/systemd/systemd-pcrextend";
+
+/// The highest PCR index of a TPM 2.0
+const PCR_MAX: u32 = 23;
+
+/// The key of the image reference in a deployment's `.origin` file
+const ORIGIN_CONTAINER: &str = "container-image-reference";
+
+/// The strings measured for the composefs image `image`, given the contents of its `.origin`
+/// file; they follow the `word:value` form of the measurements made by systemd.
+fn measurements(image: &str, origin: &str) -> Vec<String> {
+    let imgref = origin.lines().find_map(|line| {
+        let (k, v) = line.split_once('=')?;
+        (k.trim() == ORIGIN_CONTAINER).then_some(v.trim())
+    });
+
+    let mut r = vec![format!("composefs:{image}")];
+    if let Some(imgref) = imgref {
+        r.push(format!("composefs-origin:{imgref}"));
+    }
+    r
+}
+
+/// Extends the configured PCR with the digest of the composefs image and the image
+/// reference recorded in its deployment state.
+fn measure_image(config: &MeasureConfig, state: impl AsFd, image: &str) -> Result<()> {
+    measure_image_with(Path::new(PCREXTEND), config, state, image)
+}
+
+/// Like [`measure_image`], running `pcrextend` instead of systemd-pcrextend.
+#[context("Measuring composefs image")]
+fn measure_image_with(
+    pcrextend: &Path,
+    config: &MeasureConfig,
+    state: impl AsFd,
+    image: &str,
+) -> Result<()> {
+    let Some(pcr) = config.pcr else {
+        return Ok(());
+    };
+    anyhow::ensure!(pcr <= PCR_MAX, "Invalid PCR {pcr}");
+
+    let origin = match openat(
+        &state,
+        format!("{image}.origin"),
+        OFlags::RDONLY | OFlags::CLOEXEC,
+        Mode::empty(),
+    ) {
+        Ok(fd) => std::io::read_to_string(std::fs::File::from(fd)).context("Reading origin")?,
+        Err(Errno::NOENT) => String::new(),
+        Err(err) => Err(err).context("Opening origin")?,
+    };
+
+    for measurement in measurements(image, &origin) {
+        let mut cmd = Command::new(pcrextend);
+        cmd.arg(format!("--pcr={pcr}"));
+        if let Some(device) = config.tpm2_device.as_deref() {
+            cmd.arg(format!("--tpm2-device={device}"));
+        }
+        if !config.required {
+            cmd.arg("--graceful");
+        }
+        // systemd-pcrextend is only included in the initramfs if available
+        let r = match cmd.arg(&measurement).status() {
+            Ok(status) if status.success() => Ok(()),
+            Ok(status) => Err(anyhow::anyhow!("Measuring {measurement}: {status}")),
+            Err(err) => Err(anyhow::Error::new(err).context(format!("Running {pcrextend:?}"))),
+        };
+        match r {
+            Ok(()) => {}
+            Err(err) if config.required => return Err(err),
+            Err(err) => {
+                // stderr goes to the journal and the console
+                eprintln!("Not measuring composefs image: {err:#}");
+                return Ok(());
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Records `image` as known-good, clearing any earlier boot failure
+#[context("Recording known-good image")]
+fn record_known_good(sysroot: &Path, image: &str) -> Result<()> {
+    let failure = sysroot
+        .join("state/deploy")
+        .join(image)
+        .join(BOOT_FAILURE_FILE);
+    match std::fs::remove_file(&failure) {
+        Ok(()) => {}
+        Err(err) if err.kind() == ErrorKind::NotFound => {}
+        Err(err) => Err(err).with_context(|| format!("Removing {failure:?}"))?,
+    }
+
+    let path = sysroot.join(KNOWN_GOOD_FILE);
+    if std::fs::read_to_string(&path).is_ok_and(|s| s.trim() == image) {
+        return Ok(());
+    }
+    let tmp = path.with_extension("tmp");
+    std::fs::write(&tmp, image).with_context(|| format!("Writing {tmp:?}"))?;
+    std::fs::rename(&tmp, &path).with_context(|| format!("Renaming to {path:?}"))
+}
+
+/// Records the booted composefs image as known-good if falling back is enabled in
+/// `config`; this is run once the boot completed, by
+/// bootc-composefs-known-good.service. After falling back, the known-good image was
+/// booted, and the failure of the requested one is kept.
+pub fn record_booted_known_good(sysroot: &Path, config: &Path, cmdline: &str) -> Result<()> {
+    let config = load_config(config)?;
+    if !config.fallback.enabled || Path::new(FALLBACK_BOOTED_FILE).exists() {
+        return Ok(());
+    }
+    let (image, _) = get_cmdline_composefs::<Sha512HashValue>(cmdline)?;
+    record_known_good(sysroot, &image.to_hex())
+}
+
+/// Mounts the composefs image `image`. If that fails and falling back is enabled, this
+/// mounts the last known-good image instead, recording the failure in the state of
+/// `image`. Returns the name of the mounted image.
+fn mount_image_with_fallback(
+    config: &FallbackConfig,
+    sysroot_path: &Path,
+    sysroot: &OwnedFd,
+    image: String,
+    insecure: bool,
+) -> Result<(String, OwnedFd)> {
+    let err = match mount_composefs_image(sysroot, &image, insecure) {
+        Ok(fs) => return Ok((image, fs)),
+        Err(err) if config.enabled => err,
+        Err(err) => return Err(err),
+    };
+
+    let known_good = match std::fs::read_to_string(sysroot_path.join(KNOWN_GOOD_FILE)) {
+        Ok(s) => s.trim().to_owned(),
+        Err(e) if e.kind() == ErrorKind::NotFound => {
+            return Err(err.context("No known-good image to fall back to"))
+        }
+        Err(e) => return Err(err.context(format!("Reading {KNOWN_GOOD_FILE}: {e}"))),
+    };
+    if known_good == image {
+        return Err(err);
+    }
+
+    // stderr goes to the journal and the console
+    let reason = format!("{err:#}");
+    eprintln!("Failed to mount composefs image {image}: {reason}");
+    eprintln!("Falling back to the last known-good composefs image {known_good}");
+
+    // This is informational, so don't let it prevent booting
+    let failure = sysroot_path
+        .join("state/deploy")
+        .join(&image)
+        .join(BOOT_FAILURE_FILE);
+    if let Err(e) = std::fs::write(&failure, &reason) {
+        eprintln!("Failed to write {failure:?}: {e}");
+    }
+
+    let fs = mount_composefs_image(sysroot, &known_good, insecure)
+        .context("Mounting known-good image")?;
+
+    let insecure = if insecure { "?" } else { "" };
+    if let Err(e) = write_fallback_booted(Path::new(FALLBACK_BOOTED_FILE), &known_good, insecure) {
+        eprintln!("{e:#}");
+    }
+
+    Ok((known_good, fs))
+}
+
+#[context("Writing {path:?}")]
+fn write_fallback_booted(path: &Path, image: &str, insecure: &str) -> Result<()> {
+    if let Some(parent) = path.parent() {
+        std::fs::create_dir_all(parent)?;
+    }
+    std::fs::write(path, format!("{insecure}{image}"))?;
+    Ok(())
+}
+
+#[context("Loading config")]
+fn load_config(path: &Path) -> Result<Config> {
+    match std::fs::read_to_string(path) {
+        Ok(text) => Ok(toml::from_str(&text)?),
+        Err(err) if err.kind() == ErrorKind::NotFound => Ok(Config::default()),
+        Err(err) => Err(err.into()),
+    }
+}
+
+/// Mounts the sysroot and the deployment state on top of the composefs image in `new_root`
+#[context("Composing root filesystem")]
+fn compose_root(
+    config: Config,
+    sysroot: impl AsFd,
+    sysroot_clone: impl AsFd,
+    new_root: impl AsFd,
+    image: &str,
+) -> Result<()> {
+    if config.root.transient {
+        transient_overlay(
+            &new_root,
+            config.root.size.as_deref(),
+            MountAttrFlags::empty(),
+        )?;
+    }
+
+    match composefs::mount::mount_at(&sysroot_clone, &new_root, "sysroot") {
+        Ok(()) | Err(Errno::NOENT) => {}
+        Err(err) => Err(err)?,
+    }
+
+    // etc + var
+    let state = open_dir(open_dir(&sysroot, "state/deploy")?, image)?;
+    let (etc, var) = (Path::new("etc"), Path::new("var"));
+    mount_subdir(&new_root, &state, etc, etc, &config.etc, MountType::Bind)?;
+    mount_subdir(&new_root, &state, var, var, &config.var, MountType::Bind)?;
+
+    for mount in &config.mounts {
+        mount_state_dir(&new_root, &state, mount)?;
+    }
+
+    // A persistent /usr overlay set up via `bootc usr-overlay --persistent`
+    match openat(
+        &state,
+        USR_OVERLAY_DIR,
+        OFlags::PATH | OFlags::DIRECTORY | OFlags::CLOEXEC,
+        Mode::empty(),
+    ) {
+        Ok(usr_state) => overlay_state(
+            open_dir(&new_root, "usr")?,
+            usr_state,
+            "usr-overlay",
+            MountAttrFlags::empty(),
+        )?,
+        Err(Errno::NOENT) => {}
+        Err(err) => Err(err)?,
+    }
+
+    measure_image(&config.measure, &state, image)
+}
+
 /// Sets up /sysroot for switch-root
 #[context("Setting up /sysroot")]
 pub fn setup_root(args: Args) -> Result<()> {
-    let config = match std::fs::read_to_string(args.config) {
-        Ok(text) => toml::from_str(&text)?,
-        Err(err) if err.kind() == ErrorKind::NotFound => Config::default(),
-        Err(err) => Err(err)?,
-    };
+    let config = load_config(&args.config)?;
 
     let sysroot = open_dir(CWD, &args.sysroot)
         .with_context(|| format!("Failed to open sysroot {:?}", args.sysroot))?;
@@ -332,42 +891,387 @@ pub fn setup_root(args: Args) -> Result<()> {
     };
     let (image, insecure) = get_cmdline_composefs::<Sha512HashValue>(cmdline)?;
 
-    let new_root = match args.root_fs {
-        Some(path) => open_root_fs(&path).context("Failed to clone specified root fs")?,
-        None => mount_composefs_image(&sysroot, &image.to_hex(), insecure)?,
+    let (image, new_root) = match args.root_fs {
+        Some(path) => (
+            image.to_hex(),
+            open_root_fs(&path).context("Failed to clone specified root fs")?,
+        ),
+        None => mount_image_with_fallback(
+            &config.fallback,
+            &args.sysroot,
+            &sysroot,
+            image.to_hex(),
+            insecure,
+        )?,
     };
 
+    // This must happen before anything is mounted over the sysroot path
+    let state_path = args.sysroot.join("state/deploy").join(&image);
+    apply_etc_reset(&state_path)?;
+    remove_dir_all_optional(&state_path.join(USR_OVERLAY_DISCARDED_DIR))?;
+
     // we need to clone this before the next step to make sure we get the old one
-    let sysroot_clone = bind_mount(&sysroot, "")?;
+    let sysroot_clone = bind_mount(&sysroot, Path::new(""))?;
 
     set_mount_readonly(&sysroot_clone)?;
 
+    let mountpoint = args.target.as_deref().unwrap_or(&args.sysroot);
+
     // Ideally we build the new root filesystem together before we mount it, but that only works on
     // 6.15 and later.  Before 6.15 we can't mount into a floating tree, so mount it first.  This
     // will leave an abandoned clone of the sysroot mounted under it, but that's OK for now.
     if cfg!(feature = "pre-6.15") {
-        mount_at_wrapper(&new_root, CWD, &args.sysroot)?;
+        mount_at_wrapper(&new_root, CWD, mountpoint)?;
     }
 
-    if config.root.transient {
-        overlay_transient(&new_root)?;
+    compose_root(config, &sysroot, &sysroot_clone, &new_root, &image)?;
+
+    if cfg!(not(feature = "pre-6.15")) {
+        // Replace the /sysroot with the new composed root filesystem
+        if args.target.is_none() {
+            unmount(&args.sysroot, UnmountFlags::DETACH)?;
+        }
+        mount_at_wrapper(&new_root, CWD, mountpoint)?;
     }
 
-    match composefs::mount::mount_at(&sysroot_clone, &new_root, "sysroot") {
-        Ok(()) | Err(Errno::NOENT) => {}
-        Err(err) => Err(err)?,
+    Ok(())
+}
+
+/// Sets up `target` with the root filesystem of the composefs image `image` from a booted
+/// system, the same way [`setup_root`] does from the initramfs, so that systemd can
+/// soft-reboot into it (`target` is normally `/run/nextroot`).
+///
+/// The deployment state is not modified, so pending state changes (such as a factory
+/// reset of `/etc`) are only applied when the deployment is next booted via the initramfs.
+#[context("Setting up {target:?}")]
+pub fn setup_nextroot(
+    sysroot: &Path,
+    config: &Path,
+    image: &str,
+    insecure: bool,
+    target: &Path,
+) -> Result<()> {
+    let config = load_config(config)?;
+
+    let sysroot =
+        open_dir(CWD, sysroot).with_context(|| format!("Failed to open sysroot {sysroot:?}"))?;
+    let new_root = mount_composefs_image(&sysroot, image, insecure)?;
+
+    let sysroot_clone = bind_mount(&sysroot, Path::new(""))?;
+    set_mount_readonly(&sysroot_clone)?;
+
+    // Attach the image before mounting into it, which works regardless of kernel version
+    mount_at_wrapper(&new_root, CWD, target)?;
+
+    compose_root(config, &sysroot, &sysroot_clone, &new_root, image)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use indoc::indoc;
+
+    #[test]
+    fn test_measurements() -> Result<()> {
+        let config: Config = toml::from_str("[measure]\npcr = 12\n")?;
+        assert_eq!(config.measure.pcr, Some(12));
+        assert!(!config.measure.required);
+
+        let origin = "[origin]\ncontainer-image-reference = ostree-unverified-image:docker://quay.io/example/os:latest\n\n[boot]\nboot_type = bls\n";
+        assert_eq!(
+            measurements("abcd", origin),
+            [
+                "composefs:abcd",
+                "composefs-origin:ostree-unverified-image:docker://quay.io/example/os:latest"
+            ]
+        );
+        assert_eq!(measurements("abcd", ""), ["composefs:abcd"]);
+        Ok(())
     }
 
-    // etc + var
-    let state = open_dir(open_dir(&sysroot, "state/deploy")?, image.to_hex())?;
-    mount_subdir(&new_root, &state, "etc", config.etc, MountType::Bind)?;
-    mount_subdir(&new_root, &state, "var", config.var, MountType::Bind)?;
+    /// Measures an image into a software TPM, which requires swtpm and tpm2-tools
+    #[test]
+    fn test_measure_image_swtpm() -> Result<()> {
+        let have = |cmd: &str| Command::new(cmd).arg("--version").output().is_ok();
+        if !Path::new(PCREXTEND).exists() || !have("swtpm") || !have("tpm2_pcrread") {
+            eprintln!("Skipping test; missing {PCREXTEND}, swtpm or tpm2-tools");
+            return Ok(());
+        }
 
-    if cfg!(not(feature = "pre-6.15")) {
-        // Replace the /sysroot with the new composed root filesystem
-        unmount(&args.sysroot, UnmountFlags::DETACH)?;
-        mount_at_wrapper(&new_root, CWD, &args.sysroot)?;
+        let td = tempfile::tempdir()?;
+        let td = td.path();
+        let socket = td.join("swtpm.sock");
+        struct Swtpm(std::process::Child);
+        impl Drop for Swtpm {
+            fn drop(&mut self) {
+                let _ = self.0.kill();
+                let _ = self.0.wait();
+            }
+        }
+        let _swtpm = Swtpm(
+            Command::new("swtpm")
+                .args(["socket", "--tpm2", "--flags", "startup-clear", "--tpmstate"])
+                .arg(format!("dir={}", td.display()))
+                .arg("--server")
+                .arg(format!("type=unixio,path={}", socket.display()))
+                .arg("--ctrl")
+                .arg(format!(
+                    "type=unixio,path={}",
+                    td.join("ctrl.sock").display()
+                ))
+                .spawn()?,
+        );
+        for _ in 0..50 {
+            if socket.exists() {
+                break;
+            }
+            std::thread::sleep(std::time::Duration::from_millis(100));
+        }
+        let device = format!("swtpm:path={}", socket.display());
+
+        let read_pcr = || -> Result<Vec<u8>> {
+            let out = td.join("pcr");
+            let status = Command::new("tpm2_pcrread")
+                .args(["-T", &device, "sha256:12", "-o"])
+                .arg(&out)
+                .status()?;
+            anyhow::ensure!(status.success(), "tpm2_pcrread: {status}");
+            Ok(std::fs::read(&out)?)
+        };
+        let initial = read_pcr()?;
+        assert_eq!(initial, [0u8; 32]);
+
+        let state = td.join("state");
+        std::fs::create_dir(&state)?;
+        std::fs::write(
+            state.join("abcd.origin"),
+            "[origin]\ncontainer-image-reference = ostree-unverified-image:docker://quay.io/example/os:latest\n",
+        )?;
+        let config = MeasureConfig {
+            pcr: Some(12),
+            required: true,
+            tpm2_device: Some(device.clone()),
+        };
+        measure_image(&config, open_dir(CWD, &state)?, "abcd")?;
+        let measured = read_pcr()?;
+        assert_ne!(measured, initial);
+
+        // Measuring again extends the PCR further
+        measure_image(&config, open_dir(CWD, &state)?, "abcd")?;
+        assert_ne!(read_pcr()?, measured);
+        Ok(())
     }
 
-    Ok(())
+    #[test]
+    fn test_measure_image_no_pcrextend() -> Result<()> {
+        let td = tempfile::tempdir()?;
+        let state = open_dir(CWD, td.path())?;
+        let missing = td.path().join("systemd-pcrextend");
+        let mut config = MeasureConfig {
+            pcr: Some(12),
+            ..Default::default()
+        };
+
+        // Booting continues unless the measurement is required
+        measure_image_with(&missing, &config, &state, "abcd")?;
+        config.required = true;
+        assert!(measure_image_with(&missing, &config, &state, "abcd").is_err());
+        Ok(())
+    }
+
+    const CONFIG: &str = indoc! { r#"
+        [root]
+        transient = true
+        size = "50%"
+
+        [etc]
+        mount = "tmpfs"
+        size = "1M"
+        options = "nosuid,nodev"
+
+        [[mounts]]
+        path = "/home"
+
+        [[mounts]]
+        path = "/srv"
+        source = "srv"
+        mount = "overlay"
+        options = "noexec"
+    "# };
+
+    #[test]
+    fn test_config() -> Result<()> {
+        let config: Config = toml::from_str(CONFIG)?;
+        assert_eq!(config.root.size.as_deref(), Some("50%"));
+        assert_eq!(config.etc.mount, Some(MountType::Tmpfs));
+        assert_eq!(config.etc.size.as_deref(), Some("1M"));
+        assert_eq!(config.var.mount, None);
+        assert_eq!(config.mounts.len(), 2);
+        assert_eq!(config.mounts[0].path, Path::new("/home"));
+        assert_eq!(config.mounts[0].source, None);
+        assert_eq!(config.mounts[1].source.as_deref(), Some(Path::new("srv")));
+        assert_eq!(config.mounts[1].config.mount, Some(MountType::Overlay));
+        assert_eq!(config.mounts[1].config.options.as_deref(), Some("noexec"));
+
+        assert!(toml::from_str::<Config>("[etc]\nmount = \"ramfs\"\n").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_parse_mount_options() -> Result<()> {
+        assert_eq!(parse_mount_options("")?, MountAttrFlags::empty());
+        assert_eq!(
+            parse_mount_options("nosuid, nodev,")?,
+            MountAttrFlags::MOUNT_ATTR_NOSUID | MountAttrFlags::MOUNT_ATTR_NODEV
+        );
+        assert!(parse_mount_options("nosuid,suid").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_record_known_good() -> Result<()> {
+        let td = tempfile::tempdir()?;
+        let sysroot = td.path();
+        let state = sysroot.join("state/deploy/abcd");
+        std::fs::create_dir_all(&state)?;
+        std::fs::write(state.join(BOOT_FAILURE_FILE), "missing object")?;
+
+        record_known_good(sysroot, "abcd")?;
+        assert_eq!(
+            std::fs::read_to_string(sysroot.join(KNOWN_GOOD_FILE))?,
+            "abcd"
+        );
+        assert!(!state.join(BOOT_FAILURE_FILE).exists());
+
+        let config: Config = toml::from_str("[fallback]\nenabled = true\n")?;
+        assert!(config.fallback.enabled);
+        Ok(())
+    }
+
+    #[test]
+    fn test_record_booted_known_good() -> Result<()> {
+        let td = tempfile::tempdir()?;
+        let sysroot = td.path().join("sysroot");
+        std::fs::create_dir_all(sysroot.join("state"))?;
+        let config = td.path().join("setup-root-conf.toml");
+        let image = "ab".repeat(64);
+        let cmdline = format!("rw composefs={image}");
+
+        // Nothing is recorded unless falling back is enabled
+        record_booted_known_good(&sysroot, &config, &cmdline)?;
+        assert!(!sysroot.join(KNOWN_GOOD_FILE).exists());
+
+        std::fs::write(&config, "[fallback]\nenabled = true\n")?;
+        record_booted_known_good(&sysroot, &config, &cmdline)?;
+        assert_eq!(
+            std::fs::read_to_string(sysroot.join(KNOWN_GOOD_FILE))?,
+            image
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_mount_image_with_fallback() -> Result<()> {
+        let td = tempfile::tempdir()?;
+        let sysroot_path = td.path();
+        let sysroot = open_dir(CWD, sysroot_path)?;
+        let (image, known_good) = ("ab".repeat(64), "cd".repeat(64));
+        let failure = sysroot_path
+            .join("state/deploy")
+            .join(&image)
+            .join(BOOT_FAILURE_FILE);
+        std::fs::create_dir_all(failure.parent().unwrap())?;
+        let mount = |enabled| {
+            let config = FallbackConfig { enabled };
+            mount_image_with_fallback(&config, sysroot_path, &sysroot, image.clone(), false)
+        };
+
+        // There is no repository, so mounting the image fails
+        let err = mount(false).unwrap_err();
+        assert!(!format!("{err:#}").contains("known-good"));
+        let err = mount(true).unwrap_err();
+        assert!(format!("{err:#}").contains("No known-good image to fall back to"));
+        assert!(!failure.exists());
+
+        // The image itself is known-good
+        std::fs::create_dir_all(sysroot_path.join("state"))?;
+        std::fs::write(sysroot_path.join(KNOWN_GOOD_FILE), &image)?;
+        assert!(mount(true).is_err());
+        assert!(!failure.exists());
+
+        // Falling back records the failure, then tries the known-good image
+        std::fs::write(sysroot_path.join(KNOWN_GOOD_FILE), &known_good)?;
+        let err = mount(true).unwrap_err();
+        assert!(format!("{err:#}").starts_with("Mounting known-good image"));
+        assert!(!std::fs::read_to_string(&failure)?.is_empty());
+        Ok(())
+    }
+
+    /// Runs [`setup_root`] via the testing arguments, which requires privileges to mount
+    #[test]
+    fn test_setup_root() -> Result<()> {
+        if mount_tmpfs(None).is_err() {
+            eprintln!("Skipping test; mounting is not permitted");
+            return Ok(());
+        }
+
+        let td = tempfile::tempdir()?;
+        let td = td.path();
+        let image = "ab".repeat(64);
+        let sysroot = td.join("sysroot");
+        let rootfs = td.join("rootfs");
+        let target = td.join("target");
+        let state = sysroot.join("state/deploy").join(&image);
+        for dir in ["etc", "var", "srv"] {
+            std::fs::create_dir_all(state.join(dir))?;
+        }
+        for dir in ["etc", "var", "home", "srv", "sysroot"] {
+            std::fs::create_dir_all(rootfs.join(dir))?;
+        }
+        std::fs::create_dir(&target)?;
+        std::fs::write(rootfs.join("etc/hostname"), "image")?;
+        std::fs::write(state.join("etc/hostname"), "state")?;
+        let config = td.join("setup-root-conf.toml");
+        std::fs::write(
+            &config,
+            CONFIG.replace("transient = true", "transient = false"),
+        )?;
+
+        setup_root(Args {
+            cmd: Vec::new(),
+            sysroot: sysroot.clone(),
+            config,
+            root_fs: Some(rootfs),
+            cmdline: Some(format!("composefs={image}")),
+            target: Some(target.clone()),
+        })?;
+
+        let r = check_setup_root(&target, &sysroot, &state);
+        unmount(&target, UnmountFlags::DETACH)?;
+        r
+    }
+
+    fn check_setup_root(target: &Path, sysroot: &Path, state: &Path) -> Result<()> {
+        // /etc is a copy of the image's
+        assert_eq!(
+            std::fs::read_to_string(target.join("etc/hostname"))?,
+            "image"
+        );
+        let flags = rustix::fs::statvfs(target.join("etc"))?.f_flag;
+        assert!(flags.contains(
+            rustix::fs::StatVfsMountFlags::NOSUID | rustix::fs::StatVfsMountFlags::NODEV
+        ));
+
+        // /home defaults to the shared state
+        std::fs::write(target.join("home/file"), "home")?;
+        let shared = sysroot.join("state/os/default/home/file");
+        assert_eq!(std::fs::read_to_string(shared)?, "home");
+
+        // /srv is an overlay of the deployment's state
+        std::fs::write(target.join("srv/file"), "srv")?;
+        assert!(state.join("srv/upper/file").exists());
+        let flags = rustix::fs::statvfs(target.join("srv"))?.f_flag;
+        assert!(flags.contains(rustix::fs::StatVfsMountFlags::NOEXEC));
+        Ok(())
+    }
 }
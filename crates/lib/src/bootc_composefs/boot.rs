@@ -98,8 +98,9 @@ use serde::{Deserialize, Serialize};
 
 use crate::bootc_kargs::compute_new_kargs;
 use crate::composefs_consts::{TYPE1_ENT_PATH, TYPE1_ENT_PATH_STAGED};
-use crate::parsers::bls_config::{BLSConfig, BLSConfigType};
+use crate::parsers::bls_config::{parse_bls_config, BLSConfig, BLSConfigType};
 use crate::parsers::grub_menuconfig::MenuEntry;
+use crate::secure_boot::SecureBootSigner;
 use crate::task::Task;
 use crate::{
     bootc_composefs::repo::open_composefs_repo,
@@ -109,7 +110,10 @@ use crate::{
     bootc_composefs::state::{get_booted_bls, write_composefs_state},
     bootloader::esp_in,
 };
-use crate::{bootc_composefs::status::get_sorted_grub_uki_boot_entries, install::PostFetchState};
+use crate::{
+    bootc_composefs::status::{get_bootloader, get_sorted_grub_uki_boot_entries},
+    install::PostFetchState,
+};
 use crate::{
     composefs_consts::{
         BOOT_LOADER_ENTRIES, COMPOSEFS_CMDLINE, ORIGIN_KEY_BOOT, ORIGIN_KEY_BOOT_DIGEST,
@@ -150,6 +154,14 @@ pub(crate) enum BootSetupType<'a> {
     ),
     /// For `bootc upgrade`
     Upgrade((&'a Storage, &'a ComposefsFilesystem, &'a Host)),
+    /// For migrating the booted ostree deployment, i.e. `bootc switch --backend composefs`
+    Migrate(
+        (
+            &'a Storage,
+            &'a ComposefsFilesystem,
+            &'a ostree_ext::ostree::Deployment,
+        ),
+    ),
 }
 
 #[derive(
@@ -329,6 +341,28 @@ fn compute_boot_digest(
     Ok(hex::encode(digest))
 }
 
+/// Compute SHA256Sum of the `.linux` + `.initrd` sections of a UKI
+///
+/// This matches [`compute_boot_digest`], so that the digest identifies the kernel and
+/// initramfs regardless of the (per-deployment) embedded command line.
+#[context("Computing UKI boot digest")]
+fn compute_uki_boot_digest(efi_bin: &[u8]) -> Result<String> {
+    let mut hasher = openssl::hash::Hasher::new(openssl::hash::MessageDigest::sha256())
+        .context("Creating hasher")?;
+
+    for section in [".linux", ".initrd"] {
+        let data = uki::get_section(efi_bin, section)
+            .ok_or_else(|| anyhow!("UKI is missing the {section} section"))??;
+        hasher
+            .update(data)
+            .with_context(|| format!("hashing {section}"))?;
+    }
+
+    let digest: &[u8] = &hasher.finish().context("Finishing digest")?;
+
+    Ok(hex::encode(digest))
+}
+
 /// Given the SHA256 sum of current VMlinuz + Initrd combo, find boot entry with the same SHA256Sum
 ///
 /// # Returns
@@ -554,11 +588,37 @@ pub(crate) fn setup_composefs_bls_boot(
                 bootloader,
             )
         }
+
+        BootSetupType::Migrate((storage, fs, deployment)) => {
+            let sysroot_parent = get_sysroot_parent_dev(&storage.physical_root)?;
+
+            // Start from the kernel arguments of the booted ostree deployment
+            let options = deployment
+                .bootconfig()
+                .and_then(|bootcfg| bootcfg.get("options"))
+                .ok_or_else(|| anyhow!("No options found for the booted ostree deployment"))?;
+            let mut cmdline = Cmdline::from(options.to_string());
+            cmdline.remove(&"ostree".into());
+
+            let param = format!("{COMPOSEFS_CMDLINE}={id_hex}");
+            let param =
+                Parameter::parse(&param).context("Failed to create 'composefs=' parameter")?;
+            cmdline.add_or_modify(&param);
+
+            (
+                Utf8PathBuf::from("/sysroot"),
+                get_esp_partition(&sysroot_parent)?.0,
+                cmdline,
+                fs,
+                get_bootloader()?,
+            )
+        }
     };
 
     let is_upgrade = matches!(setup_type, BootSetupType::Upgrade(..));
+    let is_migrate = matches!(setup_type, BootSetupType::Migrate(..));
 
-    let current_root = if is_upgrade {
+    let current_root = if is_upgrade || is_migrate {
         Some(&Dir::open_ambient_dir("/", ambient_authority()).context("Opening root")?)
     } else {
         None
@@ -747,6 +807,23 @@ pub(crate) fn setup_composefs_bls_boot(
         )?;
     }
 
+    if is_upgrade {
+        // Keep the entries of ostree deployments which were left behind by a migration
+        let entries_dir =
+            Dir::open_ambient_dir(loader_path.join(BOOT_LOADER_ENTRIES), ambient_authority())
+                .context("Opening loader entries")?;
+
+        for (name, _) in ostree_entries(&entries_dir)? {
+            entries_dir
+                .copy(&name, &loader_entries_dir, &name)
+                .with_context(|| format!("Copying {name}"))?;
+        }
+    }
+
+    if is_migrate {
+        demote_ostree_entries(&loader_entries_dir, &os_id)?;
+    }
+
     let owned_loader_entries_fd = loader_entries_dir
         .reopen_as_ownedfd()
         .context("Reopening as owned fd")?;
@@ -756,10 +833,61 @@ pub(crate) fn setup_composefs_bls_boot(
     Ok(boot_digest)
 }
 
+/// Whether `cfg` boots an ostree deployment, e.g. one left over from
+/// migrating to composefs
+pub(crate) fn is_ostree_entry(cfg: &BLSConfig) -> bool {
+    matches!(
+        &cfg.cfg_type,
+        BLSConfigType::NonEFI { options: Some(options), .. } if options.find("ostree").is_some()
+    )
+}
+
+/// The Type1 boot entries in `entries_dir` which boot ostree deployments
+pub(crate) fn ostree_entries(entries_dir: &Dir) -> Result<Vec<(String, BLSConfig)>> {
+    let mut entries = vec![];
+
+    for entry in entries_dir.entries_utf8()? {
+        let name = entry?.file_name()?;
+        if !name.ends_with(".conf") {
+            continue;
+        }
+
+        let contents = entries_dir
+            .read_to_string(&name)
+            .with_context(|| format!("Reading {name}"))?;
+        let cfg = parse_bls_config(&contents).with_context(|| format!("Parsing {name}"))?;
+
+        if is_ostree_entry(&cfg) {
+            entries.push((name, cfg));
+        }
+    }
+
+    Ok(entries)
+}
+
+/// Rename the boot entries of ostree deployments so that they sort after the composefs
+/// entry for `os_id`, the same way as the entry of a previously booted deployment.
+/// ostree finds its entries by their `ostree=` kernel argument, so it is unaffected.
+#[context("Demoting ostree boot entries")]
+fn demote_ostree_entries(entries_dir: &Dir, os_id: &str) -> Result<()> {
+    for (name, cfg) in ostree_entries(entries_dir)? {
+        let new_name =
+            type1_entry_conf_file_name(os_id, &cfg.version(), FILENAME_PRIORITY_SECONDARY);
+        entries_dir
+            .rename(&name, entries_dir, &new_name)
+            .with_context(|| format!("Renaming {name}"))?;
+    }
+
+    rustix::fs::fsync(entries_dir.reopen_as_ownedfd()?).context("fsync")?;
+
+    Ok(())
+}
+
 struct UKILabels {
     boot_label: String,
     version: Option<String>,
     os_id: Option<String>,
+    boot_digest: String,
 }
 
 /// Writes a PortableExecutable to ESP along with any PE specific or Global addons
@@ -773,6 +901,7 @@ fn write_pe_to_esp(
     is_insecure_from_opts: bool,
     mounted_efi: impl AsRef<Path>,
     bootloader: &Bootloader,
+    signer: Option<&SecureBootSigner>,
 ) -> Result<Option<UKILabels>> {
     let efi_bin = read_file(file, &repo).context("Reading .efi binary")?;
 
@@ -815,6 +944,7 @@ fn write_pe_to_esp(
             boot_label: uki::get_boot_label(&efi_bin).context("Getting UKI boot label")?,
             version: parsed_osrel.get_version(),
             os_id: parsed_osrel.get_value(&["ID"]),
+            boot_digest: compute_uki_boot_digest(&efi_bin)?,
         });
     }
 
@@ -862,6 +992,11 @@ fn write_pe_to_esp(
             .as_str(),
     };
 
+    let efi_bin = match signer {
+        Some(signer) => signer.sign(&efi_bin)?,
+        None => efi_bin.into(),
+    };
+
     pe_dir
         .atomic_write(pe_name, efi_bin)
         .context("Writing UKI")?;
@@ -980,7 +1115,7 @@ fn write_systemd_uki_config(
         .with_version(boot_label.version.unwrap_or_else(|| id.to_hex()));
 
     let (entries_dir, booted_bls) = match setup_type {
-        BootSetupType::Setup(..) => {
+        BootSetupType::Setup(..) | BootSetupType::Migrate(..) => {
             esp_dir
                 .create_dir_all(TYPE1_ENT_PATH)
                 .with_context(|| format!("Creating {TYPE1_ENT_PATH}"))?;
@@ -1035,36 +1170,54 @@ pub(crate) fn setup_composefs_uki_boot(
     repo: crate::store::ComposefsRepository,
     id: &Sha512HashValue,
     entries: Vec<ComposefsBootEntry<Sha512HashValue>>,
-) -> Result<()> {
-    let (root_path, esp_device, bootloader, is_insecure_from_opts, uki_addons) = match setup_type {
-        BootSetupType::Setup((root_setup, state, postfetch, ..)) => {
-            state.require_no_kargs_for_uki()?;
-
-            let esp_part = esp_in(&root_setup.device_info)?;
-
-            (
-                root_setup.physical_root_path.clone(),
-                esp_part.node.clone(),
-                postfetch.detected_bootloader.clone(),
-                state.composefs_options.insecure,
-                state.composefs_options.uki_addon.as_ref(),
-            )
-        }
+) -> Result<String> {
+    // Only installs sign UKIs; the signing key is not available afterwards, so
+    // upgrades install UKIs as shipped in the image.
+    let (root_path, esp_device, bootloader, is_insecure_from_opts, uki_addons, signer) =
+        match setup_type {
+            BootSetupType::Setup((root_setup, state, postfetch, ..)) => {
+                state.require_no_kargs_for_uki()?;
+
+                let esp_part = esp_in(&root_setup.device_info)?;
+
+                (
+                    root_setup.physical_root_path.clone(),
+                    esp_part.node.clone(),
+                    postfetch.detected_bootloader.clone(),
+                    state.composefs_options.insecure,
+                    state.composefs_options.uki_addon.as_ref(),
+                    SecureBootSigner::from_opts(&state.composefs_options),
+                )
+            }
 
-        BootSetupType::Upgrade((storage, _, host)) => {
-            let sysroot = Utf8PathBuf::from("/sysroot"); // Still needed for root_path
-            let sysroot_parent = get_sysroot_parent_dev(&storage.physical_root)?;
-            let bootloader = host.require_composefs_booted()?.bootloader.clone();
+            BootSetupType::Upgrade((storage, _, host)) => {
+                let sysroot = Utf8PathBuf::from("/sysroot"); // Still needed for root_path
+                let sysroot_parent = get_sysroot_parent_dev(&storage.physical_root)?;
+                let bootloader = host.require_composefs_booted()?.bootloader.clone();
+
+                (
+                    sysroot,
+                    get_esp_partition(&sysroot_parent)?.0,
+                    bootloader,
+                    false,
+                    None,
+                    None,
+                )
+            }
 
-            (
-                sysroot,
-                get_esp_partition(&sysroot_parent)?.0,
-                bootloader,
-                false,
-                None,
-            )
-        }
-    };
+            BootSetupType::Migrate((storage, ..)) => {
+                let sysroot_parent = get_sysroot_parent_dev(&storage.physical_root)?;
+
+                (
+                    Utf8PathBuf::from("/sysroot"),
+                    get_esp_partition(&sysroot_parent)?.0,
+                    get_bootloader()?,
+                    false,
+                    None,
+                    None,
+                )
+            }
+        };
 
     let esp_mount = mount_esp(&esp_device).context("Mounting ESP")?;
 
@@ -1114,6 +1267,7 @@ pub(crate) fn setup_composefs_uki_boot(
                     is_insecure_from_opts,
                     esp_mount.dir.path(),
                     &bootloader,
+                    signer.as_ref(),
                 )?;
 
                 if let Some(label) = ret {
@@ -1125,6 +1279,7 @@ pub(crate) fn setup_composefs_uki_boot(
 
     let uki_label = uki_label
         .ok_or_else(|| anyhow::anyhow!("Failed to get version and boot label from UKI"))?;
+    let boot_digest = uki_label.boot_digest.clone();
 
     match bootloader {
         Bootloader::Grub => write_grub_uki_menuentry(
@@ -1138,7 +1293,7 @@ pub(crate) fn setup_composefs_uki_boot(
         Bootloader::Systemd => write_systemd_uki_config(&esp_mount.fd, &setup_type, uki_label, id)?,
     };
 
-    Ok(())
+    Ok(boot_digest)
 }
 
 #[context("Setting up composefs boot")]
@@ -1146,6 +1301,7 @@ pub(crate) fn setup_composefs_boot(
     root_setup: &RootSetup,
     state: &State,
     image_id: &str,
+    signers: &[String],
 ) -> Result<()> {
     let repo = open_composefs_repo(&root_setup.physical_root)?;
     let mut fs = create_composefs_filesystem(&repo, image_id, None)?;
@@ -1159,6 +1315,16 @@ pub(crate) fn setup_composefs_boot(
 
     let postfetch = PostFetchState::new(state, &mounted_fs)?;
 
+    let Some(entry) = entries.iter().next() else {
+        anyhow::bail!("No boot entries!");
+    };
+    let boot_type = BootType::from(entry);
+    crate::secure_boot::require_supported(
+        &state.composefs_options,
+        boot_type,
+        &postfetch.detected_bootloader,
+    )?;
+
     let boot_uuid = root_setup
         .get_boot_uuid()?
         .or(root_setup.rootfs_uuid.as_deref())
@@ -1179,29 +1345,19 @@ pub(crate) fn setup_composefs_boot(
             &root_setup.device_info,
             &root_setup.physical_root_path,
             &state.config_opts,
+            &state.composefs_options,
             None,
         )?;
     }
 
-    let Some(entry) = entries.iter().next() else {
-        anyhow::bail!("No boot entries!");
-    };
-
-    let boot_type = BootType::from(entry);
-    let mut boot_digest: Option<String> = None;
-
-    match boot_type {
-        BootType::Bls => {
-            let digest = setup_composefs_bls_boot(
-                BootSetupType::Setup((&root_setup, &state, &postfetch, &fs)),
-                repo,
-                &id,
-                entry,
-                &mounted_fs,
-            )?;
-
-            boot_digest = Some(digest);
-        }
+    let boot_digest = match boot_type {
+        BootType::Bls => setup_composefs_bls_boot(
+            BootSetupType::Setup((&root_setup, &state, &postfetch, &fs)),
+            repo,
+            &id,
+            entry,
+            &mounted_fs,
+        )?,
         BootType::Uki => setup_composefs_uki_boot(
             BootSetupType::Setup((&root_setup, &state, &postfetch, &fs)),
             repo,
@@ -1216,7 +1372,8 @@ pub(crate) fn setup_composefs_boot(
         &crate::spec::ImageReference::from(state.target_imgref.clone()),
         false,
         boot_type,
-        boot_digest,
+        Some(boot_digest),
+        signers,
     )?;
 
     Ok(())
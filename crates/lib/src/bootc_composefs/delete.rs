@@ -1,15 +1,13 @@
-use std::{collections::HashSet, io::Write, path::Path};
+use std::{io::Write, path::Path};
 
 use anyhow::{Context, Result};
 use cap_std_ext::{cap_std::fs::Dir, dirext::CapStdExtDirExt};
-use composefs::fsverity::Sha512HashValue;
 use composefs_boot::bootloader::{EFI_ADDON_DIR_EXT, EFI_EXT};
 
 use crate::{
     bootc_composefs::{
         boot::{find_vmlinuz_initrd_duplicates, get_efi_uuid_source, BootType, SYSTEMD_UKI_DIR},
-        gc::composefs_gc,
-        repo::open_composefs_repo,
+        gc::{composefs_gc, GcReport},
         rollback::{composefs_rollback, rename_exchange_user_cfg},
         status::{get_composefs_status, get_sorted_grub_uki_boot_entries},
     },
@@ -244,35 +242,6 @@ fn delete_depl_boot_entries(
     }
 }
 
-#[fn_error_context::context("Getting image objects")]
-pub(crate) fn get_image_objects(sysroot: &Dir) -> Result<HashSet<Sha512HashValue>> {
-    let repo = open_composefs_repo(&sysroot)?;
-
-    let images_dir = sysroot
-        .open_dir("composefs/images")
-        .context("Opening images dir")?;
-
-    let image_entries = images_dir
-        .entries_utf8()
-        .context("Reading entries in images dir")?;
-
-    let mut object_refs = HashSet::new();
-
-    for image in image_entries {
-        let image = image?;
-
-        let img_name = image.file_name().context("Getting image name")?;
-
-        let objects = repo
-            .objects_for_image(&img_name)
-            .with_context(|| format!("Getting objects for image {img_name}"))?;
-
-        object_refs.extend(objects);
-    }
-
-    Ok(object_refs)
-}
-
 #[fn_error_context::context("Deleting image for deployment {}", deployment_id)]
 pub(crate) fn delete_image(sysroot: &Dir, deployment_id: &str) -> Result<()> {
     let img_path = Path::new("composefs").join("images").join(deployment_id);
@@ -312,7 +281,7 @@ pub(crate) async fn delete_composefs_deployment(
     deployment_id: &str,
     storage: &Storage,
     booted_cfs: &BootedComposefs,
-) -> Result<()> {
+) -> Result<GcReport> {
     let host = get_composefs_status(storage, booted_cfs).await?;
 
     let booted = host.require_composefs_booted()?;
@@ -355,7 +324,5 @@ pub(crate) async fn delete_composefs_deployment(
 
     delete_depl_boot_entries(&depl_to_del, &storage, deleting_staged)?;
 
-    composefs_gc(storage, booted_cfs).await?;
-
-    Ok(())
+    composefs_gc(storage, booted_cfs, false).await
 }
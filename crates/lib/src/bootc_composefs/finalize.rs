@@ -4,6 +4,7 @@ use crate::bootc_composefs::boot::BootType;
 use crate::bootc_composefs::rollback::{rename_exchange_bls_entries, rename_exchange_user_cfg};
 use crate::bootc_composefs::status::get_composefs_status;
 use crate::composefs_consts::STATE_DIR_ABS;
+use crate::hooks::{run_hooks, HookType};
 use crate::spec::Bootloader;
 use crate::store::{BootedComposefs, Storage};
 use anyhow::{Context, Result};
@@ -56,8 +57,15 @@ pub(crate) async fn composefs_backend_finalize(
         "Staged deployment is not a composefs deployment"
     ))?;
 
-    // Mount the booted EROFS image to get pristine etc
     let sysroot_fd = storage.physical_root.reopen_as_ownedfd()?;
+
+    if let Some(image) = staged_depl.image.as_ref() {
+        let staged_fd = mount_composefs_image(&sysroot_fd, &staged_composefs.verity, false)?;
+        let staged_mnt = TempMount::mount_fd(&staged_fd)?;
+        run_hooks(staged_mnt.dir.path(), HookType::PreFinalize, &image.into()).await?;
+    }
+
+    // Mount the booted EROFS image to get pristine etc
     let composefs_fd = mount_composefs_image(&sysroot_fd, &booted_composefs.verity, false)?;
 
     let erofs_tmp_mnt = TempMount::mount_fd(&composefs_fd)?;
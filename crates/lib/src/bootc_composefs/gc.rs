@@ -3,6 +3,13 @@
 //! There could be the following cases (See ./delete.rs:delete_composefs_deployment):
 //! - We delete the bootloader entry but fail to delete image
 //! - We delete bootloader + image but fail to delete the state/unrefenced objects etc
+//!
+//! It also accounts for the disk space used by deployments, both for reporting what
+//! garbage collection frees and for `bootc status --verbose`.
+
+use std::collections::{HashMap, HashSet};
+use std::io::Write;
+use std::path::Path;
 
 use anyhow::{Context, Result};
 use cap_std_ext::{cap_std::fs::Dir, dirext::CapStdExtDirExt};
@@ -10,15 +17,17 @@ use composefs::fsverity::{FsVerityHashValue, Sha512HashValue};
 
 use crate::{
     bootc_composefs::{
-        delete::{delete_image, delete_staged, delete_state_dir, get_image_objects},
+        boot::is_ostree_entry,
+        delete::{delete_image, delete_staged, delete_state_dir},
+        repo::open_composefs_repo,
         status::{
             get_bootloader, get_composefs_status, get_sorted_grub_uki_boot_entries,
             get_sorted_type1_boot_entries,
         },
     },
     composefs_consts::{STATE_DIR_RELATIVE, USER_CFG},
-    spec::Bootloader,
-    store::{BootedComposefs, Storage},
+    spec::{Bootloader, DiskUsage, Host},
+    store::{BootedComposefs, ComposefsRepository, Storage},
 };
 
 #[fn_error_context::context("Listing EROFS images")]
@@ -63,28 +72,26 @@ fn list_bootloader_entries(storage: &Storage) -> Result<Vec<String>> {
                     .collect::<Result<Vec<_>, _>>()?
             } else {
                 // Type1 Entry
-                let boot_entries = get_sorted_type1_boot_entries(boot_dir, true)?;
-
-                boot_entries
-                    .into_iter()
-                    .map(|entry| entry.get_verity())
-                    .collect::<Result<Vec<_>, _>>()?
+                list_type1_entries(boot_dir)?
             }
         }
 
-        Bootloader::Systemd => {
-            let boot_entries = get_sorted_type1_boot_entries(boot_dir, true)?;
-
-            boot_entries
-                .into_iter()
-                .map(|entry| entry.get_verity())
-                .collect::<Result<Vec<_>, _>>()?
-        }
+        Bootloader::Systemd => list_type1_entries(boot_dir)?,
     };
 
     Ok(entries)
 }
 
+/// The fsverity of EROFS images corresponding to Type1 boot entries. The
+/// entries of ostree deployments left over from a migration are skipped.
+fn list_type1_entries(boot_dir: &Dir) -> Result<Vec<String>> {
+    get_sorted_type1_boot_entries(boot_dir, true)?
+        .into_iter()
+        .filter(|entry| !is_ostree_entry(entry))
+        .map(|entry| entry.get_verity())
+        .collect()
+}
+
 #[fn_error_context::context("Listing state directories")]
 fn list_state_dirs(sysroot: &Dir) -> Result<Vec<String>> {
     let state = sysroot
@@ -106,27 +113,184 @@ fn list_state_dirs(sysroot: &Dir) -> Result<Vec<String>> {
     Ok(dirs)
 }
 
-/// Deletes objects in sysroot/composefs/objects that are not being referenced by any of the
-/// present EROFS images
-///
-/// We do not delete streams though
-#[fn_error_context::context("Garbage collecting objects")]
-// TODO(Johan-Liebert1): This will be moved to composefs-rs
-pub(crate) fn gc_objects(sysroot: &Dir) -> Result<()> {
-    tracing::debug!("Running garbage collection on unreferenced objects");
+/// The images, state directories and objects removed by garbage collection, or
+/// which would be removed in a dry run
+#[derive(Debug, Default, PartialEq, Eq)]
+pub(crate) struct GcReport {
+    /// EROFS images without a bootloader entry
+    pub(crate) images: Vec<String>,
+    /// State directories without an EROFS image
+    pub(crate) state_dirs: Vec<String>,
+    /// The number of objects not referenced by any remaining image
+    pub(crate) objects: u64,
+    /// The space used by all of the above
+    pub(crate) bytes: u64,
+}
+
+impl GcReport {
+    /// Describe what was removed, or in a dry run what would be removed
+    pub(crate) fn write(&self, mut out: impl Write, dry_run: bool) -> Result<()> {
+        let verb = if dry_run { "Would remove" } else { "Removed" };
+        for image in &self.images {
+            writeln!(out, "{verb} image {image}")?;
+        }
+        for state_dir in &self.state_dirs {
+            writeln!(out, "{verb} state directory {state_dir}")?;
+        }
+        writeln!(out, "{verb} {} unreferenced objects", self.objects)?;
 
-    // Get all the objects referenced by all available images
-    let obj_refs = get_image_objects(sysroot)?;
+        let size = ostree_ext::glib::format_size(self.bytes);
+        if dry_run {
+            writeln!(out, "Would free {size}")?;
+        } else {
+            writeln!(out, "Freed {size}")?;
+        }
+        Ok(())
+    }
+}
 
-    // List all objects in the objects directory
+/// The size of the files in `dir`, recursively; symlinks are not followed
+fn dir_size(dir: &Dir) -> Result<u64> {
+    let mut size = 0;
+    for entry in dir.entries()? {
+        let entry = entry?;
+        let meta = entry.metadata()?;
+        size += meta.len();
+        if meta.is_dir() {
+            size += dir_size(&entry.open_dir()?)?;
+        }
+    }
+    Ok(size)
+}
+
+/// The size of the EROFS image and state directory of a deployment
+fn deployment_size(sysroot: &Dir, verity: &str) -> Result<u64> {
+    let image = Path::new("composefs/images").join(verity);
+    let image_size = sysroot
+        .symlink_metadata_optional(&image)
+        .with_context(|| format!("Querying {image:?}"))?
+        .map_or(0, |m| m.len());
+
+    let state = Path::new(STATE_DIR_RELATIVE).join(verity);
+    let state_size = match sysroot
+        .open_dir_optional(&state)
+        .with_context(|| format!("Opening {state:?}"))?
+    {
+        Some(dir) => dir_size(&dir).with_context(|| format!("Computing size of {state:?}"))?,
+        None => 0,
+    };
+
+    Ok(image_size + state_size)
+}
+
+/// The size of an object, or zero if it is missing
+fn object_size(objects_dir: &Dir, id: &Sha512HashValue) -> Result<u64> {
+    let hex = id.to_hex();
+    let path = format!("{}/{}", &hex[..2], &hex[2..]);
+    let meta = objects_dir
+        .symlink_metadata_optional(&path)
+        .with_context(|| format!("Querying object {path}"))?;
+    Ok(meta.map_or(0, |m| m.len()))
+}
+
+/// Get the objects referenced by each of the given EROFS images
+#[fn_error_context::context("Getting image objects")]
+fn get_image_objects(
+    repo: &ComposefsRepository,
+    images: &[String],
+) -> Result<HashMap<String, HashSet<Sha512HashValue>>> {
+    images
+        .iter()
+        .map(|image| {
+            let objects = repo
+                .objects_for_image(image)
+                .with_context(|| format!("Getting objects for image {image}"))?;
+            Ok((image.clone(), objects.into_iter().collect()))
+        })
+        .collect()
+}
+
+/// The object a symlink in `composefs/images` or `composefs/streams` points to
+fn symlink_object(dir: &Dir, name: &str) -> Result<Sha512HashValue> {
+    let target = dir
+        .read_link_contents(name)
+        .with_context(|| format!("Reading link {name}"))?;
+    let object = || {
+        let basename = target.file_name()?.to_str()?;
+        let dir_name = target.parent()?.file_name()?.to_str()?;
+        let dir_name = u8::from_str_radix(dir_name, 16).ok()?;
+        Sha512HashValue::from_object_dir_and_basename(dir_name, basename.as_bytes()).ok()
+    };
+    object().with_context(|| format!("Link {name} does not point to an object: {target:?}"))
+}
+
+/// Get every object which must be kept for the given EROFS images: the images
+/// themselves, the objects they reference, and every split stream along with the
+/// objects it references
+#[fn_error_context::context("Getting referenced objects")]
+fn get_referenced_objects(
+    sysroot: &Dir,
+    repo: &ComposefsRepository,
+    images: &[String],
+) -> Result<HashSet<Sha512HashValue>> {
+    let mut obj_refs = get_image_objects(repo, images)?
+        .into_values()
+        .flatten()
+        .collect::<HashSet<_>>();
+
+    let images_dir = sysroot
+        .open_dir("composefs/images")
+        .context("Opening images dir")?;
+    for image in images {
+        obj_refs.insert(symlink_object(&images_dir, image)?);
+    }
+
+    let Some(streams_dir) = sysroot
+        .open_dir_optional("composefs/streams")
+        .context("Opening streams dir")?
+    else {
+        return Ok(obj_refs);
+    };
+
+    // Named references to streams live in a subdirectory and point to the streams
+    // themselves, so only the symlinks at the top level need to be followed
+    for entry in streams_dir.entries_utf8()? {
+        let entry = entry?;
+        if !entry.file_type()?.is_symlink() {
+            continue;
+        }
+        let name = entry.file_name()?;
+
+        obj_refs.insert(symlink_object(&streams_dir, &name)?);
+
+        let mut stream = repo
+            .open_stream(&name, None)
+            .with_context(|| format!("Opening stream {name}"))?;
+        stream
+            .get_object_refs(|id| {
+                obj_refs.insert(id.clone());
+            })
+            .with_context(|| format!("Reading object references of stream {name}"))?;
+    }
+
+    Ok(obj_refs)
+}
+
+/// Call `f` with the ID of each object in sysroot/composefs/objects, the directory
+/// containing it and its file name
+fn for_each_object(
+    sysroot: &Dir,
+    mut f: impl FnMut(Sha512HashValue, &Dir, &str) -> Result<()>,
+) -> Result<()> {
     let objects_dir = sysroot
         .open_dir("composefs/objects")
         .context("Opening objects dir")?;
 
     for dir_name in 0x0..=0xff {
+        let dir_path = format!("{dir_name:02x}");
         let dir = objects_dir
-            .open_dir_optional(dir_name.to_string())
-            .with_context(|| format!("Opening {dir_name}"))?;
+            .open_dir_optional(&dir_path)
+            .with_context(|| format!("Opening {dir_path}"))?;
 
         let Some(dir) = dir else {
             continue;
@@ -137,21 +301,63 @@ pub(crate) fn gc_objects(sysroot: &Dir) -> Result<()> {
             let filename = entry.file_name()?;
 
             let id = Sha512HashValue::from_object_dir_and_basename(dir_name, filename.as_bytes())?;
-
-            // If this object is not referenced by any image, delete it
-            if !obj_refs.contains(&id) {
-                tracing::trace!("Deleting unreferenced object: {filename}");
-
-                entry
-                    .remove_file()
-                    .with_context(|| format!("Removing object {filename}"))?;
-            }
+            f(id, &dir, &filename)?;
         }
     }
 
     Ok(())
 }
 
+/// List all objects in sysroot/composefs/objects
+#[fn_error_context::context("Listing objects")]
+pub(crate) fn list_objects(sysroot: &Dir) -> Result<HashSet<Sha512HashValue>> {
+    let mut objects = HashSet::new();
+    for_each_object(sysroot, |id, _, _| {
+        objects.insert(id);
+        Ok(())
+    })?;
+    Ok(objects)
+}
+
+/// Deletes objects in sysroot/composefs/objects that are not in `obj_refs`, adding them
+/// to `report`. In a dry run, nothing is deleted.
+///
+/// `obj_refs` must include the EROFS images and split streams themselves, see
+/// [`get_referenced_objects`]. We do not delete the stream symlinks though
+#[fn_error_context::context("Garbage collecting objects")]
+// TODO(Johan-Liebert1): This will be moved to composefs-rs
+pub(crate) fn gc_objects(
+    sysroot: &Dir,
+    obj_refs: &HashSet<Sha512HashValue>,
+    dry_run: bool,
+    report: &mut GcReport,
+) -> Result<()> {
+    tracing::debug!("Running garbage collection on unreferenced objects");
+
+    for_each_object(sysroot, |id, dir, filename| {
+        // If this object is not referenced by any image, delete it
+        if obj_refs.contains(&id) {
+            return Ok(());
+        }
+
+        report.objects += 1;
+        report.bytes += dir
+            .symlink_metadata(filename)
+            .with_context(|| format!("Querying object {filename}"))?
+            .len();
+
+        if dry_run {
+            tracing::trace!("Would delete unreferenced object: {filename}");
+            return Ok(());
+        }
+
+        tracing::trace!("Deleting unreferenced object: {filename}");
+
+        dir.remove_file(filename)
+            .with_context(|| format!("Removing object {filename}"))
+    })
+}
+
 /// 1. List all bootloader entries
 /// 2. List all EROFS images
 /// 3. List all state directories
@@ -162,8 +368,14 @@ pub(crate) fn gc_objects(sysroot: &Dir) -> Result<()> {
 ///
 /// Similarly if EROFS image B1 doesn't exist, but state dir does, then delete the state dir and
 /// perform GC
+///
+/// With `dry_run`, nothing is deleted, but the returned report is the same.
 #[fn_error_context::context("Running composefs garbage collection")]
-pub(crate) async fn composefs_gc(storage: &Storage, booted_cfs: &BootedComposefs) -> Result<()> {
+pub(crate) async fn composefs_gc(
+    storage: &Storage,
+    booted_cfs: &BootedComposefs,
+    dry_run: bool,
+) -> Result<GcReport> {
     let host = get_composefs_status(storage, booted_cfs).await?;
     let booted_cfs_status = host.require_composefs_booted()?;
 
@@ -187,7 +399,16 @@ pub(crate) async fn composefs_gc(storage: &Storage, booted_cfs: &BootedComposefs
         )
     }
 
+    let mut report = GcReport::default();
+
     for verity in &img_bootloader_diff {
+        report.bytes += deployment_size(sysroot, verity)?;
+        report.images.push(verity.to_string());
+
+        if dry_run {
+            continue;
+        }
+
         tracing::debug!("Cleaning up orphaned image: {verity}");
 
         delete_staged(staged)?;
@@ -205,12 +426,217 @@ pub(crate) async fn composefs_gc(storage: &Storage, booted_cfs: &BootedComposefs
         .collect::<Vec<_>>();
 
     for verity in &state_img_diff {
+        report.bytes += deployment_size(sysroot, verity)?;
+        report.state_dirs.push(verity.to_string());
+
+        if dry_run {
+            continue;
+        }
+
         delete_staged(staged)?;
         delete_state_dir(&sysroot, verity)?;
     }
 
+    // Get all the objects referenced by the remaining images
+    let remaining_images = images
+        .iter()
+        .filter(|i| !img_bootloader_diff.contains(i))
+        .cloned()
+        .collect::<Vec<_>>();
+    let repo = open_composefs_repo(sysroot)?;
+    let obj_refs = get_referenced_objects(sysroot, &repo, &remaining_images)?;
+
     // Run garbage collection on objects after deleting images
-    gc_objects(&sysroot)?;
+    gc_objects(&sysroot, &obj_refs, dry_run, &mut report)?;
+
+    Ok(report)
+}
+
+/// Compute the disk space each composefs deployment in `host` uses by itself, and how
+/// much of it is shared with other images.
+#[fn_error_context::context("Computing disk usage")]
+pub(crate) fn fill_disk_usage(sysroot: &Dir, host: &mut Host) -> Result<()> {
+    let images = list_erofs_images(sysroot)?;
+    let repo = open_composefs_repo(sysroot)?;
+    let image_objects = get_image_objects(&repo, &images)?;
+
+    let mut refcounts = HashMap::<&Sha512HashValue, usize>::new();
+    for id in image_objects.values().flatten() {
+        *refcounts.entry(id).or_default() += 1;
+    }
+
+    let objects_dir = sysroot
+        .open_dir("composefs/objects")
+        .context("Opening objects dir")?;
+
+    let entries = [
+        &mut host.status.staged,
+        &mut host.status.booted,
+        &mut host.status.rollback,
+    ];
+    for cfs in entries
+        .into_iter()
+        .flatten()
+        .filter_map(|entry| entry.composefs.as_mut())
+    {
+        let mut usage = DiskUsage {
+            unique_bytes: deployment_size(sysroot, &cfs.verity)?,
+            shared_bytes: 0,
+        };
+
+        for id in image_objects.get(&cfs.verity).into_iter().flatten() {
+            let size = object_size(&objects_dir, id)?;
+            if refcounts[id] > 1 {
+                usage.shared_bytes += size;
+            } else {
+                usage.unique_bytes += size;
+            }
+        }
+
+        cfs.disk_usage = Some(usage);
+    }
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use cap_std_ext::cap_std;
+
+    #[test]
+    fn test_gc_report() -> Result<()> {
+        let report = GcReport {
+            images: vec!["abcd".into()],
+            state_dirs: vec!["ef01".into()],
+            objects: 3,
+            bytes: 1_500_000,
+        };
+
+        let mut out = Vec::new();
+        report.write(&mut out, true)?;
+        assert_eq!(
+            String::from_utf8(out)?,
+            indoc::indoc! { "
+                Would remove image abcd
+                Would remove state directory ef01
+                Would remove 3 unreferenced objects
+                Would free 1.5 MB
+            " }
+        );
+
+        let mut out = Vec::new();
+        GcReport::default().write(&mut out, false)?;
+        assert_eq!(
+            String::from_utf8(out)?,
+            "Removed 0 unreferenced objects\nFreed 0 bytes\n"
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_list_type1_entries() -> Result<()> {
+        let td = cap_std_ext::cap_tempfile::TempDir::new(cap_std::ambient_authority())?;
+        td.create_dir_all("loader/entries")?;
+        td.atomic_write(
+            "loader/entries/bootc_fedora-42-1.conf",
+            indoc::indoc! { "
+                title Fedora 42
+                version 42
+                linux /boot/abcd/vmlinuz
+                initrd /boot/abcd/initramfs.img
+                options root=UUID=abc123 rw composefs=abcd
+            " },
+        )?;
+        // An ostree entry demoted by a migration to composefs
+        td.atomic_write(
+            "loader/entries/bootc_fedora-41-0.conf",
+            indoc::indoc! { "
+                title Fedora 41 (ostree:0)
+                version 1
+                linux /boot/ostree/default-1234/vmlinuz
+                initrd /boot/ostree/default-1234/initramfs.img
+                options root=UUID=abc123 rw ostree=/ostree/boot.1/default/1234/0
+            " },
+        )?;
+
+        assert_eq!(list_type1_entries(&td)?, vec!["abcd".to_owned()]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_deployment_size() -> Result<()> {
+        let td = cap_std_ext::cap_tempfile::TempDir::new(cap_std::ambient_authority())?;
+        assert_eq!(deployment_size(&td, "abcd")?, 0);
+
+        let state = Path::new(STATE_DIR_RELATIVE).join("abcd");
+        td.create_dir_all(state.join("etc/sysconfig"))?;
+        td.write(state.join("abcd.origin"), "0123456789")?;
+        td.write(state.join("etc/sysconfig/foo"), "01234")?;
+        td.symlink("../../os/default/var", state.join("var"))?;
+
+        let size = deployment_size(&td, "abcd")?;
+        let dirs = ["etc", "etc/sysconfig"]
+            .iter()
+            .map(|d| td.symlink_metadata(state.join(d)).map(|m| m.len()))
+            .sum::<std::io::Result<u64>>()?;
+        let link = td.symlink_metadata(state.join("var"))?.len();
+        assert_eq!(size, 15 + dirs + link);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_gc_keeps_images_and_streams() -> Result<()> {
+        use composefs::splitstream::SplitStreamWriter;
+        use rustix::fs::CWD;
+
+        let td = tempfile::tempdir()?;
+        let sysroot = Dir::open_ambient_dir(td.path(), cap_std::ambient_authority())?;
+        sysroot.create_dir("composefs")?;
+        // The temporary directory may not support fsverity
+        let mut repo = ComposefsRepository::open_path(&sysroot, "composefs")?;
+        repo.set_insecure(true);
+
+        // An image with a file large enough to be stored as an object
+        let src = td.path().join("src");
+        std::fs::create_dir(&src)?;
+        std::fs::write(src.join("file"), vec![b'x'; 4096])?;
+        let fs = composefs::fs::read_filesystem(CWD, &src, Some(&repo), false)?;
+        let image = fs.commit_image(&repo, None)?.to_hex();
+
+        // A stream with its data stored as an object
+        let data = vec![b'y'; 4096];
+        let mut w = SplitStreamWriter::new(&repo, None, Some(openssl::sha::sha256(&data)));
+        w.write_external(&data, vec![])?;
+        repo.write_stream(w, Some("layer"))?;
+
+        // An object nothing references
+        repo.ensure_object(b"unreferenced")?;
+
+        let obj_refs = get_referenced_objects(&sysroot, &repo, &[image.clone()])?;
+        let mut report = GcReport::default();
+        gc_objects(&sysroot, &obj_refs, false, &mut report)?;
+        assert_eq!(report.objects, 1);
+
+        // Everything retained can still be read
+        let objects = repo.objects_for_image(&image)?;
+        assert_eq!(objects.len(), 1);
+        let mut out = Vec::new();
+        repo.merge_splitstream("refs/layer", None, &mut out)?;
+        assert_eq!(out, data);
+
+        // Mounting needs privileges
+        if rustix::process::getuid().is_root() {
+            let mnt = tempfile::tempdir()?;
+            repo.mount_at(&image, mnt.path())?;
+            let content = std::fs::read(mnt.path().join("file"));
+            rustix::mount::unmount(mnt.path(), rustix::mount::UnmountFlags::DETACH)?;
+            assert_eq!(content?, vec![b'x'; 4096]);
+        }
+
+        Ok(())
+    }
+}
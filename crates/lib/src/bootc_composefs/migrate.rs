@@ -0,0 +1,248 @@
+//! # Migrating from the ostree backend to the composefs backend
+//!
+//! `bootc switch --backend composefs` converts a host booted from an ostree
+//! deployment in place: the target image is pulled into a new composefs
+//! repository in the physical root, the merged `/etc` and `/var` of the booted
+//! deployment become the state of the new composefs deployment, and its boot
+//! entry is written alongside the ostree ones. The ostree deployments are left
+//! on disk and stay in the boot menu as rollback targets.
+//!
+//! Writing ostree deployments regenerates the boot entries from the ostree
+//! deployments alone, dropping the composefs ones, so this is refused once a
+//! system was migrated; see [`ensure_not_migrated`].
+
+use std::path::Path;
+
+use anyhow::{Context, Result};
+use camino::Utf8PathBuf;
+use cap_std_ext::cap_std::{ambient_authority, fs::Dir};
+use cap_std_ext::dirext::CapStdExtDirExt;
+use composefs::fsverity::FsVerityHashValue;
+use etc_merge::{compute_diff, merge, traverse_etc};
+use fn_error_context::context;
+use ostree_ext::container::composefs_fetch::check_signature_policy;
+use ostree_ext::container::OstreeImageReference;
+
+use crate::bootc_composefs::boot::{
+    setup_composefs_bls_boot, setup_composefs_uki_boot, BootSetupType, BootType,
+};
+use crate::bootc_composefs::repo::pull_composefs_repo;
+use crate::bootc_composefs::state::{write_composefs_origin, write_composefs_state};
+use crate::bootc_composefs::status::get_bootloader;
+use crate::cli::{imgref_for_switch, SwitchOpts};
+use crate::composefs_consts::{SHARED_VAR_PATH, STATE_DIR_RELATIVE, USER_CFG};
+use crate::disk_space::BootArtifacts;
+use crate::progress_jsonl::{Phase, ProgressWriter};
+use crate::spec::Bootloader;
+use crate::store::{BootedOstree, Storage};
+use crate::task::Task;
+
+/// Fail if the system was migrated to the composefs backend, as writing ostree
+/// deployments would remove the boot entries of the composefs deployments.
+#[context("Checking for a migration to composefs")]
+pub(crate) fn ensure_not_migrated(physical_root: &Dir) -> Result<()> {
+    let Some(state) = physical_root
+        .open_dir_optional(STATE_DIR_RELATIVE)
+        .context("Opening state dir")?
+    else {
+        return Ok(());
+    };
+    if state.entries()?.next().is_some() {
+        anyhow::bail!(
+            "This system was migrated to the composefs backend; boot the composefs deployment to change deployments"
+        );
+    }
+    Ok(())
+}
+
+/// The composefs backend expects the boot partition at /sysroot/boot, while on
+/// ostree systems it is only mounted at /boot; bind mount it in our mount namespace.
+#[context("Mounting /sysroot/boot")]
+fn mount_boot(physical_root: &Dir) -> Result<()> {
+    let root = Dir::open_ambient_dir("/", ambient_authority()).context("Opening /")?;
+    if root.is_mountpoint("boot")? != Some(true)
+        || physical_root.is_mountpoint("boot")? == Some(true)
+    {
+        return Ok(());
+    }
+
+    Task::new("Mounting /boot", "mount")
+        .args(["--bind", "/boot", "/sysroot/boot"])
+        .quiet()
+        .run()?;
+    Task::new("Remounting /sysroot/boot writable", "mount")
+        .args(["-o", "remount,rw", "/sysroot/boot"])
+        .quiet()
+        .run()
+}
+
+/// Merge the local modifications of the booted deployment's /etc into `new_etc`
+#[context("Merging /etc")]
+fn merge_etc(booted_ostree: &BootedOstree, physical_root: &Dir, new_etc: &Dir) -> Result<()> {
+    // ostree keeps the default configuration of a deployment in /usr/etc
+    let deployment_path = booted_ostree
+        .sysroot
+        .deployment_dirpath(&booted_ostree.deployment);
+    let pristine_etc = physical_root
+        .open_dir(Path::new(deployment_path.as_str()).join("usr/etc"))
+        .context("Opening deployment /usr/etc")?;
+    let current_etc = Dir::open_ambient_dir("/etc", ambient_authority()).context("Opening /etc")?;
+
+    let (pristine_files, current_files, new_files) =
+        traverse_etc(&pristine_etc, &current_etc, Some(new_etc))?;
+    let new_files = new_files.ok_or(anyhow::anyhow!("Failed to get dirtree for new etc"))?;
+
+    let diff = compute_diff(&pristine_files, &current_files)?;
+    merge(&current_etc, &current_files, new_etc, &new_files, diff)
+}
+
+/// Copy the /var of the booted stateroot into the /var shared by composefs deployments,
+/// unless an earlier migration already populated it.
+#[context("Copying /var")]
+fn copy_var(booted_ostree: &BootedOstree, physical_root: &Dir) -> Result<()> {
+    let var = physical_root
+        .open_dir(SHARED_VAR_PATH)
+        .with_context(|| format!("Opening {SHARED_VAR_PATH}"))?;
+    if var.entries()?.next().is_some() {
+        println!("Keeping existing /sysroot/{SHARED_VAR_PATH}");
+        return Ok(());
+    }
+
+    let stateroot_var = format!("/sysroot/ostree/deploy/{}/var/.", booted_ostree.stateroot());
+    Task::new("Copying /var", "cp")
+        .args([
+            "-a",
+            "--reflink=auto",
+            &stateroot_var,
+            &format!("/sysroot/{SHARED_VAR_PATH}/."),
+        ])
+        .run()
+}
+
+/// Implementation of `bootc switch --backend composefs`
+#[context("Migrating to composefs")]
+pub(crate) async fn migrate_to_composefs(
+    opts: SwitchOpts,
+    prog: ProgressWriter,
+    storage: &Storage,
+    booted_ostree: &BootedOstree<'_>,
+) -> Result<()> {
+    let target = imgref_for_switch(&opts)?;
+
+    // The composefs backend could not honor the tag policy of the booted deployment
+    if let Some(origin) = booted_ostree.deployment.origin() {
+        let policy = crate::tag_policy::policy_from_origin(&origin)?;
+        crate::tag_policy::reject_for_composefs(policy.as_ref())?;
+    }
+
+    // It would be finalized on shutdown, replacing the boot entries written here
+    if booted_ostree.sysroot.staged_deployment().is_some() {
+        anyhow::bail!("Cannot migrate to composefs with a staged ostree deployment");
+    }
+
+    let signers = check_signature_policy(&OstreeImageReference::from(target.clone()))?;
+
+    let physical_root = &storage.physical_root;
+    physical_root
+        .create_dir_all("composefs")
+        .context("Creating dir composefs")?;
+    mount_boot(physical_root)?;
+    let boot = physical_root.open_dir("boot").context("Opening boot")?;
+
+    let pull = pull_composefs_repo(
+        storage,
+        &boot,
+        BootArtifacts::Kernel,
+        &target.transport,
+        &target.image,
+        opts.quiet,
+        prog.clone(),
+    );
+    let (repo, entries, id, fs, _) = prog.phase(Phase::Pull, "Fetching image", pull).await?;
+
+    let Some(entry) = entries.iter().next() else {
+        anyhow::bail!("No boot entries!");
+    };
+
+    let boot_type = BootType::from(entry);
+
+    // There is no staged user.cfg to finalize on an ostree system, so it is written directly
+    let user_cfg = Path::new("boot/grub2").join(USER_CFG);
+    if boot_type == BootType::Uki
+        && get_bootloader()? == Bootloader::Grub
+        && physical_root.try_exists(&user_cfg)?
+    {
+        anyhow::bail!("Refusing to overwrite existing {user_cfg:?}");
+    }
+
+    // Set up the deployment state before writing a boot entry for it
+    write_composefs_state(
+        &Utf8PathBuf::from("/sysroot"),
+        id.clone(),
+        &target,
+        false,
+        boot_type,
+        None,
+        &signers,
+    )?;
+
+    let state_dir = physical_root
+        .open_dir(Path::new(STATE_DIR_RELATIVE).join(id.to_hex()))
+        .context("Opening state dir")?;
+    merge_etc(
+        booted_ostree,
+        physical_root,
+        &state_dir.open_dir("etc").context("Opening state etc")?,
+    )?;
+    copy_var(booted_ostree, physical_root)?;
+
+    let mounted_fs = Dir::reopen_dir(
+        &repo
+            .mount(&id.to_hex())
+            .context("Failed to mount composefs image")?,
+    )?;
+
+    let setup_type = BootSetupType::Migrate((storage, &fs, &booted_ostree.deployment));
+    prog.begin_phase(Phase::BootEntries, "Writing boot entries")
+        .await;
+    let boot_digest = match boot_type {
+        BootType::Bls => setup_composefs_bls_boot(setup_type, repo, &id, entry, &mounted_fs)?,
+        BootType::Uki => setup_composefs_uki_boot(setup_type, repo, &id, entries)?,
+    };
+    prog.end_phase(Phase::BootEntries).await;
+
+    write_composefs_origin(
+        &state_dir,
+        &id,
+        &target,
+        boot_type,
+        Some(boot_digest),
+        &signers,
+    )?;
+
+    println!("Migrated to the composefs backend; the ostree deployments are kept for rollback.");
+    prog.reboot_required().await;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use cap_std_ext::cap_tempfile::TempDir;
+
+    #[test]
+    fn test_ensure_not_migrated() -> Result<()> {
+        let td = TempDir::new(ambient_authority())?;
+        ensure_not_migrated(&td)?;
+
+        // An interrupted migration before any deployment was written
+        td.create_dir_all(STATE_DIR_RELATIVE)?;
+        ensure_not_migrated(&td)?;
+
+        td.create_dir_all(Path::new(STATE_DIR_RELATIVE).join("abcd"))?;
+        let e = ensure_not_migrated(&td).unwrap_err();
+        assert!(format!("{e:#}").contains("migrated to the composefs backend"));
+        Ok(())
+    }
+}
@@ -2,9 +2,11 @@ pub(crate) mod boot;
 pub(crate) mod delete;
 pub(crate) mod finalize;
 pub(crate) mod gc;
+pub(crate) mod migrate;
 pub(crate) mod repo;
 pub(crate) mod rollback;
 pub(crate) mod service;
+pub(crate) mod soft_reboot;
 pub(crate) mod state;
 pub(crate) mod status;
 pub(crate) mod switch;
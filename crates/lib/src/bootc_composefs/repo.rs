@@ -1,4 +1,5 @@
 use fn_error_context::context;
+use std::collections::HashSet;
 use std::sync::Arc;
 
 use anyhow::{Context, Result};
@@ -12,21 +13,37 @@ use ostree_ext::composefs_oci::{
     image::create_filesystem as create_composefs_filesystem, pull as composefs_oci_pull,
 };
 
-use ostree_ext::container::ImageReference as OstreeExtImgRef;
+use ostree_ext::container::composefs_fetch::{
+    check_signature_policy, ComposefsImporter, ComposefsPreparedImport,
+};
+use ostree_ext::container::{
+    ImageReference as OstreeExtImgRef, OstreeImageReference, SignatureSource, Transport,
+};
+use ostree_ext::containers_image_proxy::ImageProxyConfig;
 
 use cap_std_ext::cap_std::{ambient_authority, fs::Dir};
 
+use crate::bootc_composefs::gc::list_objects;
+use crate::deploy::{handle_layer_progress_print, send_import_completed, LayerProgressConfig};
+use crate::disk_space::{BootArtifacts, SpaceCheck};
 use crate::install::{RootSetup, State};
+use crate::progress_jsonl::ProgressWriter;
 
 pub(crate) fn open_composefs_repo(rootfs_dir: &Dir) -> Result<crate::store::ComposefsRepository> {
     crate::store::ComposefsRepository::open_path(rootfs_dir, "composefs")
         .context("Failed to open composefs repository")
 }
 
+/// Pull the source image of an install into a new composefs repository
+///
+/// # Returns
+/// The image ID, its fsverity digest, and the signers the image was verified against
 pub(crate) async fn initialize_composefs_repository(
     state: &State,
     root_setup: &RootSetup,
-) -> Result<(Sha256Digest, impl FsVerityHashValue)> {
+) -> Result<(Sha256Digest, impl FsVerityHashValue, Vec<String>)> {
+    let signers = install_signers(state)?;
+
     let rootfs_dir = &root_setup.physical_root;
 
     rootfs_dir
@@ -41,13 +58,39 @@ pub(crate) async fn initialize_composefs_repository(
     } = &state.source.imageref;
 
     // transport's display is already of type "<transport_type>:"
-    composefs_oci_pull(
-        &Arc::new(repo),
-        &format!("{transport}{image_name}"),
-        None,
-        None,
-    )
-    .await
+    let imgref = format!("{transport}{image_name}");
+    // The image proxy enforces containers-policy.json for the image being fetched,
+    // so a verification failure aborts the pull before any layer is written.
+    let (id, verity) =
+        composefs_oci_pull(&Arc::new(repo), &imgref, None, Some(proxy_config(&imgref)?)).await?;
+
+    Ok((id, verity, signers))
+}
+
+/// The signers which the source image of an install is verified against when it is
+/// pulled, to be recorded in the deployment state
+///
+/// Images in containers-storage, such as the running container, carry no signatures,
+/// so no signers are recorded for them.
+fn install_signers(state: &State) -> Result<Vec<String>> {
+    // Ensure later updates from the target image will be verified
+    check_signature_policy(&state.target_imgref)?;
+
+    let source = &state.source.imageref;
+    if source.transport == Transport::ContainerStorage {
+        if matches!(
+            state.target_imgref.sigverify,
+            SignatureSource::ContainerPolicy
+        ) {
+            println!("Signatures cannot be verified for {source}; not recording any signers");
+        }
+        return Ok(Vec::new());
+    }
+
+    check_signature_policy(&OstreeImageReference {
+        sigverify: state.target_imgref.sigverify.clone(),
+        imgref: source.clone(),
+    })
 }
 
 /// skopeo (in composefs-rs) doesn't understand "registry:"
@@ -70,29 +113,151 @@ pub(crate) fn get_imgref(transport: &str, image: &str) -> String {
     }
 }
 
+/// Pin `imgref` to the manifest `digest`, so that pulling it cannot resolve a tag to
+/// a different image. Only registry references can carry a digest; references with
+/// other transports are returned unchanged.
+fn digested_imgref(imgref: &str, digest: &str) -> String {
+    let Some(name) = imgref.strip_prefix("docker://") else {
+        return imgref.to_owned();
+    };
+    let name = name.split_once('@').map_or(name, |(name, _)| name);
+    // A tag follows the last path component, while a registry port precedes it
+    let name = match name.rfind(':') {
+        Some(i) if !name[i..].contains('/') => &name[..i],
+        _ => name,
+    };
+    format!("docker://{name}@{digest}")
+}
+
+/// Count the objects referenced by the layers with the given diff IDs, and how many of
+/// them were among the `existing` objects of the repository before they were fetched
+#[context("Counting reused objects")]
+fn count_reused_objects<'a>(
+    repo: &crate::store::ComposefsRepository,
+    diff_ids: impl IntoIterator<Item = &'a Sha256Digest>,
+    existing: &HashSet<Sha512HashValue>,
+) -> Result<(usize, usize)> {
+    let mut objects = HashSet::new();
+    for diff_id in diff_ids {
+        let name = hex::encode(diff_id);
+        let mut stream = repo
+            .open_stream(&name, None)
+            .with_context(|| format!("Opening stream {name}"))?;
+        stream.get_object_refs(|id| {
+            objects.insert(id.clone());
+        })?;
+    }
+    let reused = objects.intersection(existing).count();
+    Ok((objects.len(), reused))
+}
+
+/// The container image proxy configuration for `imgref`, with the same defaults as
+/// pulls for the ostree backend
+fn proxy_config(imgref: &str) -> Result<ImageProxyConfig> {
+    let mut config = ImageProxyConfig::default();
+    if imgref.starts_with("containers-storage:") {
+        // Fetching from containers-storage, may require privileges to read files
+        ostree_ext::container::merge_default_container_proxy_opts_with_isolation(
+            &mut config,
+            None,
+        )?;
+    } else {
+        ostree_ext::container::merge_default_container_proxy_opts(&mut config)?;
+    }
+    Ok(config)
+}
+
 /// Pulls the `image` from `transport` into a composefs repository at /sysroot
-/// Checks for boot entries in the image and returns them
+/// Checks for boot entries in the image and returns them, along with the manifest
+/// and configuration of the pulled image
+///
+/// Before fetching any layers, ensures there is enough space in the physical root
+/// and in `boot`, where the boot entry with `artifacts` will be written.
 #[context("Pulling composefs repository")]
 pub(crate) async fn pull_composefs_repo(
+    storage: &crate::store::Storage,
+    boot: &Dir,
+    artifacts: BootArtifacts<'_>,
     transport: &String,
     image: &String,
+    quiet: bool,
+    prog: ProgressWriter,
 ) -> Result<(
     crate::store::ComposefsRepository,
     Vec<ComposefsBootEntry<Sha512HashValue>>,
     Sha512HashValue,
     crate::store::ComposefsFilesystem,
+    ComposefsPreparedImport<Sha512HashValue>,
 )> {
     let rootfs_dir = Dir::open_ambient_dir("/sysroot", ambient_authority())?;
 
-    let repo = open_composefs_repo(&rootfs_dir).context("Opening composefs repo")?;
+    let repo = Arc::new(open_composefs_repo(&rootfs_dir).context("Opening composefs repo")?);
 
     let final_imgref = get_imgref(transport, image);
 
     tracing::debug!("Image to pull {final_imgref}");
 
-    let (id, verity) = composefs_oci_pull(&Arc::new(repo), &final_imgref, None, None)
-        .await
-        .context("Pulling composefs repo")?;
+    // Fetch the layers ourselves first so that we can report progress; the pull
+    // below then finds them in the repository.
+    let mut imp = ComposefsImporter::new(
+        Arc::clone(&repo),
+        &final_imgref,
+        proxy_config(&final_imgref)?,
+    )
+    .await?;
+    let mut prep = imp.prepare().await?;
+    crate::deploy::check_bootc_label(&prep.config, &prog).await;
+    let bytes_to_fetch = prep.layers_to_fetch().map(|l| l.layer.size()).sum();
+    if crate::disk_space::ensure_space(storage, boot, artifacts, bytes_to_fetch, true).await?
+        == SpaceCheck::CleanedUp
+    {
+        // The garbage collection may have removed layers counted as present
+        prep = imp.prepare().await?;
+        let bytes_to_fetch = prep.layers_to_fetch().map(|l| l.layer.size()).sum();
+        crate::disk_space::ensure_space(storage, boot, artifacts, bytes_to_fetch, false).await?;
+    }
+    if let Some(status) = prep.format_layer_status() {
+        println!("{status}");
+    }
+
+    let digest: Box<str> = prep.manifest_digest.as_ref().into();
+    let layer_progress_config = LayerProgressConfig {
+        layers: imp.request_progress(),
+        layer_bytes: imp.request_layer_progress(),
+        digest: digest.clone(),
+        n_layers_to_fetch: prep.layers_to_fetch().count(),
+        layers_total: prep.layers.len(),
+        bytes_to_download: prep.layers_to_fetch().map(|l| l.layer.size()).sum(),
+        bytes_total: prep.layers.iter().map(|l| l.layer.size()).sum(),
+        prog,
+        quiet,
+    };
+    let existing_objects = if prep.layers_to_fetch().next().is_some() {
+        list_objects(&rootfs_dir)?
+    } else {
+        Default::default()
+    };
+    let printer = tokio::task::spawn(handle_layer_progress_print(layer_progress_config));
+    let import = imp.import(&prep).await;
+    let prog = printer.await?;
+    import?;
+
+    let fetched = prep.layers_to_fetch().map(|l| &l.diff_id);
+    let (n_objects, n_reused) = count_reused_objects(&repo, fetched, &existing_objects)?;
+    if n_objects > 0 {
+        println!("Objects reused from the repository: {n_reused} of {n_objects}");
+    }
+
+    // Pull exactly the image whose layers were fetched, even if the tag has moved since
+    let pinned_imgref = digested_imgref(&final_imgref, prep.manifest_digest.as_ref());
+    let (id, verity) = composefs_oci_pull(
+        &repo,
+        &pinned_imgref,
+        None,
+        Some(proxy_config(&final_imgref)?),
+    )
+    .await
+    .context("Pulling composefs repo")?;
 
     tracing::info!("ID: {}, Verity: {}", hex::encode(id), verity.to_hex());
 
@@ -104,7 +269,9 @@ pub(crate) async fn pull_composefs_repo(
     let entries = fs.transform_for_boot(&repo)?;
     let id = fs.commit_image(&repo, None)?;
 
-    Ok((repo, entries, id, fs))
+    send_import_completed(&prog, &digest).await;
+
+    Ok((repo, entries, id, fs, prep))
 }
 
 #[cfg(test)]
@@ -149,4 +316,54 @@ mod tests {
             format!("docker-daemon:{IMAGE_NAME}")
         );
     }
+
+    #[test]
+    fn test_digested_imgref() {
+        const DIGEST: &str = "sha256:0123";
+        assert_eq!(
+            digested_imgref(&format!("docker://{IMAGE_NAME}"), DIGEST),
+            "docker://quay.io/example/image@sha256:0123"
+        );
+        assert_eq!(
+            digested_imgref("docker://localhost:5000/image", DIGEST),
+            "docker://localhost:5000/image@sha256:0123"
+        );
+        assert_eq!(
+            digested_imgref("docker://localhost:5000/image:v1@sha256:abcd", DIGEST),
+            "docker://localhost:5000/image@sha256:0123"
+        );
+        assert_eq!(
+            digested_imgref(&format!("containers-storage:{IMAGE_NAME}"), DIGEST),
+            format!("containers-storage:{IMAGE_NAME}")
+        );
+    }
+
+    #[test]
+    fn test_count_reused_objects() -> Result<()> {
+        use cap_std_ext::cap_tempfile::TempDir;
+        use ostree_ext::composefs::splitstream::SplitStreamWriter;
+
+        let td = TempDir::new(ambient_authority())?;
+        td.create_dir("composefs")?;
+        // The temporary directory may not support fsverity
+        let mut repo = open_composefs_repo(&td)?;
+        repo.set_insecure(true);
+
+        let old = vec![b'a'; 4096];
+        let new = vec![b'b'; 4096];
+        repo.ensure_object(&old)?;
+        let existing = list_objects(&td)?;
+
+        let diff_id = openssl::sha::sha256(b"layer");
+        let mut w = SplitStreamWriter::new(&repo, None, Some(diff_id));
+        w.write_external(&old, vec![])?;
+        w.write_external(&new, vec![])?;
+        w.write_external(&old, vec![])?;
+        repo.write_stream(w, None)?;
+
+        assert_eq!(count_reused_objects(&repo, [&diff_id], &existing)?, (2, 1));
+        assert_eq!(count_reused_objects(&repo, [], &existing)?, (0, 0));
+
+        Ok(())
+    }
 }
@@ -7,11 +7,11 @@ use fn_error_context::context;
 use rustix::fs::{fsync, renameat_with, AtFlags, RenameFlags};
 
 use crate::bootc_composefs::boot::{
-    primary_sort_key, secondary_sort_key, type1_entry_conf_file_name, BootType,
-    FILENAME_PRIORITY_PRIMARY, FILENAME_PRIORITY_SECONDARY,
+    is_ostree_entry, ostree_entries, primary_sort_key, secondary_sort_key,
+    type1_entry_conf_file_name, BootType, FILENAME_PRIORITY_PRIMARY, FILENAME_PRIORITY_SECONDARY,
 };
 use crate::bootc_composefs::status::{get_composefs_status, get_sorted_type1_boot_entries};
-use crate::composefs_consts::TYPE1_ENT_PATH_STAGED;
+use crate::composefs_consts::{TYPE1_ENT_PATH, TYPE1_ENT_PATH_STAGED};
 use crate::spec::Bootloader;
 use crate::store::{BootedComposefs, Storage};
 use crate::{
@@ -118,15 +118,33 @@ fn rollback_grub_uki_entries(boot_dir: &Dir) -> Result<()> {
 fn rollback_composefs_entries(boot_dir: &Dir, bootloader: Bootloader) -> Result<()> {
     use crate::bootc_composefs::state::get_booted_bls;
 
+    // Identify which entry is the currently booted one
+    let booted_bls = get_booted_bls(&boot_dir)?;
+    let booted_verity = booted_bls.get_verity()?;
+
+    write_rollback_entries(boot_dir, &booted_verity)?;
+
+    // Atomically exchange "entries" <-> "entries.rollback"
+    let dir = boot_dir.open_dir("loader").context("Opening loader dir")?;
+
+    rename_exchange_bls_entries(&dir)
+}
+
+/// Write the boot entries with swapped priorities to the staged entries directory.
+/// The entries of ostree deployments left over from a migration are carried over as is.
+#[context("Writing rollback entries")]
+fn write_rollback_entries(boot_dir: &Dir, booted_verity: &str) -> Result<()> {
     // Get all boot entries sorted in descending order by sort-key
     let mut all_configs = get_sorted_type1_boot_entries(&boot_dir, false)?;
+    all_configs.retain(|cfg| !is_ostree_entry(cfg));
 
     // TODO(Johan-Liebert): Currently assuming there are only two deployments
-    assert!(all_configs.len() == 2);
-
-    // Identify which entry is the currently booted one
-    let booted_bls = get_booted_bls(&boot_dir)?;
-    let booted_verity = booted_bls.get_verity()?;
+    if all_configs.len() != 2 {
+        anyhow::bail!(
+            "Expected two composefs boot entries, found {}",
+            all_configs.len()
+        );
+    }
 
     // For rollback: previous gets primary sort-key, booted gets secondary sort-key
     // Use "bootc" as default os_id for rollback scenarios
@@ -171,6 +189,16 @@ fn rollback_composefs_entries(boot_dir: &Dir, bootloader: Bootloader) -> Result<
             .with_context(|| format!("Writing to {file_name}"))?;
     }
 
+    let entries_dir = boot_dir
+        .open_dir(TYPE1_ENT_PATH)
+        .context("Opening entries dir")?;
+
+    for (file_name, cfg) in ostree_entries(&entries_dir)? {
+        rollback_entries_dir
+            .atomic_write(&file_name, cfg.to_string())
+            .with_context(|| format!("Writing to {file_name}"))?;
+    }
+
     let rollback_entries_dir = rollback_entries_dir
         .reopen_as_ownedfd()
         .context("Reopening as owned fd")?;
@@ -178,10 +206,7 @@ fn rollback_composefs_entries(boot_dir: &Dir, bootloader: Bootloader) -> Result<
     // Should we sync after every write?
     fsync(rollback_entries_dir).context("fsync")?;
 
-    // Atomically exchange "entries" <-> "entries.rollback"
-    let dir = boot_dir.open_dir("loader").context("Opening loader dir")?;
-
-    rename_exchange_bls_entries(&dir)
+    Ok(())
 }
 
 #[context("Rolling back composefs")]
@@ -243,3 +268,59 @@ pub(crate) async fn composefs_rollback(
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use cap_std_ext::cap_std;
+
+    fn composefs_entry(version: &str, verity: &str) -> String {
+        format!(
+            "title Fedora {version}\nversion {version}\nlinux /boot/{verity}/vmlinuz\noptions rw composefs={verity}\n"
+        )
+    }
+
+    #[test]
+    fn test_write_rollback_entries() -> Result<()> {
+        let td = cap_std_ext::cap_tempfile::TempDir::new(cap_std::ambient_authority())?;
+        td.create_dir_all(TYPE1_ENT_PATH)?;
+        td.atomic_write(
+            "loader/entries/bootc_bootc-42-1.conf",
+            composefs_entry("42", "abcd"),
+        )?;
+        // An ostree entry demoted by a migration to composefs
+        let ostree_entry = "title Fedora 40 (ostree:0)\nversion 1\nlinux /boot/ostree/default-1234/vmlinuz\noptions rw ostree=/ostree/boot.1/default/1234/0\n";
+        td.atomic_write("loader/entries/bootc_fedora-1-0.conf", ostree_entry)?;
+
+        // Right after the migration there is nothing to roll back to
+        assert!(write_rollback_entries(&td, "abcd").is_err());
+
+        td.atomic_write(
+            "loader/entries/bootc_bootc-41-0.conf",
+            composefs_entry("41", "ef01"),
+        )?;
+        write_rollback_entries(&td, "abcd")?;
+
+        let staged = td.open_dir(TYPE1_ENT_PATH_STAGED)?;
+        let mut names = staged
+            .entries_utf8()?
+            .map(|e| e?.file_name())
+            .collect::<Result<Vec<_>, _>>()?;
+        names.sort();
+        assert_eq!(
+            names,
+            [
+                "bootc_bootc-41-1.conf",
+                "bootc_bootc-42-0.conf",
+                "bootc_fedora-1-0.conf"
+            ]
+        );
+        assert!(is_ostree_entry(
+            &crate::parsers::bls_config::parse_bls_config(
+                &staged.read_to_string("bootc_fedora-1-0.conf")?
+            )?
+        ));
+
+        Ok(())
+    }
+}
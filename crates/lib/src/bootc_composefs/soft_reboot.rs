@@ -0,0 +1,105 @@
+//! Soft rebooting into composefs deployments
+//!
+//! systemd soft-reboots into `/run/nextroot` if it is populated, restarting userspace
+//! without going through the firmware, bootloader, kernel and initramfs. For composefs
+//! deployments we prepare it the same way the initramfs prepares `/sysroot`.
+
+use std::path::Path;
+use std::process::Command;
+
+use anyhow::{Context, Result};
+use bootc_utils::CommandRunExt;
+use cap_std_ext::cap_std::{ambient_authority, fs::Dir};
+use cap_std_ext::dirext::CapStdExtDirExt;
+use fn_error_context::context;
+use rustix::mount::UnmountFlags;
+use serde::{Deserialize, Serialize};
+
+use crate::composefs_consts::{
+    COMPOSEFS_SOFT_REBOOT_FNAME, COMPOSEFS_TRANSIENT_STATE_DIR, SETUP_ROOT_CONFIG,
+};
+
+const NEXTROOT: &str = "/run/nextroot";
+
+/// The deployment prepared in `/run/nextroot`
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct SoftRebootTarget {
+    /// The value of the `composefs=` karg for the deployment
+    composefs: String,
+    /// The device of the prepared root filesystem, which becomes `/` on soft reboot
+    root_dev: rustix::fs::Dev,
+}
+
+/// Prepare a soft reboot into the composefs deployment `verity`.
+///
+/// The mounts need to be visible to systemd, but we run in a private mount namespace,
+/// so this runs `bootc internals prepare-soft-reboot` in the host's mount namespace.
+#[context("Preparing soft reboot")]
+pub(crate) fn prepare_soft_reboot(verity: &str, insecure: bool) -> Result<()> {
+    let mut cmd = Command::new("nsenter");
+    cmd.args([
+        "-m",
+        "-t",
+        "1",
+        "--",
+        "bootc",
+        "internals",
+        "prepare-soft-reboot",
+    ]);
+    if insecure {
+        cmd.arg("--insecure");
+    }
+    cmd.arg(verity).run_capture_stderr()
+}
+
+/// Implementation of `bootc internals prepare-soft-reboot`
+#[context("Setting up {NEXTROOT}")]
+pub(crate) fn setup_nextroot(verity: &str, insecure: bool) -> Result<()> {
+    let run = Dir::open_ambient_dir("/run", ambient_authority()).context("Opening /run")?;
+    run.create_dir_all("nextroot")?;
+    // Replace anything prepared previously
+    if run.is_mountpoint("nextroot")?.unwrap_or_default() {
+        rustix::mount::unmount(NEXTROOT, UnmountFlags::DETACH)
+            .with_context(|| format!("Unmounting {NEXTROOT}"))?;
+    }
+
+    bootc_initramfs_setup::setup_nextroot(
+        Path::new("/sysroot"),
+        Path::new(SETUP_ROOT_CONFIG),
+        verity,
+        insecure,
+        Path::new(NEXTROOT),
+    )?;
+
+    let target = SoftRebootTarget {
+        composefs: format!("{}{verity}", if insecure { "?" } else { "" }),
+        root_dev: rustix::fs::stat(NEXTROOT)?.st_dev,
+    };
+
+    std::fs::create_dir_all(COMPOSEFS_TRANSIENT_STATE_DIR)
+        .with_context(|| format!("Creating {COMPOSEFS_TRANSIENT_STATE_DIR}"))?;
+    let state_dir = Dir::open_ambient_dir(COMPOSEFS_TRANSIENT_STATE_DIR, ambient_authority())
+        .with_context(|| format!("Opening {COMPOSEFS_TRANSIENT_STATE_DIR}"))?;
+    state_dir
+        .atomic_write(COMPOSEFS_SOFT_REBOOT_FNAME, serde_json::to_vec(&target)?)
+        .with_context(|| format!("Writing {COMPOSEFS_SOFT_REBOOT_FNAME}"))?;
+
+    Ok(())
+}
+
+/// If we were soft rebooted into a composefs deployment, return the `composefs=` value
+/// of that deployment; the kernel command line still refers to the one we booted first.
+#[context("Checking for soft reboot")]
+pub(crate) fn soft_rebooted_composefs() -> Result<Option<String>> {
+    let path = Path::new(COMPOSEFS_TRANSIENT_STATE_DIR).join(COMPOSEFS_SOFT_REBOOT_FNAME);
+    let target: SoftRebootTarget = match std::fs::read(&path) {
+        Ok(buf) => serde_json::from_slice(&buf).with_context(|| format!("Parsing {path:?}"))?,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e).with_context(|| format!("Reading {path:?}")),
+    };
+
+    // The deployment may have been prepared, but not (yet) soft rebooted into
+    let root_dev = rustix::fs::stat("/")?.st_dev;
+    Ok((target.root_dev == root_dev).then_some(target.composefs))
+}
@@ -5,18 +5,19 @@ use std::path::Path;
 use std::{fs::create_dir_all, process::Command};
 
 use anyhow::{Context, Result};
-use bootc_initramfs_setup::overlay_transient;
+use bootc_initramfs_setup::{overlay_transient, USR_OVERLAY_DIR};
 use bootc_kernel_cmdline::utf8::Cmdline;
 use bootc_mount::tempmount::TempMount;
 use bootc_utils::CommandRunExt;
-use camino::Utf8PathBuf;
+use camino::{Utf8Path, Utf8PathBuf};
 use cap_std_ext::cap_std::ambient_authority;
-use cap_std_ext::cap_std::fs::{Dir, Permissions, PermissionsExt};
+use cap_std_ext::cap_std::fs::{Dir, MetadataExt, Permissions, PermissionsExt};
 use cap_std_ext::dirext::CapStdExtDirExt;
 use composefs::fsverity::{FsVerityHashValue, Sha512HashValue};
 use fn_error_context::context;
 
 use ostree_ext::container::deploy::ORIGIN_CONTAINER;
+use ostree_ext::container::SignatureSource;
 use rustix::{
     fs::{open, Mode, OFlags},
     path::Arg,
@@ -26,16 +27,17 @@ use crate::bootc_composefs::boot::BootType;
 use crate::bootc_composefs::repo::get_imgref;
 use crate::bootc_composefs::status::get_sorted_type1_boot_entries;
 use crate::parsers::bls_config::BLSConfigType;
-use crate::store::{BootedComposefs, Storage};
+use crate::spec::UsrOverlayState;
+use crate::store::{BootedComposefs, BootedStorageKind, Storage};
 use crate::{
     composefs_consts::{
         COMPOSEFS_CMDLINE, COMPOSEFS_STAGED_DEPLOYMENT_FNAME, COMPOSEFS_TRANSIENT_STATE_DIR,
-        ORIGIN_KEY_BOOT, ORIGIN_KEY_BOOT_DIGEST, ORIGIN_KEY_BOOT_TYPE, SHARED_VAR_PATH,
-        STATE_DIR_RELATIVE,
+        ORIGIN_KEY_BOOT, ORIGIN_KEY_BOOT_DIGEST, ORIGIN_KEY_BOOT_TYPE, ORIGIN_KEY_SIGNATURE,
+        ORIGIN_KEY_SIGNATURE_SIGNERS, SETUP_ROOT_CONFIG, SHARED_VAR_PATH, STATE_DIR_RELATIVE,
     },
     parsers::bls_config::BLSConfig,
     spec::ImageReference,
-    utils::path_relative_to,
+    utils::{open_dir_remount_rw, path_relative_to},
 };
 
 pub(crate) fn get_booted_bls(boot_dir: &Dir) -> Result<BLSConfig> {
@@ -83,6 +85,16 @@ pub(crate) fn copy_etc_to_state(
     sysroot_path: &Utf8PathBuf,
     erofs_id: &String,
     state_path: &Utf8PathBuf,
+) -> Result<()> {
+    copy_pristine_etc(sysroot_path, erofs_id, &state_path.join("etc"))
+}
+
+/// Mounts an EROFS image and copies its pristine /etc into `dest`, which must exist
+#[context("Copying pristine etc")]
+pub(crate) fn copy_pristine_etc(
+    sysroot_path: &Utf8Path,
+    erofs_id: &str,
+    dest: &Utf8Path,
 ) -> Result<()> {
     let sysroot_fd = open(
         sysroot_path.as_std_path(),
@@ -91,7 +103,7 @@ pub(crate) fn copy_etc_to_state(
     )
     .context("Opening sysroot")?;
 
-    let composefs_fd = bootc_initramfs_setup::mount_composefs_image(&sysroot_fd, &erofs_id, false)?;
+    let composefs_fd = bootc_initramfs_setup::mount_composefs_image(&sysroot_fd, erofs_id, false)?;
 
     let tempdir = TempMount::mount_fd(composefs_fd)?;
 
@@ -101,14 +113,54 @@ pub(crate) fn copy_etc_to_state(
             "-a",
             "--remove-destination",
             &format!("{}/etc/.", tempdir.dir.path().as_str()?),
-            &format!("{state_path}/etc/."),
+            &format!("{dest}/."),
         ])
         .run_capture_stderr();
 
     cp_ret
 }
 
-/// Updates the currently booted image's target imgref
+/// Record `imgref` in the origin, along with how its signature was verified
+fn set_origin_imgref(ini: tini::Ini, imgref: &ImageReference, signers: &[String]) -> tini::Ini {
+    let sigverify = match &imgref.signature {
+        Some(signature) => SignatureSource::from(signature.clone()),
+        None => SignatureSource::ContainerPolicyAllowInsecure,
+    };
+    let ini = ini.section("origin").item(
+        ORIGIN_CONTAINER,
+        format!(
+            "{sigverify}:{}",
+            get_imgref(&imgref.transport, &imgref.image)
+        ),
+    );
+
+    if signers.is_empty() {
+        ini
+    } else {
+        ini.section(ORIGIN_KEY_SIGNATURE)
+            .item(ORIGIN_KEY_SIGNATURE_SIGNERS, signers.join(";"))
+    }
+}
+
+/// Records the booted image as known-good for setup-root to fall back to, if enabled;
+/// run by bootc-composefs-known-good.service once the boot completed.
+#[context("Recording known-good image")]
+pub(crate) fn record_known_good() -> Result<()> {
+    crate::cli::prepare_for_write()?;
+    let sysroot =
+        Dir::open_ambient_dir("/sysroot", ambient_authority()).context("Opening /sysroot")?;
+    // This is only writable in our mount namespace
+    open_dir_remount_rw(&sysroot, ".".into())?;
+    let cmdline = std::fs::read_to_string("/proc/cmdline").context("Reading /proc/cmdline")?;
+    bootc_initramfs_setup::record_booted_known_good(
+        Path::new("/sysroot"),
+        Path::new(SETUP_ROOT_CONFIG),
+        &cmdline,
+    )
+}
+
+/// Updates the currently booted image's target imgref. The image was not verified
+/// under the new reference, so the recorded signers are cleared.
 pub(crate) fn update_target_imgref_in_origin(
     storage: &Storage,
     booted_cfs: &BootedComposefs,
@@ -131,10 +183,9 @@ pub(crate) fn update_target_imgref_in_origin(
         tini::Ini::from_string(&origin_file).context("Failed to parse file origin file as ini")?;
 
     // Replace the origin
-    ini = ini.section("origin").item(
-        ORIGIN_CONTAINER,
-        format!("ostree-unverified-image:{imgref}"),
-    );
+    ini = set_origin_imgref(ini, imgref, &[])
+        .section(ORIGIN_KEY_SIGNATURE)
+        .item(ORIGIN_KEY_SIGNATURE_SIGNERS, "");
 
     state_dir
         .atomic_replace_with(origin_filename, move |f| -> std::io::Result<_> {
@@ -151,6 +202,35 @@ pub(crate) fn update_target_imgref_in_origin(
     Ok(())
 }
 
+/// Writes the .origin file into the state directory of a deployment
+pub(crate) fn write_composefs_origin(
+    state_dir: &Dir,
+    deployment_id: &Sha512HashValue,
+    imgref: &ImageReference,
+    boot_type: BootType,
+    boot_digest: Option<String>,
+    signers: &[String],
+) -> Result<()> {
+    let mut config = set_origin_imgref(tini::Ini::new(), imgref, signers);
+
+    config = config
+        .section(ORIGIN_KEY_BOOT)
+        .item(ORIGIN_KEY_BOOT_TYPE, boot_type);
+
+    if let Some(boot_digest) = boot_digest {
+        config = config
+            .section(ORIGIN_KEY_BOOT)
+            .item(ORIGIN_KEY_BOOT_DIGEST, boot_digest);
+    }
+
+    state_dir
+        .atomic_write(
+            format!("{}.origin", deployment_id.to_hex()),
+            config.to_string().as_bytes(),
+        )
+        .context("Failed to write to .origin file")
+}
+
 /// Creates and populates /sysroot/state/deploy/image_id
 #[context("Writing composefs state")]
 pub(crate) fn write_composefs_state(
@@ -160,6 +240,7 @@ pub(crate) fn write_composefs_state(
     staged: bool,
     boot_type: BootType,
     boot_digest: Option<String>,
+    signers: &[String],
 ) -> Result<()> {
     let state_path = root_path
         .join(STATE_DIR_RELATIVE)
@@ -179,39 +260,17 @@ pub(crate) fn write_composefs_state(
     )
     .context("Failed to create symlink for /var")?;
 
-    let ImageReference {
-        image: image_name,
-        transport,
-        ..
-    } = &imgref;
-
-    let imgref = get_imgref(&transport, &image_name);
-
-    let mut config = tini::Ini::new().section("origin").item(
-        ORIGIN_CONTAINER,
-        // TODO (Johan-Liebert1): The image won't always be unverified
-        format!("ostree-unverified-image:{imgref}"),
-    );
-
-    config = config
-        .section(ORIGIN_KEY_BOOT)
-        .item(ORIGIN_KEY_BOOT_TYPE, boot_type);
-
-    if let Some(boot_digest) = boot_digest {
-        config = config
-            .section(ORIGIN_KEY_BOOT)
-            .item(ORIGIN_KEY_BOOT_DIGEST, boot_digest);
-    }
-
     let state_dir =
         Dir::open_ambient_dir(&state_path, ambient_authority()).context("Opening state dir")?;
 
-    state_dir
-        .atomic_write(
-            format!("{}.origin", deployment_id.to_hex()),
-            config.to_string().as_bytes(),
-        )
-        .context("Failed to write to .origin file")?;
+    write_composefs_origin(
+        &state_dir,
+        &deployment_id,
+        imgref,
+        boot_type,
+        boot_digest,
+        signers,
+    )?;
 
     if staged {
         std::fs::create_dir_all(COMPOSEFS_TRANSIENT_STATE_DIR)
@@ -232,16 +291,19 @@ pub(crate) fn write_composefs_state(
     Ok(())
 }
 
-pub(crate) fn composefs_usr_overlay() -> Result<()> {
-    let usr = Dir::open_ambient_dir("/usr", ambient_authority()).context("Opening /usr")?;
+/// Returns true if something (i.e. a writable overlay) is mounted on /usr
+pub(crate) fn usr_is_mounted(usr: &Dir) -> Result<bool> {
     let is_usr_mounted = usr
         .is_mountpoint(".")
         .context("Failed to get mount details for /usr")?;
 
-    let is_usr_mounted =
-        is_usr_mounted.ok_or_else(|| anyhow::anyhow!("Failed to get mountinfo"))?;
+    is_usr_mounted.ok_or_else(|| anyhow::anyhow!("Failed to get mountinfo"))
+}
 
-    if is_usr_mounted {
+pub(crate) fn composefs_usr_overlay() -> Result<()> {
+    let usr = Dir::open_ambient_dir("/usr", ambient_authority()).context("Opening /usr")?;
+
+    if usr_is_mounted(&usr)? {
         println!("A writeable overlayfs is already mounted on /usr");
         return Ok(());
     }
@@ -253,3 +315,68 @@ pub(crate) fn composefs_usr_overlay() -> Result<()> {
 
     Ok(())
 }
+
+/// Determine the state of the `/usr` overlay of the deployment with the given state directory
+pub(crate) fn composefs_usr_overlay_state(
+    state: &Dir,
+    booted: bool,
+) -> Result<Option<UsrOverlayState>> {
+    if state.try_exists(USR_OVERLAY_DIR)? {
+        return Ok(Some(UsrOverlayState::Persistent));
+    }
+
+    // Note this also covers a discarded persistent overlay which is still mounted
+    if booted {
+        let usr = Dir::open_ambient_dir("/usr", ambient_authority()).context("Opening /usr")?;
+        if usr_is_mounted(&usr)? {
+            return Ok(Some(UsrOverlayState::Transient));
+        }
+    }
+
+    Ok(None)
+}
+
+/// Set up a persistent overlay on `/usr` for the booted deployment, stored in its
+/// state directory. This is mounted by the initramfs from the next boot on.
+#[context("Setting up persistent /usr overlay")]
+pub(crate) async fn composefs_persistent_usr_overlay() -> Result<()> {
+    let storage = &crate::cli::get_storage().await?;
+    let BootedStorageKind::Composefs(booted_cfs) = storage.kind()? else {
+        anyhow::bail!("Not booted via composefs");
+    };
+    let state = storage
+        .physical_root
+        .open_dir(Path::new(STATE_DIR_RELATIVE).join(booted_cfs.cmdline.digest.deref()))
+        .context("Opening state dir")?;
+
+    if state.try_exists(USR_OVERLAY_DIR)? {
+        println!("A persistent writeable overlayfs is already configured for /usr");
+        return Ok(());
+    }
+
+    // The root of the upper directory provides the metadata of /usr
+    let rootfs = Dir::open_ambient_dir("/", ambient_authority())?;
+    let sepolicy = crate::lsm::new_sepolicy_at(&rootfs)?;
+    let usr_mode = rustix::fs::Mode::from_raw_mode(rootfs.symlink_metadata("usr")?.mode());
+
+    // Populate under a temporary name, so we never mount a half-written overlay
+    let tmp = format!("{USR_OVERLAY_DIR}.tmp");
+    state.remove_all_optional(&tmp)?;
+    crate::lsm::ensure_dir_labeled(&state, &tmp, None, 0o700.into(), None)?;
+    crate::lsm::ensure_dir_labeled(
+        &state,
+        format!("{tmp}/upper"),
+        Some(Utf8Path::new("/usr")),
+        usr_mode,
+        sepolicy.as_ref(),
+    )?;
+    crate::lsm::ensure_dir_labeled(&state, format!("{tmp}/work"), None, 0o700.into(), None)?;
+    state
+        .rename(&tmp, &state, USR_OVERLAY_DIR)
+        .context("Renaming overlay dir")?;
+
+    println!("A persistent writeable overlayfs will be mounted on /usr from the next boot.");
+    println!("Use `bootc usr-overlay discard` to remove it.");
+
+    Ok(())
+}
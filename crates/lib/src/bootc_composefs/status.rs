@@ -5,20 +5,23 @@ use bootc_kernel_cmdline::utf8::Cmdline;
 use fn_error_context::context;
 
 use crate::{
-    bootc_composefs::boot::BootType,
+    bootc_composefs::{
+        boot::BootType, soft_reboot::soft_rebooted_composefs, state::composefs_usr_overlay_state,
+    },
     composefs_consts::{COMPOSEFS_CMDLINE, ORIGIN_KEY_BOOT_DIGEST, TYPE1_ENT_PATH, USER_CFG},
     install::EFI_LOADER_INFO,
     parsers::{
         bls_config::{parse_bls_config, BLSConfig, BLSConfigType},
         grub_menuconfig::{parse_grub_menuentry_file, MenuEntry},
     },
-    spec::{BootEntry, BootOrder, Host, HostSpec, ImageReference, ImageStatus},
+    spec::{BootEntry, BootEntryComposefs, BootOrder, Host, HostSpec, ImageReference, ImageStatus},
     store::Storage,
     utils::{read_uefi_var, EfiError},
 };
 
 use std::str::FromStr;
 
+use bootc_initramfs_setup::{BOOT_FAILURE_FILE, FALLBACK_BOOTED_FILE};
 use bootc_utils::try_deserialize_timestamp;
 use cap_std_ext::cap_std::fs::Dir;
 use ostree_container::OstreeImageReference;
@@ -32,7 +35,7 @@ use tokio::io::AsyncReadExt;
 
 use crate::composefs_consts::{
     COMPOSEFS_STAGED_DEPLOYMENT_FNAME, COMPOSEFS_TRANSIENT_STATE_DIR, ORIGIN_KEY_BOOT,
-    ORIGIN_KEY_BOOT_TYPE, STATE_DIR_RELATIVE,
+    ORIGIN_KEY_BOOT_TYPE, ORIGIN_KEY_SIGNATURE, ORIGIN_KEY_SIGNATURE_SIGNERS, STATE_DIR_RELATIVE,
 };
 use crate::spec::Bootloader;
 
@@ -64,6 +67,17 @@ impl std::fmt::Display for ComposefsCmdline {
     }
 }
 
+/// If the initramfs booted the last known-good image because the one on the kernel
+/// command line failed to mount, return its `composefs=` value
+#[context("Checking for fallback boot")]
+fn fallback_booted_composefs() -> Result<Option<String>> {
+    match std::fs::read_to_string(FALLBACK_BOOTED_FILE) {
+        Ok(s) => Ok(Some(s.trim().to_owned())),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e).with_context(|| format!("Reading {FALLBACK_BOOTED_FILE}")),
+    }
+}
+
 /// Detect if we have composefs=<digest> in /proc/cmdline
 pub(crate) fn composefs_booted() -> Result<Option<&'static ComposefsCmdline>> {
     static CACHED_DIGEST_VALUE: OnceLock<Option<ComposefsCmdline>> = OnceLock::new();
@@ -75,7 +89,12 @@ pub(crate) fn composefs_booted() -> Result<Option<&'static ComposefsCmdline>> {
         return Ok(None);
     };
     let Some(v) = kv.value() else { return Ok(None) };
-    let v = ComposefsCmdline::new(v);
+    // After a soft reboot, or if the initramfs fell back to the last known-good image,
+    // the kernel command line does not refer to the booted deployment
+    let v = match soft_rebooted_composefs()?.or(fallback_booted_composefs()?) {
+        Some(target) => ComposefsCmdline::new(&target),
+        None => ComposefsCmdline::new(v),
+    };
     let r = CACHED_DIGEST_VALUE.get_or_init(|| Some(v));
     Ok(r.as_ref())
 }
@@ -213,6 +232,7 @@ async fn boot_entry_from_composefs_deployment(
                 timestamp,
                 image_digest,
                 architecture,
+                resolved_image: None,
             };
 
             Some(image_status)
@@ -228,6 +248,15 @@ async fn boot_entry_from_composefs_deployment(
     };
 
     let boot_digest = origin.get::<String>(ORIGIN_KEY_BOOT, ORIGIN_KEY_BOOT_DIGEST);
+    let signers = origin
+        .get::<String>(ORIGIN_KEY_SIGNATURE, ORIGIN_KEY_SIGNATURE_SIGNERS)
+        .map(|s| {
+            s.split(';')
+                .filter(|s| !s.is_empty())
+                .map(Into::into)
+                .collect()
+        })
+        .unwrap_or_default();
 
     let e = BootEntry {
         image,
@@ -241,13 +270,28 @@ async fn boot_entry_from_composefs_deployment(
             boot_type,
             bootloader: get_bootloader()?,
             boot_digest,
+            signers,
+            boot_failure: None,
+            disk_usage: None,
         }),
         soft_reboot_capable: false,
+        usr_overlay: None,
     };
 
     Ok(e)
 }
 
+/// Whether we can soft reboot from the booted deployment into `target`.
+///
+/// A soft reboot keeps the running kernel and skips the initramfs, so both must be
+/// identical. The boot digest covers the kernel and initramfs, for UKIs this is the
+/// digest of their embedded `.linux` and `.initrd` sections.
+fn can_soft_reboot_into(booted: &BootEntryComposefs, target: &BootEntryComposefs) -> bool {
+    booted.boot_type == target.boot_type
+        && booted.boot_digest.is_some()
+        && booted.boot_digest == target.boot_digest
+}
+
 /// Get composefs status using provided storage and booted composefs data
 /// instead of scraping global state.
 #[context("Getting composefs deployment status")]
@@ -275,6 +319,8 @@ pub(crate) async fn composefs_deployment_status_from(
     let host_spec = HostSpec {
         image: None,
         boot_order: BootOrder::Default,
+        // Not supported with composefs; migrating a host with a policy is refused
+        tag_policy: None,
     };
 
     let mut host = Host::new(host_spec);
@@ -301,19 +347,33 @@ pub(crate) async fn composefs_deployment_status_from(
         let depl_file_name = depl.file_name();
         let depl_file_name = depl_file_name.to_string_lossy();
 
-        // read the origin file
-        let config = depl
+        let depl_dir = depl
             .open_dir()
-            .with_context(|| format!("Failed to open {depl_file_name}"))?
+            .with_context(|| format!("Failed to open {depl_file_name}"))?;
+
+        // read the origin file
+        let config = depl_dir
             .read_to_string(format!("{depl_file_name}.origin"))
             .with_context(|| format!("Reading file {depl_file_name}.origin"))?;
 
         let ini = tini::Ini::from_string(&config)
             .with_context(|| format!("Failed to parse file {depl_file_name}.origin as ini"))?;
 
-        let boot_entry =
+        let mut boot_entry =
             boot_entry_from_composefs_deployment(ini, depl_file_name.to_string()).await?;
 
+        let is_booted = depl.file_name() == composefs_digest.as_ref();
+        boot_entry.usr_overlay = composefs_usr_overlay_state(&depl_dir, is_booted)?;
+        // SAFETY: boot_entry.composefs will always be present
+        boot_entry.composefs.as_mut().unwrap().boot_failure = depl_dir
+            .read_to_string(BOOT_FAILURE_FILE)
+            .map(|s| Some(s.trim().to_owned()))
+            .or_else(|e| match e.kind() {
+                std::io::ErrorKind::NotFound => Ok(None),
+                _ => Err(e),
+            })
+            .with_context(|| format!("Reading {BOOT_FAILURE_FILE} of {depl_file_name}"))?;
+
         // SAFETY: boot_entry.composefs will always be present
         let boot_type_from_origin = boot_entry.composefs.as_ref().unwrap().boot_type;
 
@@ -329,7 +389,7 @@ pub(crate) async fn composefs_deployment_status_from(
             }
         };
 
-        if depl.file_name() == composefs_digest.as_ref() {
+        if is_booted {
             host.spec.image = boot_entry.image.as_ref().map(|x| x.image.clone());
             host.status.booted = Some(boot_entry);
             continue;
@@ -345,6 +405,19 @@ pub(crate) async fn composefs_deployment_status_from(
         host.status.rollback = Some(boot_entry);
     }
 
+    // Staged deployments are finalized (/etc merge) on shutdown, which happens too late
+    // to prepare /run/nextroot, so only the rollback deployment can be soft rebooted into,
+    // and only if finalizing a staged deployment won't change the boot entries under us.
+    if ostree_ext::systemd_has_soft_reboot() && host.status.staged.is_none() {
+        let booted = host.require_composefs_booted()?.clone();
+        if let Some(rollback) = host.status.rollback.as_mut() {
+            rollback.soft_reboot_capable = rollback
+                .composefs
+                .as_ref()
+                .is_some_and(|target| can_soft_reboot_into(&booted, target));
+        }
+    }
+
     // Shouldn't really happen, but for sanity nonetheless
     let Some(boot_type) = boot_type else {
         anyhow::bail!("Could not determine boot type");
@@ -435,6 +508,30 @@ mod tests {
         assert_eq!(v.digest.as_ref(), DIGEST);
     }
 
+    #[test]
+    fn test_can_soft_reboot_into() {
+        let entry = |boot_type, boot_digest: Option<&str>| BootEntryComposefs {
+            verity: "verity".into(),
+            boot_type,
+            bootloader: Bootloader::Systemd,
+            boot_digest: boot_digest.map(Into::into),
+            signers: Vec::new(),
+            boot_failure: None,
+            disk_usage: None,
+        };
+
+        let booted = entry(BootType::Bls, Some("abc"));
+        let can = |target| can_soft_reboot_into(&booted, &target);
+        assert!(can(entry(BootType::Bls, Some("abc"))));
+        assert!(!can(entry(BootType::Bls, Some("def"))));
+        assert!(!can(entry(BootType::Bls, None)));
+        assert!(!can(entry(BootType::Uki, Some("abc"))));
+
+        // Deployments predating boot digests for UKIs
+        let booted = entry(BootType::Uki, None);
+        assert!(!can_soft_reboot_into(&booted, &entry(BootType::Uki, None)));
+    }
+
     #[test]
     fn test_sorted_bls_boot_entries() -> Result<()> {
         let tempdir = cap_std_ext::cap_tempfile::tempdir(cap_std::ambient_authority())?;
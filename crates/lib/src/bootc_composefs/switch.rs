@@ -1,5 +1,7 @@
 use anyhow::{Context, Result};
 use fn_error_context::context;
+use ostree_ext::container::composefs_fetch::check_signature_policy;
+use ostree_ext::container::OstreeImageReference;
 
 use crate::{
     bootc_composefs::{
@@ -8,15 +10,17 @@ use crate::{
         update::{do_upgrade, is_image_pulled, validate_update, UpdateAction},
     },
     cli::{imgref_for_switch, SwitchOpts},
+    progress_jsonl::ProgressWriter,
     store::{BootedComposefs, Storage},
 };
 
 #[context("Composefs Switching")]
 pub(crate) async fn switch_composefs(
     opts: SwitchOpts,
+    prog: ProgressWriter,
     storage: &Storage,
     booted_cfs: &BootedComposefs,
-) -> Result<()> {
+) -> Result<bool> {
     let target = imgref_for_switch(&opts)?;
     // TODO: Handle in-place
 
@@ -32,7 +36,7 @@ pub(crate) async fn switch_composefs(
 
     if new_spec == host.spec {
         println!("Image specification is unchanged.");
-        return Ok(());
+        return Ok(false);
     }
 
     let Some(target_imgref) = new_spec.image else {
@@ -40,7 +44,8 @@ pub(crate) async fn switch_composefs(
     };
 
     let repo = &*booted_cfs.repo;
-    let (image, manifest, _) = is_image_pulled(repo, &target_imgref).await?;
+    let (image, manifest, config) = is_image_pulled(repo, &target_imgref).await?;
+    let booted_status = host.status.booted.as_ref().and_then(|b| b.image.as_ref());
 
     if let Some(cfg_verity) = image {
         let action = validate_update(
@@ -55,23 +60,35 @@ pub(crate) async fn switch_composefs(
         match action {
             UpdateAction::Skip => {
                 println!("No changes in image: {target_imgref:#}");
-                return Ok(());
+                return Ok(false);
             }
 
             UpdateAction::Proceed => {
-                return do_upgrade(storage, &host, &target_imgref).await;
+                crate::image_constraints::check(
+                    booted_status,
+                    &target_imgref,
+                    &config,
+                    opts.allow_downgrade,
+                )?;
+                do_upgrade(storage, &host, &target_imgref, opts.quiet, prog).await?;
+                return Ok(true);
             }
 
             UpdateAction::UpdateOrigin => {
+                // Nothing is pulled, so no signature is verified here; the next
+                // pull enforces the policy.
+                check_signature_policy(&OstreeImageReference::from(target_imgref.clone()))?;
                 // The staged image will never be the current image's verity digest
                 println!("Image already in composefs repository");
                 println!("Updating target image reference");
-                return update_target_imgref_in_origin(storage, booted_cfs, &target_imgref);
+                update_target_imgref_in_origin(storage, booted_cfs, &target_imgref)?;
+                return Ok(false);
             }
         }
     }
 
-    do_upgrade(storage, &host, &target_imgref).await?;
+    crate::image_constraints::check(booted_status, &target_imgref, &config, opts.allow_downgrade)?;
+    do_upgrade(storage, &host, &target_imgref, opts.quiet, prog).await?;
 
-    Ok(())
+    Ok(true)
 }
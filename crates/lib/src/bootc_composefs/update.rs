@@ -1,4 +1,5 @@
 use anyhow::{Context, Result};
+use bootc_mount::tempmount::TempMount;
 use camino::Utf8PathBuf;
 use cap_std_ext::cap_std::fs::Dir;
 use composefs::{
@@ -8,6 +9,8 @@ use composefs::{
 use composefs_boot::BootOps;
 use composefs_oci::image::create_filesystem;
 use fn_error_context::context;
+use ostree_ext::container::composefs_fetch::check_signature_policy;
+use ostree_ext::container::{self as ostree_container, OstreeImageReference};
 use ostree_ext::oci_spec::image::{ImageConfiguration, ImageManifest};
 
 use crate::{
@@ -20,6 +23,9 @@ use crate::{
     },
     cli::UpgradeOpts,
     composefs_consts::{STATE_DIR_RELATIVE, TYPE1_ENT_PATH_STAGED, USER_CFG_STAGED},
+    disk_space::BootArtifacts,
+    hooks::{run_hooks, HookTarget, HookType},
+    progress_jsonl::{Phase, ProgressWriter},
     spec::{Bootloader, Host, ImageReference},
     store::{BootedComposefs, ComposefsRepository, Storage},
 };
@@ -211,34 +217,68 @@ pub(crate) async fn do_upgrade(
     storage: &Storage,
     host: &Host,
     imgref: &ImageReference,
+    quiet: bool,
+    prog: ProgressWriter,
 ) -> Result<()> {
+    // The image proxy enforces containers-policy.json while fetching, so verification
+    // failures abort before anything is written.
+    let signers = check_signature_policy(&OstreeImageReference::from(imgref.clone()))?;
+
     start_finalize_stated_svc()?;
 
-    let (repo, entries, id, fs) = pull_composefs_repo(&imgref.transport, &imgref.image).await?;
+    // UKIs are written to the ESP, Type 1 entries to the boot directory
+    let booted = host.require_composefs_booted()?;
+    let (boot, artifacts) = match booted.boot_type {
+        BootType::Uki => (
+            &storage.esp.as_ref().context("ESP not mounted")?.fd,
+            BootArtifacts::Uki { id: &booted.verity },
+        ),
+        BootType::Bls => (storage.require_boot_dir()?, BootArtifacts::Kernel),
+    };
+
+    let pull = pull_composefs_repo(
+        storage,
+        boot,
+        artifacts,
+        &imgref.transport,
+        &imgref.image,
+        quiet,
+        prog.clone(),
+    );
+    let (repo, entries, id, fs, prep) = prog.phase(Phase::Pull, "Fetching image", pull).await?;
 
     let Some(entry) = entries.iter().next() else {
         anyhow::bail!("No boot entries!");
     };
 
-    let mounted_fs = Dir::reopen_dir(
-        &repo
-            .mount(&id.to_hex())
+    let mnt = TempMount::mount_fd(
+        repo.mount(&id.to_hex())
             .context("Failed to mount composefs image")?,
     )?;
+    let hook_target = HookTarget {
+        image: imgref.clone(),
+        digest: Some(prep.manifest_digest.to_string()),
+        version: ostree_container::version_for_config(&prep.config).map(ToOwned::to_owned),
+    };
+    prog.phase(
+        Phase::Stage,
+        "Preparing deployment",
+        run_hooks(mnt.dir.path(), HookType::PreStage, &hook_target),
+    )
+    .await?;
 
     let boot_type = BootType::from(entry);
-    let mut boot_digest = None;
-
-    match boot_type {
-        BootType::Bls => {
-            boot_digest = Some(setup_composefs_bls_boot(
-                BootSetupType::Upgrade((storage, &fs, &host)),
-                repo,
-                &id,
-                entry,
-                &mounted_fs,
-            )?)
-        }
+
+    prog.begin_phase(Phase::BootEntries, "Writing boot entries")
+        .await;
+    let boot_digest = match boot_type {
+        BootType::Bls => setup_composefs_bls_boot(
+            BootSetupType::Upgrade((storage, &fs, &host)),
+            repo,
+            &id,
+            entry,
+            &mnt.fd,
+        )?,
 
         BootType::Uki => setup_composefs_uki_boot(
             BootSetupType::Upgrade((storage, &fs, &host)),
@@ -247,25 +287,34 @@ pub(crate) async fn do_upgrade(
             entries,
         )?,
     };
+    prog.end_phase(Phase::BootEntries).await;
 
+    prog.begin_phase(Phase::Deploy, "Deploying image").await;
     write_composefs_state(
         &Utf8PathBuf::from("/sysroot"),
         id,
         imgref,
         true,
         boot_type,
-        boot_digest,
+        Some(boot_digest),
+        &signers,
     )?;
+    prog.end_phase(Phase::Deploy).await;
+    prog.reboot_required().await;
+
+    run_hooks(mnt.dir.path(), HookType::PostStage, &hook_target).await?;
 
     Ok(())
 }
 
+/// Returns whether a deployment is queued for the next boot.
 #[context("Upgrading composefs")]
 pub(crate) async fn upgrade_composefs(
     opts: UpgradeOpts,
+    prog: ProgressWriter,
     storage: &Storage,
     composefs: &BootedComposefs,
-) -> Result<()> {
+) -> Result<bool> {
     let host = get_composefs_status(storage, composefs)
         .await
         .context("Getting composefs deployment status")?;
@@ -277,6 +326,7 @@ pub(crate) async fn upgrade_composefs(
         .ok_or_else(|| anyhow::anyhow!("No image source specified"))?;
 
     let repo = &*composefs.repo;
+    let booted_status = host.status.booted.as_ref().and_then(|b| b.image.as_ref());
 
     let (img_pulled, mut manifest, mut config) = is_image_pulled(&repo, booted_imgref).await?;
     let booted_img_digest = manifest.config().digest().digest().to_owned();
@@ -289,13 +339,11 @@ pub(crate) async fn upgrade_composefs(
         // We have a staged image and it has the same digest as the currently booted image's latest
         // digest
         if staged_image.image_digest == booted_img_digest {
-            if opts.apply {
-                return crate::reboot::reboot();
+            if !opts.apply {
+                println!("Update already staged. To apply update run `bootc update --apply`");
             }
-
-            println!("Update already staged. To apply update run `bootc update --apply`");
-
-            return Ok(());
+            prog.reboot_required().await;
+            return Ok(true);
         }
 
         // We have a staged image but it's not the update image.
@@ -321,11 +369,12 @@ pub(crate) async fn upgrade_composefs(
             match action {
                 UpdateAction::Skip => {
                     println!("No changes in staged image: {booted_imgref:#}");
-                    return Ok(());
+                    return Ok(false);
                 }
 
                 UpdateAction::Proceed => {
-                    return do_upgrade(storage, &host, booted_imgref).await;
+                    do_upgrade(storage, &host, booted_imgref, opts.quiet, prog).await?;
+                    return Ok(true);
                 }
 
                 UpdateAction::UpdateOrigin => {
@@ -349,11 +398,18 @@ pub(crate) async fn upgrade_composefs(
         match action {
             UpdateAction::Skip => {
                 println!("No changes in: {booted_imgref:#}");
-                return Ok(());
+                return Ok(false);
             }
 
             UpdateAction::Proceed => {
-                return do_upgrade(storage, &host, booted_imgref).await;
+                crate::image_constraints::check(
+                    booted_status,
+                    booted_imgref,
+                    &config,
+                    opts.allow_downgrade,
+                )?;
+                do_upgrade(storage, &host, booted_imgref, opts.quiet, prog).await?;
+                return Ok(true);
             }
 
             UpdateAction::UpdateOrigin => {
@@ -363,6 +419,7 @@ pub(crate) async fn upgrade_composefs(
     }
 
     if opts.check {
+        crate::image_constraints::report(booted_status, booted_imgref, &config);
         // TODO(Johan-Liebert1): If we have the previous, i.e. the current manifest with us then we can replace the
         // following with [`ostree_container::ManifestDiff::new`] which will be much cleaner
         for (idx, diff_id) in config.rootfs().diff_ids().iter().enumerate() {
@@ -387,14 +444,11 @@ pub(crate) async fn upgrade_composefs(
             }
         }
 
-        return Ok(());
+        return Ok(false);
     }
 
-    do_upgrade(storage, &host, booted_imgref).await?;
-
-    if opts.apply {
-        return crate::reboot::reboot();
-    }
+    crate::image_constraints::check(booted_status, booted_imgref, &config, opts.allow_downgrade)?;
+    do_upgrade(storage, &host, booted_imgref, opts.quiet, prog).await?;
 
-    Ok(())
+    Ok(true)
 }
@@ -10,6 +10,7 @@ use bootc_blockdev::{Partition, PartitionTable};
 use bootc_mount as mount;
 
 use crate::bootc_composefs::boot::mount_esp;
+use crate::secure_boot::{install_enrollment_keys, SecureBootSigner};
 use crate::{discoverable_partition_specification, utils};
 
 /// The name of the mountpoint for efi (as a subdirectory of /boot, or at the toplevel)
@@ -73,6 +74,7 @@ pub(crate) fn install_systemd_boot(
     device: &PartitionTable,
     _rootfs: &Utf8Path,
     _configopts: &crate::install::InstallConfigOpts,
+    composefs_opts: &crate::install::InstallComposefsOpts,
     _deployment_path: Option<&str>,
 ) -> Result<()> {
     let esp_part = device
@@ -87,7 +89,17 @@ pub(crate) fn install_systemd_boot(
     Command::new("bootctl")
         .args(["install", "--esp-path", esp_path.as_str()])
         .log_debug()
-        .run_inherited_with_cmd_context()
+        .run_inherited_with_cmd_context()?;
+
+    if let Some(signer) = SecureBootSigner::from_opts(composefs_opts) {
+        signer.sign_systemd_boot(esp_path)?;
+    }
+
+    if let Some(keys) = composefs_opts.secure_boot_enroll_keys.as_deref() {
+        install_enrollment_keys(&esp_mount.fd, keys)?;
+    }
+
+    Ok(())
 }
 
 #[context("Installing bootloader using zipl")]
@@ -0,0 +1,306 @@
+//! # Removing old deployments and unused images
+//!
+//! `bootc cleanup` applies a retention policy to the previous deployments of
+//! the booted system, and then garbage collects whatever is no longer
+//! referenced: for ostree the container images and layers in the repository
+//! and the logically bound images, for composefs the EROFS images, state
+//! directories and objects. The policy is read from `bootc/cleanup/*.toml`
+//! and can be overridden on the command line.
+
+use std::collections::HashSet;
+
+use anyhow::{Context, Result};
+use cap_std_ext::cap_std;
+use cap_std_ext::cap_std::fs::Dir;
+use fn_error_context::context;
+use ostree_ext::container::OstreeImageReference;
+use ostree_ext::gio;
+use serde::Deserialize;
+
+use crate::bootc_composefs::delete::delete_composefs_deployment;
+use crate::bootc_composefs::gc::composefs_gc;
+use crate::bootc_composefs::status::get_composefs_status;
+use crate::cli::CleanupOpts;
+use crate::store::{BootedComposefs, BootedOstree, BootedStorageKind, Storage};
+
+/// The path under which the retention policy is found.
+const CONFIG_PATH: &str = "bootc/cleanup";
+
+/// The top level of a configuration file.
+#[derive(Debug, Default, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+struct CleanupConfigToplevel {
+    cleanup: Option<CleanupConfig>,
+}
+
+/// The retention policy for `bootc cleanup`.
+#[derive(Debug, Default, Clone, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) struct CleanupConfig {
+    /// The number of previous deployments to keep; if unset, all are kept.
+    keep_previous: Option<u32>,
+    /// Remove the metadata of updates fetched with `bootc upgrade --check`.
+    clear_cached_update: Option<bool>,
+    /// Clean up when an update needs more disk space than is available.
+    auto: Option<bool>,
+}
+
+impl CleanupConfig {
+    /// Settings from later configuration files take precedence.
+    fn merge(&mut self, other: Self) {
+        self.keep_previous = other.keep_previous.or(self.keep_previous);
+        self.clear_cached_update = other.clear_cached_update.or(self.clear_cached_update);
+        self.auto = other.auto.or(self.auto);
+    }
+
+    /// Whether to clean up when an update needs more disk space than is available.
+    pub(crate) fn auto(&self) -> bool {
+        self.auto.unwrap_or_default()
+    }
+}
+
+impl From<&CleanupOpts> for CleanupConfig {
+    fn from(opts: &CleanupOpts) -> Self {
+        Self {
+            keep_previous: opts.keep_previous,
+            clear_cached_update: opts.clear_cached_update.then_some(true),
+            auto: None,
+        }
+    }
+}
+
+/// Load the retention policy from `bootc/cleanup/*.toml`.
+#[context("Loading cleanup configuration")]
+pub(crate) fn load_config() -> Result<CleanupConfig> {
+    const SYSTEMD_CONVENTIONAL_BASES: &[&str] = &["/usr/lib", "/usr/local/lib", "/etc", "/run"];
+    let fragments = liboverdrop::scan(SYSTEMD_CONVENTIONAL_BASES, CONFIG_PATH, &["toml"], true);
+    let mut config = CleanupConfig::default();
+    for (_name, path) in fragments {
+        let buf = std::fs::read_to_string(&path)?;
+        let mut unused = std::collections::HashSet::new();
+        let de = toml::Deserializer::parse(&buf).with_context(|| format!("Parsing {path:?}"))?;
+        let c: CleanupConfigToplevel = serde_ignored::deserialize(de, |path| {
+            unused.insert(path.to_string());
+        })
+        .with_context(|| format!("Parsing {path:?}"))?;
+        for key in unused {
+            eprintln!("warning: {path:?}: Unknown key {key}");
+        }
+        if let Some(c) = c.cleanup {
+            tracing::debug!("Merging cleanup config: {c:?}");
+            config.merge(c);
+        }
+    }
+    Ok(config)
+}
+
+/// Split `deployments` into the ones to keep and the ones to remove, keeping
+/// the first `keep` of those for which `is_previous` is true and all others.
+fn apply_retention<T>(
+    deployments: impl IntoIterator<Item = T>,
+    keep: u32,
+    is_previous: impl Fn(&T) -> bool,
+) -> (Vec<T>, Vec<T>) {
+    let mut n_previous = 0;
+    deployments.into_iter().partition(|d| {
+        if !is_previous(d) {
+            return true;
+        }
+        n_previous += 1;
+        n_previous <= keep
+    })
+}
+
+/// Remove the previous deployments of the booted stateroot beyond `keep`;
+/// pinned deployments and a queued rollback are never removed.
+#[context("Removing previous deployments")]
+fn remove_previous_ostree(booted_ostree: &BootedOstree<'_>, keep: u32) -> Result<()> {
+    let sysroot = booted_ostree.sysroot;
+    let booted = &booted_ostree.deployment;
+    let stateroot = booted.osname();
+
+    // The staged deployment is never removed here
+    let deployments = sysroot.deployments().into_iter().filter(|d| !d.is_staged());
+    let (kept, removed) = apply_retention(deployments, keep, |d| {
+        d.index() > booted.index() && !d.is_pinned() && d.osname() == stateroot
+    });
+    if removed.is_empty() {
+        return Ok(());
+    }
+
+    for d in &removed {
+        println!("Removing deployment {}.{}", d.csum(), d.deployserial());
+    }
+    // libostree only keeps the staged deployment if it is the first one
+    let kept = sysroot
+        .staged_deployment()
+        .into_iter()
+        .chain(kept)
+        .collect::<Vec<_>>();
+    sysroot.write_deployments(&kept, gio::Cancellable::NONE)?;
+    Ok(())
+}
+
+/// The bytes available in the filesystems of `dirs`, counting each filesystem once.
+fn available_bytes(dirs: &[&Dir]) -> Result<u64> {
+    let mut devs = HashSet::new();
+    let mut avail = 0;
+    for d in dirs {
+        if devs.insert(rustix::fs::fstat(d)?.st_dev) {
+            let stat = rustix::fs::fstatvfs(d)?;
+            avail += stat.f_bsize * stat.f_bavail;
+        }
+    }
+    Ok(avail)
+}
+
+#[context("Cleanup (ostree)")]
+async fn cleanup_ostree(
+    storage: &Storage,
+    booted_ostree: &BootedOstree<'_>,
+    config: &CleanupConfig,
+) -> Result<()> {
+    // Removing deployments frees space in /boot too
+    let boot =
+        Dir::open_ambient_dir("/boot", cap_std::ambient_authority()).context("Opening /boot")?;
+    let dirs = [&storage.physical_root, &boot];
+    let avail_before = available_bytes(&dirs)?;
+
+    if let Some(keep) = config.keep_previous {
+        crate::bootc_composefs::migrate::ensure_not_migrated(&storage.physical_root)?;
+        remove_previous_ostree(booted_ostree, keep)?;
+    }
+
+    if config.clear_cached_update == Some(true) {
+        let (_deployments, host) = crate::status::get_status(booted_ostree)?;
+        if let Some(image) = host.spec.image {
+            let imgref = OstreeImageReference::from(image);
+            ostree_ext::container::store::clear_cached_update(
+                &booted_ostree.repo(),
+                &imgref.imgref,
+            )?;
+            println!("Cleared cached update metadata");
+        }
+    }
+
+    // Prunes images, layers and objects in the repository as well as
+    // logically bound images
+    crate::deploy::cleanup(storage).await?;
+    storage.update_mtime()?;
+
+    // Other writers may use space concurrently, so this is an estimate
+    let freed = available_bytes(&dirs)?.saturating_sub(avail_before);
+    println!("Freed {}", ostree_ext::glib::format_size(freed));
+    Ok(())
+}
+
+#[context("Cleanup (composefs)")]
+async fn cleanup_composefs(
+    storage: &Storage,
+    booted_cfs: &BootedComposefs,
+    config: &CleanupConfig,
+) -> Result<()> {
+    if config.clear_cached_update == Some(true) {
+        // `bootc upgrade --check` does not store any update metadata with composefs
+        eprintln!("warning: clear-cached-update does not apply to the composefs backend");
+    }
+
+    // There is at most one previous deployment, the rollback, which is kept
+    // if it is queued for the next boot; any policy but keeping none keeps it
+    let host = get_composefs_status(storage, booted_cfs).await?;
+    let rollback = host
+        .status
+        .rollback
+        .as_ref()
+        .filter(|_| !host.status.rollback_queued)
+        .and_then(|r| r.composefs.as_ref());
+
+    let report = match (config.keep_previous, rollback) {
+        (Some(0), Some(rollback)) => {
+            println!("Removing deployment {}", rollback.verity);
+            delete_composefs_deployment(&rollback.verity, storage, booted_cfs).await?
+        }
+        _ => composefs_gc(storage, booted_cfs, false).await?,
+    };
+    report.write(std::io::stdout().lock(), false)?;
+
+    Ok(())
+}
+
+/// Apply the retention policy `config` and garbage collect unused images.
+pub(crate) async fn run(storage: &Storage, config: &CleanupConfig) -> Result<()> {
+    tracing::debug!("Cleanup policy: {config:?}");
+    match storage.kind()? {
+        BootedStorageKind::Ostree(booted_ostree) => {
+            cleanup_ostree(storage, &booted_ostree, config).await
+        }
+        BootedStorageKind::Composefs(booted_cfs) => {
+            cleanup_composefs(storage, &booted_cfs, config).await
+        }
+    }
+}
+
+/// Implementation of `bootc cleanup`.
+pub(crate) async fn cleanup(opts: CleanupOpts) -> Result<()> {
+    let mut config = load_config()?;
+    config.merge(CleanupConfig::from(&opts));
+
+    let storage = &crate::cli::get_storage().await?;
+    run(storage, &config).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_config() -> Result<()> {
+        let c: CleanupConfigToplevel = toml::from_str(indoc::indoc! { r#"
+            [cleanup]
+            keep-previous = 1
+            auto = true
+        "# })?;
+        let mut config = c.cleanup.unwrap();
+        assert_eq!(config.keep_previous, Some(1));
+        assert_eq!(config.clear_cached_update, None);
+        assert!(config.auto());
+
+        config.merge(CleanupConfig {
+            keep_previous: None,
+            clear_cached_update: Some(true),
+            auto: None,
+        });
+        assert_eq!(config.keep_previous, Some(1));
+        assert_eq!(config.clear_cached_update, Some(true));
+
+        config.merge(CleanupConfig {
+            keep_previous: Some(0),
+            clear_cached_update: None,
+            auto: Some(false),
+        });
+        assert_eq!(config.keep_previous, Some(0));
+        assert_eq!(config.clear_cached_update, Some(true));
+        assert!(!config.auto());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_apply_retention() {
+        // Deployments in boot order, the ones above 10 are pinned
+        let deployments = [1, 11, 2, 3, 12, 4];
+        let is_previous = |d: &i32| *d != 1 && *d < 10;
+
+        let (kept, removed) = apply_retention(deployments, 1, is_previous);
+        assert_eq!(kept, [1, 11, 2, 12]);
+        assert_eq!(removed, [3, 4]);
+
+        let (kept, removed) = apply_retention(deployments, 0, is_previous);
+        assert_eq!(kept, [1, 11, 12]);
+        assert_eq!(removed, [2, 3, 4]);
+
+        let (kept, removed) = apply_retention(deployments, 5, is_previous);
+        assert_eq!(kept, deployments);
+        assert!(removed.is_empty());
+    }
+}
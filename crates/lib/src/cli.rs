@@ -35,25 +35,28 @@ use serde::{Deserialize, Serialize};
 use tempfile::tempdir_in;
 
 use crate::bootc_composefs::delete::delete_composefs_deployment;
+use crate::bootc_composefs::gc::composefs_gc;
 use crate::bootc_composefs::{
     finalize::{composefs_backend_finalize, get_etc_diff},
     rollback::composefs_rollback,
-    state::composefs_usr_overlay,
+    state::{composefs_persistent_usr_overlay, composefs_usr_overlay},
+    status::get_composefs_status,
     switch::switch_composefs,
     update::upgrade_composefs,
 };
 use crate::deploy::{MergeState, RequiredHostSpec};
+use crate::history::Operation;
 use crate::lints;
 use crate::podstorage::set_additional_image_store;
-use crate::progress_jsonl::{ProgressWriter, RawProgressFd};
+use crate::progress_jsonl::{Phase, ProgressWriter, RawProgressFd};
 use crate::spec::Host;
 use crate::spec::ImageReference;
-use crate::store::{BootedOstree, ComposefsRepository, Storage};
+use crate::store::{BootedComposefs, BootedOstree, ComposefsRepository, Storage};
 use crate::store::{BootedStorage, BootedStorageKind};
 use crate::utils::sigpolicy_from_opt;
 
 /// Shared progress options
-#[derive(Debug, Parser, PartialEq, Eq)]
+#[derive(Debug, Default, Clone, Parser, PartialEq, Eq)]
 pub(crate) struct ProgressOptions {
     /// File descriptor number which must refer to an open pipe.
     ///
@@ -100,6 +103,12 @@ pub(crate) struct UpgradeOpts {
     #[clap(long = "soft-reboot", conflicts_with = "check")]
     pub(crate) soft_reboot: Option<SoftRebootMode>,
 
+    /// Allow deploying an image older than the booted one.
+    ///
+    /// By default, an image with an older version, or else build timestamp, is refused.
+    #[clap(long, conflicts_with = "check")]
+    pub(crate) allow_downgrade: bool,
+
     #[clap(flatten)]
     pub(crate) progress: ProgressOptions,
 }
@@ -123,6 +132,13 @@ pub(crate) struct SwitchOpts {
     #[clap(long = "soft-reboot")]
     pub(crate) soft_reboot: Option<SoftRebootMode>,
 
+    /// Allow deploying an image older than the booted one.
+    ///
+    /// By default, an image from the same repository as the booted one with an older
+    /// version, or else build timestamp, is refused.
+    #[clap(long)]
+    pub(crate) allow_downgrade: bool,
+
     /// The transport; e.g. registry, oci, oci-archive, docker-daemon, containers-storage.  Defaults to `registry`.
     #[clap(long, default_value = "registry")]
     pub(crate) transport: String,
@@ -149,6 +165,13 @@ pub(crate) struct SwitchOpts {
     #[clap(long)]
     pub(crate) retain: bool,
 
+    /// The storage backend for the new deployment.
+    ///
+    /// Passing `composefs` on a system booted with the ostree backend migrates it in place;
+    /// the ostree deployments are kept as rollback targets.
+    #[clap(long)]
+    pub(crate) backend: Option<Backend>,
+
     /// Target image to use for the next boot.
     pub(crate) target: String,
 
@@ -172,6 +195,102 @@ pub(crate) struct RollbackOpts {
     /// 'required' fails if soft reboot unavailable, 'auto' falls back to regular reboot.
     #[clap(long = "soft-reboot")]
     pub(crate) soft_reboot: Option<SoftRebootMode>,
+
+    #[clap(flatten)]
+    pub(crate) progress: ProgressOptions,
+}
+
+/// Options for `bootc usr-overlay`
+#[derive(Debug, Parser, PartialEq, Eq)]
+#[clap(args_conflicts_with_subcommands = true)]
+pub(crate) struct UsrOverlayOpts {
+    /// Keep the overlay and the changes in it across reboots of the booted deployment.
+    ///
+    /// On composefs systems, the overlay is mounted from the next boot on.
+    #[clap(long)]
+    pub(crate) persistent: bool,
+
+    #[clap(subcommand)]
+    pub(crate) cmd: Option<UsrOverlayCmd>,
+}
+
+/// Operations on an existing `/usr` overlay
+#[derive(Debug, clap::Subcommand, PartialEq, Eq)]
+pub(crate) enum UsrOverlayCmd {
+    /// List all files in `/usr` added, modified or removed relative to the image.
+    Status {
+        /// The output format.
+        #[clap(long)]
+        format: Option<OutputFormat>,
+    },
+    /// Discard a persistent overlay on `/usr` on the next boot.
+    Discard,
+}
+
+/// Options controlling a factory reset
+#[derive(Debug, Parser, PartialEq, Eq)]
+pub(crate) struct FactoryResetOpts {
+    /// Preserve this path under `/var`; may be specified multiple times.
+    ///
+    /// This is in addition to paths configured in `bootc/factory-reset/*.toml`.
+    #[clap(long, value_name = "PATH")]
+    pub(crate) preserve: Vec<Utf8PathBuf>,
+
+    /// Reboot to apply the factory reset.
+    #[clap(long)]
+    pub(crate) apply: bool,
+
+    #[clap(flatten)]
+    pub(crate) progress: ProgressOptions,
+}
+
+/// Options for the `cleanup` subcommand
+#[derive(Debug, Parser, PartialEq, Eq)]
+pub(crate) struct CleanupOpts {
+    /// Keep this many previous deployments and remove older ones.
+    ///
+    /// Pinned deployments are always kept. This overrides `keep-previous`
+    /// in `bootc/cleanup/*.toml`; by default all previous deployments are kept.
+    #[clap(long, value_name = "N")]
+    pub(crate) keep_previous: Option<u32>,
+
+    /// Remove the metadata of updates fetched with `bootc upgrade --check`.
+    #[clap(long)]
+    pub(crate) clear_cached_update: bool,
+}
+
+/// Options for the `history` subcommand
+#[derive(Debug, Parser, PartialEq, Eq)]
+pub(crate) struct HistoryOpts {
+    /// The output format.
+    #[clap(long)]
+    pub(crate) format: Option<OutputFormat>,
+}
+
+/// Options for the `diff` subcommand
+#[derive(Debug, Parser, PartialEq, Eq)]
+pub(crate) struct DiffOpts {
+    /// The deployment or image to compare from: `booted`, `staged`, `rollback`
+    /// or an image reference.
+    ///
+    /// If only this is given, the booted deployment is compared with it. By
+    /// default, the booted deployment is compared with the staged one.
+    pub(crate) from: Option<String>,
+
+    /// The deployment or image to compare to.
+    pub(crate) to: Option<String>,
+
+    /// Also compare the installed packages.
+    ///
+    /// The package versions are read from the rpm database in the images;
+    /// otherwise the packages listed in the layer annotations of chunked
+    /// images are compared, without versions.
+    #[clap(long)]
+    pub(crate) packages: bool,
+
+    /// The output format.
+    #[clap(long)]
+    pub(crate) format: Option<OutputFormat>,
 }
 
 /// Perform an edit operation
@@ -206,6 +325,16 @@ pub(crate) enum SoftRebootMode {
     Auto,
 }
 
+/// A storage backend
+#[derive(Debug, Clone, Copy, ValueEnum, PartialEq, Eq)]
+#[clap(rename_all = "lowercase")]
+pub(crate) enum Backend {
+    /// Deployments are ostree commits
+    Ostree,
+    /// Deployments are composefs images
+    Composefs,
+}
+
 /// Perform an status operation
 #[derive(Debug, Parser, PartialEq, Eq)]
 pub(crate) struct StatusOpts {
@@ -230,7 +359,8 @@ pub(crate) struct StatusOpts {
     #[clap(long)]
     pub(crate) booted: bool,
 
-    /// Include additional fields in human readable format.
+    /// Include additional fields in human readable format, and the disk space used
+    /// by each composefs deployment.
     #[clap(long, short = 'v')]
     pub(crate) verbose: bool,
 }
@@ -249,6 +379,17 @@ pub(crate) enum InstallOpts {
     /// complex such as RAID, LVM, LUKS etc.
     #[cfg(feature = "install-to-disk")]
     ToDisk(crate::install::InstallToDiskOpts),
+    /// Install to a newly created disk image file.
+    ///
+    /// This creates a sparse file of the given size and installs to it via a
+    /// loopback device, in the same way as `install to-disk --via-loopback`.
+    /// The image is configured as a "generic" disk image (see `--generic-image`),
+    /// and can optionally be converted to a format such as qcow2.
+    ///
+    /// A JSON manifest describing the partitions and installed image is
+    /// written alongside the disk image.
+    #[cfg(feature = "install-to-disk")]
+    ToDiskImage(crate::install::disk_image::InstallToDiskImageOpts),
     /// Install to an externally created filesystem structure.
     ///
     /// In this variant of installation, the root filesystem alongside any necessary
@@ -487,6 +628,22 @@ pub(crate) enum InternalsOpts {
         late_dir: Option<Utf8PathBuf>,
     },
     FixupEtcFstab,
+    /// Empty /var as requested by `bootc factory-reset`; run by bootc-factory-reset.service
+    ApplyFactoryReset,
+    /// Set up /run/nextroot with a composefs deployment; must run in the host mount namespace
+    PrepareSoftReboot {
+        /// The composefs image of the deployment
+        verity: String,
+        /// Don't require fsverity on the composefs image
+        #[clap(long)]
+        insecure: bool,
+    },
+    /// Serve the varlink API on a connection from bootc-varlink.socket
+    Varlink,
+    /// Run the pre-finalize or post-boot hooks shipped in an image
+    RunHooks {
+        hook: crate::hooks::HookType,
+    },
     /// Should only be used by `make update-generated`
     PrintJsonSchema {
         #[clap(long)]
@@ -497,7 +654,13 @@ pub(crate) enum InternalsOpts {
     /// Perform consistency checking.
     Fsck,
     /// Perform cleanup actions
-    Cleanup,
+    Cleanup {
+        /// Only report what would be removed and the space this would free.
+        ///
+        /// Only supported for the composefs backend.
+        #[clap(long)]
+        dry_run: bool,
+    },
     Relabel {
         #[clap(long)]
         /// Relabel using this path as root
@@ -549,6 +712,18 @@ pub(crate) enum InternalsOpts {
     #[cfg(feature = "rhsm")]
     /// Publish subscription-manager facts to /etc/rhsm/facts/bootc.facts
     PublishRhsmFacts,
+    /// Publish the status to /run/bootc/status.json; run by bootc-publish-status.service
+    PublishStatus {
+        /// Also write Prometheus metrics to bootc.prom in this node exporter
+        /// textfile collector directory
+        #[clap(long)]
+        textfile_collector: Option<Utf8PathBuf>,
+    },
+    /// Record the current boot in the history; run by bootc-record-boot.service
+    RecordBoot,
+    /// Record the booted composefs image as known-good; run by
+    /// bootc-composefs-known-good.service
+    RecordKnownGood,
     /// Internal command for testing etc-diff/etc-merge
     DirDiff {
         /// Directory path to the pristine_etc
@@ -639,6 +814,39 @@ pub(crate) enum Opt {
         merges happen when new deployments are created.
     "#})]
     Rollback(RollbackOpts),
+    /// Reset the system to the pristine state of the booted image.
+    ///
+    /// This queues the currently booted image to be started on the next boot with
+    /// a fresh `/etc` copied from the image defaults and an empty `/var`.  Paths
+    /// under `/var` can be preserved with `--preserve`, or via configuration in
+    /// `/usr/lib/bootc/factory-reset/*.toml`.
+    ///
+    /// Kernel arguments are retained.  All other local state is lost, including
+    /// e.g. SSH host keys and local users in `/etc`.
+    FactoryReset(FactoryResetOpts),
+    /// Remove previous deployments and unused images.
+    ///
+    /// Previous deployments beyond the retention policy configured in
+    /// `/usr/lib/bootc/cleanup/*.toml` or via `--keep-previous` are removed, and
+    /// then container images, layers and objects no longer referenced by any
+    /// deployment are garbage collected.
+    Cleanup(CleanupOpts),
+    /// Show the differences between two deployments or images.
+    ///
+    /// This lists the files in `/usr` which were added, modified or removed
+    /// with their change in size, as well as changes to the kernel, the kernel
+    /// arguments in `/usr/lib/bootc/kargs.d` and the logically bound images.
+    /// Deployments are given as `booted`, `staged` or `rollback`; images must
+    /// be stored on the system already.
+    Diff(DiffOpts),
+    /// Show the history of changes to the deployments and of boots.
+    ///
+    /// Upgrades, switches, rollbacks and edits are recorded with the image
+    /// deployed, the user who ran them and whether they succeeded; a boot is
+    /// recorded as failed if the system did not boot the image deployed last.
+    /// The history is kept in `/var/lib/bootc/history.jsonl` and also logged
+    /// to the journal.
+    History(HistoryOpts),
     /// Apply full changes to the host specification.
     ///
     /// This command operates very similarly to `kubectl apply`; if invoked interactively,
@@ -656,8 +864,9 @@ pub(crate) enum Opt {
     /// Add a transient writable overlayfs on `/usr`.
     ///
     /// Allows temporary package installation that will be discarded on reboot.
+    /// With `--persistent`, changes are instead kept across reboots until discarded.
     #[clap(alias = "usroverlay")]
-    UsrOverlay,
+    UsrOverlay(UsrOverlayOpts),
     /// Install the running container to a target.
     ///
     /// Takes a container image and installs it to disk in a bootable format.
@@ -858,6 +1067,24 @@ fn soft_reboot_rollback(booted_ostree: &BootedOstree<'_>) -> Result<()> {
     prepare_soft_reboot(booted_ostree.sysroot, target_deployment)
 }
 
+/// Perform a soft reboot into the composefs rollback deployment
+#[context("Soft reboot rollback deployment")]
+fn soft_reboot_composefs_rollback(host: &Host, booted_cfs: &BootedComposefs) -> Result<()> {
+    println!("Rollback deployment is soft-reboot capable, preparing for soft-reboot...");
+
+    let target = host
+        .status
+        .rollback
+        .as_ref()
+        .and_then(|d| d.composefs.as_ref())
+        .ok_or_else(|| anyhow::anyhow!("No rollback deployment found!"))?;
+
+    crate::bootc_composefs::soft_reboot::prepare_soft_reboot(
+        &target.verity,
+        booted_cfs.cmdline.insecure,
+    )
+}
+
 /// A few process changes that need to be made for writing.
 /// IMPORTANT: This may end up re-executing the current process,
 /// so anything that happens before this should be idempotent.
@@ -887,17 +1114,20 @@ pub(crate) fn prepare_for_write() -> Result<()> {
 }
 
 /// Implementation of the `bootc upgrade` CLI command.
+///
+/// Returns whether a deployment is queued for the next boot, which
+/// `--apply` reboots into.
 #[context("Upgrading")]
 async fn upgrade(
     opts: UpgradeOpts,
+    prog: ProgressWriter,
     storage: &Storage,
     booted_ostree: &BootedOstree<'_>,
-) -> Result<()> {
+) -> Result<bool> {
     let repo = &booted_ostree.repo();
 
     let host = crate::status::get_status(booted_ostree)?.1;
     let imgref = host.spec.image.as_ref();
-    let prog: ProgressWriter = opts.progress.try_into()?;
 
     // If there's no specified image, let's be nice and check if the booted system is using rpm-ostree
     if imgref.is_none() {
@@ -920,25 +1150,30 @@ async fn upgrade(
         .map(|b| b.query_image(repo))
         .transpose()?
         .flatten();
+    let booted_status = host.status.booted.as_ref().and_then(|b| b.image.as_ref());
     let imgref = imgref.ok_or_else(|| anyhow::anyhow!("No image source specified"))?;
+    let resolved = crate::tag_policy::resolve(imgref, spec.tag_policy)?;
+    let imgref = resolved.as_ref().unwrap_or(imgref);
     // Find the currently queued digest, if any before we pull
     let staged = host.status.staged.as_ref();
     let staged_image = staged.as_ref().and_then(|s| s.image.as_ref());
     let mut changed = false;
+    let mut staged_present = false;
     if opts.check {
-        let imgref = imgref.clone().into();
+        let imgref = imgref.clone().canonicalize()?.into();
         let mut imp = crate::deploy::new_importer(repo, &imgref).await?;
         match imp.prepare().await? {
             PrepareResult::AlreadyPresent(_) => {
                 println!("No changes in: {imgref:#}");
             }
             PrepareResult::Ready(r) => {
-                crate::deploy::check_bootc_label(&r.config);
+                crate::deploy::check_bootc_label(&r.config, &prog).await;
                 println!("Update available for: {imgref:#}");
                 if let Some(version) = r.version() {
                     println!("  Version: {version}");
                 }
                 println!("  Digest: {}", r.manifest_digest);
+                crate::image_constraints::report(booted_status, imgref, &r.config);
                 changed = true;
                 if let Some(previous_image) = booted_image.as_ref() {
                     let diff =
@@ -948,7 +1183,9 @@ async fn upgrade(
             }
         }
     } else {
-        let fetched = crate::deploy::pull(repo, imgref, None, opts.quiet, prog.clone()).await?;
+        let mut fetched =
+            crate::deploy::pull(storage, repo, imgref, None, opts.quiet, prog.clone()).await?;
+        fetched.resolved = resolved;
         let staged_digest = staged_image.map(|s| s.digest().expect("valid digest in status"));
         let fetched_digest = &fetched.manifest_digest;
         tracing::debug!("staged: {staged_digest:?}");
@@ -964,12 +1201,18 @@ async fn upgrade(
         if staged_unchanged {
             println!("Staged update present, not changed.");
             handle_staged_soft_reboot(booted_ostree, opts.soft_reboot, &host)?;
-            if opts.apply {
-                crate::reboot::reboot()?;
-            }
+            prog.reboot_required().await;
+            staged_present = true;
         } else if booted_unchanged {
             println!("No update available.")
         } else {
+            crate::image_constraints::check_ostree(
+                repo,
+                &fetched.ostree_commit,
+                booted_status,
+                imgref,
+                opts.allow_downgrade,
+            )?;
             let stateroot = booted_ostree.stateroot();
             let from = MergeState::from_stateroot(storage, &stateroot)?;
             crate::deploy::stage(storage, from, &fetched, &spec, prog.clone()).await?;
@@ -992,15 +1235,11 @@ async fn upgrade(
             let updated_host = crate::status::get_status(booted_ostree)?.1;
             handle_staged_soft_reboot(booted_ostree, opts.soft_reboot, &updated_host)?;
         }
-
-        if opts.apply {
-            crate::reboot::reboot()?;
-        }
     } else {
         tracing::debug!("No changes");
     }
 
-    Ok(())
+    Ok((changed && !opts.check) || staged_present)
 }
 
 pub(crate) fn imgref_for_switch(opts: &SwitchOpts) -> Result<ImageReference> {
@@ -1017,14 +1256,16 @@ pub(crate) fn imgref_for_switch(opts: &SwitchOpts) -> Result<ImageReference> {
 }
 
 /// Implementation of the `bootc switch` CLI command for ostree backend.
+///
+/// Returns whether a deployment is queued for the next boot.
 #[context("Switching (ostree)")]
 async fn switch_ostree(
     opts: SwitchOpts,
+    prog: ProgressWriter,
     storage: &Storage,
     booted_ostree: &BootedOstree<'_>,
-) -> Result<()> {
+) -> Result<bool> {
     let target = imgref_for_switch(&opts)?;
-    let prog: ProgressWriter = opts.progress.try_into()?;
     let cancellable = gio::Cancellable::NONE;
 
     let repo = &booted_ostree.repo();
@@ -1033,12 +1274,14 @@ async fn switch_ostree(
     let new_spec = {
         let mut new_spec = host.spec.clone();
         new_spec.image = Some(target.clone());
+        // Switching to an explicit image reference stops tracking tags
+        new_spec.tag_policy = None;
         new_spec
     };
 
     if new_spec == host.spec {
         println!("Image specification is unchanged.");
-        return Ok(());
+        return Ok(false);
     }
 
     // Log the switch operation to systemd journal
@@ -1062,7 +1305,16 @@ async fn switch_ostree(
 
     let new_spec = RequiredHostSpec::from_spec(&new_spec)?;
 
-    let fetched = crate::deploy::pull(repo, &target, None, opts.quiet, prog.clone()).await?;
+    let fetched =
+        crate::deploy::pull(storage, repo, &target, None, opts.quiet, prog.clone()).await?;
+    let booted_status = host.status.booted.as_ref().and_then(|b| b.image.as_ref());
+    crate::image_constraints::check_ostree(
+        repo,
+        &fetched.ostree_commit,
+        booted_status,
+        &target,
+        opts.allow_downgrade,
+    )?;
 
     if !opts.retain {
         // By default, we prune the previous ostree ref so it will go away after later upgrades
@@ -1088,19 +1340,31 @@ async fn switch_ostree(
         handle_staged_soft_reboot(booted_ostree, opts.soft_reboot, &updated_host)?;
     }
 
-    if opts.apply {
-        crate::reboot::reboot()?;
-    }
-
-    Ok(())
+    Ok(true)
 }
 
 /// Implementation of the `bootc switch` CLI command.
+///
+/// Returns whether a deployment is queued for the next boot, which
+/// `--apply` reboots into.
 #[context("Switching")]
-async fn switch(opts: SwitchOpts) -> Result<()> {
+async fn switch(opts: SwitchOpts, prog: ProgressWriter) -> Result<bool> {
     let storage = &get_storage().await?;
     match storage.kind()? {
         BootedStorageKind::Ostree(booted_ostree) => {
+            if opts.backend == Some(Backend::Composefs) {
+                if opts.mutate_in_place {
+                    anyhow::bail!("--mutate-in-place cannot be combined with --backend composefs");
+                }
+                crate::bootc_composefs::migrate::migrate_to_composefs(
+                    opts,
+                    prog,
+                    storage,
+                    &booted_ostree,
+                )
+                .await?;
+                return Ok(true);
+            }
             // If we're doing an in-place mutation, we shortcut most of the rest of the work here
             if opts.mutate_in_place {
                 let target = imgref_for_switch(&opts)?;
@@ -1115,15 +1379,18 @@ async fn switch(opts: SwitchOpts) -> Result<()> {
                     .await??
                 };
                 println!("Updated {deployid} to pull from {target}");
-                return Ok(());
+                return Ok(false);
             }
-            switch_ostree(opts, storage, &booted_ostree).await
+            switch_ostree(opts, prog, storage, &booted_ostree).await
         }
         BootedStorageKind::Composefs(booted_cfs) => {
             if opts.mutate_in_place {
                 anyhow::bail!("--mutate-in-place is not yet supported for composefs backend");
             }
-            switch_composefs(opts, storage, &booted_cfs).await
+            if opts.backend == Some(Backend::Ostree) {
+                anyhow::bail!("Migrating from the composefs backend to ostree is not supported");
+            }
+            switch_composefs(opts, prog, storage, &booted_cfs).await
         }
     }
 }
@@ -1132,10 +1399,17 @@ async fn switch(opts: SwitchOpts) -> Result<()> {
 #[context("Rollback (ostree)")]
 async fn rollback_ostree(
     opts: &RollbackOpts,
+    prog: &ProgressWriter,
     storage: &Storage,
     booted_ostree: &BootedOstree<'_>,
 ) -> Result<()> {
-    crate::deploy::rollback(storage).await?;
+    prog.phase(
+        Phase::BootEntries,
+        "Reordering deployments",
+        crate::deploy::rollback(storage),
+    )
+    .await?;
+    prog.reboot_required().await;
 
     if opts.soft_reboot.is_some() {
         // Get status of rollback deployment to check soft-reboot capability
@@ -1152,15 +1426,49 @@ async fn rollback_ostree(
     Ok(())
 }
 
+#[context("Rollback (composefs)")]
+async fn rollback_composefs(
+    opts: &RollbackOpts,
+    prog: &ProgressWriter,
+    storage: &Storage,
+    booted_cfs: &BootedComposefs,
+) -> Result<()> {
+    prog.phase(
+        Phase::BootEntries,
+        "Reordering boot entries",
+        composefs_rollback(storage, booted_cfs),
+    )
+    .await?;
+    prog.reboot_required().await;
+
+    if opts.soft_reboot.is_some() {
+        let host = get_composefs_status(storage, booted_cfs).await?;
+
+        // When reverting a queued rollback the next boot is the booted deployment
+        if host.status.rollback_queued {
+            handle_soft_reboot(
+                opts.soft_reboot,
+                host.status.rollback.as_ref(),
+                "rollback",
+                || soft_reboot_composefs_rollback(&host, booted_cfs),
+            )?;
+        }
+    }
+
+    Ok(())
+}
+
 /// Implementation of the `bootc rollback` CLI command.
 #[context("Rollback")]
-async fn rollback(opts: &RollbackOpts) -> Result<()> {
+async fn rollback(opts: &RollbackOpts, prog: &ProgressWriter) -> Result<()> {
     let storage = &get_storage().await?;
     match storage.kind()? {
         BootedStorageKind::Ostree(booted_ostree) => {
-            rollback_ostree(opts, storage, &booted_ostree).await
+            rollback_ostree(opts, prog, storage, &booted_ostree).await
+        }
+        BootedStorageKind::Composefs(booted_cfs) => {
+            rollback_composefs(opts, prog, storage, &booted_cfs).await
         }
-        BootedStorageKind::Composefs(booted_cfs) => composefs_rollback(storage, &booted_cfs).await,
     }
 }
 
@@ -1200,7 +1508,17 @@ async fn edit_ostree(
         return crate::deploy::rollback(storage).await;
     }
 
-    let fetched = crate::deploy::pull(repo, new_spec.image, None, opts.quiet, prog.clone()).await?;
+    let resolved = crate::tag_policy::resolve(new_spec.image, new_spec.tag_policy)?;
+    let mut fetched = crate::deploy::pull(
+        storage,
+        repo,
+        resolved.as_ref().unwrap_or(new_spec.image),
+        None,
+        opts.quiet,
+        prog.clone(),
+    )
+    .await?;
+    fetched.resolved = resolved;
 
     // TODO gc old layers here
 
@@ -1228,13 +1546,16 @@ async fn edit(opts: EditOpts) -> Result<()> {
 }
 
 /// Implementation of `bootc usroverlay`
-async fn usroverlay() -> Result<()> {
+async fn usroverlay(persistent: bool) -> Result<()> {
     // This is just a pass-through today.  At some point we may make this a libostree API
     // or even oxidize it.
-    Err(Command::new("ostree")
-        .args(["admin", "unlock"])
-        .exec()
-        .into())
+    let mut cmd = Command::new("ostree");
+    cmd.args(["admin", "unlock"]);
+    // A "hotfix" unlock is ostree's persistent mode
+    if persistent {
+        cmd.arg("--hotfix");
+    }
+    Err(cmd.exec().into())
 }
 
 /// Perform process global initialization. This should be called as early as possible
@@ -1321,35 +1642,83 @@ impl Opt {
 async fn run_from_opt(opt: Opt) -> Result<()> {
     let root = &Dir::open_ambient_dir("/", cap_std::ambient_authority())?;
     match opt {
-        Opt::Upgrade(opts) => {
-            let storage = &get_storage().await?;
-            match storage.kind()? {
-                BootedStorageKind::Ostree(booted_ostree) => {
-                    upgrade(opts, storage, &booted_ostree).await
-                }
-                BootedStorageKind::Composefs(booted_cfs) => {
-                    upgrade_composefs(opts, storage, &booted_cfs).await
+        Opt::Upgrade(mut opts) => {
+            let check = opts.check;
+            let apply = opts.apply;
+            let prog: ProgressWriter = std::mem::take(&mut opts.progress).try_into()?;
+            let r = async {
+                let storage = &get_storage().await?;
+                match storage.kind()? {
+                    BootedStorageKind::Ostree(booted_ostree) => {
+                        upgrade(opts, prog.clone(), storage, &booted_ostree).await
+                    }
+                    BootedStorageKind::Composefs(booted_cfs) => {
+                        upgrade_composefs(opts, prog.clone(), storage, &booted_cfs).await
+                    }
                 }
+            };
+            let r = if check {
+                r.await
+            } else {
+                let r = crate::history::record(root, Operation::Upgrade, None, r).await;
+                crate::status_export::record_update_result(root, "upgrade", &r).await;
+                r
+            };
+            prog.finish(&r).await;
+            // Reboot only once the result is recorded, as it does not return
+            if r? && apply {
+                crate::reboot::reboot()?;
             }
+            Ok(())
         }
-        Opt::Switch(opts) => switch(opts).await,
-        Opt::Rollback(opts) => {
-            rollback(&opts).await?;
-            if opts.apply {
+        Opt::Switch(mut opts) => {
+            let target = Some(opts.target.clone());
+            let apply = opts.apply;
+            let prog: ProgressWriter = std::mem::take(&mut opts.progress).try_into()?;
+            let r = switch(opts, prog.clone());
+            let r = crate::history::record(root, Operation::Switch, target, r).await;
+            crate::status_export::record_update_result(root, "switch", &r).await;
+            prog.finish(&r).await;
+            if r? && apply {
                 crate::reboot::reboot()?;
             }
             Ok(())
         }
-        Opt::Edit(opts) => edit(opts).await,
-        Opt::UsrOverlay => {
-            use crate::store::Environment;
-            let env = Environment::detect()?;
-            match env {
-                Environment::OstreeBooted => usroverlay().await,
-                Environment::ComposefsBooted(_) => composefs_usr_overlay(),
-                _ => anyhow::bail!("usroverlay only applies on booted hosts"),
+        Opt::Rollback(mut opts) => {
+            let prog: ProgressWriter = std::mem::take(&mut opts.progress).try_into()?;
+            let r = rollback(&opts, &prog);
+            let r = crate::history::record(root, Operation::Rollback, None, r).await;
+            crate::status_export::record_update_result(root, "rollback", &r).await;
+            prog.finish(&r).await;
+            r?;
+            if opts.apply {
+                crate::reboot::reboot()?;
             }
+            Ok(())
         }
+        Opt::FactoryReset(opts) => crate::factory_reset::factory_reset(opts).await,
+        Opt::Cleanup(opts) => crate::cleanup::cleanup(opts).await,
+        Opt::Diff(opts) => crate::diff::diff(opts).await,
+        Opt::Edit(opts) => crate::history::record(root, Operation::Edit, None, edit(opts)).await,
+        Opt::History(opts) => crate::history::history(root, opts),
+        Opt::UsrOverlay(opts) => match opts.cmd {
+            None => {
+                use crate::store::Environment;
+                let env = Environment::detect()?;
+                match env {
+                    Environment::OstreeBooted => usroverlay(opts.persistent).await,
+                    Environment::ComposefsBooted(_) if opts.persistent => {
+                        composefs_persistent_usr_overlay().await
+                    }
+                    Environment::ComposefsBooted(_) => composefs_usr_overlay(),
+                    _ => anyhow::bail!("usroverlay only applies on booted hosts"),
+                }
+            }
+            Some(UsrOverlayCmd::Status { format }) => {
+                crate::usr_overlay::usr_overlay_status(format).await
+            }
+            Some(UsrOverlayCmd::Discard) => crate::usr_overlay::usr_overlay_discard().await,
+        },
         Opt::Container(opts) => match opts {
             ContainerOpts::Inspect { rootfs } => {
                 let root = &Dir::open_ambient_dir(&rootfs, cap_std::ambient_authority())?;
@@ -1488,15 +1857,48 @@ async fn run_from_opt(opt: Opt) -> Result<()> {
         },
         Opt::Install(opts) => match opts {
             #[cfg(feature = "install-to-disk")]
-            InstallOpts::ToDisk(opts) => crate::install::install_to_disk(opts).await,
-            InstallOpts::ToFilesystem(opts) => {
-                crate::install::install_to_filesystem(opts, false, crate::install::Cleanup::Skip)
-                    .await
+            InstallOpts::ToDisk(mut opts) => {
+                let prog: ProgressWriter = std::mem::take(&mut opts.progress).try_into()?;
+                let r = crate::install::install_to_disk(opts, prog.clone()).await;
+                prog.finish(&r).await;
+                r
+            }
+            #[cfg(feature = "install-to-disk")]
+            InstallOpts::ToDiskImage(mut opts) => {
+                let prog: ProgressWriter = std::mem::take(&mut opts.progress).try_into()?;
+                let r = crate::install::disk_image::install_to_disk_image(opts, prog.clone()).await;
+                prog.finish(&r).await;
+                r
+            }
+            InstallOpts::ToFilesystem(mut opts) => {
+                let prog: ProgressWriter = std::mem::take(&mut opts.progress).try_into()?;
+                let r = crate::install::install_to_filesystem(
+                    opts,
+                    false,
+                    crate::install::Cleanup::Skip,
+                    prog.clone(),
+                )
+                .await;
+                prog.finish(&r).await;
+                r
             }
-            InstallOpts::ToExistingRoot(opts) => {
-                crate::install::install_to_existing_root(opts).await
+            InstallOpts::ToExistingRoot(mut opts) => {
+                let prog: ProgressWriter = std::mem::take(&mut opts.progress).try_into()?;
+                let r = crate::install::install_to_existing_root(opts, prog.clone()).await;
+                prog.finish(&r).await;
+                r
+            }
+            InstallOpts::Reset(mut opts) => {
+                let apply = opts.apply;
+                let prog: ProgressWriter = std::mem::take(&mut opts.progress).try_into()?;
+                let r = crate::install::install_reset(opts, prog.clone()).await;
+                prog.finish(&r).await;
+                r?;
+                if apply {
+                    crate::reboot::reboot()?;
+                }
+                Ok(())
             }
-            InstallOpts::Reset(opts) => crate::install::install_reset(opts).await,
             InstallOpts::PrintConfiguration(opts) => crate::install::print_configuration(opts),
             InstallOpts::EnsureCompletion {} => {
                 let rootfs = &Dir::open_ambient_dir("/", cap_std::ambient_authority())?;
@@ -1567,6 +1969,12 @@ async fn run_from_opt(opt: Opt) -> Result<()> {
                 Ok(())
             }
             InternalsOpts::FixupEtcFstab => crate::deploy::fixup_etc_fstab(&root),
+            InternalsOpts::ApplyFactoryReset => crate::factory_reset::apply_factory_reset(&root),
+            InternalsOpts::PrepareSoftReboot { verity, insecure } => {
+                crate::bootc_composefs::soft_reboot::setup_nextroot(&verity, insecure)
+            }
+            InternalsOpts::Varlink => crate::varlink::serve().await,
+            InternalsOpts::RunHooks { hook } => crate::hooks::run_from_unit(hook).await,
             InternalsOpts::PrintJsonSchema { of } => {
                 let schema = match of {
                     SchemaType::Host => schema_for!(crate::spec::Host),
@@ -1576,9 +1984,19 @@ async fn run_from_opt(opt: Opt) -> Result<()> {
                 serde_json::to_writer_pretty(&mut stdout, &schema)?;
                 Ok(())
             }
-            InternalsOpts::Cleanup => {
+            InternalsOpts::Cleanup { dry_run } => {
                 let storage = get_storage().await?;
-                crate::deploy::cleanup(&storage).await
+                match storage.kind()? {
+                    BootedStorageKind::Ostree(_) if dry_run => {
+                        anyhow::bail!("--dry-run is only supported for composefs backend")
+                    }
+                    BootedStorageKind::Ostree(_) => crate::deploy::cleanup(&storage).await,
+                    BootedStorageKind::Composefs(booted_cfs) => {
+                        composefs_gc(&storage, &booted_cfs, dry_run)
+                            .await?
+                            .write(std::io::stdout().lock(), dry_run)
+                    }
+                }
             }
             InternalsOpts::Relabel { as_path, path } => {
                 let root = &Dir::open_ambient_dir("/", cap_std::ambient_authority())?;
@@ -1617,6 +2035,11 @@ async fn run_from_opt(opt: Opt) -> Result<()> {
             }
             #[cfg(feature = "rhsm")]
             InternalsOpts::PublishRhsmFacts => crate::rhsm::publish_facts(&root).await,
+            InternalsOpts::PublishStatus { textfile_collector } => {
+                crate::status_export::publish(root, textfile_collector.as_deref()).await
+            }
+            InternalsOpts::RecordBoot => crate::history::record_boot(root).await,
+            InternalsOpts::RecordKnownGood => crate::bootc_composefs::state::record_known_good(),
             #[cfg(feature = "docgen")]
             InternalsOpts::DumpCliJson => {
                 use clap::CommandFactory;
@@ -1691,7 +2114,9 @@ async fn run_from_opt(opt: Opt) -> Result<()> {
                     anyhow::bail!("DeleteDeployment is only supported for composefs backend")
                 }
                 BootedStorageKind::Composefs(booted_cfs) => {
-                    delete_composefs_deployment(&depl_id, storage, &booted_cfs).await
+                    delete_composefs_deployment(&depl_id, storage, &booted_cfs)
+                        .await?
+                        .write(std::io::stdout().lock(), false)
                 }
             }
         }
@@ -1794,6 +2219,34 @@ mod tests {
         ));
     }
 
+    #[test]
+    fn test_parse_usr_overlay() {
+        assert!(matches!(
+            Opt::parse_including_static(["bootc", "usroverlay"]),
+            Opt::UsrOverlay(UsrOverlayOpts {
+                persistent: false,
+                cmd: None
+            })
+        ));
+        assert!(matches!(
+            Opt::parse_including_static(["bootc", "usr-overlay", "--persistent"]),
+            Opt::UsrOverlay(UsrOverlayOpts {
+                persistent: true,
+                cmd: None
+            })
+        ));
+        assert!(matches!(
+            Opt::parse_including_static(["bootc", "usr-overlay", "status", "--format=json"]),
+            Opt::UsrOverlay(UsrOverlayOpts {
+                cmd: Some(UsrOverlayCmd::Status {
+                    format: Some(OutputFormat::Json)
+                }),
+                ..
+            })
+        ));
+        assert!(Opt::try_parse_from(["bootc", "usr-overlay", "--persistent", "discard"]).is_err());
+    }
+
     #[test]
     fn test_parse_generator() {
         assert!(matches!(
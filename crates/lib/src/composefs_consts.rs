@@ -7,6 +7,8 @@ pub const COMPOSEFS_CMDLINE: &str = "composefs";
 pub(crate) const COMPOSEFS_TRANSIENT_STATE_DIR: &str = "/run/composefs";
 /// File created in /run/composefs to record a staged-deployment
 pub(crate) const COMPOSEFS_STAGED_DEPLOYMENT_FNAME: &str = "staged-deployment";
+/// File created in /run/composefs to record the deployment prepared for a soft reboot
+pub(crate) const COMPOSEFS_SOFT_REBOOT_FNAME: &str = "soft-reboot";
 
 /// Absolute path to composefs-backend state directory
 pub(crate) const STATE_DIR_ABS: &str = "/sysroot/state/deploy";
@@ -21,6 +23,10 @@ pub(crate) const ORIGIN_KEY_BOOT: &str = "boot";
 pub(crate) const ORIGIN_KEY_BOOT_TYPE: &str = "boot_type";
 /// Key to store the SHA256 sum of vmlinuz + initrd for a deployment
 pub(crate) const ORIGIN_KEY_BOOT_DIGEST: &str = "digest";
+/// Section in .origin file to store image signature metadata
+pub(crate) const ORIGIN_KEY_SIGNATURE: &str = "signature";
+/// Key to store the signers an image was verified against, separated by `;`
+pub(crate) const ORIGIN_KEY_SIGNATURE_SIGNERS: &str = "signers";
 
 /// Filename for `loader/entries`
 pub(crate) const BOOT_LOADER_ENTRIES: &str = "entries";
@@ -38,3 +44,7 @@ pub(crate) const TYPE1_ENT_PATH: &str = "loader/entries";
 pub(crate) const TYPE1_ENT_PATH_STAGED: &str = "loader/entries.staged";
 
 pub(crate) const BOOTC_FINALIZE_STAGED_SERVICE: &str = "bootc-finalize-staged.service";
+
+/// The configuration of the initramfs setup-root. Deployments which can be soft
+/// rebooted into share the initramfs, and so also this file.
+pub(crate) const SETUP_ROOT_CONFIG: &str = "/usr/lib/composefs/setup-root-conf.toml";
@@ -4,6 +4,7 @@
 
 use std::collections::HashSet;
 use std::io::{BufRead, Write};
+use std::path::Path;
 
 use anyhow::Ok;
 use anyhow::{anyhow, Context, Result};
@@ -22,9 +23,11 @@ use ostree_ext::ostree::{self, Sysroot};
 use ostree_ext::sysroot::SysrootLock;
 use ostree_ext::tokio_util::spawn_blocking_cancellable_flatten;
 
-use crate::progress_jsonl::{Event, ProgressWriter, SubTaskBytes, SubTaskStep};
+use crate::disk_space::{BootArtifacts, SpaceCheck};
+use crate::hooks::{run_hooks, HookTarget, HookType};
+use crate::progress_jsonl::{Event, Phase, ProgressWriter, SubTaskBytes, SubTaskStep};
 use crate::spec::ImageReference;
-use crate::spec::{BootOrder, HostSpec};
+use crate::spec::{BootOrder, HostSpec, TagPolicy};
 use crate::status::labels_of_config;
 use crate::store::Storage;
 use crate::utils::async_task_with_spinner;
@@ -38,6 +41,7 @@ const BOOTC_DERIVED_KEY: &str = "bootc.derived";
 /// Variant of HostSpec but required to be filled out
 pub(crate) struct RequiredHostSpec<'a> {
     pub(crate) image: &'a ImageReference,
+    pub(crate) tag_policy: Option<&'a TagPolicy>,
 }
 
 /// State of a locally fetched image
@@ -45,6 +49,8 @@ pub(crate) struct ImageState {
     pub(crate) manifest_digest: Digest,
     pub(crate) version: Option<String>,
     pub(crate) ostree_commit: String,
+    /// The digest-pinned reference the tag policy resolved the image to, if any
+    pub(crate) resolved: Option<ImageReference>,
 }
 
 impl<'a> RequiredHostSpec<'a> {
@@ -55,7 +61,10 @@ impl<'a> RequiredHostSpec<'a> {
             .image
             .as_ref()
             .ok_or_else(|| anyhow::anyhow!("Missing image in specification"))?;
-        Ok(Self { image })
+        Ok(Self {
+            image,
+            tag_policy: spec.tag_policy.as_ref(),
+        })
     }
 }
 
@@ -67,11 +76,39 @@ impl From<ostree_container::store::LayeredImageState> for ImageState {
             manifest_digest: value.manifest_digest,
             version,
             ostree_commit,
+            resolved: None,
         }
     }
 }
 
 impl ImageState {
+    /// Find the locally stored image of the booted deployment, along with a host
+    /// specification targeting it; this is used to redeploy the booted image.
+    pub(crate) fn from_booted(
+        host: &crate::spec::Host,
+        repo: &ostree::Repo,
+    ) -> Result<(Self, HostSpec)> {
+        let booted = host
+            .status
+            .booted
+            .as_ref()
+            .ok_or_else(|| anyhow!("No booted deployment"))?;
+        let booted_image = booted
+            .image
+            .as_ref()
+            .ok_or_else(|| anyhow!("Booted deployment is not from a container image"))?;
+        let imgstate = booted
+            .query_image(repo)?
+            .ok_or_else(|| anyhow!("Failed to find image state for booted deployment"))?;
+        let mut spec = host.spec.clone();
+        spec.image = Some(booted_image.image.clone());
+        let imgstate = ImageState {
+            resolved: booted_image.resolved_image.clone(),
+            ..(*imgstate).into()
+        };
+        Ok((imgstate, spec))
+    }
+
     /// Fetch the manifest corresponding to this image.  May not be available in all backends.
     pub(crate) fn get_manifest(
         &self,
@@ -93,30 +130,32 @@ pub(crate) async fn new_importer(
     Ok(imp)
 }
 
-pub(crate) fn check_bootc_label(config: &ostree_ext::oci_spec::image::ImageConfiguration) {
-    if let Some(label) =
+pub(crate) async fn check_bootc_label(
+    config: &ostree_ext::oci_spec::image::ImageConfiguration,
+    prog: &ProgressWriter,
+) {
+    let notice = if let Some(label) =
         labels_of_config(config).and_then(|labels| labels.get(crate::metadata::BOOTC_COMPAT_LABEL))
     {
         match label.as_str() {
-            crate::metadata::COMPAT_LABEL_V1 => {}
-            o => crate::journal::journal_print(
-                libsystemd::logging::Priority::Warning,
-                &format!(
-                    "notice: Unknown {} value {}",
-                    crate::metadata::BOOTC_COMPAT_LABEL,
-                    o
-                ),
+            crate::metadata::COMPAT_LABEL_V1 => return,
+            o => format!(
+                "Unknown {} value {}",
+                crate::metadata::BOOTC_COMPAT_LABEL,
+                o
             ),
         }
     } else {
-        crate::journal::journal_print(
-            libsystemd::logging::Priority::Warning,
-            &format!(
-                "notice: Image is missing label: {}",
-                crate::metadata::BOOTC_COMPAT_LABEL
-            ),
+        format!(
+            "Image is missing label: {}",
+            crate::metadata::BOOTC_COMPAT_LABEL
         )
-    }
+    };
+    crate::journal::journal_print(
+        libsystemd::logging::Priority::Warning,
+        &format!("notice: {notice}"),
+    );
+    prog.warn(&notice).await;
 }
 
 fn descriptor_of_progress(p: &ImportProgress) -> &Descriptor {
@@ -140,20 +179,21 @@ fn prefix_of_progress(p: &ImportProgress) -> &'static str {
 }
 
 /// Configuration for layer progress printing
-struct LayerProgressConfig {
-    layers: tokio::sync::mpsc::Receiver<ostree_container::store::ImportProgress>,
-    layer_bytes: tokio::sync::watch::Receiver<Option<ostree_container::store::LayerProgress>>,
-    digest: Box<str>,
-    n_layers_to_fetch: usize,
-    layers_total: usize,
-    bytes_to_download: u64,
-    bytes_total: u64,
-    prog: ProgressWriter,
-    quiet: bool,
+pub(crate) struct LayerProgressConfig {
+    pub(crate) layers: tokio::sync::mpsc::Receiver<ostree_container::store::ImportProgress>,
+    pub(crate) layer_bytes:
+        tokio::sync::watch::Receiver<Option<ostree_container::store::LayerProgress>>,
+    pub(crate) digest: Box<str>,
+    pub(crate) n_layers_to_fetch: usize,
+    pub(crate) layers_total: usize,
+    pub(crate) bytes_to_download: u64,
+    pub(crate) bytes_total: u64,
+    pub(crate) prog: ProgressWriter,
+    pub(crate) quiet: bool,
 }
 
 /// Write container fetch progress to standard output.
-async fn handle_layer_progress_print(mut config: LayerProgressConfig) -> ProgressWriter {
+pub(crate) async fn handle_layer_progress_print(mut config: LayerProgressConfig) -> ProgressWriter {
     let start = std::time::Instant::now();
     let mut total_read = 0u64;
     let bar = indicatif::MultiProgress::new();
@@ -307,6 +347,26 @@ async fn handle_layer_progress_print(mut config: LayerProgressConfig) -> Progres
     config.prog
 }
 
+/// Signal the completion of the "importing" step started by [`handle_layer_progress_print`].
+pub(crate) async fn send_import_completed(prog: &ProgressWriter, digest: &str) {
+    prog.send(Event::ProgressSteps {
+        task: "importing".into(),
+        description: "Importing Image".into(),
+        id: digest.into(),
+        steps_cached: 0,
+        steps: 1,
+        steps_total: 1,
+        subtasks: [SubTaskStep {
+            subtask: "importing".into(),
+            description: "Importing Image".into(),
+            id: "importing".into(),
+            completed: true,
+        }]
+        .into(),
+    })
+    .await;
+}
+
 /// Gather all bound images in all deployments, then prune the image store,
 /// using the gathered images as the roots (that will not be GC'd).
 pub(crate) async fn prune_container_store(sysroot: &Storage) -> Result<()> {
@@ -346,6 +406,7 @@ pub(crate) async fn prepare_for_pull(
     repo: &ostree::Repo,
     imgref: &ImageReference,
     target_imgref: Option<&OstreeImageReference>,
+    prog: &ProgressWriter,
 ) -> Result<PreparedPullResult> {
     let imgref_canonicalized = imgref.clone().canonicalize()?;
     tracing::debug!("Canonicalized image reference: {imgref_canonicalized:#}");
@@ -361,7 +422,7 @@ pub(crate) async fn prepare_for_pull(
         }
         PrepareResult::Ready(p) => p,
     };
-    check_bootc_label(&prep.config);
+    check_bootc_label(&prep.config, prog).await;
     if let Some(warning) = prep.deprecated_warning() {
         ostree_ext::cli::print_deprecated_warning(warning).await;
     }
@@ -410,22 +471,7 @@ pub(crate) async fn pull_from_prepared(
     let import = prepared_image.imp.import(prepared_image.prep).await;
     let prog = printer.await?;
     // Both the progress and the import are done, so import is done as well
-    prog.send(Event::ProgressSteps {
-        task: "importing".into(),
-        description: "Importing Image".into(),
-        id: digest_imp.clone().as_ref().into(),
-        steps_cached: 0,
-        steps: 1,
-        steps_total: 1,
-        subtasks: [SubTaskStep {
-            subtask: "importing".into(),
-            description: "Importing Image".into(),
-            id: "importing".into(),
-            completed: true,
-        }]
-        .into(),
-    })
-    .await;
+    send_import_completed(&prog, digest_imp.as_ref()).await;
     let import = import?;
     let imgref_canonicalized = imgref.clone().canonicalize()?;
     tracing::debug!("Canonicalized image reference: {imgref_canonicalized:#}");
@@ -453,14 +499,29 @@ pub(crate) async fn pull_from_prepared(
 }
 
 /// Wrapper for pulling a container image, wiring up status output.
+///
+/// Before fetching any layers, this ensures there is enough disk space.
 pub(crate) async fn pull(
+    sysroot: &Storage,
     repo: &ostree::Repo,
     imgref: &ImageReference,
     target_imgref: Option<&OstreeImageReference>,
     quiet: bool,
     prog: ProgressWriter,
 ) -> Result<Box<ImageState>> {
-    match prepare_for_pull(repo, imgref, target_imgref).await? {
+    let inner = pull_inner(sysroot, repo, imgref, target_imgref, quiet, prog.clone());
+    prog.phase(Phase::Pull, "Fetching image", inner).await
+}
+
+async fn pull_inner(
+    sysroot: &Storage,
+    repo: &ostree::Repo,
+    imgref: &ImageReference,
+    target_imgref: Option<&OstreeImageReference>,
+    quiet: bool,
+    prog: ProgressWriter,
+) -> Result<Box<ImageState>> {
+    match prepare_for_pull(repo, imgref, target_imgref, &prog).await? {
         PreparedPullResult::AlreadyPresent(existing) => {
             // Log that the image was already present (Debug level since it's not actionable)
             const IMAGE_ALREADY_PRESENT_ID: &str = "5c4d3e2f1a0b9c8d7e6f5a4b3c2d1e0f9";
@@ -474,7 +535,7 @@ pub(crate) async fn pull(
             );
             Ok(existing)
         }
-        PreparedPullResult::Ready(prepared_image_meta) => {
+        PreparedPullResult::Ready(mut prepared_image_meta) => {
             // Log that we're pulling a new image
             const PULLING_NEW_IMAGE_ID: &str = "6d5e4f3a2b1c0d9e8f7a6b5c4d3e2f1a0";
             tracing::info!(
@@ -485,6 +546,32 @@ pub(crate) async fn pull(
                 "Pulling new image: {}",
                 imgref
             );
+            let boot = Dir::open_ambient_dir("/boot", cap_std::ambient_authority())
+                .context("Opening /boot")?;
+            let space = crate::disk_space::ensure_space(
+                sysroot,
+                &boot,
+                BootArtifacts::Kernel,
+                prepared_image_meta.bytes_to_fetch,
+                true,
+            )
+            .await?;
+            if space == SpaceCheck::CleanedUp {
+                // The cleanup may have pruned layers the import counted as present
+                prepared_image_meta =
+                    match prepare_for_pull(repo, imgref, target_imgref, &prog).await? {
+                        PreparedPullResult::AlreadyPresent(existing) => return Ok(existing),
+                        PreparedPullResult::Ready(p) => p,
+                    };
+                crate::disk_space::ensure_space(
+                    sysroot,
+                    &boot,
+                    BootArtifacts::Kernel,
+                    prepared_image_meta.bytes_to_fetch,
+                    false,
+                )
+                .await?;
+            }
             Ok(pull_from_prepared(imgref, quiet, prog, *prepared_image_meta).await?)
         }
     }
@@ -692,6 +779,8 @@ pub(crate) async fn stage(
     spec: &RequiredHostSpec<'_>,
     prog: ProgressWriter,
 ) -> Result<()> {
+    crate::bootc_composefs::migrate::ensure_not_migrated(&sysroot.physical_root)?;
+
     // Log the staging operation to systemd journal with comprehensive upgrade information
     const STAGE_JOURNAL_ID: &str = "8f7a2b1c3d4e5f6a7b8c9d0e1f2a3b4c";
 
@@ -747,8 +836,29 @@ pub(crate) async fn stage(
             .collect(),
     })
     .await;
-    let origin = origin_from_imageref(spec.image)?;
-    let deployment = crate::deploy::deploy(sysroot, from, image, &origin).await?;
+    let hook_target = HookTarget {
+        image: spec.image.clone(),
+        digest: Some(image.manifest_digest.to_string()),
+        version: image.version.clone(),
+    };
+    let ostree = sysroot.get_ostree()?;
+    let origin = prog
+        .phase(Phase::Stage, "Preparing deployment", async {
+            crate::hooks::run_ostree_pre_stage(&ostree.repo(), &image.ostree_commit, &hook_target)
+                .await?;
+            // The resolved image is deployed, so it must be in the origin to not be pruned
+            let origin = origin_from_imageref(image.resolved.as_ref().unwrap_or(spec.image))?;
+            crate::tag_policy::set_origin(&origin, spec.tag_policy, spec.image);
+            anyhow::Ok(origin)
+        })
+        .await?;
+    let deployment = prog
+        .phase(
+            Phase::Deploy,
+            "Deploying image",
+            crate::deploy::deploy(sysroot, from, image, &origin),
+        )
+        .await?;
 
     subtask.completed = true;
     subtasks.push(subtask.clone());
@@ -770,7 +880,12 @@ pub(crate) async fn stage(
             .collect(),
     })
     .await;
-    crate::boundimage::pull_bound_images(sysroot, &deployment).await?;
+    prog.phase(
+        Phase::BoundImages,
+        "Pulling bound images",
+        crate::boundimage::pull_bound_images(sysroot, &deployment),
+    )
+    .await?;
 
     subtask.completed = true;
     subtasks.push(subtask.clone());
@@ -792,7 +907,12 @@ pub(crate) async fn stage(
             .collect(),
     })
     .await;
-    crate::deploy::cleanup(sysroot).await?;
+    prog.phase(
+        Phase::Cleanup,
+        "Removing old images",
+        crate::deploy::cleanup(sysroot),
+    )
+    .await?;
     println!("Queued for next boot: {:#}", spec.image);
     if let Some(version) = image.version.as_deref() {
         println!("  Version: {version}");
@@ -819,6 +939,12 @@ pub(crate) async fn stage(
     // Unconditionally create or update /run/reboot-required to signal a reboot is needed.
     // This is monitored by kured (Kubernetes Reboot Daemon).
     write_reboot_required(&image.manifest_digest.as_ref())?;
+    prog.reboot_required().await;
+
+    let deployment_root = format!("/sysroot/{}", ostree.deployment_dirpath(&deployment));
+    let deployment_root = Path::new(&deployment_root);
+    run_hooks(deployment_root, HookType::PostStage, &hook_target).await?;
+    crate::hooks::start_pre_finalize_unit(deployment_root)?;
 
     Ok(())
 }
@@ -837,6 +963,7 @@ fn write_reboot_required(image: &str) -> Result<()> {
 /// Implementation of rollback functionality
 pub(crate) async fn rollback(sysroot: &Storage) -> Result<()> {
     const ROLLBACK_JOURNAL_ID: &str = "26f3b1eb24464d12aa5e7b544a6b5468";
+    crate::bootc_composefs::migrate::ensure_not_migrated(&sysroot.physical_root)?;
     let ostree = sysroot.get_ostree()?;
     let (booted_ostree, deployments, host) = crate::status::get_status_require_booted(ostree)?;
 
@@ -0,0 +1,764 @@
+//! # Comparing deployments and images
+//!
+//! `bootc diff` shows what changes when booting into another deployment or
+//! image: the files in `/usr` with their size changes, the kernel, the kernel
+//! arguments from `/usr/lib/bootc/kargs.d` and the logically bound images.
+//! For ostree the commits are compared with [`ostree_ext::diff`]; for composefs
+//! the dumpfiles of the images are compared. Optionally, the installed packages
+//! are compared as well; see [`crate::packages`].
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::io::Write;
+use std::path::Path;
+
+use anyhow::{Context, Result};
+use bootc_mount::tempmount::TempMount;
+use camino::{Utf8Path, Utf8PathBuf};
+use canon_json::CanonJsonSerialize;
+use cap_std_ext::cap_std::fs::Dir;
+use cap_std_ext::dirext::CapStdExtDirExt;
+use composefs::dumpfile;
+use fn_error_context::context;
+use ostree_ext::container as ostree_container;
+use ostree_ext::gio;
+use ostree_ext::ostree;
+use ostree_ext::prelude::{Cast, FileEnumeratorExt, FileExt};
+use serde::Serialize;
+
+use crate::bootc_composefs::status::get_composefs_status;
+use crate::cli::{DiffOpts, OutputFormat};
+use crate::packages::{diff_packages, PackageDiff, Packages};
+use crate::spec::BootEntry;
+use crate::status::Slot;
+use crate::store::{BootedComposefs, BootedOstree, BootedStorageKind, Storage};
+
+/// The attributes queried for files in ostree commits
+const QUERYATTRS: &str = "standard::name,standard::type,standard::size";
+
+/// A deployment or a container image stored on the system
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Target {
+    Slot(Slot),
+    Image(ostree_container::ImageReference),
+}
+
+impl Target {
+    fn new(s: &str) -> Self {
+        match s {
+            "booted" => Self::Slot(Slot::Booted),
+            "staged" => Self::Slot(Slot::Staged),
+            "rollback" => Self::Slot(Slot::Rollback),
+            // Without a transport, the image is assumed to be from a registry
+            s => Self::Image(
+                ostree_container::ImageReference::try_from(s).unwrap_or_else(|_| {
+                    ostree_container::ImageReference {
+                        transport: ostree_container::Transport::Registry,
+                        name: s.to_owned(),
+                    }
+                }),
+            ),
+        }
+    }
+}
+
+/// How a file or directory changed
+#[derive(Debug, Clone, Copy, Serialize, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+enum Change {
+    Added,
+    Modified,
+    Removed,
+}
+
+/// A changed file or directory in `/usr`
+#[derive(Debug, Serialize, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+struct FileChange {
+    /// The path; directories end with `/`
+    path: String,
+    change: Change,
+    /// The change in size in bytes, including the contents of directories
+    size_delta: i64,
+}
+
+/// Items which are only in one of the compared deployments or images
+#[derive(Debug, Default, Serialize, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+struct ListDiff {
+    added: Vec<String>,
+    removed: Vec<String>,
+}
+
+impl ListDiff {
+    fn new<'a>(
+        from: impl IntoIterator<Item = &'a String>,
+        to: impl IntoIterator<Item = &'a String>,
+    ) -> Self {
+        let from = from.into_iter().collect::<BTreeSet<_>>();
+        let to = to.into_iter().collect::<BTreeSet<_>>();
+        Self {
+            added: to.difference(&from).map(|s| s.to_string()).collect(),
+            removed: from.difference(&to).map(|s| s.to_string()).collect(),
+        }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.added.is_empty() && self.removed.is_empty()
+    }
+}
+
+/// The difference between two deployments or images
+#[derive(Debug, Serialize, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+struct Diff {
+    from: String,
+    to: String,
+    /// Files and directories in `/usr`
+    files: Vec<FileChange>,
+    /// Kernel versions
+    kernel: ListDiff,
+    /// Kernel arguments from `/usr/lib/bootc/kargs.d`
+    kargs: ListDiff,
+    /// Logically bound images; unknown for ostree images which are not deployed
+    #[serde(skip_serializing_if = "Option::is_none")]
+    bound_images: Option<ListDiff>,
+    /// Installed packages, if requested
+    #[serde(skip_serializing_if = "Option::is_none")]
+    packages: Option<PackageDiff>,
+}
+
+impl Diff {
+    fn new(
+        from: &Target,
+        to: &Target,
+        files: Vec<FileChange>,
+        from_info: TreeInfo,
+        to_info: TreeInfo,
+    ) -> Self {
+        let name = |t: &Target| match t {
+            Target::Slot(slot) => slot.to_string(),
+            Target::Image(imgref) => imgref.to_string(),
+        };
+        let bound_images = from_info
+            .bound_images
+            .as_ref()
+            .zip(to_info.bound_images.as_ref())
+            .map(|(from, to)| ListDiff::new(from, to));
+        Self {
+            from: name(from),
+            to: name(to),
+            files,
+            kernel: ListDiff::new(&from_info.kernels, &to_info.kernels),
+            kargs: ListDiff::new(&from_info.kargs, &to_info.kargs),
+            bound_images,
+            packages: diff_packages(&from_info.packages, &to_info.packages),
+        }
+    }
+}
+
+/// What is compared apart from files
+#[derive(Debug, Default)]
+struct TreeInfo {
+    kernels: Vec<String>,
+    kargs: Vec<String>,
+    bound_images: Option<Vec<String>>,
+    /// The installed packages from the available sources, in order of preference
+    packages: Vec<Packages>,
+}
+
+impl TreeInfo {
+    /// Gather the information from the root of a deployment or mounted image
+    fn from_root(root: &Dir) -> Result<Self> {
+        let mut kernels = Vec::new();
+        if let Some(modules) = root.open_dir_optional("usr/lib/modules")? {
+            for entry in modules.entries()? {
+                let entry = entry?;
+                let kver = entry.file_name();
+                let Some(kver) = kver.to_str() else {
+                    continue;
+                };
+                if modules.try_exists(Path::new(kver).join("vmlinuz"))? {
+                    kernels.push(kver.to_owned());
+                }
+            }
+        }
+        let kargs = crate::bootc_kargs::get_kargs_in_root(root, std::env::consts::ARCH)?;
+        let bound_images = crate::boundimage::query_bound_images(root)?;
+        Ok(Self {
+            kernels,
+            kargs: kargs.iter_str().map(String::from).collect(),
+            bound_images: Some(bound_images.into_iter().map(|i| i.image).collect()),
+            packages: Vec::new(),
+        })
+    }
+}
+
+fn format_size_delta(delta: i64) -> String {
+    let sign = if delta < 0 { '-' } else { '+' };
+    format!(
+        "{sign}{}",
+        ostree_ext::glib::format_size(delta.unsigned_abs())
+    )
+}
+
+fn human_readable_output(mut out: impl Write, diff: &Diff) -> Result<()> {
+    writeln!(out, "Comparing {} with {}", diff.from, diff.to)?;
+    if !diff.kernel.is_empty() {
+        writeln!(
+            out,
+            "Kernel: {} -> {}",
+            diff.kernel.removed.join(" "),
+            diff.kernel.added.join(" ")
+        )?;
+    }
+    let lists = [
+        ("Kernel arguments", Some(&diff.kargs)),
+        ("Bound images", diff.bound_images.as_ref()),
+    ];
+    for (name, list) in lists {
+        let Some(list) = list.filter(|l| !l.is_empty()) else {
+            continue;
+        };
+        let added = list.added.iter().map(|s| format!("+{s}"));
+        let removed = list.removed.iter().map(|s| format!("-{s}"));
+        let items = added.chain(removed).collect::<Vec<_>>();
+        writeln!(out, "{name}: {}", items.join(" "))?;
+    }
+    if let Some(packages) = diff.packages.as_ref() {
+        crate::packages::human_readable_output(&mut out, packages)?;
+    }
+    for file in &diff.files {
+        let prefix = match file.change {
+            Change::Added => "A",
+            Change::Modified => "M",
+            Change::Removed => "D",
+        };
+        write!(out, "{prefix} {}", file.path)?;
+        if file.size_delta != 0 {
+            write!(out, " ({})", format_size_delta(file.size_delta))?;
+        }
+        writeln!(out)?;
+    }
+    if diff.files.is_empty() {
+        writeln!(out, "No changes in /usr")?;
+    } else {
+        let count = |change: Change| diff.files.iter().filter(|f| f.change == change).count();
+        let total: i64 = diff.files.iter().map(|f| f.size_delta).sum();
+        writeln!(
+            out,
+            "Files: {} added, {} modified, {} removed ({})",
+            count(Change::Added),
+            count(Change::Modified),
+            count(Change::Removed),
+            format_size_delta(total)
+        )?;
+    }
+    Ok(())
+}
+
+/// An entry of a composefs dumpfile
+#[derive(Debug, PartialEq, Eq)]
+struct DumpEntry<'a> {
+    size: u64,
+    is_dir: bool,
+    /// All fields except the path, link count and modification time, which
+    /// are not relevant for a comparison
+    fields: Vec<&'a str>,
+}
+
+/// Parse a dumpfile into its entries by (escaped) path
+fn parse_dumpfile(dump: &str) -> Result<BTreeMap<&str, DumpEntry<'_>>> {
+    dump.lines()
+        .map(|line| {
+            let mut fields = line.split(' ');
+            let (Some(path), Some(size), Some(mode)) =
+                (fields.next(), fields.next(), fields.next())
+            else {
+                anyhow::bail!("Invalid dumpfile line: {line}");
+            };
+            let size = size
+                .parse()
+                .with_context(|| format!("Invalid size in dumpfile line: {line}"))?;
+            // Hardlinks have a mode starting with '@'
+            let is_dir = u32::from_str_radix(mode, 8).is_ok_and(|m| m & 0o170000 == 0o040000);
+            // Skip the link count; the modification time follows uid, gid and rdev
+            let rest = fields.skip(1).collect::<Vec<_>>();
+            let mut entry_fields = vec![mode];
+            entry_fields.extend(rest.iter().take(3));
+            entry_fields.extend(rest.iter().skip(4));
+            let entry = DumpEntry {
+                size,
+                is_dir,
+                fields: entry_fields,
+            };
+            Ok((path, entry))
+        })
+        .collect()
+}
+
+/// Add a change to `files`, or if it is inside an added or removed directory,
+/// add its size to that of the directory.
+fn push_change(
+    files: &mut Vec<FileChange>,
+    dirs: &mut BTreeMap<String, usize>,
+    path: &str,
+    is_dir: bool,
+    change: Change,
+    size_delta: i64,
+) {
+    let parent = Path::new(path)
+        .ancestors()
+        .skip(1)
+        .find_map(|p| dirs.get(p.to_str()?).copied());
+    if let Some(i) = parent {
+        files[i].size_delta += size_delta;
+        return;
+    }
+    if is_dir && change != Change::Modified {
+        dirs.insert(path.to_owned(), files.len());
+    }
+    let path = if is_dir {
+        format!("{path}/")
+    } else {
+        path.to_owned()
+    };
+    files.push(FileChange {
+        path,
+        change,
+        size_delta,
+    });
+}
+
+/// Compare two dumpfiles of `/usr`
+fn diff_dumpfiles(from: &str, to: &str) -> Result<Vec<FileChange>> {
+    let from = parse_dumpfile(from)?;
+    let to = parse_dumpfile(to)?;
+    let paths = from.keys().chain(to.keys()).collect::<BTreeSet<_>>();
+
+    let mut files = Vec::new();
+    let mut dirs = BTreeMap::new();
+    for path in paths {
+        if *path == "/" {
+            continue;
+        }
+        let usr_path = format!("/usr{path}");
+        let (change, entry, size_delta) = match (from.get(path), to.get(path)) {
+            (None, Some(e)) => (Change::Added, e, e.size as i64),
+            (Some(e), None) => (Change::Removed, e, -(e.size as i64)),
+            (Some(f), Some(t)) if f != t => (Change::Modified, t, t.size as i64 - f.size as i64),
+            _ => continue,
+        };
+        push_change(
+            &mut files,
+            &mut dirs,
+            &usr_path,
+            entry.is_dir,
+            change,
+            size_delta,
+        );
+    }
+    Ok(files)
+}
+
+/// The size of a file, or of the files in a directory
+fn ostree_size(f: &ostree::RepoFile) -> Result<u64> {
+    let cancellable = gio::Cancellable::NONE;
+    let queryflags = gio::FileQueryInfoFlags::NOFOLLOW_SYMLINKS;
+    let info = f.query_info(QUERYATTRS, queryflags, cancellable)?;
+    if info.file_type() != gio::FileType::Directory {
+        return Ok(info.size() as u64);
+    }
+    let iter = f.enumerate_children(QUERYATTRS, queryflags, cancellable)?;
+    let mut size = 0;
+    while let Some(info) = iter.next_file(cancellable)? {
+        let child = iter.child(&info);
+        let child = child.downcast::<ostree::RepoFile>().expect("downcast");
+        size += ostree_size(&child)?;
+    }
+    Ok(size)
+}
+
+/// Compare `/usr` in two ostree commits
+#[context("Comparing commits")]
+fn diff_commits(repo: &ostree::Repo, from: &str, to: &str) -> Result<Vec<FileChange>> {
+    let diff = ostree_ext::diff::diff(repo, from, to, Some("/usr"))?;
+    let (fromroot, _) = repo.read_commit(from, gio::Cancellable::NONE)?;
+    let (toroot, _) = repo.read_commit(to, gio::Cancellable::NONE)?;
+    let size = |root: &gio::File, path: &str| {
+        let f = root.resolve_relative_path(format!("usr{path}"));
+        let f = f.downcast::<ostree::RepoFile>().expect("downcast");
+        ostree_size(&f).map(|s| s as i64)
+    };
+
+    let mut files = Vec::new();
+    let mut dirs = BTreeMap::new();
+    let changes = [
+        (&diff.added_dirs, true, Change::Added),
+        (&diff.added_files, false, Change::Added),
+        (&diff.changed_dirs, true, Change::Modified),
+        (&diff.changed_files, false, Change::Modified),
+        (&diff.removed_dirs, true, Change::Removed),
+        (&diff.removed_files, false, Change::Removed),
+    ];
+    for (paths, is_dir, change) in changes {
+        for path in paths {
+            let size_delta = match change {
+                Change::Added => size(&toroot, path)?,
+                Change::Removed => -size(&fromroot, path)?,
+                Change::Modified if is_dir => 0,
+                Change::Modified => size(&toroot, path)? - size(&fromroot, path)?,
+            };
+            let path = format!("/usr{path}");
+            push_change(&mut files, &mut dirs, &path, is_dir, change, size_delta);
+        }
+    }
+    files.sort_by(|a, b| a.path.cmp(&b.path));
+    Ok(files)
+}
+
+/// The kernel versions in an ostree commit
+fn ostree_kernels(root: &gio::File) -> Result<Vec<String>> {
+    let cancellable = gio::Cancellable::NONE;
+    let modules = root.resolve_relative_path("usr/lib/modules");
+    if !modules.query_exists(cancellable) {
+        return Ok(Vec::new());
+    }
+    let queryflags = gio::FileQueryInfoFlags::NOFOLLOW_SYMLINKS;
+    let iter = modules.enumerate_children(QUERYATTRS, queryflags, cancellable)?;
+    let mut kernels = Vec::new();
+    while let Some(info) = iter.next_file(cancellable)? {
+        let kver = info.name();
+        let Some(kver) = kver.to_str() else {
+            continue;
+        };
+        if iter.child(&info).child("vmlinuz").query_exists(cancellable) {
+            kernels.push(kver.to_owned());
+        }
+    }
+    Ok(kernels)
+}
+
+/// Resolve `target` to an ostree commit, and the deployment of it if any
+fn resolve_ostree(
+    booted_ostree: &BootedOstree<'_>,
+    target: &Target,
+) -> Result<(String, Option<ostree::Deployment>)> {
+    let sysroot = booted_ostree.sysroot;
+    let deployment = match target {
+        Target::Slot(Slot::Booted) => booted_ostree.deployment.clone(),
+        Target::Slot(slot) => {
+            let (deployments, _host) = crate::status::get_status(booted_ostree)?;
+            let deployment = match slot {
+                Slot::Staged => deployments.staged,
+                _ => deployments.rollback,
+            };
+            deployment.ok_or_else(|| anyhow::anyhow!("No {slot} deployment"))?
+        }
+        Target::Image(imgref) => {
+            let repo = &booted_ostree.repo();
+            let image = ostree_container::store::query_image(repo, imgref)?
+                .ok_or_else(|| anyhow::anyhow!("Image {imgref} not found"))?;
+            let commit = image.merge_commit;
+            let deployment = sysroot
+                .deployments()
+                .into_iter()
+                .find(|d| d.csum().as_str() == commit);
+            return Ok((commit, deployment));
+        }
+    };
+    Ok((deployment.csum().to_string(), Some(deployment)))
+}
+
+#[context("Comparing (ostree)")]
+fn diff_ostree(
+    booted_ostree: &BootedOstree<'_>,
+    from: &Target,
+    to: &Target,
+    packages: bool,
+) -> Result<Diff> {
+    let sysroot = booted_ostree.sysroot;
+    let repo = &booted_ostree.repo();
+    let info = |commit: &str, deployment: Option<ostree::Deployment>| -> Result<TreeInfo> {
+        let mut info = if let Some(deployment) = deployment {
+            let root = crate::utils::deployment_fd(sysroot, &deployment)?;
+            let mut info = TreeInfo::from_root(&root)?;
+            if packages {
+                let path = Utf8PathBuf::from(format!(
+                    "/sysroot/{}",
+                    sysroot.deployment_dirpath(&deployment)
+                ));
+                info.packages.extend(Packages::from_rpmdb(&root, &path)?);
+            }
+            info
+        } else {
+            // Bound images are only determined for deployments
+            let (root, _) = repo.read_commit(commit, gio::Cancellable::NONE)?;
+            let root_file = root.downcast_ref::<ostree::RepoFile>().expect("downcast");
+            let kargs = crate::bootc_kargs::get_kargs_from_ostree_root(
+                repo,
+                root_file,
+                std::env::consts::ARCH,
+            )?;
+            TreeInfo {
+                kernels: ostree_kernels(&root)?,
+                kargs: kargs.iter_str().map(String::from).collect(),
+                bound_images: None,
+                packages: Vec::new(),
+            }
+        };
+        if packages {
+            let image = ostree_container::store::query_image_commit(repo, commit)?;
+            info.packages
+                .extend(Packages::from_manifest(&image.manifest));
+        }
+        Ok(info)
+    };
+
+    let (from_commit, from_deployment) = resolve_ostree(booted_ostree, from)?;
+    let (to_commit, to_deployment) = resolve_ostree(booted_ostree, to)?;
+    let files = diff_commits(repo, &from_commit, &to_commit)?;
+    let from_info = info(&from_commit, from_deployment)?;
+    let to_info = info(&to_commit, to_deployment)?;
+    Ok(Diff::new(from, to, files, from_info, to_info))
+}
+
+/// Resolve `target` to the verity digest of a composefs image
+fn resolve_composefs(entries: &[(Slot, &BootEntry)], target: &Target) -> Result<String> {
+    let entry = match target {
+        Target::Slot(slot) => entries
+            .iter()
+            .find(|(s, _)| s == slot)
+            .map(|(_, e)| e)
+            .ok_or_else(|| anyhow::anyhow!("No {slot} deployment"))?,
+        Target::Image(imgref) => entries
+            .iter()
+            .map(|(_, e)| e)
+            .find(|e| {
+                e.image
+                    .as_ref()
+                    .is_some_and(|i| i.image.image == imgref.name)
+            })
+            .ok_or_else(|| anyhow::anyhow!("No deployment of image {imgref}"))?,
+    };
+    let composefs = entry
+        .composefs
+        .as_ref()
+        .ok_or_else(|| anyhow::anyhow!("Not a composefs deployment"))?;
+    Ok(composefs.verity.clone())
+}
+
+/// The dumpfile of `/usr` in a mounted composefs image
+fn composefs_dumpfile(booted_cfs: &BootedComposefs, root: &Dir) -> Result<String> {
+    let fs = composefs::fs::read_filesystem(root, Path::new("usr"), Some(&*booted_cfs.repo), false)
+        .context("Reading /usr")?;
+    let mut dump = Vec::new();
+    dumpfile::write_dumpfile(&mut dump, &fs).context("Writing dumpfile")?;
+    String::from_utf8(dump).context("Invalid dumpfile")
+}
+
+#[context("Comparing (composefs)")]
+async fn diff_composefs(
+    storage: &Storage,
+    booted_cfs: &BootedComposefs,
+    from: &Target,
+    to: &Target,
+    packages: bool,
+) -> Result<Diff> {
+    let host = get_composefs_status(storage, booted_cfs).await?;
+    let slots = [
+        (Slot::Booted, host.status.booted.as_ref()),
+        (Slot::Staged, host.status.staged.as_ref()),
+        (Slot::Rollback, host.status.rollback.as_ref()),
+    ];
+    let entries = slots
+        .into_iter()
+        .filter_map(|(slot, e)| Some((slot, e?)))
+        .collect::<Vec<_>>();
+
+    let mut dumps = Vec::new();
+    let mut infos = Vec::new();
+    for target in [from, to] {
+        let verity = resolve_composefs(&entries, target)?;
+        let image = booted_cfs
+            .repo
+            .mount(&verity)
+            .with_context(|| format!("Mounting {verity}"))?;
+        let mnt = TempMount::mount_fd(&image)?;
+        dumps.push(composefs_dumpfile(booted_cfs, &mnt.fd)?);
+        let mut info = TreeInfo::from_root(&mnt.fd)?;
+        if packages {
+            let path = Utf8Path::from_path(mnt.dir.path()).context("Non-UTF8 mount path")?;
+            info.packages.extend(Packages::from_rpmdb(&mnt.fd, path)?);
+        }
+        infos.push(info);
+    }
+
+    let files = diff_dumpfiles(&dumps[0], &dumps[1])?;
+    let to_info = infos.pop().unwrap();
+    let from_info = infos.pop().unwrap();
+    Ok(Diff::new(from, to, files, from_info, to_info))
+}
+
+/// Implementation of `bootc diff`
+pub(crate) async fn diff(opts: DiffOpts) -> Result<()> {
+    // With a single argument, the booted deployment is compared with it
+    let (from, to) = match (opts.from.as_deref(), opts.to.as_deref()) {
+        (None, _) => (Target::Slot(Slot::Booted), Target::Slot(Slot::Staged)),
+        (Some(to), None) => (Target::Slot(Slot::Booted), Target::new(to)),
+        (Some(from), Some(to)) => (Target::new(from), Target::new(to)),
+    };
+
+    let storage = &crate::cli::get_storage().await?;
+    let diff = match storage.kind()? {
+        BootedStorageKind::Ostree(booted_ostree) => {
+            diff_ostree(&booted_ostree, &from, &to, opts.packages)?
+        }
+        BootedStorageKind::Composefs(booted_cfs) => {
+            diff_composefs(storage, &booted_cfs, &from, &to, opts.packages).await?
+        }
+    };
+    if opts.packages && diff.packages.is_none() {
+        anyhow::bail!("Cannot compare packages: no rpm database or ostree.components annotations");
+    }
+
+    let mut out = std::io::stdout().lock();
+    match opts.format.unwrap_or(OutputFormat::HumanReadable) {
+        OutputFormat::Json => diff
+            .to_canon_json_writer(&mut out)
+            .map_err(anyhow::Error::new),
+        OutputFormat::Yaml => serde_yaml::to_writer(&mut out, &diff).map_err(anyhow::Error::new),
+        OutputFormat::HumanReadable => human_readable_output(&mut out, &diff),
+    }
+    .context("Writing to stdout")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_target() {
+        assert_eq!(Target::new("staged"), Target::Slot(Slot::Staged));
+        let Target::Image(imgref) = Target::new("quay.io/example/os:latest") else {
+            panic!("Expected an image");
+        };
+        assert_eq!(imgref.transport, ostree_container::Transport::Registry);
+        assert_eq!(imgref.name, "quay.io/example/os:latest");
+        let Target::Image(imgref) = Target::new("containers-storage:localhost/os") else {
+            panic!("Expected an image");
+        };
+        assert_eq!(
+            imgref.transport,
+            ostree_container::Transport::ContainerStorage
+        );
+        assert_eq!(imgref.name, "localhost/os");
+    }
+
+    #[test]
+    fn test_diff_dumpfiles() -> Result<()> {
+        let from = indoc::indoc! { "
+            / 0 40755 5 0 0 0 0.0 - - -
+            /bin 0 40755 2 0 0 0 0.0 - - -
+            /bin/vi 3000 100755 1 0 0 0 1.0 ab/cd - abcd
+            /lib 0 40755 2 0 0 0 0.0 - - -
+            /lib/os-release 20 100644 1 0 0 0 1.0 - VERSION=1 -
+            /lib/python 0 40755 2 0 0 0 0.0 - - -
+            /lib/python/a.py 100 100644 1 0 0 0 1.0 - - -
+            /lib/python/b.py 200 100644 1 0 0 0 1.0 - - -
+            /lib/unchanged 5 100644 1 0 0 0 1.0 - hello -
+        " };
+        let to = indoc::indoc! { "
+            / 0 40755 5 0 0 0 0.0 - - -
+            /bin 0 40755 2 0 0 0 0.0 - - -
+            /bin/strace 1500 100755 1 0 0 0 2.0 ef/01 - ef01
+            /lib 0 40755 2 0 0 0 0.0 - - -
+            /lib/os-release 21 100644 1 0 0 0 2.0 - VERSION=22 -
+            /lib/unchanged 5 100644 2 0 0 0 2.0 - hello -
+        " };
+
+        let files = diff_dumpfiles(from, to)?;
+        assert_eq!(
+            files,
+            [
+                FileChange {
+                    path: "/usr/bin/strace".into(),
+                    change: Change::Added,
+                    size_delta: 1500
+                },
+                FileChange {
+                    path: "/usr/bin/vi".into(),
+                    change: Change::Removed,
+                    size_delta: -3000
+                },
+                FileChange {
+                    path: "/usr/lib/os-release".into(),
+                    change: Change::Modified,
+                    size_delta: 1
+                },
+                FileChange {
+                    path: "/usr/lib/python/".into(),
+                    change: Change::Removed,
+                    size_delta: -300
+                },
+            ]
+        );
+
+        assert!(parse_dumpfile("/bin").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_human_readable() -> Result<()> {
+        let diff = Diff {
+            from: "booted".into(),
+            to: "staged".into(),
+            files: vec![
+                FileChange {
+                    path: "/usr/bin/strace".into(),
+                    change: Change::Added,
+                    size_delta: 1500,
+                },
+                FileChange {
+                    path: "/usr/lib/os-release".into(),
+                    change: Change::Modified,
+                    size_delta: 0,
+                },
+                FileChange {
+                    path: "/usr/lib/python/".into(),
+                    change: Change::Removed,
+                    size_delta: -3000,
+                },
+            ],
+            kernel: ListDiff {
+                added: vec!["6.12.0".into()],
+                removed: vec!["6.11.0".into()],
+            },
+            kargs: ListDiff {
+                added: vec!["quiet".into()],
+                removed: vec![],
+            },
+            bound_images: Some(ListDiff::default()),
+            packages: None,
+        };
+        let mut out = Vec::new();
+        human_readable_output(&mut out, &diff)?;
+        assert_eq!(
+            String::from_utf8(out)?,
+            indoc::indoc! { "
+                Comparing booted with staged
+                Kernel: 6.11.0 -> 6.12.0
+                Kernel arguments: +quiet
+                A /usr/bin/strace (+1.5 kB)
+                M /usr/lib/os-release
+                D /usr/lib/python/ (-3.0 kB)
+                Files: 1 added, 1 modified, 1 removed (-1.5 kB)
+            " }
+        );
+
+        let json = serde_json::to_value(&diff)?;
+        assert_eq!(json["files"][0]["sizeDelta"], 1500);
+        assert_eq!(json["files"][2]["change"], "removed");
+        assert_eq!(json["boundImages"]["added"], serde_json::json!([]));
+        Ok(())
+    }
+}
@@ -0,0 +1,237 @@
+//! # Checking for free disk space before pulling an update
+//!
+//! Running out of space while importing an image leaves partially imported
+//! layers behind and fails with confusing errors. So before fetching any layers,
+//! the space an update needs is estimated from the sizes of the layers to fetch
+//! plus the size of the booted kernel and initramfs, or of the booted UKI, which
+//! a new boot entry will likely need as well, and compared with the free space in
+//! the root and boot filesystems.
+//!
+//! The layer sizes are those of the compressed layers and a new kernel may be
+//! shared with existing boot entries, so this is only an estimate.
+
+use std::os::fd::AsFd;
+use std::path::Path;
+
+use anyhow::{Context, Result};
+use bootc_utils::ExitCodeError;
+use cap_std_ext::cap_std::ambient_authority;
+use cap_std_ext::cap_std::fs::Dir;
+use cap_std_ext::dirext::CapStdExtDirExt;
+use fn_error_context::context;
+use ostree_ext::composefs_boot::bootloader::EFI_EXT;
+use ostree_ext::glib::format_size;
+
+use crate::bootc_composefs::boot::{EFI_LINUX, SYSTEMD_UKI_DIR};
+use crate::store::Storage;
+
+/// The exit code if there is not enough disk space for an update; the value of `ENOSPC`.
+pub(crate) const EXIT_INSUFFICIENT_SPACE: i32 = 28;
+
+/// A filesystem an update needs space in
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Filesystem {
+    /// The device, to detect whether the root and boot filesystems are the same
+    dev: u64,
+    /// The number of bytes available
+    avail: u64,
+}
+
+impl Filesystem {
+    fn new(fd: impl AsFd) -> Result<Self> {
+        let dev = rustix::fs::fstat(&fd)?.st_dev;
+        let stat = rustix::fs::fstatvfs(&fd)?;
+        Ok(Self {
+            dev,
+            avail: stat.f_bsize * stat.f_bavail,
+        })
+    }
+}
+
+/// Describe the filesystems lacking space, if `root` needs `root_needed` bytes
+/// and `boot` needs `boot_needed` bytes.
+fn shortfalls(
+    root: Filesystem,
+    root_needed: u64,
+    boot: Filesystem,
+    boot_needed: u64,
+) -> Vec<String> {
+    let needs = if root.dev == boot.dev {
+        vec![("root", root, root_needed + boot_needed)]
+    } else {
+        vec![("root", root, root_needed), ("boot", boot, boot_needed)]
+    };
+    needs
+        .into_iter()
+        .filter(|(_, fs, needed)| *needed > fs.avail)
+        .map(|(name, fs, needed)| {
+            format!(
+                "{name} filesystem (required: {} available: {})",
+                format_size(needed),
+                format_size(fs.avail)
+            )
+        })
+        .collect()
+}
+
+/// The boot artifacts a new boot entry will write, estimated from the booted ones
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum BootArtifacts<'a> {
+    /// A kernel and initramfs, copied from the booted root
+    Kernel,
+    /// A UKI; the booted one is named after the composefs image `id`
+    Uki { id: &'a str },
+}
+
+/// The size of the kernel and initramfs for kernel version `kver` in `root`.
+fn kernel_size(root: &Dir, kver: &str) -> Result<u64> {
+    let moddir = Path::new("usr/lib/modules").join(kver);
+    ["vmlinuz", "initramfs.img"]
+        .iter()
+        .map(|name| {
+            let path = moddir.join(name);
+            let meta = root
+                .metadata_optional(&path)
+                .with_context(|| format!("Querying {path:?}"))?;
+            Ok(meta.map_or(0, |m| m.len()))
+        })
+        .sum()
+}
+
+/// The size of the UKI for the composefs image `id` in the ESP `esp`.
+fn uki_size(esp: &Dir, id: &str) -> Result<u64> {
+    for dir in [SYSTEMD_UKI_DIR, EFI_LINUX] {
+        let path = Path::new(dir).join(format!("{id}{EFI_EXT}"));
+        let meta = esp
+            .metadata_optional(&path)
+            .with_context(|| format!("Querying {path:?}"))?;
+        if let Some(meta) = meta {
+            return Ok(meta.len());
+        }
+    }
+    Ok(0)
+}
+
+/// The result of [`ensure_space`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum SpaceCheck {
+    /// There is enough space
+    Available,
+    /// The cleanup policy was applied to make space. It may have removed layers
+    /// which were counted as present, so the pull must be prepared and the space
+    /// checked again.
+    CleanedUp,
+}
+
+/// Ensure there is space to fetch `bytes_to_fetch` into the physical root and to
+/// write `artifacts` into `boot`. If there is not and `cleanup` is set and the
+/// cleanup policy is configured, the policy is applied; otherwise this fails with
+/// [`EXIT_INSUFFICIENT_SPACE`].
+#[context("Checking disk space")]
+pub(crate) async fn ensure_space(
+    storage: &Storage,
+    boot: &Dir,
+    artifacts: BootArtifacts<'_>,
+    bytes_to_fetch: u64,
+    cleanup: bool,
+) -> Result<SpaceCheck> {
+    let boot_needed = match artifacts {
+        BootArtifacts::Kernel => {
+            let rootfs = Dir::open_ambient_dir("/", ambient_authority()).context("Opening /")?;
+            let uname = rustix::system::uname();
+            let kver = uname.release().to_str().context("Invalid kernel version")?;
+            kernel_size(&rootfs, kver)?
+        }
+        BootArtifacts::Uki { id } => uki_size(boot, id)?,
+    };
+    tracing::debug!("Space needed: {bytes_to_fetch} (root), {boot_needed} (boot)");
+
+    let check = || -> Result<Vec<String>> {
+        let root = Filesystem::new(&storage.physical_root)?;
+        let boot = Filesystem::new(boot)?;
+        Ok(shortfalls(root, bytes_to_fetch, boot, boot_needed))
+    };
+
+    let missing = check()?;
+    if missing.is_empty() {
+        return Ok(SpaceCheck::Available);
+    }
+
+    if cleanup {
+        let config = crate::cleanup::load_config()?;
+        if config.auto() {
+            println!("Insufficient disk space for the update; cleaning up");
+            crate::cleanup::run(storage, &config).await?;
+            return Ok(SpaceCheck::CleanedUp);
+        }
+    }
+
+    Err(ExitCodeError {
+        code: EXIT_INSUFFICIENT_SPACE,
+        message: format!("Insufficient disk space: {}", missing.join(", ")),
+    }
+    .into())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use cap_std_ext::cap_std;
+
+    #[test]
+    fn test_shortfalls() {
+        let root = Filesystem {
+            dev: 1,
+            avail: 1_000_000,
+        };
+        let boot = Filesystem {
+            dev: 2,
+            avail: 100_000,
+        };
+
+        assert!(shortfalls(root, 900_000, boot, 100_000).is_empty());
+        assert_eq!(
+            shortfalls(root, 2_000_000, boot, 150_000),
+            [
+                "root filesystem (required: 2.0 MB available: 1.0 MB)",
+                "boot filesystem (required: 150.0 kB available: 100.0 kB)"
+            ]
+        );
+
+        // Without a separate boot filesystem, the kernel needs space in root
+        assert_eq!(
+            shortfalls(root, 900_000, root, 200_000),
+            ["root filesystem (required: 1.1 MB available: 1.0 MB)"]
+        );
+    }
+
+    #[test]
+    fn test_kernel_size() -> Result<()> {
+        let td = cap_std_ext::cap_tempfile::TempDir::new(cap_std::ambient_authority())?;
+        assert_eq!(kernel_size(&td, "6.12.0")?, 0);
+
+        td.create_dir_all("usr/lib/modules/6.12.0")?;
+        td.write("usr/lib/modules/6.12.0/vmlinuz", "kernel")?;
+        assert_eq!(kernel_size(&td, "6.12.0")?, 6);
+        td.write("usr/lib/modules/6.12.0/initramfs.img", "initramfs")?;
+        assert_eq!(kernel_size(&td, "6.12.0")?, 15);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_uki_size() -> Result<()> {
+        let td = cap_std_ext::cap_tempfile::TempDir::new(cap_std::ambient_authority())?;
+        assert_eq!(uki_size(&td, "abcd")?, 0);
+
+        td.create_dir_all("EFI/Linux/bootc")?;
+        td.write("EFI/Linux/other.efi", "other")?;
+        assert_eq!(uki_size(&td, "abcd")?, 0);
+        td.write("EFI/Linux/abcd.efi", "grub uki")?;
+        assert_eq!(uki_size(&td, "abcd")?, 8);
+        td.write("EFI/Linux/bootc/abcd.efi", "systemd-boot uki")?;
+        assert_eq!(uki_size(&td, "abcd")?, 16);
+
+        Ok(())
+    }
+}
@@ -0,0 +1,382 @@
+//! # Resetting a system to the pristine state of its image
+//!
+//! `bootc factory-reset` queues the booted image to be started again on the
+//! next boot with a fresh `/etc` copied from the image defaults (without any
+//! three-way merge) and an empty `/var`. Selected paths in `/var` can be
+//! preserved via the command line or the `bootc/factory-reset` configuration.
+//!
+//! The `/etc` part is handled at staging time; for ostree we stage a new
+//! deployment without a merge deployment, and for composefs we write a pristine
+//! copy next to the deployment state which the initramfs swaps in. In both cases
+//! a marker file is placed in the new `/etc`, which causes our generator to
+//! enable `bootc-factory-reset.service` to clean out `/var` early in boot.
+
+use std::path::{Path, PathBuf};
+
+use anyhow::{anyhow, Context, Result};
+use bootc_initramfs_setup::ETC_RESET_DIR;
+use bootc_kernel_cmdline::utf8::{Cmdline, CmdlineOwned};
+use camino::{Utf8Component, Utf8Path, Utf8PathBuf};
+use cap_std_ext::cap_std::ambient_authority;
+use cap_std_ext::cap_std::fs::Dir;
+use cap_std_ext::dirext::CapStdExtDirExt;
+use fn_error_context::context;
+use serde::{Deserialize, Serialize};
+
+use crate::bootc_composefs::state::copy_pristine_etc;
+use crate::bootc_composefs::status::get_composefs_status;
+use crate::cli::FactoryResetOpts;
+use crate::composefs_consts::STATE_DIR_RELATIVE;
+use crate::deploy::{ImageState, MergeState, RequiredHostSpec};
+use crate::progress_jsonl::ProgressWriter;
+use crate::store::{BootedComposefs, BootedOstree, BootedStorageKind, Storage};
+
+/// The name of the marker file in `/etc` requesting a reset of `/var`.
+const MARKER_NAME: &str = "bootc-factory-reset.json";
+/// The marker file, relative to the root of a deployment.
+pub(crate) const FACTORY_RESET_MARKER: &str = "etc/bootc-factory-reset.json";
+/// The path under which configured paths to preserve are found.
+const CONFIG_PATH: &str = "bootc/factory-reset";
+
+/// The top level of a configuration file.
+#[derive(Debug, Default, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+struct FactoryResetConfigToplevel {
+    factory_reset: Option<FactoryResetConfig>,
+}
+
+/// Configuration for a factory reset; this is also serialized as the marker
+/// file which requests the reset of `/var` on the next boot.
+#[derive(Debug, Default, Clone, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) struct FactoryResetConfig {
+    /// Absolute paths under `/var` which will be preserved.
+    #[serde(default)]
+    pub(crate) preserve: Vec<Utf8PathBuf>,
+}
+
+impl FactoryResetConfig {
+    fn merge(&mut self, other: Self) {
+        self.preserve.extend(other.preserve);
+        self.preserve.sort();
+        self.preserve.dedup();
+    }
+
+    /// Verify all paths to preserve, and return them relative to `/var`.
+    fn var_relative_paths(&self) -> Result<Vec<PathBuf>> {
+        self.preserve
+            .iter()
+            .map(|p| var_relative_path(p).map(|p| p.into_std_path_buf()))
+            .collect()
+    }
+}
+
+/// Load the configured paths to preserve from `bootc/factory-reset/*.toml`.
+#[context("Loading factory reset configuration")]
+fn load_config() -> Result<FactoryResetConfig> {
+    const SYSTEMD_CONVENTIONAL_BASES: &[&str] = &["/usr/lib", "/usr/local/lib", "/etc", "/run"];
+    let fragments = liboverdrop::scan(SYSTEMD_CONVENTIONAL_BASES, CONFIG_PATH, &["toml"], true);
+    let mut config = FactoryResetConfig::default();
+    for (_name, path) in fragments {
+        let buf = std::fs::read_to_string(&path)?;
+        let mut unused = std::collections::HashSet::new();
+        let de = toml::Deserializer::parse(&buf).with_context(|| format!("Parsing {path:?}"))?;
+        let c: FactoryResetConfigToplevel = serde_ignored::deserialize(de, |path| {
+            unused.insert(path.to_string());
+        })
+        .with_context(|| format!("Parsing {path:?}"))?;
+        for key in unused {
+            eprintln!("warning: {path:?}: Unknown key {key}");
+        }
+        if let Some(c) = c.factory_reset {
+            tracing::debug!("Merging factory reset config: {c:?}");
+            config.merge(c);
+        }
+    }
+    Ok(config)
+}
+
+/// Validate a path to preserve, returning it relative to `/var`.
+fn var_relative_path(path: &Utf8Path) -> Result<Utf8PathBuf> {
+    let relpath = path
+        .strip_prefix("/var")
+        .map_err(|_| anyhow!("Path to preserve must be absolute and under /var: {path}"))?;
+    if relpath.as_str().is_empty() {
+        anyhow::bail!("Cannot preserve all of /var");
+    }
+    if relpath
+        .components()
+        .any(|c| !matches!(c, Utf8Component::Normal(_)))
+    {
+        anyhow::bail!("Invalid path to preserve: {path}");
+    }
+    Ok(relpath.to_owned())
+}
+
+/// Write the marker file which requests resetting `/var` on the next boot.
+fn write_marker(etc: &Dir, config: &FactoryResetConfig) -> Result<()> {
+    crate::lsm::atomic_replace_labeled(etc, MARKER_NAME, 0o644.into(), None, |w| {
+        serde_json::to_writer_pretty(w, config).map_err(Into::into)
+    })
+}
+
+/// Implementation of `bootc factory-reset`.
+pub(crate) async fn factory_reset(opts: FactoryResetOpts) -> Result<()> {
+    let prog: ProgressWriter = opts.progress.try_into()?;
+
+    let mut config = load_config()?;
+    config.merge(FactoryResetConfig {
+        preserve: opts.preserve,
+    });
+    // Validate the paths before we do anything
+    config.var_relative_paths()?;
+
+    let storage = &crate::cli::get_storage().await?;
+    match storage.kind()? {
+        BootedStorageKind::Ostree(booted_ostree) => {
+            stage_ostree(storage, &booted_ostree, &config, prog).await?
+        }
+        BootedStorageKind::Composefs(booted_cfs) => {
+            stage_composefs(storage, &booted_cfs, &config).await?
+        }
+    }
+
+    if config.preserve.is_empty() {
+        println!("Queued factory reset for next boot");
+    } else {
+        println!("Queued factory reset for next boot, preserving:");
+        for p in config.preserve.iter() {
+            println!("  {p}");
+        }
+    }
+
+    if opts.apply {
+        crate::reboot::reboot()?;
+    }
+    Ok(())
+}
+
+/// Stage the booted image as a new deployment without a merge deployment,
+/// which gives us a pristine `/etc`.
+#[context("Staging factory reset")]
+async fn stage_ostree(
+    storage: &Storage,
+    booted_ostree: &BootedOstree<'_>,
+    config: &FactoryResetConfig,
+    prog: ProgressWriter,
+) -> Result<()> {
+    let rootfs = &Dir::open_ambient_dir("/", ambient_authority())?;
+    let ostree = storage.get_ostree()?;
+    let repo = &ostree.repo();
+    let (_deployments, host) = crate::status::get_status(booted_ostree)?;
+    let (fetched, spec) = ImageState::from_booted(&host, repo)?;
+    let spec = RequiredHostSpec::from_spec(&spec)?;
+
+    // Keep the kernel arguments we booted with
+    let bootcfg = booted_ostree
+        .deployment
+        .bootconfig()
+        .ok_or_else(|| anyhow!("Missing bootcfg for booted deployment"))?;
+    let kargs = bootcfg
+        .get("options")
+        .map(|options| CmdlineOwned::from(options.to_string()))
+        .unwrap_or_else(Cmdline::new);
+
+    let from = MergeState::Reset {
+        stateroot: booted_ostree.stateroot().to_string(),
+        kargs,
+    };
+    crate::deploy::stage(storage, from, &fetched, &spec, prog).await?;
+
+    let staged_deployment = ostree
+        .staged_deployment()
+        .ok_or_else(|| anyhow!("No staged deployment found"))?;
+    let deployment_path = ostree.deployment_dirpath(&staged_deployment);
+    let sysroot_dir = crate::utils::sysroot_dir(ostree)?;
+    let deployment_root = sysroot_dir.open_dir(&deployment_path)?;
+    let etc = &deployment_root.open_dir("etc")?;
+    crate::install::copy_boot_fstab_entry(rootfs, etc)?;
+    write_marker(etc, config)?;
+
+    storage.update_mtime()?;
+
+    Ok(())
+}
+
+/// Queue a pristine `/etc` for the booted deployment, which the initramfs
+/// swaps in on the next boot.
+#[context("Queueing composefs factory reset")]
+async fn stage_composefs(
+    storage: &Storage,
+    booted_cfs: &BootedComposefs,
+    config: &FactoryResetConfig,
+) -> Result<()> {
+    let host = get_composefs_status(storage, booted_cfs).await?;
+    if host.status.staged.is_some() {
+        anyhow::bail!("Cannot factory reset while a deployment is staged");
+    }
+
+    let verity = &*booted_cfs.cmdline.digest;
+    let state_dir = Utf8Path::new(STATE_DIR_RELATIVE).join(verity);
+    let state = storage
+        .physical_root
+        .open_dir(&state_dir)
+        .with_context(|| format!("Opening {state_dir}"))?;
+    state.remove_all_optional(ETC_RESET_DIR)?;
+    state.create_dir(ETC_RESET_DIR)?;
+
+    let sysroot_path = Utf8Path::new("/sysroot");
+    copy_pristine_etc(
+        sysroot_path,
+        verity,
+        &sysroot_path.join(&state_dir).join(ETC_RESET_DIR),
+    )?;
+
+    let rootfs = &Dir::open_ambient_dir("/", ambient_authority())?;
+    let pending = &state.open_dir(ETC_RESET_DIR)?;
+    crate::install::copy_boot_fstab_entry(rootfs, pending)?;
+    write_marker(pending, config)
+}
+
+/// Remove everything in `d` except for the paths in `preserve` (relative to the
+/// root of the walk) and their ancestors, without crossing mount points.
+///
+/// Returns `true` if the directory is now empty.
+fn reset_dir(d: &Dir, prefix: &Path, preserve: &[PathBuf]) -> Result<bool> {
+    let mut empty = true;
+    for entry in d.entries()? {
+        let entry = entry?;
+        let name = entry.file_name();
+        let path = prefix.join(&name);
+        if preserve.contains(&path) {
+            tracing::debug!("Preserving {path:?}");
+            empty = false;
+            continue;
+        }
+        if entry.file_type()?.is_dir() {
+            let Some(subdir) = d.open_dir_noxdev(&name)? else {
+                tracing::debug!("Skipping mount point {path:?}");
+                empty = false;
+                continue;
+            };
+            if reset_dir(&subdir, &path, preserve)? {
+                d.remove_dir(&name)
+                    .with_context(|| format!("Removing {path:?}"))?;
+            } else {
+                empty = false;
+            }
+        } else {
+            d.remove_file(&name)
+                .with_context(|| format!("Removing {path:?}"))?;
+        }
+    }
+    Ok(empty)
+}
+
+/// Implementation of `bootc internals apply-factory-reset`, run early in
+/// boot by `bootc-factory-reset.service` to empty `/var`.
+#[context("Applying factory reset")]
+pub(crate) fn apply_factory_reset(root: &Dir) -> Result<()> {
+    let Some(f) = root.open_optional(FACTORY_RESET_MARKER)? else {
+        tracing::debug!("No factory reset requested");
+        return Ok(());
+    };
+    let config: FactoryResetConfig = serde_json::from_reader(std::io::BufReader::new(f))
+        .with_context(|| format!("Parsing {FACTORY_RESET_MARKER}"))?;
+    let preserve = config.var_relative_paths()?;
+
+    let var = &root.open_dir("var").context("Opening /var")?;
+    reset_dir(var, Path::new(""), &preserve)?;
+    // Only remove the marker once we're done, so an interrupted reset is retried.
+    root.remove_file(FACTORY_RESET_MARKER)
+        .with_context(|| format!("Removing {FACTORY_RESET_MARKER}"))?;
+    println!("Reset /var");
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use cap_std_ext::cap_std;
+
+    use super::*;
+
+    #[test]
+    fn test_var_relative_path() {
+        let ok = [
+            ("/var/lib/containers", "lib/containers"),
+            ("/var/roothome", "roothome"),
+        ];
+        for (input, expected) in ok {
+            assert_eq!(var_relative_path(input.into()).unwrap(), expected);
+        }
+        let invalid = [
+            "/var",
+            "/var/",
+            "var/lib",
+            "/etc/foo",
+            "/variable",
+            "/var/lib/../../etc",
+        ];
+        for input in invalid {
+            assert!(var_relative_path(input.into()).is_err(), "{input}");
+        }
+    }
+
+    #[test]
+    fn test_config_merge() {
+        let mut config = FactoryResetConfig {
+            preserve: vec!["/var/lib/containers".into()],
+        };
+        config.merge(FactoryResetConfig {
+            preserve: vec!["/var/roothome".into(), "/var/lib/containers".into()],
+        });
+        assert_eq!(
+            config.preserve,
+            ["/var/lib/containers", "/var/roothome"].map(Utf8PathBuf::from)
+        );
+    }
+
+    #[test]
+    fn test_apply_factory_reset() -> Result<()> {
+        let td = &cap_std_ext::cap_tempfile::tempdir(cap_std::ambient_authority())?;
+        td.create_dir_all("etc")?;
+        td.create_dir_all("var/lib/containers/storage")?;
+        td.write("var/lib/containers/storage/db", "db")?;
+        td.create_dir_all("var/lib/foo")?;
+        td.write("var/lib/foo/state", "state")?;
+        td.create_dir_all("var/log/journal")?;
+        td.write("var/log/journal/log", "log")?;
+        td.symlink_contents("../lib/foo", "var/log/foolink")?;
+        td.create_dir_all("var/roothome/.ssh")?;
+        td.write("var/roothome/.ssh/authorized_keys", "key")?;
+
+        // Without a marker, this is a no-op
+        apply_factory_reset(td)?;
+        assert!(td.try_exists("var/lib/foo/state")?);
+
+        let config = FactoryResetConfig {
+            preserve: vec!["/var/lib/containers".into(), "/var/roothome".into()],
+        };
+        write_marker(&td.open_dir("etc")?, &config)?;
+        apply_factory_reset(td)?;
+
+        assert!(!td.try_exists(FACTORY_RESET_MARKER)?);
+        assert_eq!(td.read_to_string("var/lib/containers/storage/db")?, "db");
+        assert_eq!(
+            td.read_to_string("var/roothome/.ssh/authorized_keys")?,
+            "key"
+        );
+        assert!(!td.try_exists("var/lib/foo")?);
+        assert!(!td.try_exists("var/log")?);
+        let var = td.open_dir("var")?;
+        let mut names = var
+            .entries()?
+            .map(|e| e.map(|e| e.file_name()))
+            .collect::<std::io::Result<Vec<_>>>()?;
+        names.sort();
+        assert_eq!(names, ["lib", "roothome"]);
+
+        Ok(())
+    }
+}
@@ -8,12 +8,21 @@ use fn_error_context::context;
 use ostree_ext::container_utils::{is_ostree_booted_in, OSTREE_BOOTED};
 use rustix::{fd::AsFd, fs::StatVfsMountFlags};
 
+use crate::factory_reset::FACTORY_RESET_MARKER;
+use crate::hooks::HOOKS_DIR;
 use crate::install::DESTRUCTIVE_CLEANUP;
 
 const STATUS_ONBOOT_UNIT: &str = "bootc-status-updated-onboot.target";
 const STATUS_PATH_UNIT: &str = "bootc-status-updated.path";
 const CLEANUP_UNIT: &str = "bootc-destructive-cleanup.service";
 const MULTI_USER_TARGET: &str = "multi-user.target";
+const FACTORY_RESET_UNIT: &str = "bootc-factory-reset.service";
+const LOCAL_FS_TARGET: &str = "local-fs.target";
+const POST_BOOT_HOOKS_UNIT: &str = "bootc-post-boot-hooks.service";
+const RECORD_BOOT_UNIT: &str = "bootc-record-boot.service";
+const KNOWN_GOOD_UNIT: &str = "bootc-composefs-known-good.service";
+/// The configuration of the composefs setup-root, relative to the root
+const SETUP_ROOT_CONFIG: &str = "usr/lib/composefs/setup-root-conf.toml";
 const EDIT_UNIT: &str = "bootc-fstab-edit.service";
 const FSTAB_ANACONDA_STAMP: &str = "Created by anaconda";
 pub(crate) const BOOTC_EDITED_STAMP: &str = "Updated by bootc-fstab-edit.service";
@@ -70,7 +79,7 @@ pub(crate) fn enable_unit(unitdir: &Dir, name: &str, target: &str) -> Result<()>
 
 /// Enable our units
 pub(crate) fn unit_enablement_impl(sysroot: &Dir, unit_dir: &Dir) -> Result<()> {
-    for unit in [STATUS_ONBOOT_UNIT, STATUS_PATH_UNIT] {
+    for unit in [STATUS_ONBOOT_UNIT, STATUS_PATH_UNIT, RECORD_BOOT_UNIT] {
         enable_unit(unit_dir, unit, MULTI_USER_TARGET)?;
     }
 
@@ -84,8 +93,44 @@ pub(crate) fn unit_enablement_impl(sysroot: &Dir, unit_dir: &Dir) -> Result<()>
     Ok(())
 }
 
+/// Enable the unit which empties /var if a factory reset was requested;
+/// this applies to both ostree and composefs systems.
+pub(crate) fn factory_reset_enablement_impl(root: &Dir, unit_dir: &Dir) -> Result<()> {
+    if root.try_exists(FACTORY_RESET_MARKER)? {
+        tracing::debug!("Found {FACTORY_RESET_MARKER}");
+        enable_unit(unit_dir, FACTORY_RESET_UNIT, LOCAL_FS_TARGET)?;
+    }
+    Ok(())
+}
+
+/// Enable the unit which runs the post-boot hooks if the booted image ships any;
+/// this applies to both ostree and composefs systems.
+pub(crate) fn post_boot_hooks_enablement_impl(root: &Dir, unit_dir: &Dir) -> Result<()> {
+    let hooks = format!("{HOOKS_DIR}/post-boot");
+    if root.try_exists(&hooks)? {
+        tracing::debug!("Found {hooks}");
+        enable_unit(unit_dir, POST_BOOT_HOOKS_UNIT, MULTI_USER_TARGET)?;
+    }
+    Ok(())
+}
+
+/// Enable the unit which records the booted composefs image as known-good once the
+/// boot completed, if the image configures setup-root; the unit itself only runs on
+/// composefs systems.
+pub(crate) fn known_good_enablement_impl(root: &Dir, unit_dir: &Dir) -> Result<()> {
+    if root.try_exists(SETUP_ROOT_CONFIG)? {
+        tracing::debug!("Found {SETUP_ROOT_CONFIG}");
+        enable_unit(unit_dir, KNOWN_GOOD_UNIT, MULTI_USER_TARGET)?;
+    }
+    Ok(())
+}
+
 /// Main entrypoint for the generator
 pub(crate) fn generator(root: &Dir, unit_dir: &Dir) -> Result<()> {
+    factory_reset_enablement_impl(root, unit_dir)?;
+    post_boot_hooks_enablement_impl(root, unit_dir)?;
+    known_good_enablement_impl(root, unit_dir)?;
+
     // Only run on ostree systems
     if !root.try_exists(OSTREE_BOOTED)? {
         return Ok(());
@@ -181,7 +226,7 @@ mod tests {
         unit_enablement_impl(sysroot, &unit_dir).unwrap();
         unit_enablement_impl(sysroot, &unit_dir).unwrap();
         let wantsdir = &unit_dir.open_dir("multi-user.target.wants")?;
-        verify(wantsdir, 2)?;
+        verify(wantsdir, 3)?;
         assert!(wantsdir
             .symlink_metadata_optional(CLEANUP_UNIT)
             .unwrap()
@@ -189,7 +234,7 @@ mod tests {
 
         // Now create sysroot and rerun the generator
         unit_enablement_impl(sysroot, &unit_dir).unwrap();
-        verify(wantsdir, 2)?;
+        verify(wantsdir, 3)?;
 
         // Create the destructive stamp
         sysroot
@@ -197,7 +242,7 @@ mod tests {
             .unwrap();
         sysroot.atomic_write(DESTRUCTIVE_CLEANUP, b"").unwrap();
         unit_enablement_impl(sysroot, unit_dir).unwrap();
-        verify(wantsdir, 3)?;
+        verify(wantsdir, 4)?;
 
         // And now the unit should be enabled
         assert!(wantsdir
@@ -208,6 +253,64 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn test_factory_reset_unit() -> Result<()> {
+        let tempdir = &fixture()?;
+        let unit_dir = &tempdir.open_dir("run/systemd/system")?;
+
+        factory_reset_enablement_impl(tempdir, unit_dir)?;
+        assert_eq!(unit_dir.entries()?.count(), 0);
+
+        tempdir.atomic_write(FACTORY_RESET_MARKER, "{}")?;
+        // Explicitly run this twice to test idempotency
+        factory_reset_enablement_impl(tempdir, unit_dir)?;
+        factory_reset_enablement_impl(tempdir, unit_dir)?;
+        let wantsdir = &unit_dir.open_dir("local-fs.target.wants")?;
+        let r = wantsdir.read_link_contents(FACTORY_RESET_UNIT)?;
+        let r: Utf8PathBuf = r.try_into().unwrap();
+        assert_eq!(r, format!("/usr/lib/systemd/system/{FACTORY_RESET_UNIT}"));
+        assert_eq!(wantsdir.entries()?.count(), 1);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_post_boot_hooks_unit() -> Result<()> {
+        let tempdir = &fixture()?;
+        let unit_dir = &tempdir.open_dir("run/systemd/system")?;
+
+        post_boot_hooks_enablement_impl(tempdir, unit_dir)?;
+        assert_eq!(unit_dir.entries()?.count(), 0);
+
+        tempdir.create_dir_all(format!("{HOOKS_DIR}/post-boot"))?;
+        post_boot_hooks_enablement_impl(tempdir, unit_dir)?;
+        let wantsdir = &unit_dir.open_dir("multi-user.target.wants")?;
+        let r = wantsdir.read_link_contents(POST_BOOT_HOOKS_UNIT)?;
+        let r: Utf8PathBuf = r.try_into().unwrap();
+        assert_eq!(r, format!("/usr/lib/systemd/system/{POST_BOOT_HOOKS_UNIT}"));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_known_good_unit() -> Result<()> {
+        let tempdir = &fixture()?;
+        let unit_dir = &tempdir.open_dir("run/systemd/system")?;
+
+        known_good_enablement_impl(tempdir, unit_dir)?;
+        assert_eq!(unit_dir.entries()?.count(), 0);
+
+        tempdir.create_dir_all("usr/lib/composefs")?;
+        tempdir.atomic_write(SETUP_ROOT_CONFIG, "[fallback]\nenabled = true\n")?;
+        known_good_enablement_impl(tempdir, unit_dir)?;
+        let wantsdir = &unit_dir.open_dir("multi-user.target.wants")?;
+        let r = wantsdir.read_link_contents(KNOWN_GOOD_UNIT)?;
+        let r: Utf8PathBuf = r.try_into().unwrap();
+        assert_eq!(r, format!("/usr/lib/systemd/system/{KNOWN_GOOD_UNIT}"));
+
+        Ok(())
+    }
+
     #[cfg(test)]
     mod test {
         use super::*;
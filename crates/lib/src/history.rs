@@ -0,0 +1,476 @@
+//! # The deployment history
+//!
+//! Upgrades, switches, rollbacks and edits of the host, as well as every
+//! boot, are appended as JSON lines to [`HISTORY_PATH`], which is shared by
+//! all deployments, and logged to the journal with [`HISTORY_JOURNAL_ID`].
+//! A boot is recorded as failed if the system did not boot the image the
+//! last operation deployed, e.g. because it was rolled back automatically.
+
+use std::fmt;
+use std::io::{BufRead, Write};
+
+use anyhow::{Context, Result};
+use cap_std_ext::cap_std::fs::{Dir, OpenOptions};
+use cap_std_ext::dirext::CapStdExtDirExt;
+use chrono::{DateTime, Utc};
+use comfy_table::{presets::NOTHING, Table};
+use fn_error_context::context;
+use libsystemd::logging::Priority;
+use serde::{Deserialize, Serialize};
+
+use crate::cli::{HistoryOpts, OutputFormat};
+use crate::spec::{BootEntry, Host, ImageStatus};
+
+/// The history, relative to the root.
+const HISTORY_PATH: &str = "var/lib/bootc/history.jsonl";
+/// The journal message ID of history records.
+const HISTORY_JOURNAL_ID: &str = "1f0e3c7d5a9b4e2c8d6f4a2b0c9e7d5f";
+
+/// An operation in the history.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) enum Operation {
+    Upgrade,
+    Switch,
+    Rollback,
+    Edit,
+    Boot,
+}
+
+impl fmt::Display for Operation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let s = match self {
+            Operation::Upgrade => "upgrade",
+            Operation::Switch => "switch",
+            Operation::Rollback => "rollback",
+            Operation::Edit => "edit",
+            Operation::Boot => "boot",
+        };
+        f.write_str(s)
+    }
+}
+
+/// Whether an operation succeeded.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) enum Outcome {
+    Success,
+    Failure,
+}
+
+/// A record in the history.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct Record {
+    pub(crate) timestamp: DateTime<Utc>,
+    pub(crate) operation: Operation,
+    pub(crate) result: Outcome,
+    /// Why the operation failed
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) error: Option<String>,
+    /// The image reference deployed or booted
+    pub(crate) image: Option<String>,
+    /// The version of the image deployed or booted
+    pub(crate) version: Option<String>,
+    /// The digest of the image booted before
+    pub(crate) from_digest: Option<String>,
+    /// The digest of the image deployed or booted
+    pub(crate) to_digest: Option<String>,
+    /// The user who ran the operation
+    pub(crate) user: Option<String>,
+    pub(crate) boot_id: Option<String>,
+}
+
+fn image_of(entry: Option<&BootEntry>) -> Option<&ImageStatus> {
+    entry.and_then(|e| e.image.as_ref())
+}
+
+/// The login user of this process, which is preserved by e.g. sudo.
+fn current_user() -> Option<String> {
+    let loginuid = std::fs::read_to_string("/proc/self/loginuid")
+        .ok()
+        .and_then(|s| s.trim().parse::<u32>().ok())
+        // An unset login uid, e.g. for system services
+        .filter(|&uid| uid != u32::MAX);
+    let uid = loginuid.unwrap_or_else(|| rustix::process::getuid().as_raw());
+    let name = uzers::get_user_by_uid(uid)
+        .map(|u| u.name().to_string_lossy().into_owned())
+        .unwrap_or_else(|| uid.to_string());
+    Some(name)
+}
+
+fn boot_id() -> Option<String> {
+    std::fs::read_to_string("/proc/sys/kernel/random/boot_id")
+        .ok()
+        .map(|s| s.trim().to_owned())
+}
+
+/// Read the history; lines which cannot be parsed (e.g. truncated by a crash)
+/// are skipped.
+#[context("Reading history")]
+fn read_history(root: &Dir) -> Result<Vec<Record>> {
+    let Some(f) = root.open_optional(HISTORY_PATH)? else {
+        return Ok(Vec::new());
+    };
+    let mut r = Vec::new();
+    for line in std::io::BufReader::new(f).lines() {
+        let line = line?;
+        match serde_json::from_str(&line) {
+            Ok(record) => r.push(record),
+            Err(e) => tracing::warn!("Skipping invalid history record: {e}"),
+        }
+    }
+    Ok(r)
+}
+
+/// Append a record to the history and log it to the journal.
+#[context("Appending to history")]
+fn append(root: &Dir, record: &Record) -> Result<()> {
+    root.create_dir_all(std::path::Path::new(HISTORY_PATH).parent().unwrap())?;
+    let mut f = root.open_with(HISTORY_PATH, OpenOptions::new().append(true).create(true))?;
+    let mut line = serde_json::to_string(record)?;
+    line.push('\n');
+    f.write_all(line.as_bytes())?;
+    f.sync_data()?;
+
+    let priority = match record.result {
+        Outcome::Success => Priority::Info,
+        Outcome::Failure => Priority::Error,
+    };
+    let target = record.to_digest.as_deref().unwrap_or("none");
+    let msg = match record.error.as_deref() {
+        Some(e) => format!("bootc {} to {target} failed: {e}", record.operation),
+        None => format!("bootc {} to {target}", record.operation),
+    };
+    let operation = record.operation.to_string();
+    let vars = [
+        ("MESSAGE_ID", Some(HISTORY_JOURNAL_ID)),
+        ("BOOTC_OPERATION", Some(operation.as_str())),
+        (
+            "BOOTC_RESULT",
+            Some(match record.result {
+                Outcome::Success => "success",
+                Outcome::Failure => "failure",
+            }),
+        ),
+        ("BOOTC_IMAGE", record.image.as_deref()),
+        ("BOOTC_VERSION", record.version.as_deref()),
+        ("BOOTC_FROM_DIGEST", record.from_digest.as_deref()),
+        ("BOOTC_TO_DIGEST", record.to_digest.as_deref()),
+        ("BOOTC_USER", record.user.as_deref()),
+    ];
+    let vars = vars.into_iter().filter_map(|(k, v)| Some((k, v?)));
+    crate::journal::journal_send(priority, &msg, vars);
+    Ok(())
+}
+
+/// The record of `operation` with the result `result`, given the host status
+/// before and after; `None` if a successful operation changed nothing.
+fn operation_record<T>(
+    operation: Operation,
+    target: Option<String>,
+    before: Option<&Host>,
+    after: Option<&Host>,
+    result: &Result<T>,
+) -> Option<Record> {
+    let booted = before.and_then(|h| image_of(h.status.booted.as_ref()));
+    let previous = before.and_then(|h| image_of(h.status.staged.as_ref()));
+    let to = match operation {
+        // A failed operation may have left a previously staged deployment
+        _ if result.is_err() => None,
+        Operation::Rollback => before.and_then(|h| image_of(h.status.rollback.as_ref())),
+        _ => after.and_then(|h| image_of(h.status.staged.as_ref())),
+    };
+    let digest = |i: Option<&ImageStatus>| i.map(|i| i.image_digest.clone());
+    if result.is_ok() && operation != Operation::Rollback && digest(to) == digest(previous) {
+        return None;
+    }
+    let image = to.map(|i| i.image.image.clone()).or(target);
+    Some(Record {
+        timestamp: Utc::now(),
+        operation,
+        result: if result.is_ok() {
+            Outcome::Success
+        } else {
+            Outcome::Failure
+        },
+        error: result.as_ref().err().map(|e| format!("{e:#}")),
+        image,
+        version: to.and_then(|i| i.version.clone()),
+        from_digest: digest(booted),
+        to_digest: digest(to),
+        user: current_user(),
+        boot_id: boot_id(),
+    })
+}
+
+/// Run `f`, an operation targeting `target` if known, and record it in the
+/// history. Errors recording it are only logged.
+pub(crate) async fn record<T>(
+    root: &Dir,
+    operation: Operation,
+    target: Option<String>,
+    f: impl std::future::Future<Output = Result<T>>,
+) -> Result<T> {
+    record_with_status(root, operation, target, crate::status::get_host, f).await
+}
+
+/// Like [`record`], querying the host status with `status`.
+async fn record_with_status<T, S>(
+    root: &Dir,
+    operation: Operation,
+    target: Option<String>,
+    status: impl Fn() -> S,
+    f: impl std::future::Future<Output = Result<T>>,
+) -> Result<T>
+where
+    S: std::future::Future<Output = Result<Host>>,
+{
+    let before = status().await.ok();
+    let r = f.await;
+    let after = status().await.ok();
+    if let Some(record) = operation_record(operation, target, before.as_ref(), after.as_ref(), &r) {
+        if let Err(e) = append(root, &record) {
+            tracing::warn!("{e:#}");
+        }
+    }
+    r
+}
+
+/// The record of the current boot, given the history; `None` if it was
+/// already recorded.
+fn boot_record(history: &[Record], host: &Host, boot_id: Option<String>) -> Option<Record> {
+    let booted = image_of(host.status.booted.as_ref())?;
+    let last_boot = history.iter().rposition(|r| r.operation == Operation::Boot);
+    if let Some(i) = last_boot {
+        if boot_id.is_some() && history[i].boot_id == boot_id {
+            return None;
+        }
+    }
+
+    // The image the last successful operation since the previous boot deployed
+    let since = &history[last_boot.map(|i| i + 1).unwrap_or_default()..];
+    let expected = since
+        .iter()
+        .rev()
+        .find(|r| r.result == Outcome::Success && r.to_digest.is_some());
+    let error = expected
+        .and_then(|r| Some((r.operation, r.to_digest.as_deref()?)))
+        .filter(|(_, digest)| *digest != booted.image_digest)
+        .map(|(operation, digest)| {
+            format!(
+                "Expected to boot {digest} deployed by {operation}, but booted {}",
+                booted.image_digest
+            )
+        });
+
+    Some(Record {
+        timestamp: Utc::now(),
+        operation: Operation::Boot,
+        result: if error.is_none() {
+            Outcome::Success
+        } else {
+            Outcome::Failure
+        },
+        error,
+        image: Some(booted.image.image.clone()),
+        version: booted.version.clone(),
+        from_digest: last_boot.and_then(|i| history[i].to_digest.clone()),
+        to_digest: Some(booted.image_digest.clone()),
+        user: None,
+        boot_id,
+    })
+}
+
+/// Record the current boot; run by bootc-record-boot.service.
+#[context("Recording boot")]
+pub(crate) async fn record_boot(root: &Dir) -> Result<()> {
+    let host = crate::status::get_host().await?;
+    let history = read_history(root)?;
+    if let Some(record) = boot_record(&history, &host, boot_id()) {
+        append(root, &record)?;
+    }
+    Ok(())
+}
+
+/// Shorten a digest for display.
+fn short_digest(digest: &str) -> &str {
+    let digest = digest.split_once(':').map(|(_, d)| d).unwrap_or(digest);
+    &digest[..digest.len().min(12)]
+}
+
+fn human_readable_output(out: &mut impl Write, history: &[Record]) -> Result<()> {
+    let mut table = Table::new();
+    table
+        .load_preset(NOTHING)
+        .set_content_arrangement(comfy_table::ContentArrangement::Dynamic)
+        .set_header([
+            "TIMESTAMP",
+            "OPERATION",
+            "IMAGE",
+            "VERSION",
+            "DIGEST",
+            "USER",
+            "RESULT",
+        ]);
+    for r in history {
+        let result = match r.error.as_deref() {
+            Some(e) => format!("failed: {}", e.lines().next().unwrap_or_default()),
+            None => "ok".to_owned(),
+        };
+        table.add_row([
+            r.timestamp.format("%Y-%m-%dT%H:%M:%SZ").to_string(),
+            r.operation.to_string(),
+            r.image.clone().unwrap_or_default(),
+            r.version.clone().unwrap_or_default(),
+            r.to_digest
+                .as_deref()
+                .map(short_digest)
+                .unwrap_or_default()
+                .to_owned(),
+            r.user.clone().unwrap_or_default(),
+            result,
+        ]);
+    }
+    writeln!(out, "{table}")?;
+    Ok(())
+}
+
+/// Implementation of `bootc history`
+pub(crate) fn history(root: &Dir, opts: HistoryOpts) -> Result<()> {
+    let history = read_history(root)?;
+    let mut out = std::io::stdout().lock();
+    match opts.format.unwrap_or(OutputFormat::HumanReadable) {
+        OutputFormat::Json => serde_json::to_writer_pretty(&mut out, &history)
+            .map_err(anyhow::Error::new)
+            .and_then(|()| writeln!(out).map_err(Into::into)),
+        OutputFormat::Yaml => serde_yaml::to_writer(&mut out, &history).map_err(anyhow::Error::new),
+        OutputFormat::HumanReadable => human_readable_output(&mut out, &history),
+    }
+    .context("Writing to stdout")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const BOOTED: &str = "sha256:736b359467c9437c1ac915acaae952aad854e07eb4a16a94999a48af08c83c34";
+    const STAGED: &str = "sha256:16dc2b6256b4ff0d2ec18d2dbfb06d117904010c8cf9732cdb022818cf7a7566";
+
+    fn host(fixture: &str) -> Host {
+        serde_yaml::from_str(fixture).unwrap()
+    }
+
+    #[test]
+    fn test_operation_record() {
+        let before = host(include_str!("fixtures/spec-only-booted.yaml"));
+        let after = host(include_str!("fixtures/spec-staged-booted.yaml"));
+
+        let r = operation_record(
+            Operation::Upgrade,
+            None,
+            Some(&before),
+            Some(&after),
+            &Ok::<_, anyhow::Error>(()),
+        )
+        .unwrap();
+        assert_eq!(r.result, Outcome::Success);
+        assert_eq!(r.to_digest.as_deref(), Some(STAGED));
+        assert_eq!(r.version.as_deref(), Some("nightly"));
+
+        // Nothing changed
+        assert!(operation_record(
+            Operation::Upgrade,
+            None,
+            Some(&after),
+            Some(&after),
+            &Ok::<_, anyhow::Error>(())
+        )
+        .is_none());
+
+        let r = operation_record(
+            Operation::Switch,
+            Some("quay.io/example/other".into()),
+            Some(&before),
+            Some(&before),
+            &Err::<(), _>(anyhow::anyhow!("Pulling: not found")),
+        )
+        .unwrap();
+        assert_eq!(r.result, Outcome::Failure);
+        assert_eq!(r.error.as_deref(), Some("Pulling: not found"));
+        assert_eq!(r.image.as_deref(), Some("quay.io/example/other"));
+        assert_eq!(r.to_digest, None);
+    }
+
+    #[tokio::test]
+    async fn test_record() -> Result<()> {
+        let td = &cap_std_ext::cap_tempfile::tempdir(cap_std_ext::cap_std::ambient_authority())?;
+        let only_booted = || async { Ok(host(include_str!("fixtures/spec-only-booted.yaml"))) };
+        let rollback = || async { Ok(host(include_str!("fixtures/spec-staged-rollback.yaml"))) };
+
+        // A failed operation is recorded, and its error returned
+        let r = record_with_status(
+            td,
+            Operation::Upgrade,
+            Some("quay.io/example/other".into()),
+            only_booted,
+            async { Err::<bool, _>(anyhow::anyhow!("Pulling: not found")) },
+        )
+        .await;
+        assert_eq!(r.unwrap_err().to_string(), "Pulling: not found");
+
+        // A rollback targets the rollback deployment; its value is returned
+        let r =
+            record_with_status(td, Operation::Rollback, None, rollback, async { Ok(42) }).await?;
+        assert_eq!(r, 42);
+
+        let history = read_history(td)?;
+        assert_eq!(history.len(), 2);
+        assert_eq!(history[0].operation, Operation::Upgrade);
+        assert_eq!(history[0].result, Outcome::Failure);
+        assert_eq!(history[0].error.as_deref(), Some("Pulling: not found"));
+        assert_eq!(history[0].image.as_deref(), Some("quay.io/example/other"));
+        assert_eq!(history[0].to_digest, None);
+        assert_eq!(history[1].operation, Operation::Rollback);
+        assert_eq!(history[1].result, Outcome::Success);
+        assert_eq!(history[1].to_digest.as_deref(), Some(BOOTED));
+        Ok(())
+    }
+
+    #[test]
+    fn test_boot_record() {
+        let booted = host(include_str!("fixtures/spec-staged-booted.yaml"));
+        assert_eq!(
+            image_of(booted.status.booted.as_ref())
+                .unwrap()
+                .image_digest,
+            BOOTED
+        );
+        let upgrade = |digest: &str| Record {
+            timestamp: Utc::now(),
+            operation: Operation::Upgrade,
+            result: Outcome::Success,
+            error: None,
+            image: None,
+            version: None,
+            from_digest: None,
+            to_digest: Some(digest.into()),
+            user: Some("root".into()),
+            boot_id: Some("a".into()),
+        };
+
+        // The staged image was booted
+        let r = boot_record(&[upgrade(BOOTED)], &booted, Some("b".into())).unwrap();
+        assert_eq!(r.result, Outcome::Success);
+        assert_eq!(r.to_digest.as_deref(), Some(BOOTED));
+
+        // Only recorded once per boot
+        assert!(boot_record(&[r.clone()], &booted, Some("b".into())).is_none());
+
+        // The staged image was not booted
+        let r = boot_record(&[r, upgrade(STAGED)], &booted, Some("c".into())).unwrap();
+        assert_eq!(r.result, Outcome::Failure);
+        assert_eq!(r.from_digest.as_deref(), Some(BOOTED));
+        assert!(r.error.unwrap().starts_with("Expected to boot sha256:16dc"));
+    }
+}
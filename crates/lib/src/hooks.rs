@@ -0,0 +1,392 @@
+//! # Upgrade lifecycle hooks
+//!
+//! An image can ship executables in `/usr/lib/bootc/hooks.d/<type>/` which
+//! are run around updates *to* that image, in the order of their names:
+//!
+//! - `pre-stage`: after the image was fetched, before it is deployed; a hook
+//!   exiting unsuccessfully vetoes the update
+//! - `post-stage`: after the deployment was staged
+//! - `pre-finalize`: at shutdown, before the staged deployment is finalized
+//! - `post-boot`: once, when the image is booted for the first time
+//!
+//! The target image is passed as JSON on stdin and in `BOOTC_IMAGE*`
+//! environment variables. Hooks which don't finish in time are killed.
+
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+use std::process::Stdio;
+use std::time::Duration;
+
+use anyhow::{Context, Result};
+use cap_std_ext::cap_std::{ambient_authority, fs::Dir};
+use cap_std_ext::dirext::CapStdExtDirExt;
+use fn_error_context::context;
+use ostree_ext::prelude::FileExt;
+use ostree_ext::{gio, ostree};
+use serde::Serialize;
+use tokio::io::AsyncWriteExt;
+
+use crate::spec::{ImageReference, ImageStatus};
+use crate::store::BootedStorageKind;
+
+/// The directory containing the hooks, relative to the root of an image.
+pub(crate) const HOOKS_DIR: &str = "usr/lib/bootc/hooks.d";
+/// The unit running the pre-finalize hooks of a staged ostree deployment.
+const PRE_FINALIZE_UNIT: &str = "bootc-pre-finalize-hooks.service";
+/// Records the image digest for which the post-boot hooks were run.
+const POST_BOOT_STAMP: &str = "/var/lib/bootc/post-boot-hooks";
+
+/// The point in the update lifecycle at which hooks are run.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, clap::ValueEnum)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) enum HookType {
+    PreStage,
+    PostStage,
+    PreFinalize,
+    PostBoot,
+}
+
+impl HookType {
+    fn as_str(self) -> &'static str {
+        match self {
+            HookType::PreStage => "pre-stage",
+            HookType::PostStage => "post-stage",
+            HookType::PreFinalize => "pre-finalize",
+            HookType::PostBoot => "post-boot",
+        }
+    }
+
+    /// The time after which a hook is killed; pre-finalize hooks delay the
+    /// shutdown and have to complete within the finalization timeout.
+    fn timeout(self) -> Duration {
+        match self {
+            HookType::PreFinalize => Duration::from_secs(60),
+            _ => Duration::from_secs(10 * 60),
+        }
+    }
+}
+
+impl std::fmt::Display for HookType {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// The image an update targets.
+#[derive(Debug, Clone, Serialize, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct HookTarget {
+    pub(crate) image: ImageReference,
+    pub(crate) digest: Option<String>,
+    pub(crate) version: Option<String>,
+}
+
+impl From<&ImageStatus> for HookTarget {
+    fn from(status: &ImageStatus) -> Self {
+        Self {
+            image: status.image.clone(),
+            digest: Some(status.image_digest.clone()),
+            version: status.version.clone(),
+        }
+    }
+}
+
+/// The input of a hook.
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct HookInput<'a> {
+    hook: HookType,
+    #[serde(flatten)]
+    target: &'a HookTarget,
+}
+
+impl HookTarget {
+    /// The environment variables describing the target.
+    fn env(&self) -> Vec<(&'static str, &str)> {
+        let mut env = vec![
+            ("BOOTC_IMAGE", self.image.image.as_str()),
+            ("BOOTC_IMAGE_TRANSPORT", self.image.transport.as_str()),
+        ];
+        if let Some(digest) = self.digest.as_deref() {
+            env.push(("BOOTC_IMAGE_DIGEST", digest));
+        }
+        if let Some(version) = self.version.as_deref() {
+            env.push(("BOOTC_IMAGE_VERSION", version));
+        }
+        env
+    }
+}
+
+/// The executable hooks of type `hook` in the image at `root`, sorted by name.
+fn find_hooks(root: &Path, hook: HookType) -> Result<Vec<PathBuf>> {
+    let dir = root.join(HOOKS_DIR).join(hook.as_str());
+    if !dir.try_exists()? {
+        return Ok(Vec::new());
+    }
+    let mut hooks = Vec::new();
+    for entry in std::fs::read_dir(&dir).with_context(|| format!("Reading {dir:?}"))? {
+        let path = entry?.path();
+        // Follow symlinks
+        let meta = std::fs::metadata(&path)?;
+        if meta.is_file() && meta.permissions().mode() & 0o111 != 0 {
+            hooks.push(path);
+        } else {
+            tracing::debug!("Ignoring non-executable {path:?}");
+        }
+    }
+    hooks.sort();
+    Ok(hooks)
+}
+
+/// Run the hook at `path`.
+#[context("Running {}", path.display())]
+async fn run_hook(path: &Path, hook: HookType, target: &HookTarget) -> Result<()> {
+    let input = serde_json::to_vec(&HookInput { hook, target })?;
+    let mut child = tokio::process::Command::new(path)
+        .env("BOOTC_HOOK", hook.as_str())
+        .envs(target.env())
+        .current_dir("/")
+        .stdin(Stdio::piped())
+        .kill_on_drop(true)
+        .spawn()?;
+    let mut stdin = child.stdin.take().unwrap();
+    // The hook may not read its input
+    let _ = stdin.write_all(&input).await;
+    drop(stdin);
+
+    let timeout = hook.timeout();
+    let Ok(status) = tokio::time::timeout(timeout, child.wait()).await else {
+        child.kill().await?;
+        anyhow::bail!("Timed out after {}s", timeout.as_secs());
+    };
+    let status = status?;
+    anyhow::ensure!(status.success(), "Failed: {status}");
+    Ok(())
+}
+
+/// Run the hooks of type `hook` shipped in the image at `root` for an update
+/// to `target`. A failing pre-stage hook vetoes the update; failures of
+/// other hooks are only reported.
+pub(crate) async fn run_hooks(root: &Path, hook: HookType, target: &HookTarget) -> Result<()> {
+    for path in find_hooks(root, hook)? {
+        let name = path.file_name().unwrap_or_default().to_string_lossy();
+        println!("Running {hook} hook: {name}");
+        match run_hook(&path, hook, target).await {
+            Ok(()) => {}
+            Err(e) if hook == HookType::PreStage => {
+                return Err(e.context(format!("Update vetoed by {hook} hook {name}")));
+            }
+            Err(e) => eprintln!("warning: {hook} hook {name}: {e:#}"),
+        }
+    }
+    Ok(())
+}
+
+/// Whether the image at `root` ships hooks of type `hook`.
+pub(crate) fn has_hooks(root: &Path, hook: HookType) -> Result<bool> {
+    Ok(!find_hooks(root, hook)?.is_empty())
+}
+
+/// Run the pre-stage hooks of the ostree commit `commit` in `repo`, which
+/// is not deployed yet.
+#[context("Running pre-stage hooks")]
+pub(crate) async fn run_ostree_pre_stage(
+    repo: &ostree::Repo,
+    commit: &str,
+    target: &HookTarget,
+) -> Result<()> {
+    let cancellable = gio::Cancellable::NONE;
+    let subpath = Path::new(HOOKS_DIR).join(HookType::PreStage.as_str());
+    let (root, _) = repo.read_commit(commit, cancellable)?;
+    let hooksdir = root.resolve_relative_path(subpath.to_str().unwrap());
+    if !hooksdir.query_exists(cancellable) {
+        return Ok(());
+    }
+
+    // Check out into the repository rather than /tmp, which may be mounted noexec
+    let repo_tmp = repo
+        .path()
+        .path()
+        .context("Repository has no local path")?
+        .join("tmp");
+    let tempdir = tempfile::tempdir_in(&repo_tmp)
+        .with_context(|| format!("Creating temporary directory in {repo_tmp:?}"))?;
+    let dest = tempdir.path().join(&subpath);
+    std::fs::create_dir_all(dest.parent().unwrap())?;
+    let opts = ostree::RepoCheckoutAtOptions {
+        mode: ostree::RepoCheckoutMode::User,
+        subpath: Some(subpath),
+        ..Default::default()
+    };
+    repo.checkout_at(Some(&opts), ostree::AT_FDCWD, dest, commit, cancellable)?;
+    run_hooks(tempdir.path(), HookType::PreStage, target).await
+}
+
+/// Arrange for the pre-finalize hooks of the staged ostree deployment at
+/// `root` to be run at shutdown.
+#[context("Starting {PRE_FINALIZE_UNIT}")]
+pub(crate) fn start_pre_finalize_unit(root: &Path) -> Result<()> {
+    if !has_hooks(root, HookType::PreFinalize)? {
+        return Ok(());
+    }
+    crate::task::Task::new("Starting pre-finalize hooks unit", "systemctl")
+        .args(["start", "--quiet", PRE_FINALIZE_UNIT])
+        .quiet()
+        .run()
+}
+
+/// Run the pre-finalize hooks of the staged ostree deployment; for composefs
+/// these are run by `bootc composefs-finalize-staged`.
+async fn pre_finalize() -> Result<()> {
+    let storage = &crate::cli::get_storage().await?;
+    let BootedStorageKind::Ostree(booted_ostree) = storage.kind()? else {
+        return Ok(());
+    };
+    let (deployments, host) = crate::status::get_status(&booted_ostree)?;
+    let (Some(staged), Some(image)) = (
+        deployments.staged,
+        host.status.staged.as_ref().and_then(|e| e.image.as_ref()),
+    ) else {
+        tracing::debug!("No staged deployment");
+        return Ok(());
+    };
+    let root = format!(
+        "/sysroot/{}",
+        booted_ostree.sysroot.deployment_dirpath(&staged)
+    );
+    run_hooks(Path::new(&root), HookType::PreFinalize, &image.into()).await
+}
+
+/// Run the post-boot hooks of the booted image, if not done before.
+async fn post_boot() -> Result<()> {
+    let host = crate::status::get_host().await?;
+    let Some(image) = host.status.booted.as_ref().and_then(|e| e.image.as_ref()) else {
+        tracing::debug!("Not booted from an image");
+        return Ok(());
+    };
+
+    let rootfs = Dir::open_ambient_dir("/", ambient_authority())?;
+    let stamp = Path::new(POST_BOOT_STAMP).strip_prefix("/")?;
+    let done = rootfs
+        .open_optional(stamp)?
+        .map(std::io::read_to_string)
+        .transpose()
+        .with_context(|| format!("Reading {POST_BOOT_STAMP}"))?;
+    if done.as_deref() == Some(image.image_digest.as_str()) {
+        tracing::debug!("Post-boot hooks already run for {}", image.image_digest);
+        return Ok(());
+    }
+
+    run_hooks(Path::new("/"), HookType::PostBoot, &image.into()).await?;
+
+    rootfs.create_dir_all(stamp.parent().unwrap())?;
+    rootfs
+        .atomic_write(stamp, &image.image_digest)
+        .with_context(|| format!("Writing {POST_BOOT_STAMP}"))?;
+    Ok(())
+}
+
+/// Implementation of `bootc internals run-hooks`, used by the units running
+/// the hooks which are not part of staging.
+pub(crate) async fn run_from_unit(hook: HookType) -> Result<()> {
+    match hook {
+        HookType::PreFinalize => pre_finalize().await,
+        HookType::PostBoot => post_boot().await,
+        HookType::PreStage | HookType::PostStage => {
+            anyhow::bail!("{hook} hooks are run while staging")
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn target() -> HookTarget {
+        HookTarget {
+            image: ImageReference {
+                image: "quay.io/example/os:42".into(),
+                transport: "registry".into(),
+                signature: None,
+            },
+            digest: Some("sha256:0123".into()),
+            version: None,
+        }
+    }
+
+    #[test]
+    fn test_input() -> Result<()> {
+        let target = target();
+        let input = HookInput {
+            hook: HookType::PreStage,
+            target: &target,
+        };
+        assert_eq!(
+            serde_json::to_value(&input)?,
+            serde_json::json!({
+                "hook": "pre-stage",
+                "image": { "image": "quay.io/example/os:42", "transport": "registry" },
+                "digest": "sha256:0123",
+                "version": null,
+            })
+        );
+        assert_eq!(
+            target.env(),
+            [
+                ("BOOTC_IMAGE", "quay.io/example/os:42"),
+                ("BOOTC_IMAGE_TRANSPORT", "registry"),
+                ("BOOTC_IMAGE_DIGEST", "sha256:0123")
+            ]
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_run_hooks() -> Result<()> {
+        let td = tempfile::tempdir()?;
+        let root = td.path();
+        assert!(!has_hooks(root, HookType::PreStage)?);
+
+        let dir = root.join(HOOKS_DIR).join("pre-stage");
+        std::fs::create_dir_all(&dir)?;
+        let write_hook = |name: &str, contents: &str, mode: u32| -> Result<()> {
+            let path = dir.join(name);
+            std::fs::write(&path, contents)?;
+            std::fs::set_permissions(&path, std::fs::Permissions::from_mode(mode))?;
+            Ok(())
+        };
+        let out = root.join("out");
+        write_hook(
+            "20-record",
+            &format!("#!/bin/sh\ncat > {}\n", out.display()),
+            0o755,
+        )?;
+        write_hook("10-ignored", "not executable", 0o644)?;
+        assert_eq!(
+            find_hooks(root, HookType::PreStage)?,
+            [dir.join("20-record")]
+        );
+
+        run_hooks(root, HookType::PreStage, &target()).await?;
+        let input: serde_json::Value = serde_json::from_slice(&std::fs::read(&out)?)?;
+        assert_eq!(input["hook"], "pre-stage");
+        assert_eq!(input["digest"], "sha256:0123");
+
+        // A failing pre-stage hook vetoes the update, other hooks only warn
+        write_hook("30-veto", "#!/bin/sh\nexit 1\n", 0o755)?;
+        let e = run_hooks(root, HookType::PreStage, &target())
+            .await
+            .unwrap_err();
+        assert!(format!("{e:#}").contains("vetoed by pre-stage hook 30-veto"));
+
+        let dir = root.join(HOOKS_DIR).join("post-stage");
+        std::fs::create_dir_all(&dir)?;
+        std::fs::rename(
+            root.join(HOOKS_DIR).join("pre-stage/30-veto"),
+            dir.join("30-veto"),
+        )?;
+        run_hooks(root, HookType::PostStage, &target()).await?;
+
+        Ok(())
+    }
+}
@@ -0,0 +1,329 @@
+//! # Constraints on updates to an image
+//!
+//! Before `bootc upgrade` or `bootc switch` stage an image, its metadata is
+//! compared with that of the booted image:
+//!
+//! - An image older than the booted one from the same repository is refused,
+//!   unless downgrades are allowed. The versions are compared if both images
+//!   have one, otherwise their build timestamps. Images from a different
+//!   repository are not compared, as their versions are unrelated.
+//! - An image can declare the minimum version of the booted image which may
+//!   update to it directly, for updates which depend on a migration done by
+//!   an intermediate version.
+//! - An image can declare the minimum version of bootc required to deploy it.
+
+use std::cmp::Ordering;
+use std::collections::HashMap;
+use std::fmt;
+
+use anyhow::Result;
+use chrono::{DateTime, Utc};
+use ostree_ext::container as ostree_container;
+use ostree_ext::oci_spec::image::ImageConfiguration;
+use ostree_ext::ostree;
+
+use crate::metadata::{MIN_BOOTC_VERSION_LABEL, MIN_SOURCE_VERSION_LABEL};
+use crate::packages::rpmvercmp;
+use crate::spec::{ImageReference, ImageStatus};
+use crate::status::{labels_of_config, timestamp_of_config};
+
+/// The metadata of an image relevant for the constraints.
+#[derive(Debug, Default)]
+struct ImageInfo<'a> {
+    version: Option<&'a str>,
+    timestamp: Option<DateTime<Utc>>,
+    labels: Option<&'a HashMap<String, String>>,
+}
+
+impl<'a> ImageInfo<'a> {
+    fn from_config(config: &'a ImageConfiguration) -> Self {
+        Self {
+            version: ostree_container::version_for_config(config),
+            timestamp: timestamp_of_config(config),
+            labels: labels_of_config(config),
+        }
+    }
+
+    fn from_status(status: &'a ImageStatus) -> Self {
+        Self {
+            version: status.version.as_deref(),
+            timestamp: status.timestamp,
+            labels: None,
+        }
+    }
+
+    fn label(&self, name: &str) -> Option<&'a str> {
+        self.labels.and_then(|l| l.get(name)).map(|v| v.as_str())
+    }
+
+    /// A description for messages: the version or else the build timestamp.
+    fn describe(&self) -> String {
+        match (self.version, self.timestamp) {
+            (Some(version), _) => format!("version {version}"),
+            (None, Some(timestamp)) => format!("the image built {}", timestamp.to_rfc3339()),
+            (None, None) => "an unversioned image".to_owned(),
+        }
+    }
+
+    /// Compare the age of two images, if they are comparable.
+    fn cmp_age(&self, other: &Self) -> Option<Ordering> {
+        match (self.version, other.version) {
+            (Some(a), Some(b)) => Some(rpmvercmp(a, b)),
+            _ => Some(self.timestamp?.cmp(&other.timestamp?)),
+        }
+    }
+}
+
+/// A constraint an update does not satisfy.
+#[derive(Debug, PartialEq, Eq)]
+enum Violation {
+    /// The target image is older than the booted one.
+    Downgrade { from: String, to: String },
+    /// The booted image is older than the minimum source version.
+    SourceTooOld {
+        required: String,
+        booted: Option<String>,
+    },
+    /// This bootc is older than the minimum bootc version.
+    BootcTooOld { required: String, current: String },
+}
+
+impl fmt::Display for Violation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Violation::Downgrade { from, to } => write!(
+                f,
+                "{to} is older than the booted {from}; use --allow-downgrade to deploy it anyway"
+            ),
+            Violation::SourceTooOld {
+                required,
+                booted: Some(booted),
+            } => write!(
+                f,
+                "updates to it require version {required} or newer to be booted, but version {booted} is; update to an intermediate version first"
+            ),
+            Violation::SourceTooOld {
+                required,
+                booted: None,
+            } => write!(
+                f,
+                "updates to it require version {required} or newer to be booted, but the booted image has no version"
+            ),
+            Violation::BootcTooOld { required, current } => write!(
+                f,
+                "it requires bootc {required} or newer, but this is bootc {current}"
+            ),
+        }
+    }
+}
+
+/// The constraints the update from `booted` to `target` violates, with
+/// `bootc_version` running.  Downgrades are only refused within the
+/// `same_repository`.
+fn violations(
+    booted: Option<&ImageInfo>,
+    target: &ImageInfo,
+    same_repository: bool,
+    bootc_version: &str,
+) -> Vec<Violation> {
+    let mut r = Vec::new();
+
+    if let Some(required) = target.label(MIN_BOOTC_VERSION_LABEL) {
+        if rpmvercmp(bootc_version, required) == Ordering::Less {
+            r.push(Violation::BootcTooOld {
+                required: required.to_owned(),
+                current: bootc_version.to_owned(),
+            });
+        }
+    }
+
+    // Switching from a system not booted from an image
+    let Some(booted) = booted else {
+        return r;
+    };
+
+    if let Some(required) = target.label(MIN_SOURCE_VERSION_LABEL) {
+        let satisfied = booted
+            .version
+            .is_some_and(|v| rpmvercmp(v, required) != Ordering::Less);
+        if !satisfied {
+            r.push(Violation::SourceTooOld {
+                required: required.to_owned(),
+                booted: booted.version.map(ToOwned::to_owned),
+            });
+        }
+    }
+
+    if same_repository && target.cmp_age(booted) == Some(Ordering::Less) {
+        r.push(Violation::Downgrade {
+            from: booted.describe(),
+            to: target.describe(),
+        });
+    }
+
+    r
+}
+
+/// Whether `booted` and `target` are from the same repository.
+fn same_repository(booted: Option<&ImageStatus>, target: &ImageReference) -> bool {
+    booted.is_some_and(|b| b.image.same_repository(target))
+}
+
+/// Ensure that the image `target` with configuration `config` may be deployed
+/// on a system booted from `booted`.
+pub(crate) fn check(
+    booted: Option<&ImageStatus>,
+    target: &ImageReference,
+    config: &ImageConfiguration,
+    allow_downgrade: bool,
+) -> Result<()> {
+    let same_repository = same_repository(booted, target);
+    let booted = booted.map(ImageInfo::from_status);
+    let target = ImageInfo::from_config(config);
+    let mut errors = Vec::new();
+    for v in violations(
+        booted.as_ref(),
+        &target,
+        same_repository,
+        env!("CARGO_PKG_VERSION"),
+    ) {
+        match v {
+            Violation::Downgrade { from, to } if allow_downgrade => {
+                println!("Downgrading from {from} to {to}");
+            }
+            v => errors.push(v.to_string()),
+        }
+    }
+    if !errors.is_empty() {
+        anyhow::bail!(
+            "Refusing to deploy {}: {}",
+            target.describe(),
+            errors.join("; ")
+        );
+    }
+    Ok(())
+}
+
+/// Like [`check`], for the image stored in the ostree commit `commit`.
+pub(crate) fn check_ostree(
+    repo: &ostree::Repo,
+    commit: &str,
+    booted: Option<&ImageStatus>,
+    target: &ImageReference,
+    allow_downgrade: bool,
+) -> Result<()> {
+    let image = ostree_container::store::query_image_commit(repo, commit)?;
+    check(booted, target, &image.configuration, allow_downgrade)
+}
+
+/// Print the constraints an available update to the image `target` with
+/// configuration `config` violates, as for `bootc upgrade --check`.
+pub(crate) fn report(
+    booted: Option<&ImageStatus>,
+    target: &ImageReference,
+    config: &ImageConfiguration,
+) {
+    let same_repository = same_repository(booted, target);
+    let booted = booted.map(ImageInfo::from_status);
+    let target = ImageInfo::from_config(config);
+    for v in violations(
+        booted.as_ref(),
+        &target,
+        same_repository,
+        env!("CARGO_PKG_VERSION"),
+    ) {
+        println!("  Blocked: {v}");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn info<'a>(
+        version: Option<&'a str>,
+        timestamp: Option<&str>,
+        labels: Option<&'a HashMap<String, String>>,
+    ) -> ImageInfo<'a> {
+        ImageInfo {
+            version,
+            timestamp: timestamp.map(|t| t.parse().unwrap()),
+            labels,
+        }
+    }
+
+    #[test]
+    fn test_downgrade() {
+        let booted = info(Some("42.20250301.0"), Some("2025-03-01T00:00:00Z"), None);
+        let newer = info(Some("42.20250401.0"), Some("2025-02-01T00:00:00Z"), None);
+        let older = info(Some("42.20250201.0"), None, None);
+        assert!(violations(Some(&booted), &newer, true, "1.8.0").is_empty());
+        assert_eq!(
+            violations(Some(&booted), &older, true, "1.8.0"),
+            [Violation::Downgrade {
+                from: "version 42.20250301.0".into(),
+                to: "version 42.20250201.0".into()
+            }]
+        );
+
+        // Without versions, the timestamps are compared
+        let unversioned = info(None, Some("2025-02-01T00:00:00Z"), None);
+        assert_eq!(
+            violations(Some(&booted), &unversioned, true, "1.8.0"),
+            [Violation::Downgrade {
+                from: "version 42.20250301.0".into(),
+                to: "the image built 2025-02-01T00:00:00+00:00".into()
+            }]
+        );
+        assert!(violations(Some(&booted), &info(None, None, None), true, "1.8.0").is_empty());
+        assert!(violations(None, &older, true, "1.8.0").is_empty());
+
+        // Images from another repository may have unrelated versions
+        assert!(violations(Some(&booted), &older, false, "1.8.0").is_empty());
+    }
+
+    #[test]
+    fn test_labels() {
+        let labels = HashMap::from([
+            (
+                MIN_SOURCE_VERSION_LABEL.to_owned(),
+                "42.20250301.0".to_owned(),
+            ),
+            (MIN_BOOTC_VERSION_LABEL.to_owned(), "1.9".to_owned()),
+        ]);
+        let target = info(Some("43.20250501.0"), None, Some(&labels));
+
+        let booted = info(Some("42.20250301.0"), None, None);
+        assert!(violations(Some(&booted), &target, true, "1.9.0").is_empty());
+        assert_eq!(
+            violations(Some(&booted), &target, true, "1.8.2"),
+            [Violation::BootcTooOld {
+                required: "1.9".into(),
+                current: "1.8.2".into()
+            }]
+        );
+
+        let booted = info(Some("42.20250201.0"), None, None);
+        let v = violations(Some(&booted), &target, true, "1.9.0");
+        assert_eq!(
+            v,
+            [Violation::SourceTooOld {
+                required: "42.20250301.0".into(),
+                booted: Some("42.20250201.0".into())
+            }]
+        );
+        assert_eq!(
+            v[0].to_string(),
+            "updates to it require version 42.20250301.0 or newer to be booted, but version 42.20250201.0 is; update to an intermediate version first"
+        );
+
+        let booted = info(None, None, None);
+        assert_eq!(
+            violations(Some(&booted), &target, true, "1.9.0"),
+            [Violation::SourceTooOld {
+                required: "42.20250301.0".into(),
+                booted: None
+            }]
+        );
+    }
+}
@@ -11,8 +11,11 @@ mod aleph;
 pub(crate) mod baseline;
 pub(crate) mod completion;
 pub(crate) mod config;
+#[cfg(feature = "install-to-disk")]
+pub(crate) mod disk_image;
 mod osbuild;
 pub(crate) mod osconfig;
+pub(crate) mod plan;
 
 use std::collections::HashMap;
 use std::io::Write;
@@ -61,7 +64,7 @@ use crate::deploy::{
     prepare_for_pull, pull_from_prepared, MergeState, PreparedImportMeta, PreparedPullResult,
 };
 use crate::lsm;
-use crate::progress_jsonl::ProgressWriter;
+use crate::progress_jsonl::{Phase, ProgressWriter};
 use crate::spec::{Bootloader, ImageReference};
 use crate::store::Storage;
 use crate::task::Task;
@@ -250,6 +253,28 @@ pub(crate) struct InstallComposefsOpts {
     #[clap(long)]
     #[serde(default)]
     pub(crate) uki_addon: Option<Vec<String>>,
+
+    /// Sign the UKI, UKI addons and systemd-boot with this Secure Boot private key.
+    /// With `--secure-boot-engine`, this is a key identifier for the engine, e.g. a PKCS#11 URI.
+    #[clap(long, requires = "secure_boot_certificate")]
+    #[serde(default)]
+    pub(crate) secure_boot_key: Option<String>,
+
+    /// The certificate of `--secure-boot-key`, in PEM format.
+    #[clap(long, requires = "secure_boot_key")]
+    #[serde(default)]
+    pub(crate) secure_boot_certificate: Option<Utf8PathBuf>,
+
+    /// The OpenSSL engine to load `--secure-boot-key` with, e.g. `pkcs11`.
+    #[clap(long, requires = "secure_boot_key")]
+    #[serde(default)]
+    pub(crate) secure_boot_engine: Option<String>,
+
+    /// A directory with signed `PK.auth`, `KEK.auth` and `db.auth` updates for systemd-boot
+    /// to enroll on first boot; they are installed to `loader/keys/<directory name>` in the ESP.
+    #[clap(long)]
+    #[serde(default)]
+    pub(crate) secure_boot_enroll_keys: Option<Utf8PathBuf>,
 }
 
 #[cfg(feature = "install-to-disk")]
@@ -279,6 +304,14 @@ pub(crate) struct InstallToDiskOpts {
     #[clap(flatten)]
     #[serde(flatten)]
     pub(crate) composefs_opts: InstallComposefsOpts,
+
+    #[clap(flatten)]
+    #[serde(skip)]
+    pub(crate) plan_opts: plan::InstallPlanOpts,
+
+    #[clap(flatten)]
+    #[serde(skip)]
+    pub(crate) progress: crate::cli::ProgressOptions,
 }
 
 #[derive(ValueEnum, Debug, Copy, Clone, PartialEq, Eq, Serialize, Deserialize)]
@@ -357,6 +390,12 @@ pub(crate) struct InstallToFilesystemOpts {
 
     #[clap(flatten)]
     pub(crate) composefs_opts: InstallComposefsOpts,
+
+    #[clap(flatten)]
+    pub(crate) plan_opts: plan::InstallPlanOpts,
+
+    #[clap(flatten)]
+    pub(crate) progress: crate::cli::ProgressOptions,
 }
 
 #[derive(Debug, Clone, clap::Parser, PartialEq, Eq)]
@@ -391,6 +430,12 @@ pub(crate) struct InstallToExistingRootOpts {
 
     #[clap(flatten)]
     pub(crate) composefs_opts: InstallComposefsOpts,
+
+    #[clap(flatten)]
+    pub(crate) plan_opts: plan::InstallPlanOpts,
+
+    #[clap(flatten)]
+    pub(crate) progress: crate::cli::ProgressOptions,
 }
 
 #[derive(Debug, clap::Parser, PartialEq, Eq)]
@@ -485,6 +530,9 @@ pub(crate) struct State {
 
     // If Some, then --composefs_native is passed
     pub(crate) composefs_options: InstallComposefsOpts,
+
+    /// Progress reporting for the install phases
+    pub(crate) prog: ProgressWriter,
 }
 
 // Shared read-only global state
@@ -495,6 +543,34 @@ pub(crate) struct PostFetchState {
 }
 
 impl InstallTargetOpts {
+    /// Create the *target* image reference, which defaults to pulling from a registry
+    /// using the name of the source image.
+    pub(crate) fn resolve_imageref(
+        &self,
+        source: &SourceInfo,
+    ) -> Result<ostree_container::OstreeImageReference> {
+        if self.target_no_signature_verification {
+            // Perhaps log this in the future more prominently, but no reason to annoy people.
+            tracing::debug!(
+                "Use of --target-no-signature-verification flag which is enabled by default"
+            );
+        }
+        let target_sigverify = sigpolicy_from_opt(self.enforce_container_sigpolicy);
+        let target_imgname = self
+            .target_imgref
+            .as_deref()
+            .unwrap_or(source.imageref.name.as_str());
+        let target_transport =
+            ostree_container::Transport::try_from(self.target_transport.as_str())?;
+        Ok(ostree_container::OstreeImageReference {
+            sigverify: target_sigverify,
+            imgref: ostree_container::ImageReference {
+                transport: target_transport,
+                name: target_imgname.to_string(),
+            },
+        })
+    }
+
     pub(crate) fn imageref(&self) -> Result<Option<ostree_container::OstreeImageReference>> {
         let Some(target_imgname) = self.target_imgref.as_deref() else {
             return Ok(None);
@@ -650,6 +726,23 @@ impl FromStr for MountSpec {
     }
 }
 
+impl InstallComposefsOpts {
+    /// Reject the options which only apply to the composefs backend without it.
+    pub(crate) fn validate(&self) -> Result<()> {
+        if self.composefs_backend {
+            return Ok(());
+        }
+        if self.secure_boot_key.is_some()
+            || self.secure_boot_certificate.is_some()
+            || self.secure_boot_engine.is_some()
+            || self.secure_boot_enroll_keys.is_some()
+        {
+            anyhow::bail!("--secure-boot-* options require the composefs backend");
+        }
+        Ok(())
+    }
+}
+
 #[cfg(feature = "install-to-disk")]
 impl InstallToDiskOpts {
     pub(crate) fn validate(&self) -> Result<()> {
@@ -912,15 +1005,21 @@ async fn install_container(
     let repo = &sysroot.repo();
     repo.set_disable_fsync(true);
 
-    let pulled_image = match prepare_for_pull(repo, &spec_imgref, Some(&state.target_imgref))
-        .await?
-    {
-        PreparedPullResult::AlreadyPresent(existing) => existing,
-        PreparedPullResult::Ready(image_meta) => {
-            check_disk_space(root_setup.physical_root.as_fd(), &image_meta, &spec_imgref)?;
-            pull_from_prepared(&spec_imgref, false, ProgressWriter::default(), *image_meta).await?
-        }
-    };
+    let prog = &state.prog;
+    let pulled_image = prog
+        .phase(Phase::Pull, "Fetching image", async {
+            let r = match prepare_for_pull(repo, &spec_imgref, Some(&state.target_imgref), prog)
+                .await?
+            {
+                PreparedPullResult::AlreadyPresent(existing) => existing,
+                PreparedPullResult::Ready(image_meta) => {
+                    check_disk_space(root_setup.physical_root.as_fd(), &image_meta, &spec_imgref)?;
+                    pull_from_prepared(&spec_imgref, false, prog.clone(), *image_meta).await?
+                }
+            };
+            anyhow::Ok(r)
+        })
+        .await?;
 
     repo.set_disable_fsync(false);
 
@@ -990,11 +1089,16 @@ async fn install_container(
     options.proxy_cfg = proxy_cfg;
     options.skip_completion = true; // Must be set to avoid recursion!
     options.no_clean = has_ostree;
-    let imgstate = crate::utils::async_task_with_spinner(
-        "Deploying container image",
-        ostree_container::deploy::deploy(&sysroot, stateroot, &src_imageref, Some(options)),
-    )
-    .await?;
+    let imgstate = prog
+        .phase(
+            Phase::Deploy,
+            "Deploying container image",
+            crate::utils::async_task_with_spinner(
+                "Deploying container image",
+                ostree_container::deploy::deploy(&sysroot, stateroot, &src_imageref, Some(options)),
+            ),
+        )
+        .await?;
 
     let deployment = sysroot
         .deployments()
@@ -1012,6 +1116,8 @@ async fn install_container(
     // but avoid recursing into the deployment root (because that's a *distinct*
     // logical root).
     if let Some(policy) = sepolicy {
+        prog.begin_phase(Phase::Relabel, "Labeling files for SELinux")
+            .await;
         let deployment_root_meta = root.dir_metadata()?;
         let deployment_root_devino = (deployment_root_meta.dev(), deployment_root_meta.ino());
         for d in ["ostree", "boot"] {
@@ -1031,6 +1137,7 @@ async fn install_container(
         } else {
             tracing::warn!("Missing {OSTREE_COMPOSEFS_SUPER}; composefs is not enabled?");
         }
+        prog.end_phase(Phase::Relabel).await;
     }
 
     // Write the entry for /boot to /etc/fstab.  TODO: Encourage OSes to use the karg?
@@ -1155,6 +1262,24 @@ impl SELinuxFinalState {
     }
 }
 
+/// Compute the SELinux state that [`reexecute_self_for_selinux_if_needed`] would
+/// arrive at, but without mounting selinuxfs or re-executing.
+pub(crate) fn predict_selinux_state(
+    srcdata: &SourceInfo,
+    override_disable_selinux: bool,
+) -> Result<SELinuxFinalState> {
+    let r = if !srcdata.selinux {
+        SELinuxFinalState::Disabled
+    } else if override_disable_selinux {
+        SELinuxFinalState::ForceTargetDisabled
+    } else if crate::lsm::selinux_enabled()? {
+        SELinuxFinalState::Enabled(None)
+    } else {
+        SELinuxFinalState::HostDisabled
+    };
+    Ok(r)
+}
+
 /// If we detect that the target ostree commit has SELinux labels,
 /// and we aren't passed an override to disable it, then ensure
 /// the running process is labeled with install_t so it can
@@ -1318,20 +1443,15 @@ fn root_has_uki(root: &Dir) -> Result<bool> {
     crate::bootc_composefs::boot::container_root_has_uki(root)
 }
 
-/// Preparation for an install; validates and prepares some (thereafter immutable) global state.
-async fn prepare_install(
-    config_opts: InstallConfigOpts,
-    source_opts: InstallSourceOpts,
-    target_opts: InstallTargetOpts,
-    mut composefs_options: InstallComposefsOpts,
-) -> Result<Arc<State>> {
-    tracing::trace!("Preparing install");
-    let rootfs = cap_std::fs::Dir::open_ambient_dir("/", cap_std::ambient_authority())
-        .context("Opening /")?;
-
-    let host_is_container = crate::containerenv::is_container(&rootfs);
-    let external_source = source_opts.source_imgref.is_some();
-    let (source, target_rootfs) = match source_opts.source_imgref {
+/// Find the source image, either from the container we are running in or from
+/// an explicitly provided `--source-imgref`.  If the source is the running container,
+/// its root filesystem is also returned.
+fn gather_source(
+    source_opts: &InstallSourceOpts,
+    rootfs: &Dir,
+    host_is_container: bool,
+) -> Result<(SourceInfo, Option<Dir>)> {
+    let r = match source_opts.source_imgref.as_deref() {
         None => {
             ensure!(host_is_container, "Either --source-imgref must be defined or this command must be executed inside a podman container.");
 
@@ -1341,7 +1461,7 @@ async fn prepare_install(
             // Out of conservatism we only verify the host userns path when we're expecting
             // to do a self-install (e.g. not bootc-image-builder or equivalent).
             require_host_userns()?;
-            let container_info = crate::containerenv::get_container_execution_info(&rootfs)?;
+            let container_info = crate::containerenv::get_container_execution_info(rootfs)?;
             // This command currently *must* be run inside a privileged container.
             match container_info.rootless.as_deref() {
                 Some("1") => anyhow::bail!(
@@ -1356,38 +1476,36 @@ async fn prepare_install(
             };
             tracing::trace!("Read container engine info {:?}", container_info);
 
-            let source = SourceInfo::from_container(&rootfs, &container_info)?;
+            let source = SourceInfo::from_container(rootfs, &container_info)?;
             (source, Some(rootfs.try_clone()?))
         }
         Some(source) => {
             crate::cli::require_root(false)?;
-            let source = SourceInfo::from_imageref(&source, &rootfs)?;
+            let source = SourceInfo::from_imageref(source, rootfs)?;
             (source, None)
         }
     };
+    Ok(r)
+}
 
-    // Parse the target CLI image reference options and create the *target* image
-    // reference, which defaults to pulling from a registry.
-    if target_opts.target_no_signature_verification {
-        // Perhaps log this in the future more prominently, but no reason to annoy people.
-        tracing::debug!(
-            "Use of --target-no-signature-verification flag which is enabled by default"
-        );
-    }
-    let target_sigverify = sigpolicy_from_opt(target_opts.enforce_container_sigpolicy);
-    let target_imgname = target_opts
-        .target_imgref
-        .as_deref()
-        .unwrap_or(source.imageref.name.as_str());
-    let target_transport =
-        ostree_container::Transport::try_from(target_opts.target_transport.as_str())?;
-    let target_imgref = ostree_container::OstreeImageReference {
-        sigverify: target_sigverify,
-        imgref: ostree_container::ImageReference {
-            transport: target_transport,
-            name: target_imgname.to_string(),
-        },
-    };
+/// Preparation for an install; validates and prepares some (thereafter immutable) global state.
+async fn prepare_install(
+    config_opts: InstallConfigOpts,
+    source_opts: InstallSourceOpts,
+    target_opts: InstallTargetOpts,
+    mut composefs_options: InstallComposefsOpts,
+    prog: ProgressWriter,
+) -> Result<Arc<State>> {
+    tracing::trace!("Preparing install");
+    let rootfs = cap_std::fs::Dir::open_ambient_dir("/", cap_std::ambient_authority())
+        .context("Opening /")?;
+
+    let host_is_container = crate::containerenv::is_container(&rootfs);
+    let external_source = source_opts.source_imgref.is_some();
+    let (source, target_rootfs) = gather_source(&source_opts, &rootfs, host_is_container)?;
+
+    // Parse the target CLI image reference options
+    let target_imgref = target_opts.resolve_imageref(&source)?;
     tracing::debug!("Target image reference: {target_imgref}");
 
     let composefs_required = if let Some(root) = target_rootfs.as_ref() {
@@ -1401,6 +1519,7 @@ async fn prepare_install(
     if composefs_required {
         composefs_options.composefs_backend = true;
     }
+    composefs_options.validate()?;
 
     // We need to access devices that are set up by the host udev
     bootc_mount::ensure_mirrored_host_mount("/dev")?;
@@ -1484,26 +1603,28 @@ async fn prepare_install(
         host_is_container,
         composefs_required,
         composefs_options,
+        prog,
     });
 
     Ok(state)
 }
 
+/// Determine bootloader type for the target system.
+/// Priority: user-specified > bootupd availability > systemd-boot fallback
+fn detect_bootloader(composefs_options: &InstallComposefsOpts, d: &Dir) -> Result<Bootloader> {
+    let r = if let Some(bootloader) = composefs_options.bootloader.clone() {
+        bootloader
+    } else if crate::bootloader::supports_bootupd(d)? {
+        Bootloader::Grub
+    } else {
+        Bootloader::Systemd
+    };
+    Ok(r)
+}
+
 impl PostFetchState {
     pub(crate) fn new(state: &State, d: &Dir) -> Result<Self> {
-        // Determine bootloader type for the target system
-        // Priority: user-specified > bootupd availability > systemd-boot fallback
-        let detected_bootloader = {
-            if let Some(bootloader) = state.composefs_options.bootloader.clone() {
-                bootloader
-            } else {
-                if crate::bootloader::supports_bootupd(d)? {
-                    crate::spec::Bootloader::Grub
-                } else {
-                    crate::spec::Bootloader::Systemd
-                }
-            }
-        };
+        let detected_bootloader = detect_bootloader(&state.composefs_options, d)?;
         println!("Bootloader: {detected_bootloader}");
         let r = Self {
             detected_bootloader,
@@ -1541,6 +1662,9 @@ async fn install_with_sysroot(
         .context("Opening deployment dir")?;
     let postfetch = PostFetchState::new(state, &deployment_dir)?;
 
+    let prog = &state.prog;
+    prog.begin_phase(Phase::Bootloader, "Installing bootloader")
+        .await;
     if cfg!(target_arch = "s390x") {
         // TODO: Integrate s390x support into install_via_bootupd
         crate::bootloader::install_via_zipl(&rootfs.device_info, boot_uuid)?;
@@ -1559,6 +1683,7 @@ async fn install_with_sysroot(
             }
         }
     }
+    prog.end_phase(Phase::Bootloader).await;
     tracing::debug!("Installed bootloader");
 
     tracing::debug!("Performing post-deployment operations");
@@ -1566,16 +1691,24 @@ async fn install_with_sysroot(
     match bound_images {
         BoundImages::Skip => {}
         BoundImages::Resolved(resolved_bound_images) => {
-            // Now copy each bound image from the host's container storage into the target.
-            for image in resolved_bound_images {
-                let image = image.image.as_str();
-                c_storage.pull_from_host_storage(image).await?;
-            }
+            prog.phase(Phase::BoundImages, "Copying bound images", async {
+                // Now copy each bound image from the host's container storage into the target.
+                for image in resolved_bound_images {
+                    let image = image.image.as_str();
+                    c_storage.pull_from_host_storage(image).await?;
+                }
+                anyhow::Ok(())
+            })
+            .await?;
         }
         BoundImages::Unresolved(bound_images) => {
-            crate::boundimage::pull_images_impl(c_storage, bound_images)
-                .await
-                .context("pulling bound images")?;
+            prog.phase(
+                Phase::BoundImages,
+                "Fetching bound images",
+                crate::boundimage::pull_images_impl(c_storage, bound_images),
+            )
+            .await
+            .context("pulling bound images")?;
         }
     }
 
@@ -1686,20 +1819,37 @@ async fn install_to_filesystem_impl(
     if state.composefs_options.composefs_backend {
         // Load a fd for the mounted target physical root
 
-        let (id, verity) = initialize_composefs_repository(state, rootfs).await?;
+        let (id, verity, signers) = state
+            .prog
+            .phase(
+                Phase::Pull,
+                "Fetching image",
+                initialize_composefs_repository(state, rootfs),
+            )
+            .await?;
         tracing::info!("id: {}, verity: {}", hex::encode(id), verity.to_hex());
 
-        setup_composefs_boot(rootfs, state, &hex::encode(id))?;
+        state
+            .prog
+            .begin_phase(Phase::Bootloader, "Installing bootloader")
+            .await;
+        setup_composefs_boot(rootfs, state, &hex::encode(id), &signers)?;
+        state.prog.end_phase(Phase::Bootloader).await;
     } else {
         ostree_install(state, rootfs, cleanup).await?;
     }
 
     // Finalize mounted filesystems
     if !rootfs.skip_finalize {
+        state
+            .prog
+            .begin_phase(Phase::Finalize, "Finalizing filesystems")
+            .await;
         let bootfs = rootfs.boot.as_ref().map(|_| ("boot", "boot"));
         for (fsname, fs) in std::iter::once(("root", ".")).chain(bootfs) {
             finalize_filesystem(fsname, &rootfs.physical_root, fs)?;
         }
+        state.prog.end_phase(Phase::Finalize).await;
     }
 
     Ok(())
@@ -1709,10 +1859,39 @@ fn installation_complete() {
     println!("Installation complete!");
 }
 
+/// Information about the image installed to a disk, gathered before the
+/// global install state is torn down.
+#[cfg(feature = "install-to-disk")]
+#[derive(Debug)]
+pub(crate) struct InstalledDisk {
+    /// The image reference that will be used for subsequent updates
+    pub(crate) target_imgref: ostree_container::OstreeImageReference,
+    /// The digest of the installed image, if known
+    pub(crate) digest: Option<String>,
+}
+
 /// Implementation of the `bootc install to-disk` CLI command.
 #[context("Installing to disk")]
 #[cfg(feature = "install-to-disk")]
-pub(crate) async fn install_to_disk(mut opts: InstallToDiskOpts) -> Result<()> {
+pub(crate) async fn install_to_disk(opts: InstallToDiskOpts, prog: ProgressWriter) -> Result<()> {
+    if opts.plan_opts.dry_run {
+        let plan = plan::plan_to_disk(&opts)?;
+        return plan::print_plan(&plan, opts.plan_opts.format.as_ref());
+    }
+
+    install_to_disk_impl(opts, prog).await?;
+
+    installation_complete();
+
+    Ok(())
+}
+
+/// Shared implementation of installing to a block device (or a file via loopback).
+#[cfg(feature = "install-to-disk")]
+pub(crate) async fn install_to_disk_impl(
+    mut opts: InstallToDiskOpts,
+    prog: ProgressWriter,
+) -> Result<InstalledDisk> {
     opts.validate()?;
 
     // Log the disk installation operation to systemd journal
@@ -1762,6 +1941,7 @@ pub(crate) async fn install_to_disk(mut opts: InstallToDiskOpts) -> Result<()> {
         opts.source_opts,
         opts.target_opts,
         opts.composefs_opts,
+        prog,
     )
     .await?;
 
@@ -1801,6 +1981,11 @@ pub(crate) async fn install_to_disk(mut opts: InstallToDiskOpts) -> Result<()> {
         loopback_dev.close()?;
     }
 
+    let installed = InstalledDisk {
+        target_imgref: state.target_imgref.clone(),
+        digest: state.source.digest.clone(),
+    };
+
     // At this point, all other threads should be gone.
     if let Some(state) = Arc::into_inner(state) {
         state.consume()?;
@@ -1809,9 +1994,7 @@ pub(crate) async fn install_to_disk(mut opts: InstallToDiskOpts) -> Result<()> {
         tracing::warn!("Failed to consume state Arc");
     }
 
-    installation_complete();
-
-    Ok(())
+    Ok(installed)
 }
 
 #[context("Verifying empty rootfs")]
@@ -1929,6 +2112,50 @@ fn find_root_args_to_inherit(
     Ok(RootMountInfo { mount_spec, kargs })
 }
 
+/// Determine the root mount specification and associated kernel arguments
+/// for a target filesystem.
+fn resolve_root_mount_info(
+    root_mount_spec: Option<&str>,
+    inspect: &Filesystem,
+    targeting_host_root: bool,
+) -> Result<RootMountInfo> {
+    // We support overriding the mount specification for root (i.e. LABEL vs UUID versus
+    // raw paths).
+    // We also support an empty specification as a signal to omit any mountspec kargs.
+    let r = if let Some(s) = root_mount_spec {
+        RootMountInfo {
+            mount_spec: s.to_string(),
+            kargs: Vec::new(),
+        }
+    } else if targeting_host_root {
+        // In the to-existing-root case, look at /proc/cmdline
+        let cmdline = bytes::Cmdline::from_proc()?;
+        find_root_args_to_inherit(&cmdline, inspect)?
+    } else {
+        // Otherwise, gather metadata from the provided root and use its provided UUID as a
+        // default root= karg.
+        let uuid = inspect
+            .uuid
+            .as_deref()
+            .ok_or_else(|| anyhow!("No filesystem uuid found in target root"))?;
+        let kargs = match inspect.fstype.as_str() {
+            "btrfs" => {
+                let subvol = crate::utils::find_mount_option(&inspect.options, "subvol");
+                subvol
+                    .map(|vol| format!("rootflags=subvol={vol}"))
+                    .into_iter()
+                    .collect::<Vec<_>>()
+            }
+            _ => Vec::new(),
+        };
+        RootMountInfo {
+            mount_spec: format!("UUID={uuid}"),
+            kargs,
+        }
+    };
+    Ok(r)
+}
+
 fn warn_on_host_root(rootfs_fd: &Dir) -> Result<()> {
     // Seconds for which we wait while warning
     const DELAY_SECONDS: u64 = 20;
@@ -1968,7 +2195,13 @@ pub(crate) async fn install_to_filesystem(
     opts: InstallToFilesystemOpts,
     targeting_host_root: bool,
     cleanup: Cleanup,
+    prog: ProgressWriter,
 ) -> Result<()> {
+    if opts.plan_opts.dry_run {
+        let plan = plan::plan_to_filesystem(&opts, targeting_host_root)?;
+        return plan::print_plan(&plan, opts.plan_opts.format.as_ref());
+    }
+
     // Log the installation operation to systemd journal
     const INSTALL_FILESYSTEM_JOURNAL_ID: &str = "9a8b7c6d5e4f3a2b1c0d9e8f7a6b5c4d3";
     let source_image = opts
@@ -1999,6 +2232,7 @@ pub(crate) async fn install_to_filesystem(
         opts.source_opts,
         opts.target_opts,
         opts.composefs_opts,
+        prog,
     )
     .await?;
 
@@ -2081,40 +2315,11 @@ pub(crate) async fn install_to_filesystem(
     // Gather data about the root filesystem
     let inspect = bootc_mount::inspect_filesystem(&fsopts.root_path)?;
 
-    // We support overriding the mount specification for root (i.e. LABEL vs UUID versus
-    // raw paths).
-    // We also support an empty specification as a signal to omit any mountspec kargs.
-    let root_info = if let Some(s) = fsopts.root_mount_spec {
-        RootMountInfo {
-            mount_spec: s.to_string(),
-            kargs: Vec::new(),
-        }
-    } else if targeting_host_root {
-        // In the to-existing-root case, look at /proc/cmdline
-        let cmdline = bytes::Cmdline::from_proc()?;
-        find_root_args_to_inherit(&cmdline, &inspect)?
-    } else {
-        // Otherwise, gather metadata from the provided root and use its provided UUID as a
-        // default root= karg.
-        let uuid = inspect
-            .uuid
-            .as_deref()
-            .ok_or_else(|| anyhow!("No filesystem uuid found in target root"))?;
-        let kargs = match inspect.fstype.as_str() {
-            "btrfs" => {
-                let subvol = crate::utils::find_mount_option(&inspect.options, "subvol");
-                subvol
-                    .map(|vol| format!("rootflags=subvol={vol}"))
-                    .into_iter()
-                    .collect::<Vec<_>>()
-            }
-            _ => Vec::new(),
-        };
-        RootMountInfo {
-            mount_spec: format!("UUID={uuid}"),
-            kargs,
-        }
-    };
+    let root_info = resolve_root_mount_info(
+        fsopts.root_mount_spec.as_deref(),
+        &inspect,
+        targeting_host_root,
+    )?;
     tracing::debug!("Root mount: {} {:?}", root_info.mount_spec, root_info.kargs);
 
     let boot_is_mount = {
@@ -2229,7 +2434,10 @@ pub(crate) async fn install_to_filesystem(
     Ok(())
 }
 
-pub(crate) async fn install_to_existing_root(opts: InstallToExistingRootOpts) -> Result<()> {
+pub(crate) async fn install_to_existing_root(
+    opts: InstallToExistingRootOpts,
+    prog: ProgressWriter,
+) -> Result<()> {
     // Log the existing root installation operation to systemd journal
     const INSTALL_EXISTING_ROOT_JOURNAL_ID: &str = "7c6d5e4f3a2b1c0d9e8f7a6b5c4d3e2f1";
     let source_image = opts
@@ -2272,9 +2480,11 @@ pub(crate) async fn install_to_existing_root(opts: InstallToExistingRootOpts) ->
         target_opts: opts.target_opts,
         config_opts: opts.config_opts,
         composefs_opts: opts.composefs_opts,
+        plan_opts: opts.plan_opts,
+        progress: opts.progress,
     };
 
-    install_to_filesystem(opts, true, cleanup).await
+    install_to_filesystem(opts, true, cleanup, prog).await
 }
 
 /// Read the /boot entry from /etc/fstab, if it exists
@@ -2307,14 +2517,32 @@ fn read_boot_fstab_entry(root: &Dir) -> Result<Option<MountSpec>> {
     Ok(None)
 }
 
-pub(crate) async fn install_reset(opts: InstallResetOpts) -> Result<()> {
+/// Copy the /boot entry (if any) from the `/etc/fstab` in `rootfs` into the
+/// `fstab` of the target `etc` directory, which is assumed to be pristine.
+#[context("Copying /boot fstab entry")]
+pub(crate) fn copy_boot_fstab_entry(rootfs: &Dir, etc: &Dir) -> Result<()> {
+    let Some(boot_spec) = read_boot_fstab_entry(rootfs)? else {
+        return Ok(());
+    };
+
+    // Write the /boot entry to /etc/fstab in the new deployment
+    crate::lsm::atomic_replace_labeled(etc, "fstab", 0o644.into(), None, |w| {
+        writeln!(w, "{}", boot_spec.to_fstab()).map_err(Into::into)
+    })?;
+
+    tracing::debug!(
+        "Copied /boot entry to new deployment: {}",
+        boot_spec.to_fstab()
+    );
+    Ok(())
+}
+
+pub(crate) async fn install_reset(opts: InstallResetOpts, prog: ProgressWriter) -> Result<()> {
     let rootfs = &Dir::open_ambient_dir("/", cap_std::ambient_authority())?;
     if !opts.experimental {
         anyhow::bail!("This command requires --experimental");
     }
 
-    let prog: ProgressWriter = opts.progress.try_into()?;
-
     let sysroot = &crate::cli::get_storage().await?;
     let ostree = sysroot.get_ostree()?;
     let repo = &ostree.repo();
@@ -2335,6 +2563,7 @@ pub(crate) async fn install_reset(opts: InstallResetOpts) -> Result<()> {
         let mut new_spec = host.spec;
         new_spec.image = Some(target.into());
         let fetched = crate::deploy::pull(
+            sysroot,
             repo,
             &new_spec.image.as_ref().unwrap(),
             None,
@@ -2386,34 +2615,16 @@ pub(crate) async fn install_reset(opts: InstallResetOpts) -> Result<()> {
     crate::deploy::stage(sysroot, from, &fetched, &spec, prog.clone()).await?;
 
     // Copy /boot entry from /etc/fstab to the new stateroot if it exists
-    if let Some(boot_spec) = read_boot_fstab_entry(rootfs)? {
-        let staged_deployment = ostree
-            .staged_deployment()
-            .ok_or_else(|| anyhow!("No staged deployment found"))?;
-        let deployment_path = ostree.deployment_dirpath(&staged_deployment);
-        let sysroot_dir = crate::utils::sysroot_dir(ostree)?;
-        let deployment_root = sysroot_dir.open_dir(&deployment_path)?;
-
-        // Write the /boot entry to /etc/fstab in the new deployment
-        crate::lsm::atomic_replace_labeled(
-            &deployment_root,
-            "etc/fstab",
-            0o644.into(),
-            None,
-            |w| writeln!(w, "{}", boot_spec.to_fstab()).map_err(Into::into),
-        )?;
-
-        tracing::debug!(
-            "Copied /boot entry to new stateroot: {}",
-            boot_spec.to_fstab()
-        );
-    }
+    let staged_deployment = ostree
+        .staged_deployment()
+        .ok_or_else(|| anyhow!("No staged deployment found"))?;
+    let deployment_path = ostree.deployment_dirpath(&staged_deployment);
+    let sysroot_dir = crate::utils::sysroot_dir(ostree)?;
+    let deployment_root = sysroot_dir.open_dir(&deployment_path)?;
+    copy_boot_fstab_entry(rootfs, &deployment_root.open_dir("etc")?)?;
 
     sysroot.update_mtime()?;
 
-    if opts.apply {
-        crate::reboot::reboot()?;
-    }
     Ok(())
 }
 
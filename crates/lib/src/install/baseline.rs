@@ -5,7 +5,6 @@
 //! intended to add opinionated handling of TPM2-bound LUKS too.  But that's about it;
 //! other more complex flows should set things up externally and use `bootc install to-filesystem`.
 
-use std::borrow::Cow;
 use std::fmt::Display;
 use std::fmt::Write as _;
 use std::io::Write;
@@ -24,11 +23,13 @@ use fn_error_context::context;
 use serde::{Deserialize, Serialize};
 
 use super::config::Filesystem;
+use super::config::InstallConfiguration;
 use super::MountSpec;
 use super::RootSetup;
 use super::State;
 use super::RUN_BOOTC;
 use super::RW_KARG;
+use crate::progress_jsonl::Phase;
 use crate::task::Task;
 use bootc_kernel_cmdline::utf8::Cmdline;
 #[cfg(feature = "install-to-disk")]
@@ -90,6 +91,47 @@ pub(crate) struct InstallBlockDeviceOpts {
     pub(crate) root_size: Option<String>,
 }
 
+impl InstallBlockDeviceOpts {
+    /// Find the root filesystem type from the options or the install configuration.
+    pub(crate) fn root_filesystem(
+        &self,
+        install_config: Option<&InstallConfiguration>,
+    ) -> Result<Filesystem> {
+        self.filesystem
+            .or(install_config
+                .and_then(|c| c.filesystem_root())
+                .and_then(|r| r.fstype))
+            .ok_or_else(|| anyhow::anyhow!("No root filesystem specified"))
+    }
+
+    /// Use the install configuration to find the block setup, if we have one.
+    pub(crate) fn resolve_block_setup(
+        &self,
+        install_config: Option<&InstallConfiguration>,
+    ) -> Result<BlockSetup> {
+        if let Some(config) = install_config {
+            config.get_block_setup(self.block_setup.as_ref().copied())
+        } else if self.filesystem.is_some() {
+            // Otherwise, if a filesystem is specified then we default to whatever was
+            // specified via --block-setup, or the default
+            Ok(self.block_setup.unwrap_or_default())
+        } else {
+            // If there was no default filesystem, then there's no default block setup,
+            // and we need to error out.
+            anyhow::bail!("No install configuration found, and no filesystem specified")
+        }
+    }
+
+    /// Parse the requested root partition size, if any.
+    pub(crate) fn root_size_mib(&self) -> Result<Option<u64>> {
+        self.root_size
+            .as_deref()
+            .map(bootc_blockdev::parse_size_mib)
+            .transpose()
+            .context("Parsing root size")
+    }
+}
+
 impl BlockSetup {
     /// Returns true if the block setup requires a separate /boot aka XBOOTLDR partition.
     pub(crate) fn requires_bootpart(&self) -> bool {
@@ -100,6 +142,129 @@ impl BlockSetup {
     }
 }
 
+/// The purpose of a partition created by `install to-disk`.
+#[derive(Debug, Copy, Clone, Serialize, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) enum PartitionRole {
+    /// BIOS boot partition used by GRUB on x86_64
+    BiosBoot,
+    /// PowerPC PReP boot partition
+    PrepBoot,
+    /// EFI system partition
+    Esp,
+    /// The /boot (XBOOTLDR) partition
+    Boot,
+    /// The root filesystem
+    Root,
+}
+
+/// A partition in the layout generated by `install to-disk`.
+#[derive(Debug, Clone, Serialize, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) struct PlannedPartition {
+    pub(crate) role: PartitionRole,
+    /// The GPT partition name
+    pub(crate) name: &'static str,
+    /// The partition type UUID; if unset, the sfdisk default is used
+    pub(crate) parttype: Option<String>,
+    /// Size in MiB; if unset, all remaining space is used
+    pub(crate) size_mib: Option<u64>,
+    /// Whether the legacy bootable attribute is set
+    pub(crate) bootable: bool,
+}
+
+impl PlannedPartition {
+    /// Format as a partition line for sfdisk.
+    fn to_sfdisk(&self) -> String {
+        let size = self.size_mib.map(|v| format!("size={v}MiB"));
+        let bootable = self.bootable.then(|| "bootable".to_string());
+        let parttype = self.parttype.as_deref().map(|t| format!("type={t}"));
+        let name = Some(format!(r#"name="{}""#, self.name));
+        [size, bootable, parttype, name]
+            .into_iter()
+            .flatten()
+            .collect::<Vec<_>>()
+            .join(", ")
+    }
+}
+
+/// Compute the partitions created by `install to-disk` for the current architecture.
+pub(crate) fn partition_layout(
+    block_setup: BlockSetup,
+    composefs_backend: bool,
+    root_size_mib: Option<u64>,
+) -> Result<Vec<PlannedPartition>> {
+    let mut r = Vec::new();
+    if cfg!(target_arch = "x86_64") {
+        r.push(PlannedPartition {
+            role: PartitionRole::BiosBoot,
+            name: "BIOS-BOOT",
+            parttype: Some("21686148-6449-6E6F-744E-656564454649".into()),
+            size_mib: Some(1),
+            bootable: true,
+        });
+    } else if cfg!(target_arch = "powerpc64") {
+        r.push(PlannedPartition {
+            role: PartitionRole::PrepBoot,
+            name: PREPBOOT_LABEL,
+            parttype: Some(PREPBOOT_GUID.into()),
+            size_mib: Some(4),
+            bootable: true,
+        });
+    } else if cfg!(any(target_arch = "aarch64", target_arch = "s390x")) {
+        // No bootloader partition is necessary
+    } else {
+        anyhow::bail!("Unsupported architecture: {}", std::env::consts::ARCH);
+    }
+
+    if super::ARCH_USES_EFI {
+        let esp_size = if composefs_backend {
+            CFS_EFIPN_SIZE_MB
+        } else {
+            EFIPN_SIZE_MB
+        };
+        r.push(PlannedPartition {
+            role: PartitionRole::Esp,
+            name: "EFI-SYSTEM",
+            parttype: Some(crate::discoverable_partition_specification::ESP.into()),
+            size_mib: Some(esp_size.into()),
+            bootable: false,
+        });
+    }
+
+    // Initialize the /boot filesystem.  Note that in the future, we may match
+    // what systemd/uapi-group encourages and make /boot be FAT32 as well, as
+    // it would aid systemd-boot.
+    if block_setup.requires_bootpart() {
+        r.push(PlannedPartition {
+            role: PartitionRole::Boot,
+            name: "boot",
+            parttype: None,
+            size_mib: Some(BOOTPN_SIZE_MB.into()),
+            bootable: false,
+        });
+    }
+
+    let rootpart_uuid =
+        uuid::Uuid::parse_str(crate::discoverable_partition_specification::this_arch_root())?;
+    r.push(PlannedPartition {
+        role: PartitionRole::Root,
+        name: "root",
+        parttype: Some(rootpart_uuid.to_string()),
+        size_mib: root_size_mib,
+        bootable: false,
+    });
+    Ok(r)
+}
+
+/// Find the (1-based) partition number of the partition with the given role.
+fn find_partno(layout: &[PlannedPartition], role: PartitionRole) -> Option<u32> {
+    layout
+        .iter()
+        .zip(1u32..)
+        .find_map(|(p, n)| (p.role == role).then_some(n))
+}
+
 #[cfg(feature = "install-to-disk")]
 fn mkfs<'a>(
     dev: &str,
@@ -174,12 +339,7 @@ pub(crate) fn install_create_rootfs(
     let install_config = state.install_config.as_ref();
     let luks_name = "root";
     // Ensure we have a root filesystem upfront
-    let root_filesystem = opts
-        .filesystem
-        .or(install_config
-            .and_then(|c| c.filesystem_root())
-            .and_then(|r| r.fstype))
-        .ok_or_else(|| anyhow::anyhow!("No root filesystem specified"))?;
+    let root_filesystem = opts.root_filesystem(install_config)?;
     // Verify that the target is empty (if not already wiped in particular, but it's
     // also good to verify that the wipe worked)
     let device = bootc_blockdev::list_dev(&opts.device)?;
@@ -191,6 +351,9 @@ pub(crate) fn install_create_rootfs(
         anyhow::bail!("Device {} is mounted", device.path())
     }
 
+    let prog = &state.prog;
+    prog.begin_phase_blocking(Phase::Partition, "Partitioning");
+
     // Handle wiping any existing data
     if opts.wipe {
         let dev = &opts.device;
@@ -214,18 +377,7 @@ pub(crate) fn install_create_rootfs(
         std::fs::remove_dir_all(&mntdir)?;
     }
 
-    // Use the install configuration to find the block setup, if we have one
-    let block_setup = if let Some(config) = install_config {
-        config.get_block_setup(opts.block_setup.as_ref().copied())?
-    } else if opts.filesystem.is_some() {
-        // Otherwise, if a filesystem is specified then we default to whatever was
-        // specified via --block-setup, or the default
-        opts.block_setup.unwrap_or_default()
-    } else {
-        // If there was no default filesystem, then there's no default block setup,
-        // and we need to error out.
-        anyhow::bail!("No install configuration found, and no filesystem specified")
-    };
+    let block_setup = opts.resolve_block_setup(install_config)?;
     let serial = device.serial.as_deref().unwrap_or("<unknown>");
     let model = device.model.as_deref().unwrap_or("<unknown>");
     println!("Block setup: {block_setup}");
@@ -233,12 +385,7 @@ pub(crate) fn install_create_rootfs(
     println!("     Serial: {serial}");
     println!("      Model: {model}");
 
-    let root_size = opts
-        .root_size
-        .as_deref()
-        .map(bootc_blockdev::parse_size_mib)
-        .transpose()
-        .context("Parsing root size")?;
+    let root_size = opts.root_size_mib()?;
 
     // Load the policy from the container root, which also must be our install root
     let sepolicy = state.load_policy()?;
@@ -252,74 +399,22 @@ pub(crate) fn install_create_rootfs(
     std::fs::create_dir_all(bootfs)?;
 
     // Generate partitioning spec as input to sfdisk
-    let mut partno = 0;
+    let layout = partition_layout(
+        block_setup,
+        state.composefs_options.composefs_backend,
+        root_size,
+    )?;
     let mut partitioning_buf = String::new();
     writeln!(partitioning_buf, "label: gpt")?;
     let random_label = uuid::Uuid::new_v4();
     writeln!(&mut partitioning_buf, "label-id: {random_label}")?;
-    if cfg!(target_arch = "x86_64") {
-        partno += 1;
-        writeln!(
-            &mut partitioning_buf,
-            r#"size=1MiB, bootable, type=21686148-6449-6E6F-744E-656564454649, name="BIOS-BOOT""#
-        )?;
-    } else if cfg!(target_arch = "powerpc64") {
-        // PowerPC-PReP-boot
-        partno += 1;
-        let label = PREPBOOT_LABEL;
-        let uuid = PREPBOOT_GUID;
-        writeln!(
-            &mut partitioning_buf,
-            r#"size=4MiB, bootable, type={uuid}, name="{label}""#
-        )?;
-    } else if cfg!(any(target_arch = "aarch64", target_arch = "s390x")) {
-        // No bootloader partition is necessary
-    } else {
-        anyhow::bail!("Unsupported architecture: {}", std::env::consts::ARCH);
+    for part in layout.iter() {
+        writeln!(&mut partitioning_buf, "{}", part.to_sfdisk())?;
     }
-
-    let esp_partno = if super::ARCH_USES_EFI {
-        let esp_guid = crate::discoverable_partition_specification::ESP;
-        partno += 1;
-
-        let esp_size = if state.composefs_options.composefs_backend {
-            CFS_EFIPN_SIZE_MB
-        } else {
-            EFIPN_SIZE_MB
-        };
-
-        writeln!(
-            &mut partitioning_buf,
-            r#"size={esp_size}MiB, type={esp_guid}, name="EFI-SYSTEM""#
-        )?;
-        Some(partno)
-    } else {
-        None
-    };
-
-    // Initialize the /boot filesystem.  Note that in the future, we may match
-    // what systemd/uapi-group encourages and make /boot be FAT32 as well, as
-    // it would aid systemd-boot.
-    let boot_partno = if block_setup.requires_bootpart() {
-        partno += 1;
-        writeln!(
-            &mut partitioning_buf,
-            r#"size={BOOTPN_SIZE_MB}MiB, name="boot""#
-        )?;
-        Some(partno)
-    } else {
-        None
-    };
-    let rootpn = partno + 1;
-    let root_size = root_size
-        .map(|v| Cow::Owned(format!("size={v}MiB, ")))
-        .unwrap_or_else(|| Cow::Borrowed(""));
-    let rootpart_uuid =
-        uuid::Uuid::parse_str(crate::discoverable_partition_specification::this_arch_root())?;
-    writeln!(
-        &mut partitioning_buf,
-        r#"{root_size}type={rootpart_uuid}, name="root""#
-    )?;
+    let esp_partno = find_partno(&layout, PartitionRole::Esp);
+    let boot_partno = find_partno(&layout, PartitionRole::Boot);
+    // SAFETY: The layout always includes a root partition
+    let rootpn = find_partno(&layout, PartitionRole::Root).unwrap();
     tracing::debug!("Partitioning: {partitioning_buf}");
     Task::new("Initializing partitions", "sfdisk")
         .arg("--wipe=always")
@@ -332,6 +427,7 @@ pub(crate) fn install_create_rootfs(
     // Full udev sync; it'd obviously be better to await just the devices
     // we're targeting, but this is a simple coarse hammer.
     udev_settle()?;
+    prog.end_phase_blocking(Phase::Partition);
 
     // Re-read what we wrote into structured information
     let base_partitions = &bootc_blockdev::partitions_of(&devpath)?;
@@ -348,6 +444,7 @@ pub(crate) fn install_create_rootfs(
             root_partition.parttype.as_str()
         );
     }
+    prog.begin_phase_blocking(Phase::Mkfs, "Creating filesystems");
     let (rootdev, root_blockdev_kargs) = match block_setup {
         BlockSetup::Direct => (root_partition.node.to_owned(), None),
         BlockSetup::Tpm2Luks => {
@@ -486,6 +583,7 @@ pub(crate) fn install_create_rootfs(
         BlockSetup::Tpm2Luks => Some(luks_name.to_string()),
     };
     let device_info = bootc_blockdev::partitions_of(&devpath)?;
+    prog.end_phase_blocking(Phase::Mkfs);
     Ok(RootSetup {
         luks_device,
         device_info,
@@ -0,0 +1,429 @@
+//! # Writing a container to a disk image file
+//!
+//! This module implements `bootc install to-disk-image`, which is a thin
+//! wrapper around `install to-disk --via-loopback` that takes care of
+//! allocating a sparse file, optionally shrinking the root filesystem to
+//! its content, and converting the result to a virtualization-friendly format.
+
+use std::fs::File;
+use std::io::BufWriter;
+
+use anyhow::{Context, Result};
+use camino::{Utf8Path, Utf8PathBuf};
+use clap::ValueEnum;
+use fn_error_context::context;
+use serde::{Deserialize, Serialize};
+
+use super::baseline::{BlockSetup, InstallBlockDeviceOpts};
+use super::config::Filesystem;
+use super::{
+    InstallComposefsOpts, InstallConfigOpts, InstallSourceOpts, InstallTargetOpts,
+    InstallToDiskOpts,
+};
+use crate::progress_jsonl::ProgressWriter;
+use crate::spec::ImageReference;
+use crate::task::Task;
+
+/// One mebibyte; disk images are always sized in multiples of this.
+const MIB: u64 = 1024 * 1024;
+
+/// Space reserved after the last partition when shrinking; this must be
+/// large enough for the backup GPT header and partition entries.
+const GPT_BACKUP_RESERVE: u64 = MIB;
+
+/// The output format of a disk image.
+#[derive(ValueEnum, Debug, Default, Copy, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) enum DiskImageFormat {
+    /// A raw (sparse) disk image
+    #[default]
+    Raw,
+    /// QEMU copy-on-write version 2
+    Qcow2,
+    /// Fixed size VHD, as used by e.g. Azure
+    Vhd,
+    /// Stream optimized VMDK, as used by e.g. VMware
+    Vmdk,
+}
+
+impl std::fmt::Display for DiskImageFormat {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        self.to_possible_value().unwrap().get_name().fmt(f)
+    }
+}
+
+impl DiskImageFormat {
+    /// Arguments for `qemu-img convert` to produce this format from a raw
+    /// image; returns `None` if no conversion is necessary.
+    fn qemu_img_args(&self) -> Option<&'static [&'static str]> {
+        match self {
+            DiskImageFormat::Raw => None,
+            DiskImageFormat::Qcow2 => Some(&["-O", "qcow2"]),
+            DiskImageFormat::Vhd => Some(&["-O", "vpc", "-o", "subformat=fixed,force_size=on"]),
+            DiskImageFormat::Vmdk => Some(&["-O", "vmdk", "-o", "subformat=streamOptimized"]),
+        }
+    }
+}
+
+/// Options for installing to a disk image file
+#[derive(Debug, Clone, clap::Parser, PartialEq, Eq)]
+pub(crate) struct InstallToDiskImageOpts {
+    /// Path to the disk image file to create; it must not already exist.
+    pub(crate) path: Utf8PathBuf,
+
+    /// Size of the disk image (default specifier: M).  Allowed specifiers: M (mebibytes), G (gibibytes), T (tebibytes).
+    #[clap(long)]
+    pub(crate) size: String,
+
+    /// The format of the generated disk image.
+    #[clap(long, value_enum, default_value_t)]
+    pub(crate) format: DiskImageFormat,
+
+    /// Shrink the root filesystem (and the disk image) to fit its content.
+    ///
+    /// This is currently only supported for ext4; other filesystems are left
+    /// at their full size.
+    #[clap(long)]
+    pub(crate) shrink_root: bool,
+
+    /// Write a JSON manifest describing the disk image to this path.
+    ///
+    /// Defaults to the image path with a `.manifest.json` suffix.
+    #[clap(long)]
+    pub(crate) manifest: Option<Utf8PathBuf>,
+
+    /// Target root block device setup.
+    ///
+    /// direct: Filesystem written directly to block device
+    /// tpm2-luks: Bind unlock of filesystem to presence of the default tpm2 device.
+    #[clap(long, value_enum)]
+    pub(crate) block_setup: Option<BlockSetup>,
+
+    /// Target root filesystem type.
+    #[clap(long, value_enum)]
+    pub(crate) filesystem: Option<Filesystem>,
+
+    /// Size of the root partition (default specifier: M).  Allowed specifiers: M (mebibytes), G (gibibytes), T (tebibytes).
+    ///
+    /// By default, all remaining space on the disk will be used.
+    #[clap(long)]
+    pub(crate) root_size: Option<String>,
+
+    #[clap(flatten)]
+    pub(crate) source_opts: InstallSourceOpts,
+
+    #[clap(flatten)]
+    pub(crate) target_opts: InstallTargetOpts,
+
+    #[clap(flatten)]
+    pub(crate) config_opts: InstallConfigOpts,
+
+    #[clap(flatten)]
+    pub(crate) composefs_opts: InstallComposefsOpts,
+
+    #[clap(flatten)]
+    pub(crate) progress: crate::cli::ProgressOptions,
+}
+
+/// A partition in the generated disk image.
+#[derive(Debug, Serialize, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) struct DiskImagePartition {
+    /// The partition number, starting at 1
+    pub(crate) number: u32,
+    /// The partition name (GPT label)
+    pub(crate) name: Option<String>,
+    /// The partition type UUID
+    pub(crate) parttype: String,
+    /// The partition UUID
+    pub(crate) uuid: Option<String>,
+    /// Offset of the partition in bytes
+    pub(crate) start: u64,
+    /// Size of the partition in bytes
+    pub(crate) size: u64,
+}
+
+/// Metadata describing a generated disk image.
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) struct DiskImageManifest {
+    /// Path to the disk image
+    pub(crate) path: Utf8PathBuf,
+    /// Format of the disk image
+    pub(crate) format: DiskImageFormat,
+    /// Virtual size of the disk in bytes
+    pub(crate) size: u64,
+    /// The image reference the installed system will use for updates
+    pub(crate) image: ImageReference,
+    /// The digest of the installed image, if known
+    pub(crate) digest: Option<String>,
+    /// Partitions in the disk image
+    pub(crate) partitions: Vec<DiskImagePartition>,
+}
+
+/// Convert a partition table to the manifest representation, using byte units.
+fn partitions_to_manifest(pt: &bootc_blockdev::PartitionTable) -> Vec<DiskImagePartition> {
+    let sector_size = pt.sector_size();
+    pt.partitions
+        .iter()
+        .zip(1u32..)
+        .map(|(p, number)| DiskImagePartition {
+            number,
+            name: p.name.clone(),
+            parttype: p.parttype.to_ascii_lowercase(),
+            uuid: p.uuid.clone(),
+            start: p.start * sector_size,
+            size: p.size * sector_size,
+        })
+        .collect()
+}
+
+/// Find the total filesystem size in bytes from `dumpe2fs -h` output.
+fn parse_dumpe2fs_size(output: &str) -> Result<u64> {
+    let mut block_count = None;
+    let mut block_size = None;
+    for line in output.lines() {
+        let Some((k, v)) = line.split_once(':') else {
+            continue;
+        };
+        let v = v.trim();
+        match k.trim() {
+            "Block count" => block_count = Some(v.parse::<u64>().context("Parsing block count")?),
+            "Block size" => block_size = Some(v.parse::<u64>().context("Parsing block size")?),
+            _ => {}
+        }
+    }
+    let block_count = block_count.ok_or_else(|| anyhow::anyhow!("Missing block count"))?;
+    let block_size = block_size.ok_or_else(|| anyhow::anyhow!("Missing block size"))?;
+    Ok(block_count * block_size)
+}
+
+/// Shrink the root filesystem to its minimum size, then shrink the root partition
+/// and the image file to match.
+#[context("Shrinking root filesystem")]
+fn shrink_root(image: &Utf8Path) -> Result<()> {
+    let loopdev = bootc_blockdev::LoopbackDevice::new(image.as_std_path())?;
+    let pt = bootc_blockdev::partitions_of(loopdev.path())?;
+    let root_type = crate::discoverable_partition_specification::this_arch_root();
+    let (partno, rootpart) = pt
+        .partitions
+        .iter()
+        .zip(1u32..)
+        .find_map(|(p, n)| p.parttype_matches(root_type).then_some((n, p)))
+        .ok_or_else(|| anyhow::anyhow!("Failed to find root partition"))?;
+    if partno as usize != pt.partitions.len() {
+        anyhow::bail!("Root partition {partno} is not the last partition");
+    }
+    let fstype = bootc_blockdev::list_dev(rootpart.path())?.fstype;
+    if fstype.as_deref() != Some("ext4") {
+        let fstype = fstype.as_deref().unwrap_or("<unknown>");
+        crate::utils::medium_visibility_warning(&format!(
+            "Shrinking is not supported for root filesystem type {fstype}"
+        ));
+        return loopdev.close();
+    }
+    let rootdev = rootpart.node.as_str();
+    Task::new("Checking root filesystem", "e2fsck")
+        .args(["-f", "-p", rootdev])
+        .quiet_output()
+        .run()?;
+    Task::new("Shrinking root filesystem", "resize2fs")
+        .args(["-M", rootdev])
+        .quiet_output()
+        .run()?;
+    let fs_size = Task::new_quiet("dumpe2fs")
+        .args(["-h", rootdev])
+        .read()
+        .and_then(|o| parse_dumpe2fs_size(&o))?;
+    let sector_size = pt.sector_size();
+    let start = rootpart.start;
+    loopdev.close()?;
+
+    let sectors = fs_size.div_ceil(sector_size);
+    let partno = partno.to_string();
+    Task::new("Resizing root partition", "sfdisk")
+        .args(["--no-reread", "--no-tell-kernel", "-N", partno.as_str()])
+        .arg(image)
+        .quiet_output()
+        .run_with_stdin_buf(Some(format!(",{sectors}\n").as_bytes()))?;
+
+    let new_len = ((start + sectors) * sector_size + GPT_BACKUP_RESERVE).next_multiple_of(MIB);
+    File::options()
+        .write(true)
+        .open(image)
+        .and_then(|f| f.set_len(new_len))
+        .with_context(|| format!("Truncating {image}"))?;
+    Task::new("Relocating backup partition table", "sfdisk")
+        .args(["--relocate", "gpt-bak-std"])
+        .arg(image)
+        .quiet_output()
+        .run()?;
+    println!(
+        "Shrunk disk image to {}",
+        ostree_ext::glib::format_size(new_len)
+    );
+    Ok(())
+}
+
+/// Implementation of the `bootc install to-disk-image` CLI command.
+#[context("Installing to disk image")]
+pub(crate) async fn install_to_disk_image(
+    opts: InstallToDiskImageOpts,
+    prog: ProgressWriter,
+) -> Result<()> {
+    let path = opts.path;
+    if path.try_exists()? {
+        anyhow::bail!("Refusing to overwrite existing file: {path}");
+    }
+    let size = bootc_blockdev::parse_size_mib(&opts.size)
+        .context("Parsing disk image size")?
+        .checked_mul(MIB)
+        .ok_or_else(|| anyhow::anyhow!("Disk image size too large"))?;
+    let manifest_path = opts
+        .manifest
+        .unwrap_or_else(|| Utf8PathBuf::from(format!("{path}.manifest.json")));
+
+    // The raw image is written to a fixed path next to the target so that this
+    // remains idempotent if we re-execute ourselves during install preparation.
+    let partial = Utf8PathBuf::from(format!("{path}.partial"));
+    File::create(&partial)
+        .and_then(|f| f.set_len(size))
+        .with_context(|| format!("Creating {partial}"))?;
+
+    let mut config_opts = opts.config_opts;
+    config_opts.generic_image = true;
+    let disk_opts = InstallToDiskOpts {
+        block_opts: InstallBlockDeviceOpts {
+            device: partial.clone(),
+            wipe: false,
+            block_setup: opts.block_setup,
+            filesystem: opts.filesystem,
+            root_size: opts.root_size,
+        },
+        source_opts: opts.source_opts,
+        target_opts: opts.target_opts,
+        config_opts,
+        via_loopback: true,
+        composefs_opts: opts.composefs_opts,
+        plan_opts: Default::default(),
+        progress: Default::default(),
+    };
+
+    let format = opts.format;
+    let shrink = opts.shrink_root;
+    let r = async {
+        let installed = super::install_to_disk_impl(disk_opts, prog.clone()).await?;
+        if shrink {
+            shrink_root(&partial)?;
+        }
+        let pt = bootc_blockdev::partitions_of(&partial)?;
+        let size = partial.metadata()?.len();
+        match format.qemu_img_args() {
+            None => std::fs::rename(&partial, &path)
+                .with_context(|| format!("Renaming {partial} to {path}"))?,
+            Some(args) => {
+                Task::new(format!("Converting disk image to {format}"), "qemu-img")
+                    .args(["convert", "-f", "raw"])
+                    .args(args)
+                    .args([partial.as_str(), path.as_str()])
+                    .run()?;
+                std::fs::remove_file(&partial)?;
+            }
+        }
+        let manifest = DiskImageManifest {
+            path: path.clone(),
+            format,
+            size,
+            image: ImageReference::from(installed.target_imgref),
+            digest: installed.digest,
+            partitions: partitions_to_manifest(&pt),
+        };
+        let mut w = File::create(&manifest_path)
+            .map(BufWriter::new)
+            .with_context(|| format!("Creating {manifest_path}"))?;
+        serde_json::to_writer_pretty(&mut w, &manifest)?;
+        anyhow::Ok(())
+    }
+    .await;
+    if r.is_err() {
+        // Don't leave a partially written image around
+        if let Err(e) = std::fs::remove_file(&partial) {
+            tracing::debug!("Failed to remove {partial}: {e}");
+        }
+    }
+    r?;
+
+    super::installation_complete();
+    println!("Wrote {format} disk image: {path}");
+    println!("Wrote manifest: {manifest_path}");
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_qemu_img_args() {
+        assert!(DiskImageFormat::Raw.qemu_img_args().is_none());
+        assert_eq!(
+            DiskImageFormat::Qcow2.qemu_img_args().unwrap(),
+            &["-O", "qcow2"]
+        );
+        assert_eq!(DiskImageFormat::Vhd.qemu_img_args().unwrap()[1], "vpc");
+        assert_eq!(DiskImageFormat::Vmdk.qemu_img_args().unwrap()[1], "vmdk");
+    }
+
+    #[test]
+    fn test_parse_dumpe2fs_size() {
+        let output = indoc::indoc! { r#"
+            Filesystem volume name:   root
+            Block count:              262144
+            Reserved block count:     13107
+            Block size:               4096
+            Fragment size:            4096
+        "# };
+        assert_eq!(parse_dumpe2fs_size(output).unwrap(), 262144 * 4096);
+        assert!(parse_dumpe2fs_size("Block size: 4096").is_err());
+    }
+
+    #[test]
+    fn test_partitions_to_manifest() {
+        let pt: bootc_blockdev::PartitionTable = serde_json::from_str(indoc::indoc! { r#"
+            {
+               "label": "gpt",
+               "id": "A67AA901-2C72-4818-B098-7F1CAC127279",
+               "device": "disk.raw",
+               "unit": "sectors",
+               "sectorsize": 512,
+               "partitions": [
+                  {
+                     "node": "disk.raw1",
+                     "start": 2048,
+                     "size": 1048576,
+                     "type": "C12A7328-F81F-11D2-BA4B-00A0C93EC93B",
+                     "uuid": "58A4C5F0-BD12-424C-B563-195AC65A25DD",
+                     "name": "EFI-SYSTEM"
+                  },{
+                     "node": "disk.raw2",
+                     "start": 1050624,
+                     "size": 20961247,
+                     "type": "4F68BCE3-E8CD-4DB1-96E7-FBCAF984B709",
+                     "uuid": "F51ABB0D-DA16-4A21-83CB-37F4C805AAA0",
+                     "name": "root"
+                  }
+               ]
+            }
+        "# })
+        .unwrap();
+        let parts = partitions_to_manifest(&pt);
+        assert_eq!(parts.len(), 2);
+        assert_eq!(parts[0].number, 1);
+        assert_eq!(parts[0].start, 2048 * 512);
+        assert_eq!(parts[0].size, 512 * MIB);
+        assert_eq!(parts[0].parttype, "c12a7328-f81f-11d2-ba4b-00a0c93ec93b");
+        assert_eq!(parts[1].number, 2);
+        assert_eq!(parts[1].name.as_deref(), Some("root"));
+    }
+}
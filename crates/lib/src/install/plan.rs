@@ -0,0 +1,547 @@
+//! # Installation plans
+//!
+//! Support for `--dry-run` on the `install` verbs, which resolves the
+//! configuration an installation would use and prints it without touching
+//! any device or filesystem.
+
+use std::io::Write;
+
+use anyhow::{Context, Result};
+use camino::Utf8PathBuf;
+use canon_json::CanonJsonSerialize;
+use cap_std_ext::cap_std;
+use cap_std_ext::cap_std::fs::{Dir, MetadataExt};
+use cap_std_ext::dirext::CapStdExtDirExt;
+use fn_error_context::context;
+use serde::Serialize;
+
+#[cfg(feature = "install-to-disk")]
+use super::baseline::{BlockSetup, PlannedPartition};
+use super::config::InstallConfiguration;
+#[cfg(feature = "install-to-disk")]
+use super::InstallToDiskOpts;
+use super::{
+    BoundImagesOpt, InstallComposefsOpts, InstallConfigOpts, InstallSourceOpts, InstallTargetOpts,
+    InstallToFilesystemOpts, ReplaceMode, SELinuxFinalState, SourceInfo,
+};
+use crate::cli::OutputFormat;
+use crate::spec::Bootloader;
+
+/// Placeholder for values which are only generated at install time, such as filesystem UUIDs.
+#[cfg(feature = "install-to-disk")]
+const GENERATED: &str = "<generated>";
+
+/// Options for printing an installation plan instead of installing
+#[derive(Debug, Default, Clone, clap::Args, PartialEq, Eq)]
+pub(crate) struct InstallPlanOpts {
+    /// Print the resolved installation plan without modifying any device or filesystem.
+    #[clap(long)]
+    pub(crate) dry_run: bool,
+
+    /// The output format for `--dry-run`; defaults to human readable output.
+    #[clap(long, requires = "dry_run")]
+    pub(crate) format: Option<OutputFormat>,
+}
+
+/// The mechanism which will be used to install the bootloader.
+#[derive(Debug, Copy, Clone, Serialize, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) enum PlannedBootloader {
+    Bootupd,
+    SystemdBoot,
+    Zipl,
+}
+
+impl std::fmt::Display for PlannedBootloader {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let s = match self {
+            PlannedBootloader::Bootupd => "bootupd",
+            PlannedBootloader::SystemdBoot => "systemd-boot",
+            PlannedBootloader::Zipl => "zipl",
+        };
+        f.write_str(s)
+    }
+}
+
+/// Kernel arguments for the target, grouped by origin.  They are applied in this order.
+#[derive(Debug, Default, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) struct PlannedKargs {
+    /// Derived from the root (and /boot) filesystem setup
+    pub(crate) root: Vec<String>,
+    /// From the install configuration
+    pub(crate) install_config: Vec<String>,
+    /// From `kargs.d` in the container image
+    pub(crate) kargs_d: Vec<String>,
+    /// From `--karg`
+    pub(crate) cli: Vec<String>,
+}
+
+/// The installation target.
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "kebab-case", tag = "type")]
+pub(crate) enum PlannedTarget {
+    /// A block device (or file via loopback) which will be partitioned
+    #[cfg(feature = "install-to-disk")]
+    Disk {
+        device: Utf8PathBuf,
+        via_loopback: bool,
+        size: u64,
+        block_setup: BlockSetup,
+        partitions: Vec<PlannedPartition>,
+    },
+    /// An externally prepared filesystem
+    Filesystem {
+        root_path: Utf8PathBuf,
+        replace: Option<ReplaceMode>,
+        boot_mount_spec: Option<String>,
+    },
+}
+
+/// The resolved plan for an installation.
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) struct InstallPlan {
+    pub(crate) source_image: String,
+    pub(crate) source_digest: Option<String>,
+    pub(crate) target_image: String,
+    pub(crate) composefs_backend: bool,
+    pub(crate) stateroot: String,
+    pub(crate) install_config: Option<InstallConfiguration>,
+    pub(crate) target: PlannedTarget,
+    pub(crate) filesystem: Option<String>,
+    pub(crate) bootloader: PlannedBootloader,
+    pub(crate) kargs: PlannedKargs,
+    pub(crate) bound_images_mode: BoundImagesOpt,
+    pub(crate) bound_images: Vec<String>,
+    pub(crate) selinux: &'static str,
+    /// Details which can only be determined at install time
+    pub(crate) notes: Vec<String>,
+}
+
+/// State shared by all plan types, gathered from the running container.
+struct PlanBase {
+    rootfs: Dir,
+    source: SourceInfo,
+    composefs_options: InstallComposefsOpts,
+    install_config: Option<InstallConfiguration>,
+}
+
+impl PlanBase {
+    fn new(source_opts: &InstallSourceOpts, composefs_opts: &InstallComposefsOpts) -> Result<Self> {
+        let rootfs =
+            Dir::open_ambient_dir("/", cap_std::ambient_authority()).context("Opening /")?;
+        let host_is_container = crate::containerenv::is_container(&rootfs);
+        let (source, target_rootfs) =
+            super::gather_source(source_opts, &rootfs, host_is_container)?;
+        let mut composefs_options = composefs_opts.clone();
+        if let Some(root) = target_rootfs.as_ref() {
+            if super::root_has_uki(root)? {
+                composefs_options.composefs_backend = true;
+            }
+        }
+        let install_config = super::config::load_config()?;
+        Ok(Self {
+            rootfs,
+            source,
+            composefs_options,
+            install_config,
+        })
+    }
+
+    fn into_plan(
+        self,
+        target_opts: &InstallTargetOpts,
+        config_opts: &InstallConfigOpts,
+        target: PlannedTarget,
+        filesystem: Option<String>,
+        mut root_kargs: Vec<String>,
+        mut notes: Vec<String>,
+    ) -> Result<InstallPlan> {
+        let target_imgref = target_opts.resolve_imageref(&self.source)?;
+        let composefs_backend = self.composefs_options.composefs_backend;
+
+        let bootloader = if cfg!(target_arch = "s390x") {
+            PlannedBootloader::Zipl
+        } else {
+            match super::detect_bootloader(&self.composefs_options, &self.rootfs)? {
+                Bootloader::Grub => PlannedBootloader::Bootupd,
+                Bootloader::Systemd if composefs_backend => PlannedBootloader::SystemdBoot,
+                Bootloader::Systemd => {
+                    anyhow::bail!("bootupd is required for ostree-based installs")
+                }
+            }
+        };
+
+        let selinux_state =
+            super::predict_selinux_state(&self.source, config_opts.disable_selinux)?;
+        if matches!(selinux_state, SELinuxFinalState::ForceTargetDisabled) {
+            root_kargs.push("selinux=0".into());
+        }
+
+        let kargs_d = crate::bootc_kargs::get_kargs_in_root(&self.rootfs, std::env::consts::ARCH)?;
+        if !self.source.in_host_mountns {
+            notes.push(
+                "Kernel arguments from kargs.d were read from the running container, not the source image".into(),
+            );
+        }
+        let kargs = PlannedKargs {
+            root: root_kargs,
+            install_config: self
+                .install_config
+                .as_ref()
+                .and_then(|c| c.kargs.clone())
+                .unwrap_or_default(),
+            kargs_d: kargs_d.iter_str().map(ToOwned::to_owned).collect(),
+            cli: config_opts
+                .karg
+                .iter()
+                .flatten()
+                .flat_map(|k| k.iter_str().map(ToOwned::to_owned).collect::<Vec<_>>())
+                .collect(),
+        };
+
+        let bound_images = match config_opts.bound_images {
+            BoundImagesOpt::Skip => Vec::new(),
+            BoundImagesOpt::Stored | BoundImagesOpt::Pull => {
+                crate::boundimage::query_bound_images(&self.rootfs)?
+                    .into_iter()
+                    .map(|i| i.image)
+                    .collect()
+            }
+        };
+
+        let stateroot = config_opts
+            .stateroot
+            .as_deref()
+            .unwrap_or(ostree_ext::container::deploy::STATEROOT_DEFAULT)
+            .to_owned();
+
+        Ok(InstallPlan {
+            source_image: self.source.imageref.to_string(),
+            source_digest: self.source.digest,
+            target_image: target_imgref.to_string(),
+            composefs_backend,
+            stateroot,
+            install_config: self.install_config,
+            target,
+            filesystem,
+            bootloader,
+            kargs,
+            bound_images_mode: config_opts.bound_images,
+            bound_images,
+            selinux: selinux_state.to_aleph(),
+            notes,
+        })
+    }
+}
+
+/// Compute the plan for `bootc install to-disk`.
+#[cfg(feature = "install-to-disk")]
+#[context("Computing installation plan")]
+pub(crate) fn plan_to_disk(opts: &InstallToDiskOpts) -> Result<InstallPlan> {
+    opts.validate()?;
+    let base = PlanBase::new(&opts.source_opts, &opts.composefs_opts)?;
+    let install_config = base.install_config.as_ref();
+    let block_opts = &opts.block_opts;
+
+    let filesystem = block_opts.root_filesystem(install_config)?;
+    let block_setup = block_opts.resolve_block_setup(install_config)?;
+    let root_size = block_opts.root_size_mib()?;
+    let partitions = super::baseline::partition_layout(
+        block_setup,
+        base.composefs_options.composefs_backend,
+        root_size,
+    )?;
+    let size = if opts.via_loopback {
+        let meta = block_opts
+            .device
+            .metadata()
+            .with_context(|| format!("Querying {}", block_opts.device))?;
+        anyhow::ensure!(
+            meta.is_file(),
+            "Not a regular file (to be used via loopback): {}",
+            block_opts.device
+        );
+        meta.len()
+    } else {
+        bootc_blockdev::list_dev(&block_opts.device)?.size
+    };
+
+    // Keep this in sync with the kargs generated by install_create_rootfs
+    let mut root_kargs = Vec::new();
+    if block_setup == BlockSetup::Tpm2Luks {
+        root_kargs.push(format!("luks.uuid={GENERATED}"));
+        root_kargs.push("luks.options=tpm2-device=auto,headless=true".into());
+    }
+    root_kargs.push(format!("root=UUID={GENERATED}"));
+    root_kargs.push(super::RW_KARG.into());
+    if block_setup.requires_bootpart() {
+        root_kargs.push(format!("boot=UUID={GENERATED}"));
+    }
+
+    let mut notes = Vec::new();
+    if opts.via_loopback && !opts.config_opts.generic_image {
+        notes.push("--generic-image will be enabled when installing via loopback".into());
+    }
+
+    let target = PlannedTarget::Disk {
+        device: block_opts.device.clone(),
+        via_loopback: opts.via_loopback,
+        size,
+        block_setup,
+        partitions,
+    };
+    base.into_plan(
+        &opts.target_opts,
+        &opts.config_opts,
+        target,
+        Some(filesystem.to_string()),
+        root_kargs,
+        notes,
+    )
+}
+
+/// Compute the plan for `bootc install to-filesystem` and `to-existing-root`.
+#[context("Computing installation plan")]
+pub(crate) fn plan_to_filesystem(
+    opts: &InstallToFilesystemOpts,
+    targeting_host_root: bool,
+) -> Result<InstallPlan> {
+    let base = PlanBase::new(&opts.source_opts, &opts.composefs_opts)?;
+    let fsopts = &opts.filesystem_opts;
+    let mut notes = Vec::new();
+
+    let mut root_path = fsopts.root_path.clone();
+    // When targeting the host root, it may not be mounted yet; we won't do that here.
+    let root_path_exists = root_path.try_exists()?;
+    if root_path_exists {
+        let physical_root = root_path.join("sysroot");
+        if physical_root.join("ostree").exists() {
+            root_path = physical_root;
+        }
+    }
+
+    let (filesystem, root_kargs, boot_mount_spec) = if root_path_exists {
+        let inspect = bootc_mount::inspect_filesystem(&root_path)?;
+        let root_info = super::resolve_root_mount_info(
+            fsopts.root_mount_spec.as_deref(),
+            &inspect,
+            targeting_host_root,
+        )?;
+        let mut kargs = Vec::new();
+        if !root_info.mount_spec.is_empty() {
+            kargs.push(format!("root={}", root_info.mount_spec));
+            kargs.extend(root_info.kargs);
+        }
+        kargs.push(super::RW_KARG.into());
+
+        let boot_mount_spec = if let Some(spec) = fsopts.boot_mount_spec.as_deref() {
+            (!spec.is_empty()).then(|| spec.to_owned())
+        } else {
+            let rootfs_fd = Dir::open_ambient_dir(&root_path, cap_std::ambient_authority())
+                .with_context(|| format!("Opening target root directory {root_path}"))?;
+            let root_dev = rootfs_fd.dir_metadata()?.dev();
+            match rootfs_fd.symlink_metadata_optional(super::BOOT)? {
+                Some(m) if m.dev() != root_dev => {
+                    let boot_path = root_path.join(super::BOOT);
+                    bootc_mount::inspect_filesystem(&boot_path)?
+                        .uuid
+                        .map(|u| format!("UUID={u}"))
+                }
+                _ => None,
+            }
+        };
+        if let Some(spec) = boot_mount_spec.as_deref() {
+            kargs.push(format!("boot={spec}"));
+        }
+        (Some(inspect.fstype), kargs, boot_mount_spec)
+    } else {
+        notes.push(format!(
+            "Target root {root_path} does not exist; root filesystem kernel arguments will be determined at install time"
+        ));
+        (None, Vec::new(), fsopts.boot_mount_spec.clone())
+    };
+
+    let target = PlannedTarget::Filesystem {
+        root_path,
+        replace: fsopts.replace,
+        boot_mount_spec,
+    };
+    base.into_plan(
+        &opts.target_opts,
+        &opts.config_opts,
+        target,
+        filesystem,
+        root_kargs,
+        notes,
+    )
+}
+
+fn print_kargs(out: &mut impl Write, name: &str, kargs: &[String]) -> Result<()> {
+    if !kargs.is_empty() {
+        writeln!(out, "  {name}: {}", kargs.join(" "))?;
+    }
+    Ok(())
+}
+
+/// Write a human readable version of the plan.
+fn human_readable_output(mut out: impl Write, plan: &InstallPlan) -> Result<()> {
+    writeln!(out, "Source image: {}", plan.source_image)?;
+    if let Some(digest) = plan.source_digest.as_deref() {
+        writeln!(out, "Digest: {digest}")?;
+    }
+    writeln!(out, "Target image: {}", plan.target_image)?;
+    let backend = if plan.composefs_backend {
+        "composefs"
+    } else {
+        "ostree"
+    };
+    writeln!(out, "Backend: {backend}")?;
+    writeln!(out, "Stateroot: {}", plan.stateroot)?;
+    match &plan.target {
+        #[cfg(feature = "install-to-disk")]
+        PlannedTarget::Disk {
+            device,
+            via_loopback,
+            size,
+            block_setup,
+            partitions,
+        } => {
+            let size = ostree_ext::glib::format_size(*size);
+            let loopback = if *via_loopback { " (via loopback)" } else { "" };
+            writeln!(out, "Target device: {device}{loopback}, size {size}")?;
+            writeln!(out, "Block setup: {block_setup}")?;
+            writeln!(out, "Partitions:")?;
+            for (n, part) in (1..).zip(partitions) {
+                let size = part
+                    .size_mib
+                    .map(|v| format!("{v} MiB"))
+                    .unwrap_or_else(|| "remaining space".into());
+                writeln!(out, "  {n}: {} ({size})", part.name)?;
+            }
+        }
+        PlannedTarget::Filesystem {
+            root_path,
+            replace,
+            boot_mount_spec,
+        } => {
+            writeln!(out, "Target filesystem: {root_path}")?;
+            if let Some(replace) = replace {
+                writeln!(out, "Replace mode: {replace}")?;
+            }
+            if let Some(spec) = boot_mount_spec.as_deref() {
+                writeln!(out, "Boot mount: {spec}")?;
+            }
+        }
+    }
+    if let Some(fs) = plan.filesystem.as_deref() {
+        writeln!(out, "Root filesystem: {fs}")?;
+    }
+    writeln!(out, "Bootloader: {}", plan.bootloader)?;
+    writeln!(out, "SELinux: {}", plan.selinux)?;
+    writeln!(out, "Kernel arguments:")?;
+    print_kargs(&mut out, "root", &plan.kargs.root)?;
+    print_kargs(&mut out, "install config", &plan.kargs.install_config)?;
+    print_kargs(&mut out, "kargs.d", &plan.kargs.kargs_d)?;
+    print_kargs(&mut out, "command line", &plan.kargs.cli)?;
+    if plan.bound_images.is_empty() {
+        writeln!(out, "Bound images ({}): none", plan.bound_images_mode)?;
+    } else {
+        writeln!(out, "Bound images ({}):", plan.bound_images_mode)?;
+        for image in plan.bound_images.iter() {
+            writeln!(out, "  {image}")?;
+        }
+    }
+    for note in plan.notes.iter() {
+        writeln!(out, "Note: {note}")?;
+    }
+    Ok(())
+}
+
+/// Print the plan to stdout in the requested format.
+pub(crate) fn print_plan(plan: &InstallPlan, format: Option<&OutputFormat>) -> Result<()> {
+    let mut out = std::io::stdout().lock();
+    match format.unwrap_or(&OutputFormat::HumanReadable) {
+        OutputFormat::Json => plan
+            .to_canon_json_writer(&mut out)
+            .map_err(anyhow::Error::new),
+        OutputFormat::Yaml => serde_yaml::to_writer(&mut out, plan).map_err(anyhow::Error::new),
+        OutputFormat::HumanReadable => human_readable_output(&mut out, plan),
+    }
+    .context("Writing to stdout")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn test_plan() -> InstallPlan {
+        InstallPlan {
+            source_image: "containers-storage:quay.io/example/os:latest".into(),
+            source_digest: Some("sha256:0123".into()),
+            target_image: "ostree-unverified-registry:quay.io/example/os:latest".into(),
+            composefs_backend: false,
+            stateroot: "default".into(),
+            install_config: None,
+            target: PlannedTarget::Filesystem {
+                root_path: "/target".into(),
+                replace: Some(ReplaceMode::Alongside),
+                boot_mount_spec: Some("UUID=abcd".into()),
+            },
+            filesystem: Some("xfs".into()),
+            bootloader: PlannedBootloader::Bootupd,
+            kargs: PlannedKargs {
+                root: vec![
+                    "root=UUID=1234".into(),
+                    "rw".into(),
+                    "boot=UUID=abcd".into(),
+                ],
+                kargs_d: vec!["console=ttyS0".into()],
+                ..Default::default()
+            },
+            bound_images_mode: BoundImagesOpt::Stored,
+            bound_images: vec!["quay.io/example/app:latest".into()],
+            selinux: "enabled",
+            notes: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn test_human_readable() {
+        let mut buf = Vec::new();
+        human_readable_output(&mut buf, &test_plan()).unwrap();
+        let buf = String::from_utf8(buf).unwrap();
+        similar_asserts::assert_eq!(
+            buf,
+            indoc::indoc! { r"
+                Source image: containers-storage:quay.io/example/os:latest
+                Digest: sha256:0123
+                Target image: ostree-unverified-registry:quay.io/example/os:latest
+                Backend: ostree
+                Stateroot: default
+                Target filesystem: /target
+                Replace mode: alongside
+                Boot mount: UUID=abcd
+                Root filesystem: xfs
+                Bootloader: bootupd
+                SELinux: enabled
+                Kernel arguments:
+                  root: root=UUID=1234 rw boot=UUID=abcd
+                  kargs.d: console=ttyS0
+                Bound images (stored):
+                  quay.io/example/app:latest
+            "}
+        );
+    }
+
+    #[test]
+    fn test_json() {
+        let v = serde_json::to_value(test_plan()).unwrap();
+        assert_eq!(v["target"]["type"], "filesystem");
+        assert_eq!(v["target"]["replace"], "alongside");
+        assert_eq!(v["bootloader"], "bootupd");
+        assert_eq!(v["kargs"]["kargs-d"][0], "console=ttyS0");
+        assert_eq!(v["bound-images-mode"], "stored");
+    }
+}
@@ -9,31 +9,44 @@ pub(crate) mod bootc_kargs;
 mod bootloader;
 mod boundimage;
 mod cfsctl;
+mod cleanup;
 pub mod cli;
 mod composefs_consts;
 mod containerenv;
 pub(crate) mod deploy;
+mod diff;
 mod discoverable_partition_specification;
+mod disk_space;
+mod factory_reset;
 pub(crate) mod fsck;
 pub(crate) mod generator;
 mod glyph;
+mod history;
+mod hooks;
 mod image;
+mod image_constraints;
 mod install;
 pub(crate) mod journal;
 mod k8sapitypes;
 mod lints;
 mod lsm;
 pub(crate) mod metadata;
+mod packages;
 mod parsers;
 mod podman;
 mod podstorage;
 mod progress_jsonl;
 mod reboot;
+mod secure_boot;
 pub mod spec;
 mod status;
+mod status_export;
 mod store;
+mod tag_policy;
 mod task;
+mod usr_overlay;
 mod utils;
+mod varlink;
 
 #[cfg(feature = "docgen")]
 mod cli_json;
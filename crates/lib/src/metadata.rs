@@ -2,3 +2,7 @@
 pub(crate) const BOOTC_COMPAT_LABEL: &str = "containers.bootc";
 /// The current single well-known value for the label.
 pub(crate) const COMPAT_LABEL_V1: &str = "1";
+/// The minimum version of the booted image which may update directly to an image.
+pub(crate) const MIN_SOURCE_VERSION_LABEL: &str = "containers.bootc.min-source-version";
+/// The minimum version of bootc required to deploy an image.
+pub(crate) const MIN_BOOTC_VERSION_LABEL: &str = "containers.bootc.min-bootc-version";
@@ -0,0 +1,490 @@
+//! # Comparing the packages of two images
+//!
+//! The package versions are read from the rpm database of an image in
+//! `/usr/lib/sysimage/rpm`. Where it cannot be read, e.g. for ostree images
+//! which are not deployed, the `ostree.components` annotations which chunked
+//! images carry on each layer are used instead; these only name the packages
+//! in a layer, so a package is reported as changed when its layer changed.
+
+use std::cmp::Ordering;
+use std::collections::{BTreeMap, BTreeSet};
+use std::io::Write;
+
+use anyhow::Result;
+use camino::Utf8Path;
+use cap_std_ext::cap_std::fs::Dir;
+use fn_error_context::context;
+use ostree_ext::container::{COMPONENT_SEPARATOR, CONTENT_ANNOTATION};
+use ostree_ext::oci_spec::image::ImageManifest;
+use serde::Serialize;
+
+use crate::task::Task;
+
+/// The rpm database, relative to the root
+const RPMDB_PATH: &str = "usr/lib/sysimage/rpm";
+
+/// Where the packages of an image were determined from
+#[derive(Debug, Clone, Copy, Serialize, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub(crate) enum PackageSource {
+    /// The rpm database
+    Rpmdb,
+    /// The `ostree.components` layer annotations
+    Annotations,
+}
+
+/// A package which was added, removed or changed
+#[derive(Debug, Serialize, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct PackageChange {
+    name: String,
+    /// The version before, if known
+    #[serde(skip_serializing_if = "Option::is_none")]
+    from: Option<String>,
+    /// The version after, if known
+    #[serde(skip_serializing_if = "Option::is_none")]
+    to: Option<String>,
+}
+
+/// The differences between the packages of two images
+#[derive(Debug, Serialize, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct PackageDiff {
+    source: PackageSource,
+    added: Vec<PackageChange>,
+    removed: Vec<PackageChange>,
+    upgraded: Vec<PackageChange>,
+    downgraded: Vec<PackageChange>,
+    /// Packages in layers which changed, or with rpm database versions which
+    /// changed without a newer or older highest version
+    changed: Vec<PackageChange>,
+}
+
+/// The packages of an image: by name, the versions from the rpm database in
+/// ascending order or the digest of the layer they are in
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct Packages {
+    source: PackageSource,
+    packages: BTreeMap<String, Vec<String>>,
+}
+
+impl Packages {
+    /// Query the rpm database of the image at `root`, which is mounted at `path`
+    #[context("Querying rpm database")]
+    pub(crate) fn from_rpmdb(root: &Dir, path: &Utf8Path) -> Result<Option<Self>> {
+        if !root.try_exists(RPMDB_PATH)? {
+            return Ok(None);
+        }
+        let out = Task::new("Querying rpm database", "rpm")
+            .args([
+                "--root",
+                path.as_str(),
+                "--dbpath",
+                &format!("/{RPMDB_PATH}"),
+            ])
+            .args([
+                "-qa",
+                "--qf",
+                "%{NAME} %|EPOCH?{%{EPOCH}:}:{}|%{VERSION}-%{RELEASE}.%{ARCH}\\n",
+            ])
+            .quiet()
+            .read()?;
+        Ok(Some(Self::parse_rpmqa(&out)))
+    }
+
+    /// Parse `NAME EVRA` lines; installonly packages like the kernel may have
+    /// multiple versions
+    fn parse_rpmqa(out: &str) -> Self {
+        let mut packages = BTreeMap::<String, Vec<String>>::new();
+        for (name, version) in out.lines().filter_map(|l| l.split_once(' ')) {
+            packages
+                .entry(name.to_owned())
+                .or_default()
+                .push(version.to_owned());
+        }
+        for versions in packages.values_mut() {
+            versions.sort_by(|a, b| rpm_evr_cmp(a, b).then_with(|| a.cmp(b)));
+            versions.dedup();
+        }
+        Self {
+            source: PackageSource::Rpmdb,
+            packages,
+        }
+    }
+
+    /// Read the packages from the layer annotations of a chunked image
+    pub(crate) fn from_manifest(manifest: &ImageManifest) -> Option<Self> {
+        let mut packages = BTreeMap::new();
+        for layer in manifest.layers() {
+            let Some(components) = layer
+                .annotations()
+                .as_ref()
+                .and_then(|a| a.get(CONTENT_ANNOTATION))
+            else {
+                continue;
+            };
+            for name in components.split(COMPONENT_SEPARATOR) {
+                packages.insert(name.to_owned(), vec![layer.digest().to_string()]);
+            }
+        }
+        (!packages.is_empty()).then_some(Self {
+            source: PackageSource::Annotations,
+            packages,
+        })
+    }
+}
+
+/// Compare the packages of two images, given in order of preference of their
+/// sources, using the first source which is available for both.
+pub(crate) fn diff_packages(from: &[Packages], to: &[Packages]) -> Option<PackageDiff> {
+    from.iter().find_map(|f| {
+        let t = to.iter().find(|t| t.source == f.source)?;
+        Some(diff_same_source(f, t))
+    })
+}
+
+fn diff_same_source(from: &Packages, to: &Packages) -> PackageDiff {
+    let with_versions = from.source == PackageSource::Rpmdb;
+    let version = |v: &Vec<String>| with_versions.then(|| v.join(" "));
+
+    let mut diff = PackageDiff {
+        source: from.source,
+        added: Vec::new(),
+        removed: Vec::new(),
+        upgraded: Vec::new(),
+        downgraded: Vec::new(),
+        changed: Vec::new(),
+    };
+    let names = from
+        .packages
+        .keys()
+        .chain(to.packages.keys())
+        .collect::<BTreeSet<_>>();
+    for name in names {
+        let (from_v, to_v) = (from.packages.get(name), to.packages.get(name));
+        let list = match (from_v, to_v) {
+            (None, Some(_)) => &mut diff.added,
+            (Some(_), None) => &mut diff.removed,
+            (Some(f), Some(t)) if f == t => continue,
+            (Some(_), Some(_)) if !with_versions => &mut diff.changed,
+            // Compare the highest of the installed versions
+            (Some(f), Some(t)) => match f.last().zip(t.last()).map(|(f, t)| rpm_evr_cmp(f, t)) {
+                Some(Ordering::Greater) => &mut diff.downgraded,
+                Some(Ordering::Less) => &mut diff.upgraded,
+                _ => &mut diff.changed,
+            },
+            (None, None) => unreachable!(),
+        };
+        list.push(PackageChange {
+            name: name.clone(),
+            from: from_v.and_then(version),
+            to: to_v.and_then(version),
+        });
+    }
+    diff
+}
+
+/// Split `[EPOCH:]VERSION-RELEASE[.ARCH]` into the epoch, version and release
+fn split_evr(s: &str) -> (u64, &str, &str) {
+    let (epoch, vr) = match s.split_once(':') {
+        Some((epoch, rest)) if epoch.bytes().all(|c| c.is_ascii_digit()) => {
+            (epoch.parse::<u64>().unwrap_or_default(), rest)
+        }
+        _ => (0, s),
+    };
+    let (version, release) = vr.rsplit_once('-').unwrap_or((vr, ""));
+    // The versions from the rpm database end in the architecture
+    let release = release.rsplit_once('.').map_or(release, |(r, _arch)| r);
+    (epoch, version, release)
+}
+
+/// Compare two `[EPOCH:]VERSION-RELEASE[.ARCH]` strings like rpm does: by epoch,
+/// then version, then release, ignoring the architecture
+fn rpm_evr_cmp(a: &str, b: &str) -> Ordering {
+    let (a_epoch, a_version, a_release) = split_evr(a);
+    let (b_epoch, b_version, b_release) = split_evr(b);
+    a_epoch
+        .cmp(&b_epoch)
+        .then_with(|| rpmvercmp(a_version, b_version))
+        .then_with(|| rpmvercmp(a_release, b_release))
+}
+
+/// The version comparison of rpm: alphanumeric segments are compared in turn,
+/// numeric ones numerically and numeric ones are newer than alphabetic ones,
+/// `~` sorts before anything and `^` after the end of a version.
+pub(crate) fn rpmvercmp(a: &str, b: &str) -> Ordering {
+    let is_sep = |c: char| !c.is_ascii_alphanumeric() && c != '~' && c != '^';
+    let (mut a, mut b) = (a, b);
+    loop {
+        a = a.trim_start_matches(is_sep);
+        b = b.trim_start_matches(is_sep);
+
+        for special in ['~', '^'] {
+            match (a.starts_with(special), b.starts_with(special)) {
+                (true, true) => {
+                    a = &a[1..];
+                    b = &b[1..];
+                }
+                (true, false) if special == '~' => return Ordering::Less,
+                (false, true) if special == '~' => return Ordering::Greater,
+                // A caret sorts after the end of a version, but before anything else
+                (true, false) if b.is_empty() => return Ordering::Greater,
+                (true, false) => return Ordering::Less,
+                (false, true) if a.is_empty() => return Ordering::Less,
+                (false, true) => return Ordering::Greater,
+                (false, false) => {}
+            }
+        }
+        if a.is_empty() || b.is_empty() {
+            break;
+        }
+
+        let numeric = a.starts_with(|c: char| c.is_ascii_digit());
+        let segment_end = |s: &str| {
+            s.find(|c: char| {
+                if numeric {
+                    !c.is_ascii_digit()
+                } else {
+                    !c.is_ascii_alphabetic()
+                }
+            })
+            .unwrap_or(s.len())
+        };
+        let (a_seg, a_rest) = a.split_at(segment_end(a));
+        let (b_seg, b_rest) = b.split_at(segment_end(b));
+        if b_seg.is_empty() {
+            // Numeric segments are newer than alphabetic ones
+            return if numeric {
+                Ordering::Greater
+            } else {
+                Ordering::Less
+            };
+        }
+        let ord = if numeric {
+            let a_seg = a_seg.trim_start_matches('0');
+            let b_seg = b_seg.trim_start_matches('0');
+            a_seg.len().cmp(&b_seg.len()).then_with(|| a_seg.cmp(b_seg))
+        } else {
+            a_seg.cmp(b_seg)
+        };
+        if ord != Ordering::Equal {
+            return ord;
+        }
+        a = a_rest;
+        b = b_rest;
+    }
+    a.len().cmp(&b.len())
+}
+
+/// Write the package changes in human readable form
+pub(crate) fn human_readable_output(mut out: impl Write, diff: &PackageDiff) -> Result<()> {
+    let lists = [
+        ("Upgraded", &diff.upgraded),
+        ("Downgraded", &diff.downgraded),
+        ("Changed", &diff.changed),
+        ("Added", &diff.added),
+        ("Removed", &diff.removed),
+    ];
+    if lists.iter().all(|(_, l)| l.is_empty()) {
+        writeln!(out, "Packages: No changes")?;
+        return Ok(());
+    }
+    writeln!(out, "Packages:")?;
+    for (name, list) in lists {
+        for p in list {
+            write!(out, "  {name}: {}", p.name)?;
+            match (&p.from, &p.to) {
+                (Some(from), Some(to)) => write!(out, " {from} -> {to}")?,
+                (Some(v), None) | (None, Some(v)) => write!(out, " {v}")?,
+                (None, None) => {}
+            }
+            writeln!(out)?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_rpmvercmp() {
+        let cases = [
+            ("1.0", "1.0", Ordering::Equal),
+            ("1.0", "2.0", Ordering::Less),
+            ("2.0.1", "2.0", Ordering::Greater),
+            ("1.10", "1.9", Ordering::Greater),
+            ("1.010", "1.10", Ordering::Equal),
+            ("1.0a", "1.0", Ordering::Greater),
+            ("1.a", "1.1", Ordering::Less),
+            ("1.0~rc1", "1.0", Ordering::Less),
+            ("1.0~rc1", "1.0~rc2", Ordering::Less),
+            ("1.0^git1", "1.0", Ordering::Greater),
+            ("1.0^git1", "1.0.1", Ordering::Less),
+            ("6.12.0-1.fc41", "6.11.9-2.fc41", Ordering::Greater),
+        ];
+        for (a, b, expected) in cases {
+            assert_eq!(rpmvercmp(a, b), expected, "{a} vs {b}");
+            assert_eq!(rpmvercmp(b, a), expected.reverse(), "{b} vs {a}");
+        }
+    }
+
+    #[test]
+    fn test_rpm_evr_cmp() {
+        let cases = [
+            ("1:1.0-1", "2.0-1", Ordering::Greater),
+            ("1.0-2", "1.0.1-1", Ordering::Less),
+            ("1.0-10.fc41.x86_64", "1.0-9.fc41.x86_64", Ordering::Greater),
+            ("1.0-1.fc41.x86_64", "1.0-1.fc41.noarch", Ordering::Equal),
+            (
+                "0:3.2.2-2.fc41.x86_64",
+                "3.2.2-2.fc41.x86_64",
+                Ordering::Equal,
+            ),
+            (
+                "2:9.1.0-1.fc41.x86_64",
+                "1:9.2.0-1.fc41.x86_64",
+                Ordering::Greater,
+            ),
+        ];
+        for (a, b, expected) in cases {
+            assert_eq!(rpm_evr_cmp(a, b), expected, "{a} vs {b}");
+            assert_eq!(rpm_evr_cmp(b, a), expected.reverse(), "{b} vs {a}");
+        }
+        assert_eq!(split_evr("1:3.2.2-2.fc41.x86_64"), (1, "3.2.2", "2.fc41"));
+    }
+
+    #[test]
+    fn test_diff_packages() {
+        let from = Packages::parse_rpmqa(indoc::indoc! { "
+            bash 5.2.26-3.fc41.x86_64
+            kernel 6.11.0-1.fc41.x86_64
+            vim-minimal 2:9.1.0-1.fc41.x86_64
+            openssl 1:3.2.2-2.fc41.x86_64
+        " });
+        let to = Packages::parse_rpmqa(indoc::indoc! { "
+            bash 5.2.26-3.fc41.x86_64
+            kernel 6.12.0-1.fc41.x86_64
+            kernel 6.11.0-1.fc41.x86_64
+            openssl 1:3.2.1-1.fc41.x86_64
+            strace 6.10-1.fc41.x86_64
+        " });
+        let diff = diff_same_source(&from, &to);
+        assert_eq!(diff.upgraded[0].name, "kernel");
+        assert_eq!(
+            to.packages["kernel"],
+            ["6.11.0-1.fc41.x86_64", "6.12.0-1.fc41.x86_64"]
+        );
+        assert_eq!(
+            diff.upgraded[0].to.as_deref(),
+            Some("6.11.0-1.fc41.x86_64 6.12.0-1.fc41.x86_64")
+        );
+        assert_eq!(diff.downgraded[0].name, "openssl");
+        assert_eq!(diff.added[0].name, "strace");
+        assert_eq!(diff.removed[0].name, "vim-minimal");
+        assert!(diff.changed.is_empty());
+
+        let mut out = Vec::new();
+        human_readable_output(&mut out, &diff).unwrap();
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            indoc::indoc! { "
+                Packages:
+                  Upgraded: kernel 6.11.0-1.fc41.x86_64 -> 6.11.0-1.fc41.x86_64 6.12.0-1.fc41.x86_64
+                  Downgraded: openssl 1:3.2.2-2.fc41.x86_64 -> 1:3.2.1-1.fc41.x86_64
+                  Added: strace 6.10-1.fc41.x86_64
+                  Removed: vim-minimal 2:9.1.0-1.fc41.x86_64
+            " }
+        );
+    }
+
+    #[test]
+    fn test_diff_installonly_packages() {
+        let from = Packages::parse_rpmqa(indoc::indoc! { "
+            kernel 6.13.0-1.fc41.x86_64
+            kernel 6.12.0-1.fc41.x86_64
+            kernel-core 6.11.0-1.fc41.x86_64
+            kernel-core 6.12.0-1.fc41.x86_64
+        " });
+        let to = Packages::parse_rpmqa(indoc::indoc! { "
+            kernel 6.12.5-1.fc41.x86_64
+            kernel-core 6.12.0-1.fc41.x86_64
+        " });
+        assert_eq!(
+            from.packages["kernel"],
+            ["6.12.0-1.fc41.x86_64", "6.13.0-1.fc41.x86_64"]
+        );
+        let diff = diff_same_source(&from, &to);
+        // The newest kernel is older, even though 6.12.0 < 6.12.5
+        assert_eq!(
+            diff.downgraded,
+            [PackageChange {
+                name: "kernel".into(),
+                from: Some("6.12.0-1.fc41.x86_64 6.13.0-1.fc41.x86_64".into()),
+                to: Some("6.12.5-1.fc41.x86_64".into()),
+            }]
+        );
+        // Only an older version was removed
+        assert_eq!(diff.changed[0].name, "kernel-core");
+        assert!(diff.upgraded.is_empty());
+    }
+
+    #[test]
+    fn test_from_manifest() -> Result<()> {
+        let manifest = |layers: serde_json::Value| -> Result<ImageManifest> {
+            Ok(serde_json::from_value(serde_json::json!({
+                "schemaVersion": 2,
+                "config": {
+                    "mediaType": "application/vnd.oci.image.config.v1+json",
+                    "digest": format!("sha256:{}", "0".repeat(64)),
+                    "size": 1
+                },
+                "layers": layers
+            }))?)
+        };
+        let layer = |n: char, components: Option<&str>| {
+            let mut l = serde_json::json!({
+                "mediaType": "application/vnd.oci.image.layer.v1.tar+gzip",
+                "digest": format!("sha256:{}", n.to_string().repeat(64)),
+                "size": 1
+            });
+            if let Some(c) = components {
+                l["annotations"][CONTENT_ANNOTATION] = c.into();
+            }
+            l
+        };
+
+        let unchunked = manifest(serde_json::json!([layer('1', None)]))?;
+        assert!(Packages::from_manifest(&unchunked).is_none());
+
+        let from = manifest(serde_json::json!([
+            layer('1', None),
+            layer('2', Some("bash,glibc")),
+            layer('3', Some("kernel")),
+        ]))?;
+        let to = manifest(serde_json::json!([
+            layer('1', None),
+            layer('2', Some("bash,glibc")),
+            layer('4', Some("kernel,strace")),
+        ]))?;
+        let from = Packages::from_manifest(&from).unwrap();
+        let to = Packages::from_manifest(&to).unwrap();
+        let rpmdb = Packages::parse_rpmqa("kernel 6.12.0-1.fc41.x86_64\n");
+        // The rpm database is only used if both images have one
+        let diff = diff_packages(&[from], &[rpmdb.clone(), to]).unwrap();
+        assert_eq!(diff.source, PackageSource::Annotations);
+        assert_eq!(
+            diff.changed,
+            [PackageChange {
+                name: "kernel".into(),
+                from: None,
+                to: None
+            }]
+        );
+        assert_eq!(diff.added[0].name, "strace");
+        assert!(diff.upgraded.is_empty());
+        assert!(diff_packages(&[], &[rpmdb]).is_none());
+        Ok(())
+    }
+}
@@ -1,5 +1,8 @@
 //! Output progress data using the json-lines format. For more information
 //! see <https://jsonlines.org/>.
+//!
+//! Since version 0.2.0 of the protocol, operations are divided into
+//! [`Phase`]s, and the last event is always a [`Event::Result`].
 
 use anyhow::Result;
 use canon_json::CanonJsonSerialize;
@@ -18,7 +21,7 @@ use tokio::sync::Mutex;
 const REFRESH_HZ: u16 = 5;
 
 /// Semantic version of the protocol.
-const API_VERSION: &str = "0.1.0";
+const API_VERSION: &str = "0.2.0";
 
 /// An incremental update to e.g. a container image layer download.
 /// The first time a given "subtask" name is seen, a new progress bar should be created.
@@ -70,6 +73,42 @@ pub struct SubTaskStep<'t> {
     pub completed: bool,
 }
 
+/// A phase of an operation.
+#[derive(Debug, Clone, Copy, serde::Serialize, serde::Deserialize, JsonSchema, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum Phase {
+    /// Fetching the container image
+    Pull,
+    /// Preparing the deployment, e.g. running the pre-stage hooks
+    Stage,
+    /// Writing the deployment
+    Deploy,
+    /// Writing or reordering the boot entries
+    BootEntries,
+    /// Fetching the logically bound images
+    BoundImages,
+    /// Removing unused images and deployments
+    Cleanup,
+    /// Partitioning the target block device (install only)
+    Partition,
+    /// Creating filesystems on the target block device (install only)
+    Mkfs,
+    /// Labeling the target filesystem for SELinux (install only)
+    Relabel,
+    /// Installing the bootloader (install only)
+    Bootloader,
+    /// Trimming and freezing the target filesystems (install only)
+    Finalize,
+}
+
+/// Whether an operation succeeded.
+#[derive(Debug, Clone, Copy, serde::Serialize, serde::Deserialize, JsonSchema, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum Outcome {
+    Success,
+    Failure,
+}
+
 /// An event emitted as JSON.
 #[derive(Debug, Clone, serde::Serialize, serde::Deserialize, JsonSchema, PartialEq, Eq)]
 #[serde(
@@ -135,6 +174,31 @@ pub enum Event<'t> {
         /// The currently running subtasks.
         subtasks: Vec<SubTaskStep<'t>>,
     },
+    /// The beginning of a phase of the operation
+    PhaseBegin {
+        phase: Phase,
+        /// A human readable description of the phase if i18n is not available.
+        #[serde(borrow)]
+        description: Cow<'t, str>,
+    },
+    /// The successful end of a phase of the operation
+    PhaseEnd { phase: Phase },
+    /// A problem which does not stop the operation
+    Warning {
+        #[serde(borrow)]
+        message: Cow<'t, str>,
+    },
+    /// The operation changed the next boot; a reboot is required to apply it
+    RebootRequired,
+    /// The end of the operation; this is the last event
+    Result {
+        outcome: Outcome,
+        /// The phase which failed, if the failure happened during one
+        phase: Option<Phase>,
+        /// A human readable description of the failure
+        #[serde(borrow)]
+        message: Option<Cow<'t, str>>,
+    },
 }
 
 #[derive(Debug, Clone, PartialEq, Eq)]
@@ -158,6 +222,8 @@ struct ProgressWriterInner {
     /// true if we sent the initial Start message
     sent_start: bool,
     last_write: Option<std::time::Instant>,
+    /// The phase in progress
+    phase: Option<Phase>,
     fd: BufWriter<Sender>,
 }
 
@@ -180,6 +246,7 @@ impl From<Sender> for ProgressWriter {
         let inner = ProgressWriterInner {
             sent_start: false,
             last_write: None,
+            phase: None,
             fd: BufWriter::new(value),
         };
         Self {
@@ -265,6 +332,86 @@ impl ProgressWriter {
         }
     }
 
+    /// Begin the phase `phase` of the operation.
+    pub(crate) async fn begin_phase(&self, phase: Phase, description: &str) {
+        self.send(Event::PhaseBegin {
+            phase,
+            description: description.into(),
+        })
+        .await;
+        self.set_phase(Some(phase)).await;
+    }
+
+    /// End the phase `phase` successfully; a phase which is not ended is
+    /// reported as the one which failed in the result.
+    pub(crate) async fn end_phase(&self, phase: Phase) {
+        self.set_phase(None).await;
+        self.send(Event::PhaseEnd { phase }).await;
+    }
+
+    /// Like [`Self::begin_phase`], for use from a blocking task.
+    #[cfg(feature = "install-to-disk")]
+    pub(crate) fn begin_phase_blocking(&self, phase: Phase, description: &str) {
+        tokio::runtime::Handle::current().block_on(self.begin_phase(phase, description))
+    }
+
+    /// Like [`Self::end_phase`], for use from a blocking task.
+    #[cfg(feature = "install-to-disk")]
+    pub(crate) fn end_phase_blocking(&self, phase: Phase) {
+        tokio::runtime::Handle::current().block_on(self.end_phase(phase))
+    }
+
+    /// Run `f` as the phase `phase` of the operation.
+    pub(crate) async fn phase<T>(
+        &self,
+        phase: Phase,
+        description: &str,
+        f: impl std::future::Future<Output = Result<T>>,
+    ) -> Result<T> {
+        self.begin_phase(phase, description).await;
+        let r = f.await?;
+        self.end_phase(phase).await;
+        Ok(r)
+    }
+
+    async fn set_phase(&self, phase: Option<Phase>) {
+        if let Some(inner) = self.inner.lock().await.as_mut() {
+            inner.phase = phase;
+        }
+    }
+
+    /// Send a warning.
+    pub(crate) async fn warn(&self, message: &str) {
+        self.send(Event::Warning {
+            message: message.into(),
+        })
+        .await;
+    }
+
+    /// Signal that a reboot is required to apply the changes.
+    pub(crate) async fn reboot_required(&self) {
+        self.send(Event::RebootRequired).await;
+    }
+
+    /// Send the result of the operation.
+    pub(crate) async fn finish<T>(&self, r: &Result<T>) {
+        let phase = match r {
+            Ok(_) => None,
+            Err(_) => self.inner.lock().await.as_ref().and_then(|i| i.phase),
+        };
+        let message = r.as_ref().err().map(|e| format!("{e:#}").into());
+        self.send(Event::Result {
+            outcome: if r.is_ok() {
+                Outcome::Success
+            } else {
+                Outcome::Failure
+            },
+            phase,
+            message,
+        })
+        .await;
+    }
+
     /// Flush remaining data and return the underlying file.
     #[allow(dead_code)]
     pub(crate) async fn into_inner(self) -> Result<Option<Sender>> {
@@ -308,6 +455,19 @@ mod test {
                 steps_total: 3,
                 subtasks: Vec::new(),
             },
+            Event::PhaseBegin {
+                phase: Phase::Pull,
+                description: "Fetching image".into(),
+            },
+            Event::Warning {
+                message: "somewarning".into(),
+            },
+            Event::RebootRequired,
+            Event::Result {
+                outcome: Outcome::Failure,
+                phase: Some(Phase::Pull),
+                message: Some("someerror".into()),
+            },
         ];
         let (send, recv) = tokio::net::unix::pipe::pipe()?;
         let testvalues_sender = testvalues.iter().cloned();
@@ -341,4 +501,35 @@ mod test {
         tokio::try_join!(sender, receiver)?;
         Ok(())
     }
+
+    #[tokio::test]
+    async fn test_phase_result() -> Result<()> {
+        let (send, recv) = tokio::net::unix::pipe::pipe()?;
+        let w = ProgressWriter::try_from(send)?;
+        w.phase(Phase::Stage, "Staging", async { anyhow::Ok(()) })
+            .await?;
+        let r = w
+            .phase(Phase::Deploy, "Deploying", async {
+                Err::<(), _>(anyhow::anyhow!("somefailure"))
+            })
+            .await;
+        w.finish(&r).await;
+        drop(w);
+
+        let mut lines = BufReader::new(recv).lines();
+        let mut events = Vec::new();
+        while let Some(line) = lines.next_line().await? {
+            events.push(line);
+        }
+        assert_eq!(events.len(), 5);
+        assert_eq!(
+            events[3],
+            r#"{"description":"Deploying","phase":"deploy","type":"PhaseBegin"}"#
+        );
+        assert_eq!(
+            events[4],
+            r#"{"message":"somefailure","outcome":"failure","phase":"deploy","type":"Result"}"#
+        );
+        Ok(())
+    }
 }
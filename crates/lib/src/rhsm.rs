@@ -1,4 +1,6 @@
 //! Integration with Red Hat Subscription Manager
+//!
+//! The facts are rendered from the exported status; see [`crate::status_export`].
 
 use anyhow::{Context, Result};
 use cap_std::fs::Dir;
@@ -6,6 +8,8 @@ use cap_std_ext::{cap_std, dirext::CapStdExtDirExt};
 use fn_error_context::context;
 use serde::Serialize;
 
+use crate::status_export::{ExportedImage, StatusExport};
+
 const FACTS_PATH: &str = "etc/rhsm/facts/bootc.facts";
 
 #[derive(Serialize, PartialEq, Eq, Debug, Default)]
@@ -37,39 +41,26 @@ struct RhsmFacts {
 }
 
 /// Return the image reference, version and digest as owned strings.
-/// A missing version is serialized as the empty string.
-fn status_to_strings(imagestatus: &crate::spec::ImageStatus) -> (String, String, String) {
-    let image = imagestatus.image.image.clone();
-    let version = imagestatus.version.as_ref().cloned().unwrap_or_default();
-    let digest = imagestatus.image_digest.clone();
-    (image, version, digest)
+/// A missing image or version is serialized as the empty string.
+fn image_to_strings(image: Option<&ExportedImage>) -> (String, String, String) {
+    image
+        .map(|i| {
+            let version = i.version.as_ref().cloned().unwrap_or_default();
+            (i.image.clone(), version, i.digest.clone())
+        })
+        .unwrap_or_default()
 }
 
-impl From<crate::spec::HostStatus> for RhsmFacts {
-    fn from(hoststatus: crate::spec::HostStatus) -> Self {
-        let (booted_image, booted_version, booted_digest) = hoststatus
-            .booted
-            .as_ref()
-            .and_then(|boot_entry| boot_entry.image.as_ref().map(status_to_strings))
-            .unwrap_or_default();
-
-        let (staged_image, staged_version, staged_digest) = hoststatus
-            .staged
-            .as_ref()
-            .and_then(|boot_entry| boot_entry.image.as_ref().map(status_to_strings))
-            .unwrap_or_default();
-
-        let (rollback_image, rollback_version, rollback_digest) = hoststatus
-            .rollback
-            .as_ref()
-            .and_then(|boot_entry| boot_entry.image.as_ref().map(status_to_strings))
-            .unwrap_or_default();
-
-        let (available_image, available_version, available_digest) = hoststatus
-            .booted
-            .as_ref()
-            .and_then(|boot_entry| boot_entry.cached_update.as_ref().map(status_to_strings))
-            .unwrap_or_default();
+impl From<&StatusExport> for RhsmFacts {
+    fn from(export: &StatusExport) -> Self {
+        let (booted_image, booted_version, booted_digest) =
+            image_to_strings(export.booted.as_ref());
+        let (staged_image, staged_version, staged_digest) =
+            image_to_strings(export.staged.as_ref());
+        let (rollback_image, rollback_version, rollback_digest) =
+            image_to_strings(export.rollback.as_ref());
+        let (available_image, available_version, available_digest) =
+            image_to_strings(export.available_update.as_ref());
 
         Self {
             booted_image,
@@ -91,11 +82,8 @@ impl From<crate::spec::HostStatus> for RhsmFacts {
 /// Publish facts for subscription-manager consumption
 #[context("Publishing facts")]
 pub(crate) async fn publish_facts(root: &Dir) -> Result<()> {
-    let sysroot = super::cli::get_storage().await?;
-    let ostree = sysroot.get_ostree()?;
-    let (_, _, host) = crate::status::get_status_require_booted(ostree)?;
-
-    let facts = RhsmFacts::from(host.status);
+    let export = crate::status_export::get_export(root).await?;
+    let facts = RhsmFacts::from(&export);
     root.atomic_replace_with(FACTS_PATH, |w| {
         serde_json::to_writer_pretty(w, &facts)?;
         anyhow::Ok(())
@@ -114,7 +102,7 @@ mod tests {
     fn test_rhsm_facts_from_host() {
         let host: Host = serde_yaml::from_str(include_str!("fixtures/spec-staged-booted.yaml"))
             .expect("No spec found");
-        let facts = RhsmFacts::from(host.status);
+        let facts = RhsmFacts::from(&StatusExport::new(&host, None));
 
         assert_eq!(
             facts,
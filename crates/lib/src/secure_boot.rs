@@ -0,0 +1,374 @@
+//! # Secure Boot signing with local keys
+//!
+//! Organizations enrolling their own keys in the firmware's `db` can have
+//! `bootc install` sign the UKIs, UKI addons and systemd-boot binaries it
+//! writes to the ESP. The private key is either a file or, when an OpenSSL
+//! engine such as `pkcs11` is given, an identifier understood by that engine.
+//! Signing is done with `sbsign`.
+//!
+//! Additionally, signed `PK`, `KEK` and `db` updates can be installed to the ESP
+//! so that systemd-boot enrolls them on first boot.
+
+use std::path::Path;
+
+use anyhow::{Context, Result};
+use camino::{Utf8Path, Utf8PathBuf};
+use cap_std_ext::cap_std::fs::Dir;
+use cap_std_ext::dirext::CapStdExtDirExt;
+use fn_error_context::context;
+
+use crate::bootc_composefs::boot::BootType;
+use crate::install::InstallComposefsOpts;
+use crate::spec::Bootloader;
+use crate::task::Task;
+
+/// The directory in the ESP from which systemd-boot enrolls Secure Boot keys
+const LOADER_KEYS: &str = "loader/keys";
+/// The signed variable updates systemd-boot enrolls, in enrollment order
+const ENROLLMENT_FILES: &[&str] = &["db.auth", "KEK.auth", "PK.auth"];
+/// The systemd-boot binaries installed by `bootctl`, relative to the ESP
+const SYSTEMD_BOOT_DIRS: &[&str] = &["EFI/systemd", "EFI/BOOT"];
+
+/// A key and certificate to sign EFI binaries with
+#[derive(Debug, PartialEq, Eq)]
+pub(crate) struct SecureBootSigner {
+    key: String,
+    certificate: Utf8PathBuf,
+    engine: Option<String>,
+    /// Additional environment for `sbsign`, e.g. to configure a PKCS#11 module
+    env: Vec<(String, String)>,
+}
+
+impl SecureBootSigner {
+    /// The signer configured for an install, if any
+    pub(crate) fn from_opts(opts: &InstallComposefsOpts) -> Option<Self> {
+        let key = opts.secure_boot_key.clone()?;
+        let certificate = opts.secure_boot_certificate.clone()?;
+        Some(Self {
+            key,
+            certificate,
+            engine: opts.secure_boot_engine.clone(),
+            env: Vec::new(),
+        })
+    }
+
+    /// The `sbsign` invocation signing `input` to `output`
+    fn sbsign(&self, description: impl AsRef<str>, input: &Utf8Path, output: &Utf8Path) -> Task {
+        let mut task = Task::new(description, "sbsign")
+            .args(self.sbsign_args(input, output))
+            .quiet();
+        task.cmd.envs(self.env.iter().map(|(k, v)| (k, v)));
+        task
+    }
+
+    fn sbsign_args(&self, input: &Utf8Path, output: &Utf8Path) -> Vec<String> {
+        let mut args = vec![];
+        if let Some(engine) = self.engine.as_deref() {
+            args.extend(["--engine".into(), engine.into()]);
+        }
+        args.extend([
+            "--key".into(),
+            self.key.clone(),
+            "--cert".into(),
+            self.certificate.to_string(),
+            "--output".into(),
+            output.to_string(),
+            input.to_string(),
+        ]);
+        args
+    }
+
+    /// Sign an EFI binary in place
+    #[context("Signing {path}")]
+    pub(crate) fn sign_file(&self, path: &Utf8Path) -> Result<()> {
+        self.sbsign(format!("Signing {path}"), path, path).run()
+    }
+
+    /// Return a signed copy of an EFI binary
+    pub(crate) fn sign(&self, efi_bin: &[u8]) -> Result<Vec<u8>> {
+        let td = tempfile::tempdir()?;
+        let td = Utf8Path::from_path(td.path()).context("Non-UTF8 temporary directory")?;
+        let unsigned = td.join("unsigned.efi");
+        let signed = td.join("signed.efi");
+        std::fs::write(&unsigned, efi_bin)?;
+        self.sbsign("Signing EFI binary", &unsigned, &signed)
+            .run()?;
+        std::fs::read(&signed).with_context(|| format!("Reading {signed}"))
+    }
+
+    /// Sign the systemd-boot binaries in the ESP mounted at `esp`
+    #[context("Signing systemd-boot")]
+    pub(crate) fn sign_systemd_boot(&self, esp: &Utf8Path) -> Result<()> {
+        for dir in SYSTEMD_BOOT_DIRS {
+            let dir = esp.join(dir);
+            if !dir.try_exists()? {
+                continue;
+            }
+            for entry in dir.read_dir_utf8()? {
+                let path = entry?.into_path();
+                if path
+                    .extension()
+                    .is_some_and(|ext| ext.eq_ignore_ascii_case("efi"))
+                {
+                    self.sign_file(&path)?;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Ensure the Secure Boot options of an install can be honored for an image with
+/// boot entries of `boot_type`, installed with `bootloader`: signing is only done
+/// for UKIs, and only systemd-boot enrolls keys.
+pub(crate) fn require_supported(
+    opts: &InstallComposefsOpts,
+    boot_type: BootType,
+    bootloader: &Bootloader,
+) -> Result<()> {
+    let sign = opts.secure_boot_key.is_some();
+    let enroll = opts.secure_boot_enroll_keys.is_some();
+    if (sign || enroll) && boot_type != BootType::Uki {
+        anyhow::bail!(
+            "--secure-boot-key and --secure-boot-enroll-keys require an image with a UKI"
+        );
+    }
+    if enroll && *bootloader != Bootloader::Systemd {
+        anyhow::bail!("--secure-boot-enroll-keys requires systemd-boot");
+    }
+    Ok(())
+}
+
+/// Install the signed variable updates in `keys` to `loader/keys/<name>` in the ESP,
+/// where `<name>` is the name of the `keys` directory.
+#[context("Installing Secure Boot keys for enrollment")]
+pub(crate) fn install_enrollment_keys(esp: &Dir, keys: &Utf8Path) -> Result<()> {
+    let name = keys
+        .file_name()
+        .ok_or_else(|| anyhow::anyhow!("Invalid key directory {keys}"))?;
+    let src = Dir::open_ambient_dir(keys, cap_std_ext::cap_std::ambient_authority())
+        .with_context(|| format!("Opening {keys}"))?;
+
+    let contents = ENROLLMENT_FILES
+        .iter()
+        .map(|fname| {
+            src.read(fname)
+                .with_context(|| format!("Reading {keys}/{fname}"))
+        })
+        .collect::<Result<Vec<_>>>()?;
+
+    let dest = Path::new(LOADER_KEYS).join(name);
+    esp.create_dir_all(&dest)
+        .with_context(|| format!("Creating {dest:?}"))?;
+    let dest = esp.open_dir(&dest)?;
+
+    for (fname, contents) in ENROLLMENT_FILES.iter().zip(contents) {
+        dest.atomic_write(fname, contents)
+            .with_context(|| format!("Writing {fname}"))?;
+    }
+
+    println!("Installed Secure Boot keys for enrollment: {name}");
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use cap_std_ext::cap_std;
+
+    #[test]
+    fn test_sbsign_args() {
+        let mut signer = SecureBootSigner {
+            key: "/etc/pki/db.key".into(),
+            certificate: "/etc/pki/db.crt".into(),
+            engine: None,
+            env: Vec::new(),
+        };
+        let efi = Utf8Path::new("/boot/efi/EFI/Linux/foo.efi");
+        assert_eq!(
+            signer.sbsign_args(efi, efi),
+            [
+                "--key",
+                "/etc/pki/db.key",
+                "--cert",
+                "/etc/pki/db.crt",
+                "--output",
+                efi.as_str(),
+                efi.as_str()
+            ]
+        );
+
+        signer.engine = Some("pkcs11".into());
+        signer.key = "pkcs11:token=bootc;object=db".into();
+        assert_eq!(
+            &signer.sbsign_args(efi, efi)[..4],
+            [
+                "--engine",
+                "pkcs11",
+                "--key",
+                "pkcs11:token=bootc;object=db"
+            ]
+        );
+    }
+
+    #[test]
+    fn test_from_opts() {
+        let mut opts = InstallComposefsOpts::default();
+        assert_eq!(SecureBootSigner::from_opts(&opts), None);
+
+        opts.secure_boot_key = Some("/etc/pki/db.key".into());
+        opts.secure_boot_certificate = Some("/etc/pki/db.crt".into());
+        let signer = SecureBootSigner::from_opts(&opts).unwrap();
+        assert_eq!(signer.key, "/etc/pki/db.key");
+        assert_eq!(signer.engine, None);
+    }
+
+    #[test]
+    fn test_require_supported() {
+        let mut opts = InstallComposefsOpts::default();
+        require_supported(&opts, BootType::Bls, &Bootloader::Grub).unwrap();
+
+        opts.secure_boot_key = Some("/etc/pki/db.key".into());
+        opts.secure_boot_certificate = Some("/etc/pki/db.crt".into());
+        assert!(require_supported(&opts, BootType::Bls, &Bootloader::Systemd).is_err());
+        require_supported(&opts, BootType::Uki, &Bootloader::Grub).unwrap();
+
+        opts.secure_boot_enroll_keys = Some("/etc/pki/keys".into());
+        assert!(require_supported(&opts, BootType::Uki, &Bootloader::Grub).is_err());
+        require_supported(&opts, BootType::Uki, &Bootloader::Systemd).unwrap();
+    }
+
+    /// Signs systemd-boot with a key in a SoftHSM token, which requires SoftHSM,
+    /// sbsigntools, systemd-boot and the OpenSSL PKCS#11 engine
+    #[test]
+    fn test_sign_pkcs11_softhsm() -> Result<()> {
+        use std::process::Command;
+
+        let have = |cmd: &str, args: &[&str]| {
+            Command::new(cmd)
+                .args(args)
+                .output()
+                .is_ok_and(|o| o.status.success())
+        };
+        let efi = Path::new("/usr/lib/systemd/boot/efi")
+            .read_dir()
+            .into_iter()
+            .flatten()
+            .filter_map(|e| e.ok().map(|e| e.path()))
+            .find(|p| p.extension().is_some_and(|ext| ext == "efi"));
+        let tools = have("openssl", &["engine", "pkcs11", "-t"])
+            && have("softhsm2-util", &["--version"])
+            && have("sbsign", &["--version"])
+            && have("sbverify", &["--version"]);
+        let (Some(efi), true) = (efi, tools) else {
+            eprintln!("Skipping test; missing SoftHSM, sbsigntools, systemd-boot or the OpenSSL PKCS#11 engine");
+            return Ok(());
+        };
+
+        let td = tempfile::tempdir()?;
+        let td = Utf8Path::from_path(td.path()).context("Non-UTF8 temporary directory")?;
+        let tokens = td.join("tokens");
+        std::fs::create_dir(&tokens)?;
+        let conf = td.join("softhsm2.conf");
+        std::fs::write(&conf, format!("directories.tokendir = {tokens}\n"))?;
+        let env = [("SOFTHSM2_CONF".to_owned(), conf.to_string())];
+
+        let run = |cmd: &str, args: &[&str]| -> Result<()> {
+            let status = Command::new(cmd).args(args).envs(env.clone()).status()?;
+            anyhow::ensure!(status.success(), "{cmd}: {status}");
+            Ok(())
+        };
+
+        let (key, pk8, cert) = (td.join("db.key"), td.join("db.pk8"), td.join("db.crt"));
+        run(
+            "openssl",
+            &[
+                "req",
+                "-x509",
+                "-newkey",
+                "rsa:2048",
+                "-nodes",
+                "-days",
+                "1",
+                "-subj",
+                "/CN=bootc test db",
+                "-keyout",
+                key.as_str(),
+                "-out",
+                cert.as_str(),
+            ],
+        )?;
+        run(
+            "openssl",
+            &[
+                "pkcs8",
+                "-topk8",
+                "-nocrypt",
+                "-in",
+                key.as_str(),
+                "-out",
+                pk8.as_str(),
+            ],
+        )?;
+        run(
+            "softhsm2-util",
+            &[
+                "--init-token",
+                "--free",
+                "--label",
+                "bootc",
+                "--so-pin",
+                "0000",
+                "--pin",
+                "1234",
+            ],
+        )?;
+        run(
+            "softhsm2-util",
+            &[
+                "--import",
+                pk8.as_str(),
+                "--token",
+                "bootc",
+                "--label",
+                "db",
+                "--id",
+                "01",
+                "--pin",
+                "1234",
+            ],
+        )?;
+
+        let signer = SecureBootSigner {
+            key: "pkcs11:token=bootc;object=db;type=private;pin-value=1234".into(),
+            certificate: cert.clone(),
+            engine: Some("pkcs11".into()),
+            // sbsign loads SoftHSM through the engine
+            env: env.to_vec(),
+        };
+        let signed = td.join("signed.efi");
+        std::fs::write(&signed, signer.sign(&std::fs::read(efi)?)?)?;
+        run("sbverify", &["--cert", cert.as_str(), signed.as_str()])?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_install_enrollment_keys() -> Result<()> {
+        let td = tempfile::tempdir()?;
+        let keys = Utf8Path::from_path(td.path()).unwrap().join("mykeys");
+        std::fs::create_dir(&keys)?;
+        let esp = cap_std_ext::cap_tempfile::TempDir::new(cap_std::ambient_authority())?;
+
+        // All of PK, KEK and db are required
+        std::fs::write(keys.join("db.auth"), "db")?;
+        std::fs::write(keys.join("KEK.auth"), "kek")?;
+        assert!(install_enrollment_keys(&esp, &keys).is_err());
+        assert!(!esp.try_exists("loader/keys/mykeys")?);
+
+        std::fs::write(keys.join("PK.auth"), "pk")?;
+        install_enrollment_keys(&esp, &keys)?;
+        assert_eq!(esp.read_to_string("loader/keys/mykeys/PK.auth")?, "pk");
+        assert_eq!(esp.read_to_string("loader/keys/mykeys/db.auth")?, "db");
+        Ok(())
+    }
+}
@@ -67,6 +67,21 @@ pub struct HostSpec {
     /// If set, and there is a rollback deployment, it will be set for the next boot.
     #[serde(default)]
     pub boot_order: BootOrder,
+    /// If set, the tags of the image repository are used to select the image to deploy.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tag_policy: Option<TagPolicy>,
+}
+
+/// A policy selecting the image to deploy from the tags of a registry repository
+#[derive(Serialize, Deserialize, Default, Debug, Clone, PartialEq, Eq, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct TagPolicy {
+    /// Track the highest tag matching this semantic version range (e.g. `1.4.*`), instead of the tag of the image reference
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub semver: Option<String>,
+    /// Path to a file listing the image digests which may be deployed, one per line
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub allowed_digests: Option<String>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq, JsonSchema)]
@@ -134,6 +149,51 @@ impl ImageReference {
     }
 }
 
+impl ImageReference {
+    /// Returns this image reference pinned to `digest`, with its tag replaced by `tag`
+    /// if set.  Only the registry transport is supported.
+    pub(crate) fn resolve_to(&self, tag: Option<&str>, digest: &str) -> Result<Self> {
+        let transport = Transport::try_from(self.transport.as_str())?;
+        if !matches!(transport, Transport::Registry) {
+            anyhow::bail!("Cannot resolve tags with transport {}", self.transport);
+        }
+        let reference: Reference = self.image.parse()?;
+        let name = format!("{}/{}", reference.registry(), reference.repository());
+        let image = match tag.or(reference.tag()) {
+            Some(tag) => format!("{name}:{tag}@{digest}"),
+            None => format!("{name}@{digest}"),
+        };
+        // Validate the result
+        let _: Reference = image.parse()?;
+        Ok(ImageReference {
+            image,
+            transport: self.transport.clone(),
+            signature: self.signature.clone(),
+        })
+    }
+
+    /// Whether this and `other` refer to the same repository.  For the registry
+    /// transport, tags and digests are ignored.
+    pub(crate) fn same_repository(&self, other: &Self) -> bool {
+        if self.transport != other.transport {
+            return false;
+        }
+        if !matches!(
+            Transport::try_from(self.transport.as_str()),
+            Ok(Transport::Registry)
+        ) {
+            return self.image == other.image;
+        }
+        match (
+            self.image.parse::<Reference>(),
+            other.image.parse::<Reference>(),
+        ) {
+            (Ok(a), Ok(b)) => a.registry() == b.registry() && a.repository() == b.repository(),
+            _ => self.image == other.image,
+        }
+    }
+}
+
 /// The status of the booted image
 #[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq, JsonSchema)]
 #[serde(rename_all = "camelCase")]
@@ -148,6 +208,40 @@ pub struct ImageStatus {
     pub image_digest: String,
     /// The hardware architecture of this image
     pub architecture: String,
+    /// The digest-pinned reference the tag policy resolved the image to, if any
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub resolved_image: Option<ImageReference>,
+}
+
+/// The kind of writable overlay on `/usr`
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub enum UsrOverlayState {
+    /// Changes to `/usr` will be discarded on reboot
+    Transient,
+    /// Changes to `/usr` persist across reboots of this deployment
+    Persistent,
+}
+
+impl Display for UsrOverlayState {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let string = match self {
+            UsrOverlayState::Transient => "transient",
+            UsrOverlayState::Persistent => "persistent",
+        };
+
+        write!(f, "{}", string)
+    }
+}
+
+/// The disk space used by a composefs deployment
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct DiskUsage {
+    /// Bytes used only by this deployment, which deleting it would free
+    pub unique_bytes: u64,
+    /// Bytes of objects this deployment shares with other images
+    pub shared_bytes: u64,
 }
 
 /// A bootable entry
@@ -210,6 +304,16 @@ pub struct BootEntryComposefs {
     /// The sha256sum of vmlinuz + initrd
     /// Only `Some` for Type1 boot entries
     pub boot_digest: Option<String>,
+    /// The signers the image was verified against, as required by containers-policy.json
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub signers: Vec<String>,
+    /// Why the image could not be mounted the last time this deployment was booted,
+    /// in which case the last known-good image was booted instead
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub boot_failure: Option<String>,
+    /// The disk space used by this deployment
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub disk_usage: Option<DiskUsage>,
 }
 
 /// A bootable entry
@@ -227,6 +331,9 @@ pub struct BootEntry {
     /// This is true if (relative to the booted system) this is a possible target for a soft reboot
     #[serde(default)]
     pub soft_reboot_capable: bool,
+    /// If set, this entry has a writable overlay on `/usr` which may contain local modifications
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub usr_overlay: Option<UsrOverlayState>,
     /// The container storage backend
     #[serde(default)]
     pub store: Option<Store>,
@@ -381,7 +488,7 @@ impl HostSpec {
     /// such as fetching a new image and doing a rollback.
     pub(crate) fn verify_transition(&self, new: &Self) -> anyhow::Result<()> {
         let rollback = self.boot_order != new.boot_order;
-        let image_change = self.image != new.image;
+        let image_change = self.image != new.image || self.tag_policy != new.tag_policy;
         if rollback && image_change {
             anyhow::bail!("Invalid state transition: rollback and image change");
         }
@@ -553,6 +660,62 @@ mod tests {
         assert_eq!(imgref, canonicalized);
     }
 
+    #[test]
+    fn test_resolve_to() {
+        let digest = "sha256:5db6d8b5f34d3cbdaa1e82ed0152a5ac980076d19317d4269db149cbde057bb2";
+        let imgref = ImageReference {
+            image: "quay.io/example/someimage".into(),
+            transport: "registry".into(),
+            signature: None,
+        };
+        let resolved = imgref.resolve_to(Some("1.4.2"), digest).unwrap();
+        assert_eq!(
+            resolved.image,
+            format!("quay.io/example/someimage:1.4.2@{digest}")
+        );
+        assert_eq!(
+            resolved.canonicalize().unwrap().image,
+            format!("quay.io/example/someimage@{digest}")
+        );
+
+        // Without a new tag, the one of the reference is kept
+        let imgref = ImageReference {
+            image: "quay.io/example/someimage:stable".into(),
+            ..imgref
+        };
+        let resolved = imgref.resolve_to(None, digest).unwrap();
+        assert_eq!(
+            resolved.image,
+            format!("quay.io/example/someimage:stable@{digest}")
+        );
+
+        let imgref = ImageReference {
+            transport: "oci".into(),
+            ..imgref
+        };
+        assert!(imgref.resolve_to(None, digest).is_err());
+    }
+
+    #[test]
+    fn test_same_repository() {
+        let imgref = |image: &str, transport: &str| ImageReference {
+            image: image.into(),
+            transport: transport.into(),
+            signature: None,
+        };
+        let a = imgref("quay.io/example/someimage:stable", "registry");
+        let digest = "sha256:5db6d8b5f34d3cbdaa1e82ed0152a5ac980076d19317d4269db149cbde057bb2";
+        assert!(a.same_repository(&imgref("quay.io/example/someimage", "registry")));
+        assert!(a.same_repository(&imgref(
+            &format!("quay.io/example/someimage:1.4.2@{digest}"),
+            "registry"
+        )));
+        assert!(!a.same_repository(&imgref("quay.io/example/otherimage:stable", "registry")));
+        assert!(!a.same_repository(&imgref("example.com/example/someimage:stable", "registry")));
+        assert!(!a.same_repository(&imgref("quay.io/example/someimage:stable", "oci")));
+        assert!(imgref("/var/lib/image", "oci").same_repository(&imgref("/var/lib/image", "oci")));
+    }
+
     #[test]
     fn test_parse_spec_v1_null() {
         const SPEC_FIXTURE: &str = include_str!("fixtures/spec-v1-null.json");
@@ -635,6 +798,7 @@ mod tests {
                 cached_update: None,
                 incompatible: false,
                 soft_reboot_capable: false,
+                usr_overlay: None,
                 pinned: false,
                 store: None,
                 ostree: None,
@@ -22,7 +22,7 @@ use crate::cli::OutputFormat;
 use crate::spec::BootEntryComposefs;
 use crate::spec::ImageStatus;
 use crate::spec::{BootEntry, BootOrder, Host, HostSpec, HostStatus, HostType};
-use crate::spec::{ImageReference, ImageSignature};
+use crate::spec::{ImageReference, ImageSignature, UsrOverlayState};
 use crate::store::BootedStorage;
 use crate::store::BootedStorageKind;
 use crate::store::CachedImageStatus;
@@ -130,6 +130,16 @@ fn check_selinux_policy_compatible(
     }
 }
 
+/// Map the ostree unlocked state of a deployment to the state of its `/usr` overlay
+pub(crate) fn usr_overlay_state(deployment: &ostree::Deployment) -> Option<UsrOverlayState> {
+    match deployment.unlocked() {
+        ostree::DeploymentUnlockedState::Hotfix => Some(UsrOverlayState::Persistent),
+        ostree::DeploymentUnlockedState::Development
+        | ostree::DeploymentUnlockedState::Transient => Some(UsrOverlayState::Transient),
+        _ => None,
+    }
+}
+
 /// Check if a deployment has soft reboot capability
 // TODO: Lower SELinux policy check into ostree's deployment_can_soft_reboot API
 fn has_soft_reboot_capability(sysroot: &SysrootLock, deployment: &ostree::Deployment) -> bool {
@@ -192,20 +202,26 @@ pub(crate) fn labels_of_config(
     config.config().as_ref().and_then(|c| c.labels().as_ref())
 }
 
-/// Convert between a subset of ostree-ext metadata and the exposed spec API.
-fn create_imagestatus(
-    image: ImageReference,
-    manifest_digest: &Digest,
+/// The build timestamp of an image, if any.
+pub(crate) fn timestamp_of_config(
     config: &ImageConfiguration,
-) -> ImageStatus {
-    let labels = labels_of_config(config);
-    let timestamp = labels
+) -> Option<chrono::DateTime<chrono::Utc>> {
+    labels_of_config(config)
         .and_then(|l| {
             l.get(oci_spec::image::ANNOTATION_CREATED)
                 .map(|s| s.as_str())
         })
         .or_else(|| config.created().as_deref())
-        .and_then(bootc_utils::try_deserialize_timestamp);
+        .and_then(bootc_utils::try_deserialize_timestamp)
+}
+
+/// Convert between a subset of ostree-ext metadata and the exposed spec API.
+fn create_imagestatus(
+    image: ImageReference,
+    manifest_digest: &Digest,
+    config: &ImageConfiguration,
+) -> ImageStatus {
+    let timestamp = timestamp_of_config(config);
 
     let version = ostree_container::version_for_config(config).map(ToOwned::to_owned);
     let architecture = config.architecture().to_string();
@@ -215,21 +231,25 @@ fn create_imagestatus(
         timestamp,
         image_digest: manifest_digest.to_string(),
         architecture,
+        resolved_image: None,
     }
 }
 
 fn imagestatus(
     sysroot: &SysrootLock,
     deployment: &ostree::Deployment,
-    image: ostree_container::OstreeImageReference,
+    image: ImageReference,
+    resolved_image: Option<ImageReference>,
 ) -> Result<CachedImageStatus> {
     let repo = &sysroot.repo();
     let imgstate = ostree_container::store::query_image_commit(repo, &deployment.csum())?;
-    let image = ImageReference::from(image);
     let cached = imgstate
         .cached_update
         .map(|cached| create_imagestatus(image.clone(), &cached.manifest_digest, &cached.config));
-    let imagestatus = create_imagestatus(image, &imgstate.manifest_digest, &imgstate.configuration);
+    let imagestatus = ImageStatus {
+        resolved_image,
+        ..create_imagestatus(image, &imgstate.manifest_digest, &imgstate.configuration)
+    };
 
     Ok(CachedImageStatus {
         image: Some(imagestatus),
@@ -255,7 +275,12 @@ fn boot_entry_from_deployment(
             // If there are local changes, we can't represent it as a bootc compatible image.
             CachedImageStatus::default()
         } else if let Some(image) = get_image_origin(origin)? {
-            imagestatus(sysroot, deployment, image)?
+            // With a tag policy, the image of the origin is the resolved one
+            let image = ImageReference::from(image);
+            match crate::tag_policy::tracked_from_origin(origin)? {
+                Some(tracked) => imagestatus(sysroot, deployment, tracked, Some(image))?,
+                None => imagestatus(sysroot, deployment, image, None)?,
+            }
         } else {
             // The deployment isn't using a container image
             CachedImageStatus::default()
@@ -273,6 +298,7 @@ fn boot_entry_from_deployment(
         cached_update,
         incompatible,
         soft_reboot_capable,
+        usr_overlay: usr_overlay_state(deployment),
         store,
         pinned: deployment.is_pinned(),
         ostree: Some(crate::spec::BootEntryOstree {
@@ -388,6 +414,14 @@ pub(crate) fn get_status(
         .map(|d| boot_entry_from_deployment(sysroot, d))
         .collect::<Result<Vec<_>>>()
         .context("Other deployments")?;
+    let tag_policy = deployments
+        .staged
+        .as_ref()
+        .or(booted_deployment)
+        .and_then(|d| d.origin())
+        .map(|origin| crate::tag_policy::policy_from_origin(&origin))
+        .transpose()?
+        .flatten();
     let spec = staged
         .as_ref()
         .or(booted.as_ref())
@@ -395,6 +429,7 @@ pub(crate) fn get_status(
         .map(|img| HostSpec {
             image: Some(img.image.clone()),
             boot_order,
+            tag_policy,
         })
         .unwrap_or_default();
 
@@ -421,7 +456,14 @@ pub(crate) fn get_status(
     Ok((deployments, host))
 }
 
-async fn get_host() -> Result<Host> {
+pub(crate) async fn get_host() -> Result<Host> {
+    get_host_impl(false).await
+}
+
+/// Like [`get_host`], but with `disk_usage` also compute the disk space used by each
+/// composefs deployment. This reads every image, so it is only done on request, and
+/// failing to do it is not fatal.
+async fn get_host_impl(disk_usage: bool) -> Result<Host> {
     let env = crate::store::Environment::detect()?;
     if env.needs_mount_namespace() {
         crate::cli::prepare_for_write()?;
@@ -439,7 +481,18 @@ async fn get_host() -> Result<Host> {
                 host
             }
             BootedStorageKind::Composefs(booted_cfs) => {
-                crate::bootc_composefs::status::get_composefs_status(&storage, &booted_cfs).await?
+                let mut host =
+                    crate::bootc_composefs::status::get_composefs_status(&storage, &booted_cfs)
+                        .await?;
+                if disk_usage {
+                    if let Err(e) = crate::bootc_composefs::gc::fill_disk_usage(
+                        &storage.physical_root,
+                        &mut host,
+                    ) {
+                        tracing::warn!("{e:#}");
+                    }
+                }
+                host
             }
         },
         Err(_) => {
@@ -460,7 +513,7 @@ pub(crate) async fn status(opts: super::cli::StatusOpts) -> Result<()> {
         0 | 1 => {}
         o => anyhow::bail!("Unsupported format version: {o}"),
     };
-    let mut host = get_host().await?;
+    let mut host = get_host_impl(opts.verbose).await?;
 
     // We could support querying the staged or rollback deployments
     // here too, but it's not a common use case at the moment.
@@ -493,7 +546,7 @@ pub(crate) async fn status(opts: super::cli::StatusOpts) -> Result<()> {
     Ok(())
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum Slot {
     Staged,
     Booted,
@@ -594,6 +647,11 @@ fn human_render_slot(
     let digest = &image.image_digest;
     writeln!(out, "{digest} ({arch})")?;
 
+    if let Some(resolved) = image.resolved_image.as_ref() {
+        write_row_name(&mut out, "Resolved", prefix_len)?;
+        writeln!(out, "{resolved:#}")?;
+    }
+
     // Write the EROFS verity if present
     if let Some(composefs) = &entry.composefs {
         write_row_name(&mut out, "Verity", prefix_len)?;
@@ -626,6 +684,27 @@ fn human_render_slot(
         writeln!(out, "yes")?;
     }
 
+    if let Some(usr_overlay) = entry.usr_overlay {
+        write_row_name(&mut out, "/usr overlay", prefix_len)?;
+        writeln!(out, "{usr_overlay}")?;
+    }
+
+    if let Some(failure) = entry
+        .composefs
+        .as_ref()
+        .and_then(|c| c.boot_failure.as_deref())
+    {
+        write_row_name(&mut out, "Boot failure", prefix_len)?;
+        writeln!(out, "{failure}")?;
+    }
+
+    if let Some(usage) = entry.composefs.as_ref().and_then(|c| c.disk_usage) {
+        write_row_name(&mut out, "Disk usage", prefix_len)?;
+        let unique = ostree_ext::glib::format_size(usage.unique_bytes);
+        let shared = ostree_ext::glib::format_size(usage.shared_bytes);
+        writeln!(out, "{unique} unique, {shared} shared")?;
+    }
+
     if verbose {
         // Show additional information in verbose mode similar to rpm-ostree
         if let Some(ostree) = &entry.ostree {
@@ -652,6 +731,14 @@ fn human_render_slot(
             }
         }
 
+        // Show the signers a composefs image was verified against
+        if let Some(composefs) = entry.composefs.as_ref() {
+            for signer in &composefs.signers {
+                write_row_name(&mut out, "Signed by", prefix_len)?;
+                writeln!(out, "{signer}")?;
+            }
+        }
+
         // Show soft-reboot capability
         write_soft_reboot(&mut out, entry, prefix_len)?;
     }
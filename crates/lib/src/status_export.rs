@@ -0,0 +1,284 @@
+//! # Exporting the host status for configuration management tools
+//!
+//! A summary of the host status is written to [`EXPORT_PATH`] by
+//! `bootc-publish-status.service`, which runs whenever the status changes
+//! (and on boot), as well as directly after `bootc upgrade` and `bootc switch`.
+//! Unlike `bootc status`, the format is flat and stable, and it includes the
+//! error of the last failed update.
+//!
+//! Other formats, such as Prometheus metrics for the node exporter textfile
+//! collector or facts for subscription-manager, are rendered from the same data.
+
+use std::fmt::Write as _;
+
+use anyhow::{Context, Result};
+use camino::Utf8Path;
+use cap_std_ext::cap_std::fs::Dir;
+use cap_std_ext::dirext::CapStdExtDirExt;
+use chrono::{DateTime, Utc};
+use fn_error_context::context;
+use serde::{Deserialize, Serialize};
+
+use crate::spec::{Host, ImageStatus};
+
+/// The path of the exported status, relative to the root.
+const EXPORT_PATH: &str = "run/bootc/status.json";
+/// The error of the last failed update, relative to the root.
+const LAST_ERROR_PATH: &str = "var/lib/bootc/last-update-error.json";
+/// The name of the file written to the textfile collector directory.
+const PROMETHEUS_FILENAME: &str = "bootc.prom";
+
+/// The version of the exported format; incremented on incompatible changes.
+const EXPORT_VERSION: u32 = 1;
+
+/// A deployed or available image.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct ExportedImage {
+    pub(crate) image: String,
+    pub(crate) version: Option<String>,
+    pub(crate) digest: String,
+    pub(crate) timestamp: Option<DateTime<Utc>>,
+}
+
+impl From<&ImageStatus> for ExportedImage {
+    fn from(status: &ImageStatus) -> Self {
+        Self {
+            image: status.image.image.clone(),
+            version: status.version.clone(),
+            digest: status.image_digest.clone(),
+            timestamp: status.timestamp,
+        }
+    }
+}
+
+/// The error of the last failed `bootc upgrade`, `bootc switch` or `bootc rollback`.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct UpdateError {
+    pub(crate) operation: String,
+    pub(crate) timestamp: DateTime<Utc>,
+    pub(crate) message: String,
+}
+
+/// The exported status.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct StatusExport {
+    pub(crate) version: u32,
+    pub(crate) booted: Option<ExportedImage>,
+    pub(crate) staged: Option<ExportedImage>,
+    pub(crate) rollback: Option<ExportedImage>,
+    /// An update which was fetched by `bootc upgrade --check`, but not deployed
+    pub(crate) available_update: Option<ExportedImage>,
+    /// Whether rebooting will change the booted deployment
+    pub(crate) reboot_pending: bool,
+    pub(crate) last_update_error: Option<UpdateError>,
+}
+
+impl StatusExport {
+    pub(crate) fn new(host: &Host, last_update_error: Option<UpdateError>) -> Self {
+        let image = |entry: Option<&crate::spec::BootEntry>| {
+            entry
+                .and_then(|e| e.image.as_ref())
+                .map(ExportedImage::from)
+        };
+        let booted = image(host.status.booted.as_ref());
+        let staged = image(host.status.staged.as_ref());
+        let rollback = image(host.status.rollback.as_ref());
+        let available_update = host
+            .status
+            .booted
+            .as_ref()
+            .and_then(|e| e.cached_update.as_ref())
+            .map(ExportedImage::from)
+            .filter(|available| {
+                [&booted, &staged]
+                    .into_iter()
+                    .flatten()
+                    .all(|i| i.digest != available.digest)
+            });
+        let reboot_pending = staged.is_some() || host.status.rollback_queued;
+        Self {
+            version: EXPORT_VERSION,
+            booted,
+            staged,
+            rollback,
+            available_update,
+            reboot_pending,
+            last_update_error,
+        }
+    }
+
+    /// Render as metrics for the Prometheus node exporter textfile collector.
+    pub(crate) fn to_prometheus(&self) -> String {
+        let mut r = String::new();
+        let slots = [
+            ("booted", &self.booted),
+            ("staged", &self.staged),
+            ("rollback", &self.rollback),
+            ("available", &self.available_update),
+        ];
+
+        r.push_str("# HELP bootc_image_info Images of the deployments and the available update.\n");
+        r.push_str("# TYPE bootc_image_info gauge\n");
+        for (slot, image) in slots {
+            let Some(image) = image else { continue };
+            let _ = writeln!(
+                r,
+                "bootc_image_info{{slot=\"{slot}\",image=\"{}\",version=\"{}\",digest=\"{}\"}} 1",
+                escape_label(&image.image),
+                escape_label(image.version.as_deref().unwrap_or_default()),
+                escape_label(&image.digest)
+            );
+        }
+
+        r.push_str("# HELP bootc_image_timestamp_seconds Build timestamps of the images.\n");
+        r.push_str("# TYPE bootc_image_timestamp_seconds gauge\n");
+        for (slot, image) in slots {
+            if let Some(timestamp) = image.as_ref().and_then(|i| i.timestamp) {
+                let _ = writeln!(
+                    r,
+                    "bootc_image_timestamp_seconds{{slot=\"{slot}\"}} {}",
+                    timestamp.timestamp()
+                );
+            }
+        }
+
+        r.push_str("# HELP bootc_update_available Whether an update is available.\n");
+        r.push_str("# TYPE bootc_update_available gauge\n");
+        let _ = writeln!(
+            r,
+            "bootc_update_available {}",
+            u8::from(self.available_update.is_some())
+        );
+
+        r.push_str(
+            "# HELP bootc_reboot_pending Whether rebooting will change the booted deployment.\n",
+        );
+        r.push_str("# TYPE bootc_reboot_pending gauge\n");
+        let _ = writeln!(r, "bootc_reboot_pending {}", u8::from(self.reboot_pending));
+
+        if let Some(e) = self.last_update_error.as_ref() {
+            r.push_str(
+                "# HELP bootc_last_update_error_timestamp_seconds When the last update failed.\n",
+            );
+            r.push_str("# TYPE bootc_last_update_error_timestamp_seconds gauge\n");
+            let _ = writeln!(
+                r,
+                "bootc_last_update_error_timestamp_seconds{{operation=\"{}\"}} {}",
+                escape_label(&e.operation),
+                e.timestamp.timestamp()
+            );
+        }
+        r
+    }
+}
+
+/// Escape a Prometheus label value.
+fn escape_label(s: &str) -> String {
+    s.replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+/// Compute the exported status of the running system.
+pub(crate) async fn get_export(root: &Dir) -> Result<StatusExport> {
+    let host = crate::status::get_host().await?;
+    let last_update_error = root
+        .open_optional(LAST_ERROR_PATH)?
+        .map(|f| serde_json::from_reader(std::io::BufReader::new(f)))
+        .transpose()
+        .with_context(|| format!("Parsing {LAST_ERROR_PATH}"))?;
+    Ok(StatusExport::new(&host, last_update_error))
+}
+
+/// Write the exported status, and optionally Prometheus metrics to `textfile_dir`.
+#[context("Publishing status")]
+pub(crate) async fn publish(root: &Dir, textfile_dir: Option<&Utf8Path>) -> Result<()> {
+    let export = get_export(root).await?;
+    root.create_dir_all(Utf8Path::new(EXPORT_PATH).parent().unwrap())?;
+    root.atomic_replace_with(EXPORT_PATH, |w| {
+        serde_json::to_writer_pretty(w, &export)?;
+        anyhow::Ok(())
+    })
+    .with_context(|| format!("Writing {EXPORT_PATH}"))?;
+
+    if let Some(dir) = textfile_dir {
+        let dir = Dir::open_ambient_dir(dir, cap_std_ext::cap_std::ambient_authority())
+            .with_context(|| format!("Opening {dir}"))?;
+        // The collector ignores files without the .prom extension, so this is
+        // written atomically too.
+        dir.atomic_write(PROMETHEUS_FILENAME, export.to_prometheus())
+            .with_context(|| format!("Writing {PROMETHEUS_FILENAME}"))?;
+    }
+    Ok(())
+}
+
+/// Record the result of an update operation and publish the status. Errors
+/// doing so are only logged, as they must not mask the result.
+pub(crate) async fn record_update_result<T>(root: &Dir, operation: &str, result: &Result<T>) {
+    let r = match result {
+        Ok(_) => root
+            .remove_file_optional(LAST_ERROR_PATH)
+            .map(|_| ())
+            .map_err(Into::into),
+        Err(e) => {
+            let e = UpdateError {
+                operation: operation.to_owned(),
+                timestamp: Utc::now(),
+                message: format!("{e:#}"),
+            };
+            root.create_dir_all(Utf8Path::new(LAST_ERROR_PATH).parent().unwrap())
+                .map_err(anyhow::Error::from)
+                .and_then(|()| {
+                    root.atomic_replace_with(LAST_ERROR_PATH, |w| {
+                        serde_json::to_writer(w, &e)?;
+                        anyhow::Ok(())
+                    })
+                })
+        }
+    };
+    let r = match r {
+        Ok(()) => publish(root, None).await,
+        Err(e) => Err(e),
+    };
+    if let Err(e) = r {
+        tracing::warn!("Failed to publish status: {e:#}");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_export() {
+        let host: Host =
+            serde_yaml::from_str(include_str!("fixtures/spec-staged-booted.yaml")).unwrap();
+        let error = UpdateError {
+            operation: "upgrade".into(),
+            timestamp: "2025-03-01T00:00:00Z".parse().unwrap(),
+            message: "Pulling: \"quay.io/example/someimage:latest\" not found".into(),
+        };
+        let export = StatusExport::new(&host, Some(error));
+        assert!(export.reboot_pending);
+        assert_eq!(export.available_update, None);
+        assert_eq!(
+            export.staged.as_ref().unwrap().digest,
+            "sha256:16dc2b6256b4ff0d2ec18d2dbfb06d117904010c8cf9732cdb022818cf7a7566"
+        );
+
+        let metrics = export.to_prometheus();
+        assert!(metrics.contains(
+            "bootc_image_info{slot=\"booted\",image=\"quay.io/example/someimage:latest\",version=\"nightly\",digest=\"sha256:736b359467c9437c1ac915acaae952aad854e07eb4a16a94999a48af08c83c34\"} 1\n"
+        ));
+        assert!(metrics.contains("bootc_reboot_pending 1\n"));
+        assert!(metrics.contains("bootc_update_available 0\n"));
+        assert!(metrics.contains(
+            "bootc_last_update_error_timestamp_seconds{operation=\"upgrade\"} 1740787200\n"
+        ));
+
+        assert_eq!(escape_label("a\"b\\c\nd"), "a\\\"b\\\\c\\nd");
+    }
+}
@@ -0,0 +1,319 @@
+//! # Selecting the image to deploy with a tag policy
+//!
+//! A [`TagPolicy`] in the host spec makes `bootc upgrade` select the image
+//! to deploy from the tags of the image repository, rather than just fetch
+//! the tag of the image reference:
+//!
+//! - With a semantic version range, the highest matching tag is used.
+//! - With a list of allowed digests, the selected tag must currently point
+//!   to one of them.
+//!
+//! The result is a reference pinned by digest, which is what is fetched
+//! and stored as the image reference in the origin of the deployment, so
+//! that the image is not pruned. The policy and the tracked (unresolved)
+//! image reference are stored next to it.
+
+use std::process::Command;
+
+use anyhow::{Context, Result};
+use bootc_utils::CommandRunExt;
+use camino::Utf8Path;
+use cap_std_ext::cap_std;
+use cap_std_ext::cap_std::fs::Dir;
+use fn_error_context::context;
+use ostree_ext::container::OstreeImageReference;
+use ostree_ext::keyfileext::KeyFileExt;
+use ostree_ext::oci_spec::distribution::Reference;
+use ostree_ext::ostree::glib;
+use serde::Deserialize;
+
+use crate::spec::{ImageReference, TagPolicy};
+
+/// The origin group holding the tag policy.
+const ORIGIN_GROUP: &str = "bootc";
+const ORIGIN_KEY_SEMVER: &str = "tag-policy-semver";
+const ORIGIN_KEY_ALLOWED_DIGESTS: &str = "tag-policy-allowed-digests";
+const ORIGIN_KEY_TRACKED: &str = "tag-policy-image";
+
+/// Access to the tags of an image repository.
+pub(crate) trait Registry {
+    /// List the tags of the repository `name` (e.g. `quay.io/example/os`).
+    fn list_tags(&self, name: &str) -> Result<Vec<String>>;
+    /// Find the manifest digest the tag `tag` of the repository `name` points to.
+    fn digest(&self, name: &str, tag: &str) -> Result<String>;
+}
+
+/// Queries a remote registry with skopeo.
+struct Skopeo;
+
+impl Skopeo {
+    /// A skopeo command for `subcommand`, using the global authfile if any.
+    fn command(subcommand: &str) -> Result<Command> {
+        let mut cmd = Command::new("skopeo");
+        cmd.arg(subcommand);
+        let root = Dir::open_ambient_dir("/", cap_std::ambient_authority())?;
+        if let Some((authfile, _fd)) = ostree_ext::globals::get_global_authfile(&root)? {
+            cmd.arg("--authfile").arg(Utf8Path::new("/").join(authfile));
+        }
+        Ok(cmd)
+    }
+}
+
+impl Registry for Skopeo {
+    #[context("Listing tags of {name}")]
+    fn list_tags(&self, name: &str) -> Result<Vec<String>> {
+        #[derive(Deserialize)]
+        #[serde(rename_all = "PascalCase")]
+        struct Tags {
+            tags: Vec<String>,
+        }
+        let tags: Tags = Self::command("list-tags")?
+            .arg(format!("docker://{name}"))
+            .run_and_parse_json()?;
+        Ok(tags.tags)
+    }
+
+    #[context("Inspecting {name}:{tag}")]
+    fn digest(&self, name: &str, tag: &str) -> Result<String> {
+        let digest = Self::command("inspect")?
+            .args(["--format", "{{.Digest}}", &format!("docker://{name}:{tag}")])
+            .run_get_string()?;
+        Ok(digest.trim().to_owned())
+    }
+}
+
+/// Find the highest tag which is a version in `range`; a `v` prefix is allowed.
+fn highest_matching<'a>(tags: &'a [String], range: &semver::VersionReq) -> Option<&'a str> {
+    tags.iter()
+        .filter_map(|tag| {
+            let version = semver::Version::parse(tag.strip_prefix('v').unwrap_or(tag)).ok()?;
+            range.matches(&version).then_some((version, tag.as_str()))
+        })
+        .max_by(|a, b| a.0.cmp(&b.0))
+        .map(|(_, tag)| tag)
+}
+
+/// Parse a list of allowed digests, one per line; empty lines and comments
+/// starting with `#` are ignored.
+fn parse_allowed_digests(s: &str) -> impl Iterator<Item = &str> {
+    s.lines()
+        .map(str::trim)
+        .filter(|l| !l.is_empty() && !l.starts_with('#'))
+}
+
+/// Resolve `imgref` according to `policy`, querying `registry`.
+pub(crate) fn resolve_with(
+    registry: &impl Registry,
+    imgref: &ImageReference,
+    policy: &TagPolicy,
+) -> Result<ImageReference> {
+    if imgref.transport != "registry" {
+        anyhow::bail!(
+            "Tag policies require the registry transport, not {}",
+            imgref.transport
+        );
+    }
+    let reference: Reference = imgref.image.parse()?;
+    let name = format!("{}/{}", reference.registry(), reference.repository());
+
+    let tag = if let Some(range) = policy.semver.as_deref() {
+        if reference.digest().is_some() {
+            anyhow::bail!("A semver tag policy cannot be used with an image pinned by digest");
+        }
+        let range = semver::VersionReq::parse(range)
+            .with_context(|| format!("Parsing version range {range}"))?;
+        let tags = registry.list_tags(&name)?;
+        let tag = highest_matching(&tags, &range)
+            .ok_or_else(|| anyhow::anyhow!("No tag of {name} matches {range}"))?;
+        Some(tag.to_owned())
+    } else {
+        None
+    };
+
+    let digest = match (tag.as_deref().or(reference.tag()), reference.digest()) {
+        (_, Some(digest)) => digest.to_owned(),
+        (Some(tag), None) => registry.digest(&name, tag)?,
+        (None, None) => registry.digest(&name, "latest")?,
+    };
+
+    if let Some(path) = policy.allowed_digests.as_deref() {
+        let allowed = std::fs::read_to_string(path)
+            .with_context(|| format!("Reading allowed digests from {path}"))?;
+        if !parse_allowed_digests(&allowed).any(|d| d == digest) {
+            anyhow::bail!("{name} resolved to {digest}, which is not listed in {path}");
+        }
+    }
+
+    imgref.resolve_to(tag.as_deref(), &digest)
+}
+
+/// Resolve `imgref` according to `policy`, if any.
+pub(crate) fn resolve(
+    imgref: &ImageReference,
+    policy: Option<&TagPolicy>,
+) -> Result<Option<ImageReference>> {
+    let Some(policy) = policy else {
+        return Ok(None);
+    };
+    let resolved = resolve_with(&Skopeo, imgref, policy)?;
+    println!("Resolved {imgref:#} to {resolved:#}");
+    Ok(Some(resolved))
+}
+
+/// Read the tag policy stored in a deployment origin.
+pub(crate) fn policy_from_origin(origin: &glib::KeyFile) -> Result<Option<TagPolicy>> {
+    let semver = origin.optional_string(ORIGIN_GROUP, ORIGIN_KEY_SEMVER)?;
+    let allowed_digests = origin.optional_string(ORIGIN_GROUP, ORIGIN_KEY_ALLOWED_DIGESTS)?;
+    if semver.is_none() && allowed_digests.is_none() {
+        return Ok(None);
+    }
+    Ok(Some(TagPolicy {
+        semver: semver.map(Into::into),
+        allowed_digests: allowed_digests.map(Into::into),
+    }))
+}
+
+/// Read the image reference tracked by the tag policy from a deployment
+/// origin; if set, the image reference of the origin is the resolved one.
+pub(crate) fn tracked_from_origin(origin: &glib::KeyFile) -> Result<Option<ImageReference>> {
+    origin
+        .optional_string(ORIGIN_GROUP, ORIGIN_KEY_TRACKED)?
+        .map(|v| OstreeImageReference::try_from(v.as_str()).map(Into::into))
+        .transpose()
+}
+
+/// Store the tag policy and the image reference `tracked` it resolves in a
+/// deployment origin.
+pub(crate) fn set_origin(
+    origin: &glib::KeyFile,
+    policy: Option<&TagPolicy>,
+    tracked: &ImageReference,
+) {
+    let Some(policy) = policy else {
+        return;
+    };
+    if let Some(semver) = policy.semver.as_deref() {
+        origin.set_string(ORIGIN_GROUP, ORIGIN_KEY_SEMVER, semver);
+    }
+    if let Some(path) = policy.allowed_digests.as_deref() {
+        origin.set_string(ORIGIN_GROUP, ORIGIN_KEY_ALLOWED_DIGESTS, path);
+    }
+    let tracked = OstreeImageReference::from(tracked.clone());
+    origin.set_string(ORIGIN_GROUP, ORIGIN_KEY_TRACKED, &tracked.to_string());
+}
+
+/// Tag policies are only implemented for the ostree backend.
+pub(crate) fn reject_for_composefs(policy: Option<&TagPolicy>) -> Result<()> {
+    if policy.is_some() {
+        anyhow::bail!(
+            "Tag policies are not supported with the composefs backend; remove the tagPolicy with `bootc edit` first"
+        );
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use std::io::Write;
+
+    use super::*;
+
+    const DIGEST_A: &str =
+        "sha256:5db6d8b5f34d3cbdaa1e82ed0152a5ac980076d19317d4269db149cbde057bb2";
+    const DIGEST_B: &str =
+        "sha256:a0a97b9bf6a4f8c7c6e5d4e3b2a1f0e9d8c7b6a5f4e3d2c1b0a9f8e7d6c5b4a3";
+
+    /// A registry stand-in serving a fixed set of tags.
+    struct FakeRegistry(HashMap<&'static str, &'static str>);
+
+    impl Registry for FakeRegistry {
+        fn list_tags(&self, name: &str) -> Result<Vec<String>> {
+            assert_eq!(name, "quay.io/example/os");
+            Ok(self.0.keys().map(|t| t.to_string()).collect())
+        }
+
+        fn digest(&self, _name: &str, tag: &str) -> Result<String> {
+            self.0
+                .get(tag)
+                .map(|d| d.to_string())
+                .ok_or_else(|| anyhow::anyhow!("No such tag {tag}"))
+        }
+    }
+
+    fn registry() -> FakeRegistry {
+        FakeRegistry(HashMap::from([
+            ("1.3.9", DIGEST_B),
+            ("1.4.0", DIGEST_B),
+            ("v1.4.2", DIGEST_A),
+            ("1.4.10-rc1", DIGEST_B),
+            ("1.5.0", DIGEST_B),
+            ("latest", DIGEST_B),
+            ("stable", DIGEST_A),
+        ]))
+    }
+
+    fn imgref(image: &str) -> ImageReference {
+        ImageReference {
+            image: image.into(),
+            transport: "registry".into(),
+            signature: None,
+        }
+    }
+
+    #[test]
+    fn test_semver() -> Result<()> {
+        let policy = TagPolicy {
+            semver: Some("1.4.*".into()),
+            ..Default::default()
+        };
+        let resolved = resolve_with(&registry(), &imgref("quay.io/example/os"), &policy)?;
+        assert_eq!(
+            resolved.image,
+            format!("quay.io/example/os:v1.4.2@{DIGEST_A}")
+        );
+
+        let policy = TagPolicy {
+            semver: Some("2".into()),
+            ..Default::default()
+        };
+        let e = resolve_with(&registry(), &imgref("quay.io/example/os"), &policy).unwrap_err();
+        assert_eq!(e.to_string(), "No tag of quay.io/example/os matches ^2");
+        Ok(())
+    }
+
+    #[test]
+    fn test_allowed_digests() -> Result<()> {
+        let mut allowed = tempfile::NamedTempFile::new()?;
+        writeln!(allowed, "# Released builds\n\n{DIGEST_A}")?;
+        let policy = TagPolicy {
+            allowed_digests: Some(allowed.path().to_str().unwrap().into()),
+            ..Default::default()
+        };
+
+        let resolved = resolve_with(&registry(), &imgref("quay.io/example/os:stable"), &policy)?;
+        assert_eq!(
+            resolved.image,
+            format!("quay.io/example/os:stable@{DIGEST_A}")
+        );
+        let e = resolve_with(&registry(), &imgref("quay.io/example/os"), &policy).unwrap_err();
+        assert!(e.to_string().contains("which is not listed in"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_origin() -> Result<()> {
+        let origin = glib::KeyFile::new();
+        assert_eq!(policy_from_origin(&origin)?, None);
+
+        let policy = TagPolicy {
+            semver: Some("1.4.*".into()),
+            allowed_digests: Some("/etc/bootc/allowed-digests".into()),
+        };
+        let tracked = imgref("quay.io/example/os");
+        set_origin(&origin, Some(&policy), &tracked);
+        assert_eq!(policy_from_origin(&origin)?, Some(policy));
+        assert_eq!(tracked_from_origin(&origin)?, Some(tracked));
+        Ok(())
+    }
+}
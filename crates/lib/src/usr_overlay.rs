@@ -0,0 +1,244 @@
+//! # Inspecting and discarding writable overlays on `/usr`
+//!
+//! `bootc usr-overlay` mounts a writable overlay on `/usr`, which is transient
+//! by default. With `--persistent` the changes survive reboots of the booted
+//! deployment: for ostree this is a "hotfix" unlock, and for composefs the
+//! upper directory is kept in the deployment state directory and mounted by the
+//! initramfs. This module implements listing the changes relative to the image
+//! and discarding a persistent overlay.
+
+use std::io::Write;
+use std::ops::Deref;
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context, Result};
+use bootc_initramfs_setup::{mount_composefs_image, USR_OVERLAY_DIR, USR_OVERLAY_DISCARDED_DIR};
+use bootc_mount::tempmount::TempMount;
+use canon_json::CanonJsonSerialize;
+use cap_std_ext::cap_std::ambient_authority;
+use cap_std_ext::cap_std::fs::Dir;
+use cap_std_ext::dirext::CapStdExtDirExt;
+use etc_merge::{compute_diff, traverse_etc};
+use fn_error_context::context;
+use serde::Serialize;
+
+use crate::bootc_composefs::state::composefs_usr_overlay_state;
+use crate::cli::OutputFormat;
+use crate::composefs_consts::STATE_DIR_RELATIVE;
+use crate::deploy::{ImageState, MergeState, RequiredHostSpec};
+use crate::progress_jsonl::ProgressWriter;
+use crate::spec::UsrOverlayState;
+use crate::store::{BootedComposefs, BootedStorageKind, Storage};
+
+/// Changes to `/usr` relative to the booted image
+#[derive(Debug, Default, Serialize, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct UsrOverlayStatus {
+    /// The writable overlay on `/usr`, if any
+    overlay: Option<UsrOverlayState>,
+    /// Paths which were added
+    added: Vec<String>,
+    /// Paths whose content or metadata was changed
+    modified: Vec<String>,
+    /// Paths which were removed
+    removed: Vec<String>,
+}
+
+impl UsrOverlayStatus {
+    fn new(overlay: UsrOverlayState, diff: &etc_merge::Diff) -> Self {
+        let absolute = |paths: &[PathBuf]| {
+            paths
+                .iter()
+                .map(|p| Path::new("/usr").join(p).to_string_lossy().into_owned())
+                .collect()
+        };
+        Self {
+            overlay: Some(overlay),
+            added: absolute(diff.added()),
+            modified: absolute(diff.modified()),
+            removed: absolute(diff.removed()),
+        }
+    }
+}
+
+fn human_readable_output(mut out: impl Write, status: &UsrOverlayStatus) -> Result<()> {
+    let Some(overlay) = status.overlay else {
+        writeln!(out, "No writable overlay on /usr")?;
+        return Ok(());
+    };
+    writeln!(out, "Overlay: {overlay}")?;
+    let changes = [
+        ("A", &status.added),
+        ("M", &status.modified),
+        ("D", &status.removed),
+    ];
+    if changes.iter().all(|(_, paths)| paths.is_empty()) {
+        writeln!(out, "No changes")?;
+    }
+    for (prefix, paths) in changes {
+        for path in paths {
+            writeln!(out, "{prefix} {path}")?;
+        }
+    }
+    Ok(())
+}
+
+/// Open the state directory of the booted composefs deployment
+fn composefs_state_dir(storage: &Storage, booted_cfs: &BootedComposefs) -> Result<Dir> {
+    storage
+        .physical_root
+        .open_dir(Path::new(STATE_DIR_RELATIVE).join(booted_cfs.cmdline.digest.deref()))
+        .context("Opening state dir")
+}
+
+/// Compare the current `/usr` with the pristine one from the image
+#[context("Computing /usr changes")]
+fn compute_status(overlay: UsrOverlayState, pristine_usr: &Dir) -> Result<UsrOverlayStatus> {
+    let current_usr = Dir::open_ambient_dir("/usr", ambient_authority()).context("Opening /usr")?;
+    let (pristine_files, current_files, _) = traverse_etc(pristine_usr, &current_usr, None)?;
+    let diff = compute_diff(&pristine_files, &current_files)?;
+    Ok(UsrOverlayStatus::new(overlay, &diff))
+}
+
+/// Implementation of `bootc usr-overlay status`
+pub(crate) async fn usr_overlay_status(format: Option<OutputFormat>) -> Result<()> {
+    let storage = &crate::cli::get_storage().await?;
+    let status = match storage.kind()? {
+        BootedStorageKind::Ostree(booted_ostree) => {
+            match crate::status::usr_overlay_state(&booted_ostree.deployment) {
+                Some(overlay) => {
+                    // The deployment directory is the lower layer of the overlay
+                    let ostree = booted_ostree.sysroot;
+                    let deployment_path = ostree.deployment_dirpath(&booted_ostree.deployment);
+                    let pristine_usr = crate::utils::sysroot_dir(ostree)?
+                        .open_dir(Path::new(deployment_path.as_str()).join("usr"))
+                        .context("Opening deployment /usr")?;
+                    compute_status(overlay, &pristine_usr)?
+                }
+                None => UsrOverlayStatus::default(),
+            }
+        }
+        BootedStorageKind::Composefs(booted_cfs) => {
+            let state = composefs_state_dir(storage, &booted_cfs)?;
+            match composefs_usr_overlay_state(&state, true)? {
+                Some(overlay) => {
+                    let sysroot_fd = storage.physical_root.reopen_as_ownedfd()?;
+                    let composefs_fd = mount_composefs_image(
+                        &sysroot_fd,
+                        &booted_cfs.cmdline.digest,
+                        booted_cfs.cmdline.insecure,
+                    )?;
+                    let erofs_tmp_mnt = TempMount::mount_fd(&composefs_fd)?;
+                    let pristine_usr = erofs_tmp_mnt
+                        .fd
+                        .open_dir("usr")
+                        .context("Opening image /usr")?;
+                    compute_status(overlay, &pristine_usr)?
+                }
+                None => UsrOverlayStatus::default(),
+            }
+        }
+    };
+
+    let mut out = std::io::stdout().lock();
+    match format.unwrap_or(OutputFormat::HumanReadable) {
+        OutputFormat::Json => status
+            .to_canon_json_writer(&mut out)
+            .map_err(anyhow::Error::new),
+        OutputFormat::Yaml => serde_yaml::to_writer(&mut out, &status).map_err(anyhow::Error::new),
+        OutputFormat::HumanReadable => human_readable_output(&mut out, &status),
+    }
+    .context("Writing to stdout")
+}
+
+/// Implementation of `bootc usr-overlay discard`
+pub(crate) async fn usr_overlay_discard() -> Result<()> {
+    let storage = &crate::cli::get_storage().await?;
+    let overlay = match storage.kind()? {
+        BootedStorageKind::Ostree(booted_ostree) => {
+            let overlay = crate::status::usr_overlay_state(&booted_ostree.deployment);
+            if overlay == Some(UsrOverlayState::Persistent) {
+                // There is no way to re-lock a hotfix deployment, so queue a fresh
+                // deployment of the booted image instead.
+                let repo = &booted_ostree.repo();
+                let (_deployments, host) = crate::status::get_status(&booted_ostree)?;
+                let (fetched, spec) = ImageState::from_booted(&host, repo)?;
+                let spec = RequiredHostSpec::from_spec(&spec)?;
+                let from = MergeState::MergeDeployment(booted_ostree.deployment.clone());
+                crate::deploy::stage(storage, from, &fetched, &spec, ProgressWriter::default())
+                    .await?;
+                println!("Queued a fresh deployment of the booted image.");
+            }
+            overlay
+        }
+        BootedStorageKind::Composefs(booted_cfs) => {
+            let state = composefs_state_dir(storage, &booted_cfs)?;
+            let overlay = composefs_usr_overlay_state(&state, true)?;
+            if overlay == Some(UsrOverlayState::Persistent) {
+                if state.try_exists(USR_OVERLAY_DISCARDED_DIR)? {
+                    // The overlay which is currently mounted (if any) was already
+                    // discarded, so this one is unused.
+                    state.remove_all_optional(USR_OVERLAY_DIR)?;
+                } else {
+                    // It may be in use; the initramfs removes it on the next boot.
+                    state
+                        .rename(USR_OVERLAY_DIR, &state, USR_OVERLAY_DISCARDED_DIR)
+                        .context("Discarding overlay")?;
+                }
+            }
+            overlay
+        }
+    };
+
+    match overlay {
+        Some(UsrOverlayState::Persistent) => {
+            println!("The persistent overlay on /usr will be discarded on the next boot.")
+        }
+        Some(UsrOverlayState::Transient) => {
+            println!("The overlay on /usr is transient and will be discarded on reboot.")
+        }
+        None => println!("No writable overlay on /usr"),
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_human_readable() -> Result<()> {
+        let mut out = Vec::new();
+        human_readable_output(&mut out, &UsrOverlayStatus::default())?;
+        assert_eq!(String::from_utf8(out)?, "No writable overlay on /usr\n");
+
+        let status = UsrOverlayStatus {
+            overlay: Some(UsrOverlayState::Persistent),
+            added: vec!["/usr/bin/strace".into()],
+            modified: vec!["/usr/lib/os-release".into()],
+            removed: vec!["/usr/bin/vi".into()],
+        };
+        let mut out = Vec::new();
+        human_readable_output(&mut out, &status)?;
+        assert_eq!(
+            String::from_utf8(out)?,
+            indoc::indoc! { "
+                Overlay: persistent
+                A /usr/bin/strace
+                M /usr/lib/os-release
+                D /usr/bin/vi
+            " }
+        );
+
+        let status = UsrOverlayStatus {
+            overlay: Some(UsrOverlayState::Transient),
+            ..Default::default()
+        };
+        let json = serde_json::to_value(&status)?;
+        assert_eq!(json["overlay"], "transient");
+        assert_eq!(json["added"], serde_json::json!([]));
+
+        Ok(())
+    }
+}
@@ -0,0 +1,636 @@
+//! # A local varlink API
+//!
+//! `bootc-varlink.socket` exposes the `org.containers.bootc` [varlink]
+//! interface. Every connection is served by a separate `bootc internals varlink`
+//! process, which checks the credentials of the peer and runs the regular CLI
+//! verbs (`bootc upgrade`, `bootc switch`, ...) as child processes, so that
+//! they keep their locking and behave exactly as if invoked directly. Their
+//! progress is read from `--progress-fd` and, if the client asked for `more`
+//! replies, streamed back as [`Event`]s.
+//!
+//! Root may call all methods. Other users are authorized via polkit: querying
+//! the status is checked against the `org.containers.bootc.status` action and
+//! everything else against `org.containers.bootc.manage`.
+//!
+//! [varlink]: https://varlink.org/
+
+use std::os::fd::{FromRawFd, OwnedFd, RawFd};
+use std::process::Stdio;
+use std::sync::Arc;
+
+use anyhow::{Context, Result};
+use cap_std_ext::cmdext::CapStdExtCommandExt;
+use fn_error_context::context;
+use rustix::net::UCred;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Map, Value};
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::net::unix::OwnedWriteHalf;
+
+use crate::progress_jsonl::Event;
+use crate::spec::Host;
+
+/// The name of our interface.
+const INTERFACE: &str = "org.containers.bootc";
+
+/// The description of our interface, as returned by `GetInterfaceDescription`.
+const INTERFACE_DESCRIPTION: &str = indoc::indoc! { r#"
+    # Manage a bootc host.
+    interface org.containers.bootc
+
+    # Return the host specification and status, as `bootc status --json`.
+    method GetStatus() -> (host: object)
+
+    # Fetch and stage an update, as `bootc upgrade`. If called with "more",
+    # progress events are streamed, as written by `--progress-fd`.
+    method Upgrade(check: ?bool, apply: ?bool) -> (progress: ?object)
+
+    # Fetch and stage another image, as `bootc switch`. If called with "more",
+    # progress events are streamed, as written by `--progress-fd`.
+    method Switch(image: string, transport: ?string, apply: ?bool) -> (progress: ?object)
+
+    # Queue the rollback deployment for the next boot, as `bootc rollback`.
+    # If called with "more", progress events are streamed.
+    method Rollback(apply: ?bool) -> (progress: ?object)
+
+    # Apply a changed host specification, as `bootc edit`.
+    method Edit(host: object) -> ()
+
+    # The caller is not authorized for the polkit action.
+    error PermissionDenied (action: string)
+
+    # The operation failed; exitCode is the one of the bootc command, if any.
+    error Failed (message: string, exitCode: ?int)
+"# };
+
+/// The description of the varlink service interface every service implements.
+const SERVICE_DESCRIPTION: &str = indoc::indoc! { r#"
+    # The Varlink Service Interface is provided by every varlink service.
+    interface org.varlink.service
+
+    # Get a list of all the interfaces a service provides and information
+    # about the implementation.
+    method GetInfo() -> (
+      vendor: string,
+      product: string,
+      version: string,
+      url: string,
+      interfaces: []string
+    )
+
+    # Get the description of an interface that is implemented by this service.
+    method GetInterfaceDescription(interface: string) -> (description: string)
+
+    error InterfaceNotFound (interface: string)
+    error MethodNotFound (method: string)
+    error MethodNotImplemented (method: string)
+    error InvalidParameter (parameter: string)
+    error PermissionDenied ()
+    error ExpectedMore ()
+"# };
+
+/// The polkit action for querying the status.
+const ACTION_STATUS: &str = "org.containers.bootc.status";
+/// The polkit action for changing the host.
+const ACTION_MANAGE: &str = "org.containers.bootc.manage";
+
+/// The first file descriptor passed by systemd.
+const SD_LISTEN_FDS_START: RawFd = 3;
+/// The file descriptor progress is written to by child processes.
+const PROGRESS_FD: i32 = 3;
+
+/// A method call.
+#[derive(Debug, Deserialize, PartialEq)]
+struct Call {
+    method: String,
+    #[serde(default)]
+    parameters: Option<Map<String, Value>>,
+    #[serde(default)]
+    more: bool,
+    #[serde(default)]
+    oneway: bool,
+}
+
+/// A reply to a method call.
+#[derive(Debug, Serialize, PartialEq)]
+struct Reply {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+    parameters: Value,
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    continues: bool,
+}
+
+impl Reply {
+    fn new(parameters: Value) -> Self {
+        Self {
+            error: None,
+            parameters,
+            continues: false,
+        }
+    }
+}
+
+/// An error returned to the client.
+#[derive(Debug, PartialEq, Eq)]
+enum Error {
+    InterfaceNotFound(String),
+    MethodNotFound(String),
+    InvalidParameter(String),
+    PermissionDenied(&'static str),
+    Failed {
+        message: String,
+        exit_code: Option<i32>,
+    },
+}
+
+impl From<anyhow::Error> for Error {
+    fn from(e: anyhow::Error) -> Self {
+        Self::Failed {
+            message: format!("{e:#}"),
+            exit_code: None,
+        }
+    }
+}
+
+impl From<Error> for Reply {
+    fn from(e: Error) -> Self {
+        let (error, parameters) = match e {
+            Error::InterfaceNotFound(interface) => (
+                "org.varlink.service.InterfaceNotFound".to_owned(),
+                json!({ "interface": interface }),
+            ),
+            Error::MethodNotFound(method) => (
+                "org.varlink.service.MethodNotFound".to_owned(),
+                json!({ "method": method }),
+            ),
+            Error::InvalidParameter(parameter) => (
+                "org.varlink.service.InvalidParameter".to_owned(),
+                json!({ "parameter": parameter }),
+            ),
+            Error::PermissionDenied(action) => (
+                format!("{INTERFACE}.PermissionDenied"),
+                json!({ "action": action }),
+            ),
+            Error::Failed { message, exit_code } => (
+                format!("{INTERFACE}.Failed"),
+                json!({ "message": message, "exitCode": exit_code }),
+            ),
+        };
+        Self {
+            error: Some(error),
+            parameters,
+            continues: false,
+        }
+    }
+}
+
+/// The parameters of `Upgrade`.
+#[derive(Debug, Default, Deserialize, PartialEq, Eq)]
+#[serde(deny_unknown_fields)]
+struct UpgradeParams {
+    #[serde(default)]
+    check: bool,
+    #[serde(default)]
+    apply: bool,
+}
+
+/// The parameters of `Switch`.
+#[derive(Debug, Default, Deserialize, PartialEq, Eq)]
+#[serde(deny_unknown_fields)]
+struct SwitchParams {
+    image: String,
+    #[serde(default)]
+    transport: Option<String>,
+    #[serde(default)]
+    apply: bool,
+}
+
+/// The parameters of `Rollback`.
+#[derive(Debug, Default, Deserialize, PartialEq, Eq)]
+#[serde(deny_unknown_fields)]
+struct RollbackParams {
+    #[serde(default)]
+    apply: bool,
+}
+
+/// The parameters of `Edit`.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct EditParams {
+    host: Host,
+}
+
+/// The parameters of `GetInterfaceDescription`.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct DescriptionParams {
+    interface: String,
+}
+
+/// Parse the parameters of a call.
+fn parse_params<T: serde::de::DeserializeOwned>(
+    params: Option<Map<String, Value>>,
+) -> Result<T, Error> {
+    let params = Value::Object(params.unwrap_or_default());
+    serde_json::from_value(params).map_err(|e| Error::InvalidParameter(e.to_string()))
+}
+
+/// The arguments for `bootc upgrade`.
+fn upgrade_args(params: &UpgradeParams) -> Result<Vec<String>, Error> {
+    if params.check && params.apply {
+        return Err(Error::InvalidParameter("apply".into()));
+    }
+    let mut args = ["upgrade", "--quiet"].map(ToOwned::to_owned).to_vec();
+    if params.check {
+        args.push("--check".into());
+    }
+    if params.apply {
+        args.push("--apply".into());
+    }
+    Ok(args)
+}
+
+/// The arguments for `bootc switch`.
+fn switch_args(params: &SwitchParams) -> Vec<String> {
+    let mut args = ["switch", "--quiet"].map(ToOwned::to_owned).to_vec();
+    if let Some(transport) = params.transport.as_deref() {
+        args.extend(["--transport".into(), transport.into()]);
+    }
+    if params.apply {
+        args.push("--apply".into());
+    }
+    args.push("--".into());
+    args.push(params.image.clone());
+    args
+}
+
+/// The polkit subject for a process, which includes its start time so that
+/// the pid cannot be reused to gain privileges.
+fn process_subject(pid: i32, uid: u32, stat: &str) -> Result<String> {
+    // The command name may contain spaces and parentheses; the fields after
+    // it start with the state, and the start time is the 22nd field overall.
+    let (_, fields) = stat.rsplit_once(')').context("Invalid stat")?;
+    let start_time = fields
+        .split_whitespace()
+        .nth(19)
+        .context("Missing start time in stat")?;
+    Ok(format!("{pid},{start_time},{uid}"))
+}
+
+/// Check whether the peer with credentials `cred` is authorized for the polkit
+/// action `action`.
+async fn authorize(cred: &UCred, action: &'static str) -> Result<(), Error> {
+    if cred.uid.is_root() {
+        return Ok(());
+    }
+    let pid = cred.pid.as_raw_nonzero().get();
+    let uid = cred.uid.as_raw();
+    let allowed = async {
+        let stat = tokio::fs::read_to_string(format!("/proc/{pid}/stat")).await?;
+        let subject = process_subject(pid, uid, &stat)?;
+        let status = tokio::process::Command::new("pkcheck")
+            .args(["--action-id", action, "--process", &subject])
+            .stdin(Stdio::null())
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .status()
+            .await?;
+        anyhow::Ok(status.success())
+    };
+    match allowed.await {
+        Ok(true) => Ok(()),
+        Ok(false) => Err(Error::PermissionDenied(action)),
+        Err(e) => {
+            // Without polkit, only root is authorized
+            tracing::debug!("Checking authorization: {e:#}");
+            Err(Error::PermissionDenied(action))
+        }
+    }
+}
+
+/// Writes replies to the client.
+struct Replier {
+    w: OwnedWriteHalf,
+    oneway: bool,
+}
+
+impl Replier {
+    async fn send(&mut self, reply: &Reply) -> Result<()> {
+        if self.oneway {
+            return Ok(());
+        }
+        let mut buf = serde_json::to_vec(reply)?;
+        buf.push(0);
+        self.w.write_all(&buf).await?;
+        Ok(())
+    }
+}
+
+/// Run `bootc` with `args` and return its exit status and standard error; if
+/// `progress` is set, the events it writes to `--progress-fd` are sent to the
+/// client as replies which continue.
+async fn spawn_bootc(
+    args: &[String],
+    progress: Option<&mut Replier>,
+) -> Result<(std::process::ExitStatus, String)> {
+    let exe = std::env::current_exe()?;
+    let mut cmd = std::process::Command::new(exe);
+    // The options of the verb, which is the first argument, may end with `--`
+    let (verb, verb_args) = args.split_first().context("Missing verb")?;
+    cmd.arg(verb);
+    cmd.stdin(Stdio::null());
+    cmd.stderr(Stdio::piped());
+    // Don't pass the socket activation on
+    cmd.env_remove("LISTEN_PID");
+    cmd.env_remove("LISTEN_FDS");
+    cmd.env_remove("LISTEN_FDNAMES");
+
+    let recv = if progress.is_some() {
+        let (send, recv) = tokio::net::unix::pipe::pipe()?;
+        cmd.take_fd_n(Arc::new(send.into_blocking_fd()?), PROGRESS_FD);
+        cmd.args(["--progress-fd", &PROGRESS_FD.to_string()]);
+        Some(recv)
+    } else {
+        None
+    };
+    cmd.args(verb_args);
+
+    tracing::debug!("Running bootc {args:?}");
+    let mut child = tokio::process::Command::from(cmd)
+        .spawn()
+        .context("Spawning bootc")?;
+    // Both pipes are always read until the end, even if the client went away,
+    // as bootc would otherwise fail writing to them in the middle of an operation.
+    let mut stderr = child.stderr.take().unwrap();
+    let read_stderr = async {
+        let mut buf = Vec::new();
+        stderr.read_to_end(&mut buf).await?;
+        anyhow::Ok(String::from_utf8_lossy(&buf).into_owned())
+    };
+    let forward_progress = async {
+        let (Some(recv), Some(replier)) = (recv, progress) else {
+            return;
+        };
+        let mut lines = BufReader::new(recv).lines();
+        let mut connected = true;
+        loop {
+            let line = match lines.next_line().await {
+                Ok(Some(line)) => line,
+                Ok(None) => break,
+                Err(e) => {
+                    tracing::warn!("Reading progress: {e}");
+                    let _ = tokio::io::copy(&mut lines.into_inner(), &mut tokio::io::sink()).await;
+                    break;
+                }
+            };
+            if !connected {
+                continue;
+            }
+            let event: Event = match serde_json::from_str(&line) {
+                Ok(e) => e,
+                Err(e) => {
+                    tracing::warn!("Invalid progress event: {e}");
+                    continue;
+                }
+            };
+            let reply = Reply {
+                continues: true,
+                ..Reply::new(json!({ "progress": event }))
+            };
+            if let Err(e) = replier.send(&reply).await {
+                tracing::debug!("Sending progress: {e:#}");
+                connected = false;
+            }
+        }
+    };
+    let (stderr, ()) = tokio::join!(read_stderr, forward_progress);
+    let status = child.wait().await?;
+    Ok((status, stderr?))
+}
+
+/// Run `bootc` with `args`, see [`spawn_bootc`].
+async fn run_bootc(args: &[String], progress: Option<&mut Replier>) -> Result<(), Error> {
+    let (status, stderr) = spawn_bootc(args, progress).await?;
+    if status.success() {
+        return Ok(());
+    }
+
+    eprint!("{stderr}");
+    let message = stderr
+        .lines()
+        .last()
+        .unwrap_or_default()
+        .trim_start_matches("error: ")
+        .to_owned();
+    Err(Error::Failed {
+        message,
+        exit_code: status.code(),
+    })
+}
+
+/// Handle a method call from the peer with credentials `cred`, returning the
+/// parameters of the final reply.
+async fn handle(call: Call, cred: &UCred, replier: &mut Replier) -> Result<Value, Error> {
+    let progress = call.more.then_some(replier);
+    match call.method.as_str() {
+        "org.varlink.service.GetInfo" => Ok(json!({
+            "vendor": "bootc",
+            "product": "bootc",
+            "version": env!("CARGO_PKG_VERSION"),
+            "url": env!("CARGO_PKG_REPOSITORY"),
+            "interfaces": ["org.varlink.service", INTERFACE],
+        })),
+        "org.varlink.service.GetInterfaceDescription" => {
+            let p: DescriptionParams = parse_params(call.parameters)?;
+            let description = match p.interface.as_str() {
+                INTERFACE => INTERFACE_DESCRIPTION,
+                "org.varlink.service" => SERVICE_DESCRIPTION,
+                _ => return Err(Error::InterfaceNotFound(p.interface)),
+            };
+            Ok(json!({ "description": description }))
+        }
+        "org.containers.bootc.GetStatus" => {
+            authorize(cred, ACTION_STATUS).await?;
+            let host = crate::status::get_host().await?;
+            Ok(json!({ "host": host }))
+        }
+        "org.containers.bootc.Upgrade" => {
+            let args = upgrade_args(&parse_params(call.parameters)?)?;
+            authorize(cred, ACTION_MANAGE).await?;
+            run_bootc(&args, progress).await?;
+            Ok(json!({}))
+        }
+        "org.containers.bootc.Switch" => {
+            let args = switch_args(&parse_params(call.parameters)?);
+            authorize(cred, ACTION_MANAGE).await?;
+            run_bootc(&args, progress).await?;
+            Ok(json!({}))
+        }
+        "org.containers.bootc.Rollback" => {
+            let p: RollbackParams = parse_params(call.parameters)?;
+            authorize(cred, ACTION_MANAGE).await?;
+            let mut args = vec!["rollback".to_owned()];
+            if p.apply {
+                args.push("--apply".into());
+            }
+            run_bootc(&args, progress).await?;
+            Ok(json!({}))
+        }
+        "org.containers.bootc.Edit" => {
+            let p: EditParams = parse_params(call.parameters)?;
+            authorize(cred, ACTION_MANAGE).await?;
+            // YAML is a superset of JSON
+            let tmpf = tempfile::NamedTempFile::new().context("Creating temporary file")?;
+            serde_json::to_writer(tmpf.as_file(), &p.host).context("Writing host")?;
+            let path = tmpf.path().to_str().context("Non-UTF8 temporary file")?;
+            let args = ["edit", "--quiet", "--filename", path].map(ToOwned::to_owned);
+            run_bootc(&args, None).await?;
+            Ok(json!({}))
+        }
+        method => Err(Error::MethodNotFound(method.to_owned())),
+    }
+}
+
+/// Take the connection passed by systemd for a socket with `Accept=yes`.
+fn activation_socket() -> Result<OwnedFd> {
+    let pid = std::env::var("LISTEN_PID").context("Not socket activated (no LISTEN_PID)")?;
+    anyhow::ensure!(
+        pid.parse::<u32>().ok() == Some(std::process::id()),
+        "LISTEN_PID does not match"
+    );
+    let fds = std::env::var("LISTEN_FDS").context("Not socket activated (no LISTEN_FDS)")?;
+    anyhow::ensure!(fds == "1", "Expected one socket, found LISTEN_FDS={fds}");
+    // SAFETY: systemd passed us this file descriptor
+    #[allow(unsafe_code)]
+    let fd = unsafe { OwnedFd::from_raw_fd(SD_LISTEN_FDS_START) };
+    rustix::io::fcntl_setfd(&fd, rustix::io::FdFlags::CLOEXEC)?;
+    Ok(fd)
+}
+
+/// Implementation of `bootc internals varlink`: serve the connection passed by
+/// `bootc-varlink.socket`.
+#[context("Serving varlink connection")]
+pub(crate) async fn serve() -> Result<()> {
+    let stream = std::os::unix::net::UnixStream::from(activation_socket()?);
+    stream.set_nonblocking(true)?;
+    let cred = rustix::net::sockopt::socket_peercred(&stream).context("Querying peer")?;
+    tracing::debug!("Serving peer {cred:?}");
+    let (r, w) = tokio::net::UnixStream::from_std(stream)?.into_split();
+    let mut r = BufReader::new(r);
+    let mut replier = Replier { w, oneway: false };
+
+    loop {
+        let mut buf = Vec::new();
+        if r.read_until(0, &mut buf).await? == 0 {
+            return Ok(());
+        }
+        anyhow::ensure!(buf.pop() == Some(0), "Truncated message");
+        let call: Call = serde_json::from_slice(&buf).context("Parsing call")?;
+        tracing::debug!("Call: {}", call.method);
+        replier.oneway = call.oneway;
+        let reply = match handle(call, &cred, &mut replier).await {
+            Ok(parameters) => Reply::new(parameters),
+            Err(e) => e.into(),
+        };
+        replier.send(&reply).await?;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_call() -> Result<()> {
+        let call: Call = serde_json::from_str(r#"{"method":"org.containers.bootc.GetStatus"}"#)?;
+        assert_eq!(call.method, "org.containers.bootc.GetStatus");
+        assert!(!call.more && !call.oneway);
+
+        let call: Call = serde_json::from_str(
+            r#"{"method":"org.containers.bootc.Switch","parameters":{"image":"quay.io/example/os:42"},"more":true}"#,
+        )?;
+        assert!(call.more);
+        let params: SwitchParams = parse_params(call.parameters).unwrap();
+        assert_eq!(
+            switch_args(&params),
+            ["switch", "--quiet", "--", "quay.io/example/os:42"]
+        );
+
+        // Missing and unknown parameters are rejected
+        assert!(matches!(
+            parse_params::<SwitchParams>(None),
+            Err(Error::InvalidParameter(_))
+        ));
+        let params = serde_json::from_str(r#"{"check":true,"force":true}"#)?;
+        assert!(matches!(
+            parse_params::<UpgradeParams>(Some(params)),
+            Err(Error::InvalidParameter(_))
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn test_args() {
+        let params = UpgradeParams::default();
+        assert_eq!(upgrade_args(&params).unwrap(), ["upgrade", "--quiet"]);
+        let params = UpgradeParams {
+            check: true,
+            apply: true,
+        };
+        assert_eq!(
+            upgrade_args(&params),
+            Err(Error::InvalidParameter("apply".into()))
+        );
+
+        let params = SwitchParams {
+            image: "/var/tmp/os.oci".into(),
+            transport: Some("oci".into()),
+            apply: true,
+        };
+        assert_eq!(
+            switch_args(&params),
+            [
+                "switch",
+                "--quiet",
+                "--transport",
+                "oci",
+                "--apply",
+                "--",
+                "/var/tmp/os.oci"
+            ]
+        );
+
+        // The image is never parsed as an option
+        let params = SwitchParams {
+            image: "--mutate-in-place".into(),
+            transport: None,
+            apply: false,
+        };
+        assert_eq!(
+            switch_args(&params),
+            ["switch", "--quiet", "--", "--mutate-in-place"]
+        );
+    }
+
+    #[test]
+    fn test_reply() -> Result<()> {
+        let reply = Reply::new(json!({}));
+        assert_eq!(serde_json::to_string(&reply)?, r#"{"parameters":{}}"#);
+
+        let reply = Reply::from(Error::PermissionDenied(ACTION_MANAGE));
+        assert_eq!(
+            serde_json::to_string(&reply)?,
+            r#"{"error":"org.containers.bootc.PermissionDenied","parameters":{"action":"org.containers.bootc.manage"}}"#
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_process_subject() -> Result<()> {
+        let stat = "4242 (my (agent)) S 1 4242 4242 0 -1 4194560 1234 0 0 0 5 3 0 0 20 0 1 0 987654 12345678 900 18446744073709551615";
+        assert_eq!(process_subject(4242, 1000, stat)?, "4242,987654,1000");
+        assert!(process_subject(4242, 1000, "4242 (agent) S 1").is_err());
+        Ok(())
+    }
+}
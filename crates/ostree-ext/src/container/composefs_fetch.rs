@@ -0,0 +1,227 @@
+//! Fetching container image layers into a composefs repository
+//!
+//! [`composefs_oci::pull`] does not report progress, so the layers are fetched here
+//! first, reporting progress the same way as [`ImageImporter`]; pulling the image
+//! afterwards finds the layers in the repository and only writes the configuration.
+//!
+//! [`ImageImporter`]: super::store::ImageImporter
+
+use std::str::FromStr;
+use std::sync::Arc;
+
+use anyhow::{Context, Result};
+use composefs::fsverity::FsVerityHashValue;
+use composefs::repository::Repository;
+use composefs::util::{parse_sha256, Sha256Digest};
+use containers_image_proxy::{ImageProxy, ImageProxyConfig, OpenedImage};
+use fn_error_context::context;
+use oci_spec::image::{Descriptor, Digest, ImageConfiguration, ImageManifest};
+use tokio::sync::mpsc::{Receiver, Sender};
+
+use super::store::{ImportProgress, LayerProgress};
+use super::{ImageReference, OstreeImageReference, SignatureSource, Transport};
+use crate::generic_decompress::Decompressor;
+
+/// A layer of a container image, and its state in a composefs repository
+#[derive(Debug)]
+pub struct ComposefsLayer<ObjectID> {
+    /// The layer as described by the manifest
+    pub layer: Descriptor,
+    /// The digest of the uncompressed layer, which identifies it in the repository
+    pub diff_id: Sha256Digest,
+    /// The layer's splitstream, if it is already in the repository
+    pub object: Option<ObjectID>,
+}
+
+/// Information about a container image to be fetched into a composefs repository
+#[derive(Debug)]
+pub struct ComposefsPreparedImport<ObjectID> {
+    /// The manifest digest of the image
+    pub manifest_digest: Digest,
+    /// The manifest
+    pub manifest: ImageManifest,
+    /// The configuration
+    pub config: ImageConfiguration,
+    /// All layers of the image
+    pub layers: Vec<ComposefsLayer<ObjectID>>,
+}
+
+impl<ObjectID> ComposefsPreparedImport<ObjectID> {
+    /// Iterate over the layers which are not in the repository yet
+    pub fn layers_to_fetch(&self) -> impl Iterator<Item = &ComposefsLayer<ObjectID>> {
+        self.layers.iter().filter(|l| l.object.is_none())
+    }
+
+    /// Describe the layers which are present and which need to be fetched, if any
+    pub fn format_layer_status(&self) -> Option<String> {
+        let stored = self.layers.len() - self.layers_to_fetch().count();
+        let to_fetch = self.layers_to_fetch().count();
+        let to_fetch_size: u64 = self.layers_to_fetch().map(|l| l.layer.size()).sum();
+        (to_fetch > 0).then(|| {
+            let size = crate::glib::format_size(to_fetch_size);
+            format!("layers already present: {stored}; layers needed: {to_fetch} ({size})")
+        })
+    }
+}
+
+/// Check that the signature verification requested by `imgref` can be enforced when
+/// fetching it into a composefs repository, and return the signers the image must
+/// be signed by; this is empty if no verification was requested.
+///
+/// The container image proxy always enforces containers-policy.json, so this only
+/// needs to ensure that the policy actually requires a signature for the image.
+#[context("Checking signature policy for {imgref}")]
+pub fn check_signature_policy(imgref: &OstreeImageReference) -> Result<Vec<String>> {
+    match &imgref.sigverify {
+        SignatureSource::ContainerPolicyAllowInsecure => Ok(Vec::new()),
+        SignatureSource::OstreeRemote(_) => {
+            anyhow::bail!("Cannot verify signatures via ostree remote for composefs")
+        }
+        SignatureSource::ContainerPolicy => {
+            let signers = super::skopeo::container_policy_required_signers(&imgref.imgref)?;
+            anyhow::ensure!(
+                !signers.is_empty(),
+                "containers-policy.json does not require a signature for {}; refusing usage",
+                imgref.imgref
+            );
+            Ok(signers)
+        }
+    }
+}
+
+/// Fetches the layers of a container image into a composefs repository
+pub struct ComposefsImporter<ObjectID: FsVerityHashValue> {
+    repo: Arc<Repository<ObjectID>>,
+    proxy: ImageProxy,
+    proxy_img: OpenedImage,
+    transport: Transport,
+
+    layer_progress: Option<Sender<ImportProgress>>,
+    layer_byte_progress: Option<tokio::sync::watch::Sender<Option<LayerProgress>>>,
+}
+
+impl<ObjectID: FsVerityHashValue> ComposefsImporter<ObjectID> {
+    /// Open the image `imgref`, in the `transport:name` form understood by skopeo.
+    #[context("Opening {imgref}")]
+    pub async fn new(
+        repo: Arc<Repository<ObjectID>>,
+        imgref: &str,
+        config: ImageProxyConfig,
+    ) -> Result<Self> {
+        let transport = ImageReference::try_from(imgref)?.transport;
+        let proxy = ImageProxy::new_with_config(config).await?;
+        let proxy_img = proxy.open_image(imgref).await?;
+        Ok(Self {
+            repo,
+            proxy,
+            proxy_img,
+            transport,
+            layer_progress: None,
+            layer_byte_progress: None,
+        })
+    }
+
+    /// Create a channel receiver that will get notifications for layer fetches.
+    pub fn request_progress(&mut self) -> Receiver<ImportProgress> {
+        assert!(self.layer_progress.is_none());
+        let (s, r) = tokio::sync::mpsc::channel(2);
+        self.layer_progress = Some(s);
+        r
+    }
+
+    /// Create a channel receiver that will get notifications for byte-level progress of layer fetches.
+    pub fn request_layer_progress(
+        &mut self,
+    ) -> tokio::sync::watch::Receiver<Option<LayerProgress>> {
+        assert!(self.layer_byte_progress.is_none());
+        let (s, r) = tokio::sync::watch::channel(None);
+        self.layer_byte_progress = Some(s);
+        r
+    }
+
+    /// Fetch the manifest and configuration, and determine which layers are present.
+    #[context("Preparing import")]
+    pub async fn prepare(&self) -> Result<ComposefsPreparedImport<ObjectID>> {
+        let (manifest_digest, manifest) = self.proxy.fetch_manifest(&self.proxy_img).await?;
+        let manifest_digest = Digest::from_str(&manifest_digest)?;
+        let config = self.proxy.fetch_config(&self.proxy_img).await?;
+
+        let diff_ids = config.rootfs().diff_ids();
+        anyhow::ensure!(
+            diff_ids.len() == manifest.layers().len(),
+            "Mismatched number of layers ({}) and diff IDs ({})",
+            manifest.layers().len(),
+            diff_ids.len()
+        );
+        let layers = manifest
+            .layers()
+            .iter()
+            .zip(diff_ids)
+            .map(|(layer, diff_id)| {
+                let diff_id = parse_sha256(diff_id.strip_prefix("sha256:").unwrap_or(diff_id))
+                    .with_context(|| format!("Parsing diff ID {diff_id}"))?;
+                let object = self.repo.has_stream(&diff_id)?;
+                Ok(ComposefsLayer {
+                    layer: layer.clone(),
+                    diff_id,
+                    object,
+                })
+            })
+            .collect::<Result<_>>()?;
+
+        Ok(ComposefsPreparedImport {
+            manifest_digest,
+            manifest,
+            config,
+            layers,
+        })
+    }
+
+    /// Fetch the layers which are not in the repository yet.
+    #[context("Fetching layers")]
+    pub async fn import(self, prep: &ComposefsPreparedImport<ObjectID>) -> Result<()> {
+        let des_layers = self.proxy.get_layer_info(&self.proxy_img).await?;
+        for layer in prep.layers_to_fetch() {
+            if let Some(p) = self.layer_progress.as_ref() {
+                p.send(ImportProgress::DerivedLayerStarted(layer.layer.clone()))
+                    .await?;
+            }
+            let (blob, driver, media_type) = super::unencapsulate::fetch_layer(
+                &self.proxy,
+                &self.proxy_img,
+                &prep.manifest,
+                &layer.layer,
+                self.layer_byte_progress.as_ref(),
+                des_layers.as_ref(),
+                self.transport,
+            )
+            .await?;
+            let repo = Arc::clone(&self.repo);
+            let diff_id = layer.diff_id;
+            let import_task = crate::tokio_util::spawn_blocking_flatten(move || {
+                let blob = tokio_util::io::SyncIoBridge::new(blob);
+                let mut blob = Decompressor::new(&media_type, blob)?;
+                composefs_oci::import_layer(&repo, &diff_id, None, &mut blob)?;
+                blob.finish()?;
+                Ok(())
+            });
+            super::unencapsulate::join_fetch(import_task, driver)
+                .await
+                .with_context(|| format!("Layer {}", layer.layer.digest()))?;
+            if let Some(p) = self.layer_progress.as_ref() {
+                p.send(ImportProgress::DerivedLayerCompleted(layer.layer.clone()))
+                    .await?;
+            }
+        }
+
+        // The progress notifiers are disconnected when we return, to signal we're done.
+        let Self {
+            proxy, proxy_img, ..
+        } = self;
+        proxy.close_image(&proxy_img).await?;
+        // We're done with the proxy, make sure it didn't have any errors.
+        proxy.finalize().await?;
+
+        Ok(())
+    }
+}
@@ -1,12 +1,13 @@
 //! Fork skopeo as a subprocess
 
-use super::ImageReference;
+use super::{ImageReference, Transport};
 use anyhow::{Context, Result};
 use cap_std_ext::cmdext::CapStdExtCommandExt;
 use containers_image_proxy::oci_spec::image as oci_image;
 use fn_error_context::context;
 use io_lifetimes::OwnedFd;
 use serde::Deserialize;
+use std::collections::HashMap;
 use std::io::Read;
 use std::path::Path;
 use std::process::Stdio;
@@ -20,14 +21,92 @@ use tokio::process::Command;
 const POLICY_PATH: &str = "/etc/containers/policy.json";
 const INSECURE_ACCEPT_ANYTHING: &str = "insecureAcceptAnything";
 
+const REJECT: &str = "reject";
+
 #[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
 struct PolicyEntry {
     #[serde(rename = "type")]
     ty: String,
+    key_path: Option<String>,
+    #[serde(default)]
+    key_paths: Vec<String>,
+    key_data: Option<String>,
+    fulcio: Option<FulcioEntry>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct FulcioEntry {
+    subject_email: Option<String>,
 }
+
 #[derive(Deserialize)]
 struct ContainerPolicy {
     default: Option<Vec<PolicyEntry>>,
+    #[serde(default)]
+    transports: HashMap<String, HashMap<String, Vec<PolicyEntry>>>,
+}
+
+impl PolicyEntry {
+    /// Describe who must have signed an image for it to satisfy this requirement,
+    /// if it requires a signature at all.
+    fn signer(&self) -> Option<String> {
+        if self.ty == INSECURE_ACCEPT_ANYTHING || self.ty == REJECT {
+            return None;
+        }
+        let email = self
+            .fulcio
+            .as_ref()
+            .and_then(|f| f.subject_email.as_deref());
+        let identity = if let Some(email) = email {
+            email.to_owned()
+        } else if let Some(path) = self.key_path.as_deref() {
+            path.to_owned()
+        } else if !self.key_paths.is_empty() {
+            self.key_paths.join(" or ")
+        } else if self.key_data.is_some() {
+            "inline key".to_owned()
+        } else {
+            return Some(self.ty.clone());
+        };
+        Some(format!("{}:{identity}", self.ty))
+    }
+}
+
+/// The name of a transport in containers-policy.json
+fn policy_transport_name(transport: Transport) -> &'static str {
+    match transport {
+        Transport::Registry => "docker",
+        Transport::OciDir => "oci",
+        Transport::OciArchive => "oci-archive",
+        Transport::DockerArchive => "docker-archive",
+        Transport::ContainerStorage => "containers-storage",
+        Transport::Dir => "dir",
+        Transport::DockerDaemon => "docker-daemon",
+    }
+}
+
+/// The scopes which may match an image name, from the most to the least specific.
+/// Wildcard scopes (`*.example.com`) are not supported.
+fn policy_scopes(name: &str) -> Vec<&str> {
+    let repo = match name.split_once('@') {
+        Some((repo, _)) => repo,
+        None => match name.rsplit_once(':') {
+            Some((repo, tag)) if !tag.contains('/') => repo,
+            _ => name,
+        },
+    };
+    let mut scopes = vec![name];
+    let mut scope = Some(repo);
+    while let Some(s) = scope {
+        if scopes.last() != Some(&s) {
+            scopes.push(s);
+        }
+        scope = s.rsplit_once('/').map(|(parent, _)| parent);
+    }
+    scopes.push("");
+    scopes
 }
 
 impl ContainerPolicy {
@@ -41,12 +120,43 @@ impl ContainerPolicy {
             false
         }
     }
+
+    /// The requirements which apply to an image
+    fn requirements_for(&self, imgref: &ImageReference) -> &[PolicyEntry] {
+        self.transports
+            .get(policy_transport_name(imgref.transport))
+            .and_then(|scopes| {
+                policy_scopes(&imgref.name)
+                    .into_iter()
+                    .find_map(|scope| scopes.get(scope))
+            })
+            .or(self.default.as_ref())
+            .map(Vec::as_slice)
+            .unwrap_or_default()
+    }
+
+    /// The signers which the policy requires for an image; empty if the image is
+    /// accepted without a signature.
+    fn required_signers(&self, imgref: &ImageReference) -> Vec<String> {
+        self.requirements_for(imgref)
+            .iter()
+            .filter_map(PolicyEntry::signer)
+            .collect()
+    }
 }
 
-pub(crate) fn container_policy_is_default_insecure() -> Result<bool> {
+fn load_container_policy() -> Result<ContainerPolicy> {
     let r = std::io::BufReader::new(std::fs::File::open(POLICY_PATH)?);
-    let policy: ContainerPolicy = serde_json::from_reader(r)?;
-    Ok(policy.is_default_insecure())
+    Ok(serde_json::from_reader(r)?)
+}
+
+pub(crate) fn container_policy_is_default_insecure() -> Result<bool> {
+    Ok(load_container_policy()?.is_default_insecure())
+}
+
+/// The signers which containers-policy.json requires for an image.
+pub(crate) fn container_policy_required_signers(imgref: &ImageReference) -> Result<Vec<String>> {
+    Ok(load_container_policy()?.required_signers(imgref))
 }
 
 /// Create a Command builder for skopeo.
@@ -144,6 +254,32 @@ mod tests {
     }
     "#};
 
+    const SIGSTORE_SIGNED: &str = indoc::indoc! { r#"
+    {
+        "default": [{"type": "insecureAcceptAnything"}],
+        "transports": {
+            "docker": {
+                "quay.io/exampleos": [
+                    {
+                        "type": "sigstoreSigned",
+                        "keyPath": "/etc/pki/containers/exampleos.pub"
+                    }
+                ],
+                "quay.io": [
+                    {
+                        "type": "sigstoreSigned",
+                        "fulcio": {
+                            "caPath": "/etc/pki/containers/fulcio.pem",
+                            "oidcIssuer": "https://example.com",
+                            "subjectEmail": "builder@example.com"
+                        }
+                    }
+                ]
+            }
+        }
+    }
+    "#};
+
     #[test]
     fn policy_is_insecure() {
         let p: ContainerPolicy = serde_json::from_str(DEFAULT_POLICY).unwrap();
@@ -153,4 +289,51 @@ mod tests {
             assert!(!p.is_default_insecure());
         }
     }
+
+    #[test]
+    fn policy_scopes() {
+        assert_eq!(
+            super::policy_scopes("hostname:5000/myns/official:latest"),
+            [
+                "hostname:5000/myns/official:latest",
+                "hostname:5000/myns/official",
+                "hostname:5000/myns",
+                "hostname:5000",
+                ""
+            ]
+        );
+        assert_eq!(
+            super::policy_scopes("quay.io/foo@sha256:abcd"),
+            ["quay.io/foo@sha256:abcd", "quay.io/foo", "quay.io", ""]
+        );
+        assert_eq!(super::policy_scopes("localhost"), ["localhost", ""]);
+    }
+
+    #[test]
+    fn policy_signers() {
+        let imgref = |s: &str| ImageReference::try_from(s).unwrap();
+        let p: ContainerPolicy = serde_json::from_str(DEFAULT_POLICY).unwrap();
+        assert!(p
+            .required_signers(&imgref("docker://quay.io/foo/bar"))
+            .is_empty());
+
+        let p: ContainerPolicy = serde_json::from_str(REASONABLY_LOCKED_DOWN).unwrap();
+        assert!(p.required_signers(&imgref("dir:/tmp/foo")).is_empty());
+        assert!(p
+            .required_signers(&imgref("docker://quay.io/foo/bar"))
+            .is_empty());
+
+        let p: ContainerPolicy = serde_json::from_str(SIGSTORE_SIGNED).unwrap();
+        assert_eq!(
+            p.required_signers(&imgref("docker://quay.io/exampleos/os:latest")),
+            ["sigstoreSigned:/etc/pki/containers/exampleos.pub"]
+        );
+        assert_eq!(
+            p.required_signers(&imgref("docker://quay.io/other/os:latest")),
+            ["sigstoreSigned:builder@example.com"]
+        );
+        assert!(p
+            .required_signers(&imgref("containers-storage:localhost/os"))
+            .is_empty());
+    }
 }
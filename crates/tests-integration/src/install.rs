@@ -93,6 +93,46 @@ pub(crate) fn run_alongside(image: &str, mut testargs: libtest_mimic::Arguments)
             cmd!(sh, "sudo {BASE_ARGS...} -v {tmpdisk}:/disk {image} bootc install to-disk --via-loopback /disk").run()?;
             Ok(())
         }),
+        Trial::test("loopback install with progress", move || {
+            let sh = &xshell::Shell::new()?;
+            reset_root(sh, image)?;
+            let size = 10 * 1000 * 1000 * 1000;
+            let mut tmpdisk = tempfile::NamedTempFile::new_in("/var/tmp")?;
+            tmpdisk.as_file_mut().set_len(size)?;
+            let tmpdisk = tmpdisk.into_temp_path();
+            let tmpdisk = tmpdisk.to_str().unwrap();
+            let tmpd = &sh.create_temp_dir()?;
+            let progress = tmpd.path().join("progress.jsonl");
+            let progress = progress.to_str().unwrap();
+            // The progress fd must be a pipe, so route fd 3 through cat
+            let base_args = BASE_ARGS.join(" ");
+            let script = format!("set -o pipefail; {base_args} --preserve-fds=1 -v {tmpdisk}:/disk {image} bootc install to-disk --progress-fd 3 --via-loopback /disk 3>&1 1>&2 | cat > {progress}");
+            cmd!(sh, "sudo /bin/bash -c {script}").run()?;
+            let events = std::fs::read_to_string(progress)?
+                .lines()
+                .map(serde_json::from_str)
+                .collect::<Result<Vec<serde_json::Value>, _>>()?;
+            assert_eq!(events[0]["type"], "Start");
+            let phases = events
+                .iter()
+                .filter(|e| e["type"] == "PhaseBegin")
+                .map(|e| e["phase"].as_str().unwrap())
+                .collect::<Vec<_>>();
+            for phase in [
+                "partition",
+                "mkfs",
+                "pull",
+                "deploy",
+                "bootloader",
+                "finalize",
+            ] {
+                assert!(phases.contains(&phase), "Missing phase {phase}");
+            }
+            let last = events.last().unwrap();
+            assert_eq!(last["type"], "Result");
+            assert_eq!(last["outcome"], "success");
+            Ok(())
+        }),
         Trial::test(
             "replace=alongside with ssh keys and a karg, and SELinux disabled",
             move || {
@@ -20,6 +20,31 @@ pub use result_ext::*;
 /// The name of our binary
 pub const NAME: &str = "bootc";
 
+/// An error which makes [`run_main`] exit with `code` instead of 1, so that
+/// callers can tell specific failures apart from others.
+#[derive(Debug)]
+pub struct ExitCodeError {
+    /// The exit status
+    pub code: i32,
+    /// A description of the error
+    pub message: String,
+}
+
+impl std::fmt::Display for ExitCodeError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.message)
+    }
+}
+
+impl std::error::Error for ExitCodeError {}
+
+/// The exit status for an error; see [`ExitCodeError`].
+fn exit_code(e: &anyhow::Error) -> i32 {
+    e.chain()
+        .find_map(|e| e.downcast_ref::<ExitCodeError>())
+        .map_or(1, |e| e.code)
+}
+
 /// Intended for use in `main`, calls an inner function and
 /// handles errors by printing them.
 pub fn run_main<F>(f: F)
@@ -34,6 +59,26 @@ where
         let mut stderr = anstream::stderr();
         // Don't panic if writing fails.
         let _ = writeln!(stderr, "{}{:#}", "error: ".red(), e);
-        std::process::exit(1);
+        std::process::exit(exit_code(&e));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anyhow::Context as _;
+
+    #[test]
+    fn test_exit_code() {
+        assert_eq!(exit_code(&anyhow::anyhow!("failed")), 1);
+
+        let e = ExitCodeError {
+            code: 28,
+            message: "No space".into(),
+        };
+        let r: anyhow::Result<()> = Err(e.into());
+        let e = r.context("Upgrading").context("Pulling").unwrap_err();
+        assert_eq!(exit_code(&e), 28);
+        assert_eq!(format!("{e:#}"), "Pulling: Upgrading: No space");
     }
 }